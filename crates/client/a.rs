@@ -0,0 +1 @@
+fn a() {}
\ No newline at end of file
@@ -0,0 +1,32 @@
+//! Hosts a session from a program instead of an editor: prints whatever the
+//! peers type into `notes.md`. Join it with `JustSync --mode peer --remote-ip
+//! 127.0.0.1 --token <token>` from another (empty) directory.
+
+use justsync::JustSync;
+
+#[tokio::main]
+async fn main() {
+    let engine = JustSync::builder()
+        .host(4444)
+        .on_edit(|edit| {
+            for change in &edit.edits {
+                println!(
+                    "{} {}:{} {:?}",
+                    edit.uri,
+                    change.range.start.line,
+                    change.range.start.character,
+                    change.new_text
+                );
+            }
+        })
+        .on_error(|e| eprintln!("Network stopped: {}", e))
+        .start()
+        .await
+        .expect("Could not start the engine");
+
+    println!("Token: {}", engine.token().unwrap_or_default());
+    engine.open_document("notes.md", "# Notes\n").await;
+
+    let _ = tokio::signal::ctrl_c().await;
+    engine.shutdown().await;
+}
@@ -0,0 +1,154 @@
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{Value, json};
+
+use crate::logger;
+
+static AUDIT_FILE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Starts the audit trail: who connected from where and which files they
+/// touched, one JSON object per line in `path`. Separate from the debug
+/// log, and off unless this is called.
+pub fn init(path: PathBuf) {
+    let _ = AUDIT_FILE.set(path);
+}
+
+/// The audit entries of one connection. `connect` is written right away,
+/// the rest as it happens.
+pub struct ConnectionAudit {
+    path: Option<PathBuf>,
+    peer: usize,
+    address: String,
+    room: String,
+    /// Who spoke over the connection, (agent id, display name)
+    agents: Vec<(String, String)>,
+    files: HashSet<String>,
+}
+
+impl ConnectionAudit {
+    pub fn connected(peer: usize, address: String, room: String) -> Self {
+        Self::open(AUDIT_FILE.get().cloned(), peer, address, room)
+    }
+
+    fn open(path: Option<PathBuf>, peer: usize, address: String, room: String) -> Self {
+        let audit = Self {
+            path,
+            peer,
+            address,
+            room,
+            agents: Vec::new(),
+            files: HashSet::new(),
+        };
+        audit.record("connect", json!({ "room": audit.room }));
+        audit
+    }
+
+    /// Someone introduced themselves over this connection.
+    pub fn identified(&mut self, agent_id: &str, display_name: &str) {
+        let agent = (agent_id.to_string(), display_name.to_string());
+        if self.agents.contains(&agent) {
+            return;
+        }
+        self.record(
+            "hello",
+            json!({ "agent_id": agent_id, "display_name": display_name }),
+        );
+        self.agents.push(agent);
+    }
+
+    /// The other side read or changed `uri`. Only the first time is written.
+    pub fn accessed(&mut self, uri: &str) {
+        if self.path.is_none() || !self.files.insert(uri.to_string()) {
+            return;
+        }
+        self.record("file", json!({ "uri": uri }));
+    }
+
+    pub fn disconnected(&self, reason: &str) {
+        let agents: Vec<Value> = self
+            .agents
+            .iter()
+            .map(|(agent_id, display_name)| {
+                json!({ "agent_id": agent_id, "display_name": display_name })
+            })
+            .collect();
+        self.record(
+            "disconnect",
+            json!({ "reason": reason, "agents": agents, "files": self.files.len() }),
+        );
+    }
+
+    fn record(&self, event: &str, fields: Value) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let mut entry = json!({
+            "time_ms": SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis() as u64),
+            "event": event,
+            "peer": self.peer,
+            "address": self.address,
+        });
+        if let (Value::Object(entry), Value::Object(fields)) = (&mut entry, fields) {
+            entry.extend(fields);
+        }
+        if let Err(e) = append_line(path, &entry.to_string()) {
+            logger::warn(&format!("!! [Audit] Can't write {}: {}", path.display(), e));
+        }
+    }
+}
+
+fn append_line(path: &Path, line: &str) -> std::io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(path: &Path) -> Vec<Value> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).expect("audit line isn't JSON"))
+            .collect()
+    }
+
+    #[test]
+    fn test_connect_is_audited() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+
+        let mut audit =
+            ConnectionAudit::open(Some(path.clone()), 7, "10.0.0.2:5000".into(), "team".into());
+        let connect = &entries(&path)[0];
+        assert_eq!(connect["event"], "connect");
+        assert_eq!(connect["peer"], 7);
+        assert_eq!(connect["address"], "10.0.0.2:5000");
+        assert_eq!(connect["room"], "team");
+        assert!(connect["time_ms"].as_u64().unwrap() > 0);
+
+        audit.identified("agent-1", "alice");
+        audit.accessed("src/main.rs");
+        audit.accessed("src/main.rs");
+        audit.disconnected("left");
+
+        let entries = entries(&path);
+        let events: Vec<&str> = entries
+            .iter()
+            .map(|e| e["event"].as_str().unwrap())
+            .collect();
+        assert_eq!(events, vec!["connect", "hello", "file", "disconnect"]);
+        assert_eq!(entries[1]["display_name"], "alice");
+        assert_eq!(entries[2]["uri"], "src/main.rs");
+        assert_eq!(entries[3]["agents"][0]["agent_id"], "agent-1");
+        assert_eq!(entries[3]["reason"], "left");
+    }
+}
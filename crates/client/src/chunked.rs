@@ -0,0 +1,308 @@
+use diamond_types::list::{ListCRDT, encoding::EncodeOptions};
+use ropey::Rope;
+use serde::{Deserialize, Serialize};
+
+use crate::lsp::{Position, Range, TextEdit, char_to_position, utf16_to_char_offset};
+
+/// Documents at or above this many bytes are split into independent CRDT segments.
+pub const LARGE_DOC_THRESHOLD: usize = 1024 * 1024;
+
+/// Rough size of one segment. Segments are cut at the next line break after this many chars.
+pub const SEGMENT_TARGET: usize = 64 * 1024;
+
+/// Magic prefix of a diamond-types encoding, used to tell plain patches from chunked ones.
+const DT_MAGIC: &[u8] = b"DMNDTYPS";
+
+/// Wire format of a chunked patch: the encoded oplog of every touched segment.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChunkedPatch {
+    pub segments: Vec<Segment>,
+}
+
+/// One touched segment of a chunked patch.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Segment {
+    pub index: usize,
+    #[serde(with = "crate::framing::base64")]
+    pub oplog: Vec<u8>,
+}
+
+impl ChunkedPatch {
+    pub fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+
+    /// Returns `None` for anything that is not a chunked patch (e.g. a plain oplog).
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        if data.starts_with(DT_MAGIC) {
+            return None;
+        }
+        serde_json::from_slice(data).ok()
+    }
+}
+
+/// A large document, partitioned into line blocks that each carry their own CRDT.
+/// An edit only touches (and re-encodes) the segments it overlaps.
+pub struct ChunkedCrdt {
+    pub segments: Vec<ListCRDT>,
+}
+
+impl ChunkedCrdt {
+    pub fn from_content(content: &str) -> Self {
+        let mut segments = Vec::new();
+        let mut rest = content;
+        while !rest.is_empty() {
+            let cut = split_point(rest);
+            let mut crdt = ListCRDT::new();
+            let agent = crdt.get_or_create_agent_id("init");
+            crdt.insert(agent, 0, &rest[..cut]);
+            segments.push(crdt);
+            rest = &rest[cut..];
+        }
+        Self { segments }
+    }
+
+    pub fn empty() -> Self {
+        Self {
+            segments: Vec::new(),
+        }
+    }
+
+    pub fn len_chars(&self) -> usize {
+        self.segments.iter().map(|s| s.branch.len()).sum()
+    }
+
+    pub fn content(&self) -> String {
+        self.segments
+            .iter()
+            .map(|s| s.branch.content().to_string())
+            .collect()
+    }
+
+    /// Replaces `start..end` (global char offsets) with `text`.
+    /// Returns the indices of all segments that changed.
+    pub fn replace(&mut self, agent: &str, start: usize, end: usize, text: &str) -> Vec<usize> {
+        if self.segments.is_empty() {
+            self.segments.push(ListCRDT::new());
+        }
+
+        let mut touched = Vec::new();
+        let mut seg_start = 0;
+        let mut insert_at = None;
+
+        for (idx, seg) in self.segments.iter_mut().enumerate() {
+            let seg_len = seg.branch.len();
+            let seg_end = seg_start + seg_len;
+
+            // Insert position: the first segment that contains (or ends at) `start`
+            if insert_at.is_none() && start <= seg_end {
+                insert_at = Some((idx, start - seg_start));
+            }
+
+            // Deletion part that overlaps with this segment
+            let del_start = start.max(seg_start);
+            let del_end = end.min(seg_end);
+            if del_start < del_end {
+                let agent_id = seg.get_or_create_agent_id(agent);
+                seg.delete(agent_id, (del_start - seg_start)..(del_end - seg_start));
+                touched.push(idx);
+            }
+
+            seg_start = seg_end;
+            if seg_start >= end && insert_at.is_some() {
+                break;
+            }
+        }
+
+        if !text.is_empty() {
+            let (idx, local) = insert_at.unwrap_or_else(|| {
+                let last = self.segments.len() - 1;
+                (last, self.segments[last].branch.len())
+            });
+            let seg = &mut self.segments[idx];
+            let agent_id = seg.get_or_create_agent_id(agent);
+            seg.insert(agent_id, local, text);
+            if !touched.contains(&idx) {
+                touched.push(idx);
+            }
+        }
+
+        touched
+    }
+
+    pub fn encode_segments(&self, indices: &[usize]) -> Vec<u8> {
+        let segments = indices
+            .iter()
+            .map(|&index| Segment {
+                index,
+                oplog: self.segments[index].oplog.encode(EncodeOptions::default()),
+            })
+            .collect();
+        ChunkedPatch { segments }.encode()
+    }
+
+    pub fn encode_all(&self) -> Vec<u8> {
+        let all: Vec<usize> = (0..self.segments.len()).collect();
+        self.encode_segments(&all)
+    }
+
+    /// Merges a remote patch and reports what changed relative to `old` (the
+    /// document content before the merge). Only touched segments are diffed.
+    pub fn apply_patch(
+        &mut self,
+        patch: ChunkedPatch,
+        old: &Rope,
+    ) -> Result<(Rope, Vec<TextEdit>), diamond_types::list::encoding::encode_tools::ParseError>
+    {
+        // Where every segment started before the merge
+        let mut starts = Vec::with_capacity(self.segments.len());
+        let mut acc = 0;
+        for seg in &self.segments {
+            starts.push(acc);
+            acc += seg.branch.len();
+        }
+
+        let mut changed = Vec::new();
+        for Segment { index: idx, oplog } in patch.segments {
+            while self.segments.len() <= idx {
+                starts.push(acc);
+                self.segments.push(ListCRDT::new());
+            }
+            let seg = &mut self.segments[idx];
+            let old_text = seg.branch.content().to_string();
+            seg.oplog.decode_and_add(&oplog)?;
+            seg.branch.merge(&seg.oplog, seg.oplog.local_version_ref());
+            changed.push((idx, old_text));
+        }
+
+        // Edits are computed against the old document, back to front so the
+        // rope offsets stay valid while we splice in the new segment content.
+        changed.sort_by_key(|(idx, _)| std::cmp::Reverse(*idx));
+        let mut new_rope = old.clone();
+        let mut edits = Vec::new();
+        for (idx, old_text) in changed {
+            let seg_start = starts[idx];
+            let old_seg = Rope::from_str(&old_text);
+            let new_seg = Rope::from_str(&self.segments[idx].branch.content().to_string());
+
+            for edit in crate::diff::calculate_edits(&old_seg, &new_seg) {
+                let start = seg_start + position_to_char(&old_seg, &edit.range.start);
+                let end = seg_start + position_to_char(&old_seg, &edit.range.end);
+                edits.push(TextEdit {
+                    range: Range {
+                        start: char_to_position(old, start),
+                        end: char_to_position(old, end),
+                    },
+                    new_text: edit.new_text,
+                });
+            }
+
+            let old_len = old_seg.len_chars();
+            new_rope.remove(seg_start..seg_start + old_len);
+            new_rope.insert(seg_start, &new_seg.to_string());
+        }
+
+        Ok((new_rope, edits))
+    }
+}
+
+/// Byte index at which to end the next segment of `rest`.
+fn split_point(rest: &str) -> usize {
+    let mut chars = 0;
+    for (byte_idx, ch) in rest.char_indices() {
+        chars += 1;
+        if chars >= SEGMENT_TARGET && ch == '\n' {
+            return byte_idx + 1;
+        }
+    }
+    rest.len()
+}
+
+fn position_to_char(rope: &Rope, pos: &Position) -> usize {
+    utf16_to_char_offset(rope, pos.line, pos.character)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn large_text(lines: usize) -> String {
+        (0..lines)
+            .map(|i| format!("line number {:08} with some padding text\n", i))
+            .collect()
+    }
+
+    #[test]
+    fn test_partition_preserves_content() {
+        let text = large_text(5_000);
+        let chunked = ChunkedCrdt::from_content(&text);
+
+        assert!(chunked.segments.len() > 1);
+        assert_eq!(chunked.content(), text);
+        assert_eq!(chunked.len_chars(), text.chars().count());
+    }
+
+    #[test]
+    fn test_edit_spanning_segments() {
+        let text = large_text(5_000);
+        let mut chunked = ChunkedCrdt::from_content(&text);
+        let boundary = chunked.segments[0].branch.len();
+
+        let touched = chunked.replace("A", boundary - 5, boundary + 5, "XY");
+        assert_eq!(touched, vec![0, 1]);
+
+        let mut expected = Rope::from_str(&text);
+        expected.remove(boundary - 5..boundary + 5);
+        expected.insert(boundary - 5, "XY");
+        assert_eq!(chunked.content(), expected.to_string());
+    }
+
+    #[test]
+    fn test_patch_only_carries_touched_segment() {
+        let text = large_text(20_000);
+        let mut host = ChunkedCrdt::from_content(&text);
+        let mut peer = ChunkedCrdt::from_content(&text);
+        let old = Rope::from_str(&text);
+
+        let touched = host.replace("host", 10, 10, "hello");
+        let patch = host.encode_segments(&touched);
+        assert!(patch.len() < host.encode_all().len() / 4);
+
+        let (new_rope, edits) = peer
+            .apply_patch(ChunkedPatch::decode(&patch).unwrap(), &old)
+            .unwrap();
+        assert_eq!(new_rope.to_string(), host.content());
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits[0].new_text, "hello");
+        assert_eq!(
+            edits[0].range.start,
+            Position {
+                line: 0,
+                character: 10
+            }
+        );
+    }
+
+    #[test]
+    fn test_segments_travel_as_base64() {
+        let host = ChunkedCrdt::from_content(&large_text(20_000));
+        let patch = host.encode_segments(&[0]);
+        let json: serde_json::Value = serde_json::from_slice(&patch).unwrap();
+        assert!(json["segments"][0]["oplog"].is_string());
+
+        let decoded = ChunkedPatch::decode(&patch).unwrap();
+        assert_eq!(
+            decoded.segments[0].oplog,
+            host.segments[0].oplog.encode(EncodeOptions::default())
+        );
+    }
+
+    #[test]
+    fn test_plain_patch_is_not_chunked() {
+        let mut crdt = ListCRDT::new();
+        let agent = crdt.get_or_create_agent_id("a");
+        crdt.insert(agent, 0, "hi");
+        let plain = crdt.oplog.encode(EncodeOptions::default());
+        assert!(ChunkedPatch::decode(&plain).is_none());
+    }
+}
@@ -0,0 +1,91 @@
+use diamond_types::{
+    Time,
+    list::{ListCRDT, remote_ids::RemoteId},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::resume::Frontier;
+
+/// Documents whose history holds this many more operations than their content
+/// has chars get compacted once every peer has caught up. One typed or deleted
+/// character is one operation.
+pub const COMPACT_THRESHOLD: usize = 64 * 1024;
+
+/// Magic prefix that tells a compacted state from plain and chunked patches.
+const MAGIC: &[u8] = b"JSCOMPACT";
+
+/// Full state of a document whose history was replaced by a baseline.
+/// A higher epoch replaces whatever the receiver has, an equal one merges.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CompactedState {
+    /// How often the document was compacted.
+    pub epoch: u32,
+    /// The version of the previous history the baseline stands for.
+    pub base: Frontier,
+    /// Encoded oplog: the baseline plus everything since.
+    #[serde(with = "crate::framing::base64")]
+    pub oplog: Vec<u8>,
+}
+
+impl CompactedState {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(serde_json::to_vec(self).unwrap());
+        bytes
+    }
+
+    /// Returns `None` for anything that is not a compacted state.
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        serde_json::from_slice(data.strip_prefix(MAGIC)?).ok()
+    }
+}
+
+/// A fresh history that holds just `content`. Every peer building the baseline
+/// for the same epoch from the same content ends up with identical ops.
+pub fn baseline(epoch: u32, content: &str) -> ListCRDT {
+    let mut crdt = ListCRDT::new();
+    if !content.is_empty() {
+        let agent = crdt.get_or_create_agent_id(&format!("base-{}", epoch));
+        crdt.insert(agent, 0, content);
+    }
+    crdt
+}
+
+/// The version right after the baseline of `epoch` that holds `len` chars.
+/// `None` if `crdt` doesn't contain that baseline.
+pub fn baseline_version(crdt: &ListCRDT, epoch: u32, len: usize) -> Option<Vec<Time>> {
+    if len == 0 {
+        return Some(Vec::new());
+    }
+    let end = RemoteId {
+        agent: format!("base-{}", epoch).as_str().into(),
+        seq: len - 1,
+    };
+    let version = crdt.oplog.try_remote_to_local_version([end].iter()).ok()?;
+    Some(version.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compacted_state_roundtrip() {
+        let state = CompactedState {
+            epoch: 3,
+            base: vec![("alice".into(), 41)],
+            oplog: vec![1, 2, 3],
+        };
+        let decoded = CompactedState::decode(&state.encode()).unwrap();
+        assert_eq!(decoded.epoch, 3);
+        assert_eq!(decoded.base, state.base);
+        assert_eq!(decoded.oplog, state.oplog);
+        let encoded = state.encode();
+        let json: serde_json::Value =
+            serde_json::from_slice(encoded.strip_prefix(MAGIC).unwrap()).unwrap();
+        assert_eq!(json["oplog"], "AQID");
+
+        // Plain diamond-types patches are left alone
+        assert!(CompactedState::decode(b"DMNDTYPS...").is_none());
+    }
+}
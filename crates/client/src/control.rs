@@ -0,0 +1,447 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    sync::{mpsc, oneshot},
+};
+
+use crate::{core::Event, logger, network::NetworkCommand, network::PeerId};
+
+// Local control socket: `justsync status` asks a running daemon what it is doing.
+// One JSON line per request, one JSON line back.
+
+/// What the control socket understands.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "lowercase")]
+pub enum ControlRequest {
+    Status,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ControlResponse {
+    Status(StatusReport),
+    Error(String),
+}
+
+/// Everything `justsync status` prints.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StatusReport {
+    pub peers: Vec<PeerStatus>,
+    pub files: Vec<FileVersion>,
+    /// The `--share` paths, empty when the whole project is shared.
+    #[serde(default)]
+    pub shared: Vec<String>,
+    /// Totals over the live connections.
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+/// One live connection, filled in by the network actor.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerStatus {
+    pub id: PeerId,
+    pub address: String,
+    /// Display names of everyone behind this connection, filled in by the core.
+    pub names: Vec<String>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    #[serde(default)]
+    pub messages_sent: u64,
+    #[serde(default)]
+    pub messages_received: u64,
+    /// Patches refused for their size, each one costs the peer a full file.
+    #[serde(default)]
+    pub patches_dropped: u64,
+}
+
+/// One synced document, `ops` is the size of its history.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileVersion {
+    pub uri: String,
+    pub ops: usize,
+    pub open: bool,
+}
+
+/// The core's share of a status report.
+#[derive(Debug, Default)]
+pub struct CoreStatus {
+    pub files: Vec<FileVersion>,
+    pub names: HashMap<PeerId, Vec<String>>,
+    pub shared: Vec<String>,
+}
+
+/// Where the daemon running in `root` listens. The project path is hashed in,
+/// so daemons in different projects don't get in each other's way.
+pub fn socket_path(root: &Path) -> PathBuf {
+    let root = std::fs::canonicalize(root).unwrap_or_else(|_| root.to_path_buf());
+    let digest = ring::digest::digest(&ring::digest::SHA256, root.to_string_lossy().as_bytes());
+    let name = format!("justsync-{}", hex::encode(&digest.as_ref()[..8]));
+    if cfg!(windows) {
+        PathBuf::from(format!(r"\\.\pipe\{}", name))
+    } else {
+        // Unix socket paths are short, the temp dir keeps them that way
+        std::env::temp_dir().join(format!("{}.sock", name))
+    }
+}
+
+/// Removes the socket file when `serve` stops, so it doesn't outlive the daemon.
+#[cfg(unix)]
+struct SocketFile(PathBuf);
+
+#[cfg(unix)]
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Answers control requests until the task is aborted, which removes the socket.
+#[cfg(unix)]
+pub async fn serve(
+    path: PathBuf,
+    core_tx: mpsc::Sender<Event>,
+    net_tx: mpsc::Sender<NetworkCommand>,
+) {
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::net::{UnixListener, UnixStream};
+
+    if UnixStream::connect(&path).await.is_ok() {
+        logger::warn(&format!(
+            "!! [Control] Another daemon already listens on {}, not starting ours",
+            path.display()
+        ));
+        return;
+    }
+    // A daemon that crashed leaves its socket behind
+    let _ = std::fs::remove_file(&path);
+    let listener = match UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            logger::warn(&format!(
+                "!! [Control] Can't listen on {}: {}",
+                path.display(),
+                e
+            ));
+            return;
+        }
+    };
+    let socket = SocketFile(path);
+    // Only our user may ask, the temp dir is shared
+    if let Err(e) = std::fs::set_permissions(&socket.0, std::fs::Permissions::from_mode(0o600)) {
+        logger::warn(&format!(
+            "!! [Control] Can't restrict {}: {}",
+            socket.0.display(),
+            e
+        ));
+        return;
+    }
+    logger::log(&format!(">> [Control] Listening on {}", socket.0.display()));
+
+    while let Ok((stream, _)) = listener.accept().await {
+        tokio::spawn(handle_client(stream, core_tx.clone(), net_tx.clone()));
+    }
+}
+
+/// Answers control requests until the process exits.
+#[cfg(windows)]
+pub async fn serve(
+    path: PathBuf,
+    core_tx: mpsc::Sender<Event>,
+    net_tx: mpsc::Sender<NetworkCommand>,
+) {
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    let create = |first: bool| {
+        ServerOptions::new()
+            .first_pipe_instance(first)
+            .create(&path)
+    };
+    let mut server = match create(true) {
+        Ok(server) => server,
+        Err(e) => {
+            logger::warn(&format!(
+                "!! [Control] Can't listen on {}: {}",
+                path.display(),
+                e
+            ));
+            return;
+        }
+    };
+    logger::log(&format!(">> [Control] Listening on {}", path.display()));
+
+    // A pipe instance serves one client, the next one has to exist before we hand it off
+    while server.connect().await.is_ok() {
+        let client = server;
+        server = match create(false) {
+            Ok(server) => server,
+            Err(e) => {
+                logger::warn(&format!("!! [Control] Can't reopen the pipe: {}", e));
+                return;
+            }
+        };
+        tokio::spawn(handle_client(client, core_tx.clone(), net_tx.clone()));
+    }
+}
+
+async fn handle_client<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    core_tx: mpsc::Sender<Event>,
+    net_tx: mpsc::Sender<NetworkCommand>,
+) {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let response = match serde_json::from_str::<ControlRequest>(&line) {
+            Ok(ControlRequest::Status) => match collect_status(&core_tx, &net_tx).await {
+                Some(report) => ControlResponse::Status(report),
+                None => ControlResponse::Error("the daemon is shutting down".to_string()),
+            },
+            Err(e) => ControlResponse::Error(format!("bad request: {}", e)),
+        };
+        let mut json = serde_json::to_vec(&response).unwrap();
+        json.push(b'\n');
+        if writer.write_all(&json).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Asks the network for its connections and the core for its documents and names.
+async fn collect_status(
+    core_tx: &mpsc::Sender<Event>,
+    net_tx: &mpsc::Sender<NetworkCommand>,
+) -> Option<StatusReport> {
+    let (peers_tx, peers_rx) = oneshot::channel();
+    net_tx
+        .send(NetworkCommand::ReportPeers { reply: peers_tx })
+        .await
+        .ok()?;
+    let (core_reply, core_rx) = oneshot::channel();
+    core_tx
+        .send(Event::ControlStatus { reply: core_reply })
+        .await
+        .ok()?;
+
+    let mut peers = peers_rx.await.ok()?;
+    let core = core_rx.await.ok()?;
+    peers.sort_by_key(|peer| peer.id);
+    for peer in &mut peers {
+        peer.names = core.names.get(&peer.id).cloned().unwrap_or_default();
+    }
+    Some(StatusReport {
+        bytes_sent: peers.iter().map(|peer| peer.bytes_sent).sum(),
+        bytes_received: peers.iter().map(|peer| peer.bytes_received).sum(),
+        peers,
+        files: core.files,
+        shared: core.shared,
+    })
+}
+
+/// Sends one request to the daemon listening on `path` and waits for the answer.
+pub async fn query(path: &Path, request: &ControlRequest) -> io::Result<ControlResponse> {
+    #[cfg(unix)]
+    let stream = tokio::net::UnixStream::connect(path).await?;
+    #[cfg(windows)]
+    let stream = tokio::net::windows::named_pipe::ClientOptions::new().open(path)?;
+
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut json = serde_json::to_vec(request)?;
+    json.push(b'\n');
+    writer.write_all(&json).await?;
+
+    let line = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "no answer"))?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+/// `justsync status`: prints what the daemon in the current directory is doing.
+/// Returns the exit code.
+pub async fn run_status() -> i32 {
+    let path = socket_path(Path::new("."));
+    match query(&path, &ControlRequest::Status).await {
+        Ok(ControlResponse::Status(report)) => {
+            print!("{}", format_report(&report));
+            0
+        }
+        Ok(ControlResponse::Error(e)) => {
+            eprintln!("JustSync: {}", e);
+            1
+        }
+        Err(e) => {
+            eprintln!(
+                "JustSync: no daemon is running in this directory ({}: {})",
+                path.display(),
+                e
+            );
+            1
+        }
+    }
+}
+
+fn format_report(report: &StatusReport) -> String {
+    let mut out = format!("Peers ({}):\n", report.peers.len());
+    for peer in &report.peers {
+        let names = if peer.names.is_empty() {
+            "unnamed".to_string()
+        } else {
+            peer.names.join(", ")
+        };
+        let dropped = match peer.patches_dropped {
+            0 => String::new(),
+            1 => ", 1 patch dropped".to_string(),
+            n => format!(", {} patches dropped", n),
+        };
+        out.push_str(&format!(
+            "  {} at {} (sent {} in {} messages, received {} in {} messages{})\n",
+            names,
+            peer.address,
+            format_bytes(peer.bytes_sent),
+            peer.messages_sent,
+            format_bytes(peer.bytes_received),
+            peer.messages_received,
+            dropped
+        ));
+    }
+    if report.shared.is_empty() {
+        out.push_str("Sharing: the whole project\n");
+    } else {
+        out.push_str(&format!("Sharing: {}\n", report.shared.join(", ")));
+    }
+    out.push_str(&format!("Files ({}):\n", report.files.len()));
+    for file in &report.files {
+        let open = if file.open { ", open" } else { "" };
+        out.push_str(&format!("  {} ({} ops{})\n", file.uri, file.ops, open));
+    }
+    out.push_str(&format!(
+        "Traffic: sent {}, received {}\n",
+        format_bytes(report.bytes_sent),
+        format_bytes(report.bytes_received)
+    ));
+    out
+}
+
+fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        0..1024 => format!("{} B", bytes),
+        1024..1_048_576 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MiB", bytes as f64 / 1_048_576.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::Core;
+
+    #[test]
+    fn test_socket_path_depends_on_the_project() {
+        let a = tempfile::tempdir().unwrap();
+        let b = tempfile::tempdir().unwrap();
+        assert_eq!(socket_path(a.path()), socket_path(a.path()));
+        assert_ne!(socket_path(a.path()), socket_path(b.path()));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_status_of_a_running_daemon() {
+        let (core_tx, core_rx) = mpsc::channel(10);
+        let (net_tx, mut net_rx) = mpsc::channel(10);
+        let (edit_tx, _edit_rx) = mpsc::channel(10);
+        tokio::spawn(Core::new("host".into(), net_tx.clone(), edit_tx).run(core_rx));
+
+        // Stands in for the network actor with one connected peer
+        tokio::spawn(async move {
+            while let Some(cmd) = net_rx.recv().await {
+                if let NetworkCommand::ReportPeers { reply } = cmd {
+                    let _ = reply.send(vec![PeerStatus {
+                        id: 7,
+                        address: "127.0.0.1:5555".into(),
+                        names: Vec::new(),
+                        bytes_sent: 2048,
+                        bytes_received: 100,
+                        messages_sent: 12,
+                        messages_received: 3,
+                        patches_dropped: 1,
+                    }]);
+                }
+            }
+        });
+
+        // Opened before anyone connected, so it starts from the editor's copy
+        core_tx
+            .send(Event::ClientDidOpen {
+                uri: "notes.md".into(),
+                content: "hi".into(),
+            })
+            .await
+            .unwrap();
+        core_tx
+            .send(Event::PeerConnected {
+                peer_id: 7,
+                room: String::new(),
+            })
+            .await
+            .unwrap();
+        core_tx
+            .send(Event::RemoteHello {
+                agent_id: "bob-agent".into(),
+                display_name: "bob".into(),
+                peer: 7,
+            })
+            .await
+            .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control.sock");
+        let server = tokio::spawn(serve(path.clone(), core_tx.clone(), net_tx));
+
+        let mut response = None;
+        for _ in 0..50 {
+            if let Ok(answer) = query(&path, &ControlRequest::Status).await {
+                response = Some(answer);
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        let Some(ControlResponse::Status(report)) = response else {
+            panic!("Expected a status report, got {:?}", response);
+        };
+
+        assert_eq!(report.peers.len(), 1);
+        assert_eq!(report.peers[0].names, vec!["bob".to_string()]);
+        assert_eq!(report.bytes_sent, 2048);
+        assert_eq!(report.bytes_received, 100);
+        assert_eq!(
+            report.files,
+            vec![FileVersion {
+                uri: "notes.md".into(),
+                ops: 2,
+                open: true,
+            }]
+        );
+
+        let printed = format_report(&report);
+        assert!(printed.contains(
+            "bob at 127.0.0.1:5555 (sent 2.0 KiB in 12 messages, received 100 B in 3 messages, 1 patch dropped)"
+        ));
+        assert!(printed.contains("notes.md (2 ops, open)"));
+        assert!(printed.contains("Sharing: the whole project"));
+
+        // Only we may ask, and the socket goes away with the daemon
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        server.abort();
+        let _ = server.await;
+        assert!(!path.exists());
+    }
+}
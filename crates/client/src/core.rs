@@ -1,12 +1,49 @@
-use std::fs;
-use std::sync::atomic::Ordering;
-
-use crate::handler::EditorCommand;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::control::{CoreStatus, FileVersion};
+use crate::handler::{EditorCommand, MessageKind};
+use crate::ignore::IgnoreRules;
 use crate::logger;
-use crate::lsp::{Position, TextDocumentContentChangeEvent};
-use crate::network::NetworkCommand;
-use crate::state::Workspace;
-use tokio::sync::mpsc;
+use crate::lsp::{Position, Range, TextDocumentContentChangeEvent, TextEdit};
+use crate::network::{ByeReason, NetworkCommand, PeerId, RoomId};
+use crate::resume::{Frontier, VersionSummary};
+use crate::state::{FileStatus, SyncMode, Workspace};
+use ropey::Rope;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+
+/// Default number of editor-bound commands held back while the editor is not
+/// ready, or too busy to take them.
+pub const DEFAULT_EDITOR_BUFFER: usize = 256;
+
+/// Local edits to a document within this window go out as one patch,
+/// so fast typing or a multi-cursor edit doesn't send one per keystroke.
+pub const COALESCE_WINDOW: Duration = Duration::from_millis(30);
+
+/// Remote edits to an open document within this window reach the editor as
+/// one update, so a burst of patches (say, after a reconnect) doesn't make it
+/// apply every one of them on its own.
+pub const CATCH_UP_WINDOW: Duration = Duration::from_millis(20);
+
+/// How often we tell everyone we're still here.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Heartbeats an agent may miss before we consider it gone and drop its cursor.
+pub const MISSED_HEARTBEATS: u32 = 3;
+
+/// How often we send everyone a digest of each file, to catch copies that
+/// silently drifted apart.
+pub const DIGEST_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A claimed range is released once its holder hasn't edited the file for this long.
+pub const LOCK_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Default for the largest file we sync, see `Core::with_max_file_len`.
+pub const DEFAULT_MAX_FILE_LEN: usize = 10 * 1024 * 1024;
 
 #[derive(Debug)]
 pub enum Event {
@@ -16,10 +53,19 @@ pub enum Event {
         changes: Vec<TextDocumentContentChangeEvent>,
     },
 
+    /// The editor refused a `workspace/applyEdit` for this file (e.g. it moved to a
+    /// newer version while the edit was in flight)
+    EditRejected {
+        uri: String,
+    },
+
     /// A peer sent us a CRDT patch (Network)
     RemotePatch {
         uri: String,
         patch: Vec<u8>,
+        peer: PeerId,
+        /// Who made the edit, empty if the sender didn't say
+        agent_id: String,
     },
 
     /// Only for initial scan
@@ -39,35 +85,463 @@ pub enum Event {
         uri: String,
     },
 
+    /// The user saved a file
+    ClientDidSave {
+        uri: String,
+    },
+
+    /// The user created a file in the editor
+    LocalFileCreated {
+        uri: String,
+    },
+
+    /// The user deleted a file in the editor
+    LocalFileDeleted {
+        uri: String,
+    },
+
+    /// The user renamed or moved a file in the editor
+    LocalFileRenamed {
+        from: String,
+        to: String,
+    },
+
+    /// A file changed on disk outside the editor (see `watcher.rs`)
+    ExternalFileChange {
+        uri: String,
+        content: String,
+    },
+
+    /// A peer created a file
+    RemoteFileCreated {
+        uri: String,
+        content: String,
+        peer: PeerId,
+    },
+
+    /// A peer deleted a file
+    RemoteFileDeleted {
+        uri: String,
+        peer: PeerId,
+    },
+
+    /// A peer renamed or moved a file
+    RemoteFileRenamed {
+        from: String,
+        to: String,
+        peer: PeerId,
+    },
+
+    /// A peer saved a file
+    RemoteSaved {
+        uri: String,
+        peer: PeerId,
+    },
+
     LocalCursorChange {
         uri: String,
         position: Position,
     },
 
-    RemoteCursorChange {
+    /// Someone else's cursor moved, or left when `position` is `None`
+    RemoteCursor {
         uri: String,
-        position: Position,
+        agent_id: String,
+        position: Option<Position>,
+        peer: PeerId,
+    },
+
+    /// The user selected something, `None` when the selection is gone
+    LocalSelectionChange {
+        uri: String,
+        range: Option<Range>,
+    },
+
+    /// Someone else's selection changed, or cleared when `range` is `None`
+    RemoteSelection {
+        uri: String,
+        agent_id: String,
+        range: Option<Range>,
+        peer: PeerId,
+    },
+
+    /// The user scrolled, `top_line` is the first visible line
+    LocalViewportChange {
+        uri: String,
+        top_line: usize,
+    },
+
+    /// Someone else scrolled
+    RemoteViewport {
+        uri: String,
+        agent_id: String,
+        top_line: usize,
+        peer: PeerId,
+    },
+
+    /// The user claims `range` of `uri` while working on it, `None` releases it
+    LocalLock {
+        uri: String,
+        range: Option<Range>,
+    },
+
+    /// Someone else claimed a range of `uri`, or released it when `range` is `None`
+    RemoteLock {
+        uri: String,
+        agent_id: String,
+        range: Option<Range>,
+        peer: PeerId,
+    },
+
+    /// The user wants their editor to follow `agent_id` around, `None` stops it
+    Follow {
+        agent_id: Option<String>,
+    },
+
+    /// The editor wants us in the session of the host at `addr`
+    JoinSession {
+        addr: String,
+        token: Option<String>,
+    },
+
+    /// The editor asked how far we are from the others (`$/justsync/status`)
+    StatusRequest {
+        id: serde_json::Value,
+    },
+
+    /// The editor wants `uri` replaced with our content as a whole (`$/justsync/resync`)
+    ResyncRequest {
+        uri: String,
+        id: serde_json::Value,
+    },
+
+    /// `justsync status` wants our documents and who is behind each connection
+    ControlStatus {
+        reply: oneshot::Sender<CoreStatus>,
+    },
+
+    /// The user named the current version of `uri` to come back to later
+    Checkpoint {
+        uri: String,
+        label: String,
+    },
+
+    /// The user wants `uri` back the way it was at checkpoint `label`
+    Rollback {
+        uri: String,
+        label: String,
+    },
+
+    /// A peer sent its versions and wants ours
+    RemotePing {
+        versions: Vec<(String, VersionSummary)>,
+        peer: PeerId,
+    },
+
+    /// A peer answered our ping with its versions
+    RemotePong {
+        versions: Vec<(String, VersionSummary)>,
+        peer: PeerId,
+    },
+
+    /// What a peer's copy of a file hashes to at `frontier`
+    RemoteDigest {
+        uri: String,
+        sha256: String,
+        frontier: Frontier,
+        peer: PeerId,
     },
 
     /// We should stop the daemon
     Shutdown,
 
+    /// The other side closed the connection and told us why
+    RemoteBye {
+        reason: ByeReason,
+    },
+
     // Peer requests full state from hosting peer
-    PeerRequestedSync,
+    PeerRequestedSync {
+        peer: PeerId,
+    },
+
+    /// A peer confirmed it has merged everything up to `frontier`
+    RemoteAck {
+        uri: String,
+        agent: String,
+        frontier: Frontier,
+        peer: PeerId,
+    },
+
+    /// A connection came up, we should introduce ourselves. `room` is the
+    /// relay room the peer asked for, empty for the default one.
+    PeerConnected {
+        peer_id: PeerId,
+        room: RoomId,
+    },
+
+    /// Someone told us their name
+    RemoteHello {
+        agent_id: String,
+        display_name: String,
+        peer: PeerId,
+    },
+
+    /// Connecting to the host failed `attempts` times in a row, next try in `retry_in`
+    HostUnreachable {
+        attempts: u32,
+        retry_in: Duration,
+    },
+
+    /// A connection dropped without a goodbye. The session goes on without it.
+    PeerDisconnected {
+        peer_id: PeerId,
+    },
+
+    /// Someone is still around (maybe relayed through `peer`)
+    RemoteHeartbeat {
+        agent_id: String,
+        peer: PeerId,
+    },
+
+    /// Someone missed `MISSED_HEARTBEATS` heartbeats in a row, their connection may look fine
+    PeerTimedOut {
+        agent_id: String,
+    },
+
+    /// A reconnecting peer presents the token we issued earlier and wants only what it missed
+    PeerRequestedResume {
+        token: String,
+        peer: PeerId,
+    },
+
+    /// Response to PeerRequestedSync: what the host has, so we can ask for what we lack
+    RemoteSyncManifest {
+        files: Vec<(String, Option<Frontier>)>,
+        peer: PeerId,
+    },
 
-    // Response to PeerRequestedSync containing the state
+    /// A syncing peer wants the full state of these files
+    PeerRequestedFiles {
+        uris: Vec<String>,
+        peer: PeerId,
+    },
+
+    /// A peer couldn't merge our last patch for this file and wants all of it,
+    /// or its editor opened a file it has no history of
+    PeerRequestedFile {
+        uri: String,
+        peer: PeerId,
+    },
+
+    /// The host refused our change to this file, we fetch it again to undo it
+    RemoteRejected {
+        uri: String,
+        reason: String,
+        peer: PeerId,
+    },
+
+    /// The host has no history of a file we asked for, ours becomes it
+    RemoteFileUnknown {
+        uri: String,
+        peer: PeerId,
+    },
+
+    // Response to PeerRequestedFiles (one per file), or PeerRequestedResume
     RemoteFullSync {
         files: Vec<(String, Vec<u8>)>,
+        peer: PeerId,
+    },
+
+    /// The editor finished its handshake and can receive commands
+    EditorReady,
+
+    /// Time to write the documents to disk, even if nobody saved them (`--autosave`)
+    AutosaveTick,
+
+    /// We edited more while disconnected than the network kept for the host,
+    /// so every document has to go out again
+    OfflineQueueOverflowed,
+}
+
+/// An editor-bound command waiting for the editor to become ready.
+enum PendingEditorCommand {
+    /// One or more remote edits for a file, coalesced against the content they started from.
+    Edits {
+        uri: String,
+        base: Rope,
+        count: usize,
     },
+    Other(EditorCommand),
+}
+
+/// Remote edits to one document held back for `CATCH_UP_WINDOW`.
+#[derive(Debug)]
+struct CatchUp {
+    /// What the editor shows.
+    base: Rope,
+    /// The first patch's edits, all we need if no other one follows.
+    edits: Vec<TextEdit>,
+    /// Updates announced to the echo guard.
+    count: usize,
+}
+
+/// Someone's claim on a range of a file, see `Event::LocalLock`.
+struct RangeLock {
+    range: Range,
+    /// The connection it came in over, `None` for our own
+    peer: Option<PeerId>,
+    /// Claimed or last refreshed
+    claimed: Instant,
+    /// Whether the user was already told they edit inside it
+    warned: bool,
+}
+
+/// When we last heard each agent's heartbeat, and over which connection.
+/// Agents that never sent one (older builds) never time out.
+#[derive(Debug, Default)]
+struct Liveness {
+    last_seen: HashMap<String, (PeerId, Instant)>,
+}
+
+impl Liveness {
+    fn beat(&mut self, agent_id: String, peer: PeerId, now: Instant) {
+        self.last_seen.insert(agent_id, (peer, now));
+    }
+
+    fn forget(&mut self, agent_id: &str) {
+        self.last_seen.remove(agent_id);
+    }
+
+    /// Everyone behind a connection that went away, they can't time out any more.
+    fn forget_peer(&mut self, peer: PeerId) {
+        self.last_seen
+            .retain(|_, (seen_over, _)| *seen_over != peer);
+    }
+
+    /// The agent that times out first, and when.
+    fn next_timeout(&self) -> Option<(String, Instant)> {
+        self.last_seen
+            .iter()
+            .map(|(agent_id, (_, seen))| (agent_id, *seen + HEARTBEAT_INTERVAL * MISSED_HEARTBEATS))
+            .min_by_key(|(_, at)| *at)
+            .map(|(agent_id, at)| (agent_id.clone(), at))
+    }
+}
+
+/// The network outbox. While a relay with rooms handles an event of one,
+/// whatever the core broadcasts stays in that room.
+struct NetworkOutbox {
+    tx: mpsc::Sender<NetworkCommand>,
+    room: Option<RoomId>,
+}
+
+impl NetworkOutbox {
+    async fn send(
+        &self,
+        command: NetworkCommand,
+    ) -> Result<(), mpsc::error::SendError<NetworkCommand>> {
+        let command = match &self.room {
+            Some(room) => NetworkCommand::InRoom {
+                room: room.clone(),
+                command: Box::new(command),
+            },
+            None => command,
+        };
+        self.tx.send(command).await
+    }
 }
 
 pub struct Core {
     // The State
     workspace: Workspace,
 
+    // Headless relay: the workspaces of the rooms other than the one we are
+    // handling an event of (`room`), and which peers are in a room
+    rooms: HashMap<RoomId, Workspace>,
+    room: RoomId,
+    peer_rooms: HashMap<PeerId, RoomId>,
+
     // The Outputs
-    network_tx: mpsc::Sender<NetworkCommand>, // Send patches to peers
-    editor_tx: mpsc::Sender<EditorCommand>,   // Send edits to editor
+    network_tx: NetworkOutbox,              // Send patches to peers
+    editor_tx: mpsc::Sender<EditorCommand>, // Send edits to editor
+
+    // Editor-bound commands held back until `Event::EditorReady`, or until a
+    // slow editor makes room in its channel again
+    editor_ready: bool,
+    editor_buffer: VecDeque<PendingEditorCommand>,
+    editor_buffer_limit: usize,
+
+    // What we know about each connection, dropped when it goes away.
+    // Presence maps every cursor and selection seen over a connection (agent -> uri).
+    presence: HashMap<PeerId, HashMap<String, String>>,
+    selections: HashMap<PeerId, HashMap<String, String>>,
+    // Claimed ranges by (uri, agent), ours included, see `Event::LocalLock`
+    locks: HashMap<(String, String), RangeLock>,
+    peer_agents: HashMap<PeerId, HashSet<String>>,
+    greeted: HashMap<PeerId, HashSet<String>>,
+    liveness: Liveness,
+
+    // What we call ourselves, and what everyone else is called (agent -> display name)
+    display_name: String,
+    names: HashMap<String, String>,
+    /// Who sent the last remote change to each document (uri -> agent)
+    last_editor: HashMap<String, String>,
+
+    // Connections that are up, and editor status requests waiting for a pong
+    connected: HashSet<PeerId>,
+    status_requests: Vec<serde_json::Value>,
+
+    // Documents with local edits waiting for the coalesce window to end
+    unsent: HashSet<String>,
+    flush_at: Option<Instant>,
+
+    // Remote edits waiting for the catch-up window to end, before they go to the editor
+    catching_up: HashMap<String, CatchUp>,
+    catch_up_at: Option<Instant>,
+
+    // Follow mode: whom we follow, and where everyone was last seen (agent -> (uri, top line))
+    following: Option<String>,
+    viewports: HashMap<String, (String, usize)>,
+
+    // Where the session history is saved on shutdown (see `Workspace::save_to_dir`)
+    state_dir: Option<PathBuf>,
+
+    // Whether we compact histories everyone has caught up on (the host does)
+    compaction: bool,
+
+    // No editor and no project on disk, documents only live in memory (relay server)
+    headless: bool,
+
+    // Watching someone else's session: nothing we do goes out or onto the disk
+    observer: bool,
+
+    // Files guests may not change (host), and files whose next full state
+    // replaces ours because the host refused our changes (peer)
+    read_only: HashSet<String>,
+    reverting: HashSet<String>,
+
+    // Files the host is still sending us (peer), and those of them the editor
+    // opened from its outdated disk copy in the meantime
+    hydrating: HashSet<String>,
+    stale_in_editor: HashSet<String>,
+    // Files the editor opened that we asked the host about, with the editor's
+    // content in case nobody has them yet (peer)
+    opening: HashMap<String, String>,
+    // Files nobody had when a peer asked (host): who starts them from its
+    // copy, and who waits for that to arrive
+    claims: HashMap<(RoomId, String), (PeerId, Vec<PeerId>)>,
+    // Files over `max_file_len`, left out of the session for good
+    oversized: HashSet<String>,
+    max_file_len: usize,
+
+    // `.justsyncignore`: files that stay out of the session both ways
+    sync_ignore: IgnoreRules,
+    // `--share`: when set, the only paths in the session
+    share: Vec<String>,
+
+    // Hash of the content each file had when we last autosaved it
+    autosaved: HashMap<String, u64>,
 }
 
 impl Core {
@@ -77,96 +551,802 @@ impl Core {
         editor_tx: mpsc::Sender<EditorCommand>,
     ) -> Self {
         Self {
-            workspace: Workspace::new(agent_id),
-            network_tx,
+            workspace: Workspace::new(agent_id.clone()),
+            rooms: HashMap::new(),
+            room: RoomId::new(),
+            peer_rooms: HashMap::new(),
+            network_tx: NetworkOutbox {
+                tx: network_tx,
+                room: None,
+            },
             editor_tx,
+            editor_ready: true,
+            editor_buffer: VecDeque::new(),
+            editor_buffer_limit: DEFAULT_EDITOR_BUFFER,
+            presence: HashMap::new(),
+            selections: HashMap::new(),
+            locks: HashMap::new(),
+            peer_agents: HashMap::new(),
+            greeted: HashMap::new(),
+            liveness: Liveness::default(),
+            display_name: agent_id.clone(),
+            names: HashMap::new(),
+            last_editor: HashMap::new(),
+            connected: HashSet::new(),
+            status_requests: Vec::new(),
+            unsent: HashSet::new(),
+            flush_at: None,
+            catching_up: HashMap::new(),
+            catch_up_at: None,
+            following: None,
+            viewports: HashMap::new(),
+            state_dir: None,
+            compaction: false,
+            headless: false,
+            observer: false,
+            read_only: HashSet::new(),
+            reverting: HashSet::new(),
+            hydrating: HashSet::new(),
+            stale_in_editor: HashSet::new(),
+            opening: HashMap::new(),
+            claims: HashMap::new(),
+            oversized: HashSet::new(),
+            max_file_len: DEFAULT_MAX_FILE_LEN,
+            sync_ignore: IgnoreRules::default(),
+            share: Vec::new(),
+            autosaved: HashMap::new(),
+        }
+    }
+
+    /// Holds editor-bound commands (up to `limit`) until `Event::EditorReady` arrives.
+    pub fn with_editor_buffer(mut self, limit: usize) -> Self {
+        self.editor_ready = false;
+        self.editor_buffer_limit = limit.max(1);
+        self
+    }
+
+    /// The name the others see next to our cursor, instead of the agent id.
+    pub fn with_display_name(mut self, name: impl Into<String>) -> Self {
+        self.display_name = name.into();
+        self
+    }
+
+    /// Switches the session's sync strategy. Has to happen before any document exists.
+    pub fn with_sync_mode(mut self, mode: SyncMode) -> Self {
+        self.workspace.mode = mode;
+        self
+    }
+
+    /// Restores the history saved below `root` and saves it there again on shutdown.
+    /// Call after `with_sync_mode`, restored documents keep the mode they are loaded with.
+    pub fn with_state_dir(mut self, root: impl Into<PathBuf>) -> Self {
+        let root = root.into();
+        match self.workspace.load_from_dir(&root) {
+            Ok(count) => logger::log(&format!(">> [Core] Restored {} document(s)", count)),
+            // First run in this project, nothing saved yet
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => logger::warn(&format!("!! [Core] Could not restore session: {}", e)),
         }
+        self.state_dir = Some(root);
+        self
+    }
+
+    /// Compacts a document's history once all connected peers acked it (see `compact.rs`).
+    /// Only the host should do this, it's the one everybody syncs with.
+    pub fn with_compaction(mut self) -> Self {
+        self.compaction = true;
+        self
+    }
+
+    /// Runs without an editor: documents are kept in memory only, so newly joining
+    /// peers can be served, and nothing is written to disk or sent to an editor.
+    pub fn with_headless(mut self) -> Self {
+        self.headless = true;
+        self.editor_ready = true;
+        self
+    }
+
+    /// Watches a session without taking part: remote edits reach the workspace and
+    /// the editor, but local edits, saves and file operations are never sent, and
+    /// nothing is written to disk.
+    pub fn with_observer(mut self) -> Self {
+        self.observer = true;
+        self
+    }
+
+    /// Patches from peers to these files are refused and undone on the sender's side.
+    pub fn with_read_only(mut self, uris: HashSet<String>) -> Self {
+        self.read_only = uris;
+        self
+    }
+
+    /// Files matching `rules` are neither sent to peers nor taken from them.
+    pub fn with_sync_ignore(mut self, rules: IgnoreRules) -> Self {
+        self.sync_ignore = rules;
+        self
+    }
+
+    /// Only files at or under these paths are sent to peers or taken from them,
+    /// all of them without any.
+    pub fn with_share(mut self, paths: Vec<String>) -> Self {
+        self.share = paths;
+        self
+    }
+
+    /// Files above `bytes` stay out of the session: building their history would
+    /// stall every other document for a long time.
+    pub fn with_max_file_len(mut self, bytes: usize) -> Self {
+        self.max_file_len = bytes;
+        self
     }
 
     /// The Main Loop: Process one event at a time.
     pub async fn run(mut self, mut rx: mpsc::Receiver<Event>) {
-        while let Some(event) = rx.recv().await {
+        let mut heartbeat =
+            tokio::time::interval_at(Instant::now() + HEARTBEAT_INTERVAL, HEARTBEAT_INTERVAL);
+        heartbeat.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut digests =
+            tokio::time::interval_at(Instant::now() + DIGEST_INTERVAL, DIGEST_INTERVAL);
+        digests.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            // Never wait for the editor: it may be blocked handing us its own events
+            let editor_backlog = self.editor_ready && !self.editor_buffer.is_empty();
+            let editor_tx = self.editor_tx.clone();
+            let coalescing = self.flush_at.is_some();
+            let deadline = self.flush_at.unwrap_or_else(Instant::now);
+            let catching_up = self.catch_up_at.is_some();
+            let catch_up_deadline = self.catch_up_at.unwrap_or_else(Instant::now);
+            let next_timeout = self.liveness.next_timeout();
+            let timing_out = next_timeout.is_some();
+            let timeout_at = next_timeout
+                .as_ref()
+                .map_or_else(Instant::now, |(_, at)| *at);
+            let event = tokio::select! {
+                event = rx.recv() => event,
+                _ = tokio::time::sleep_until(timeout_at), if timing_out => {
+                    next_timeout.map(|(agent_id, _)| Event::PeerTimedOut { agent_id })
+                }
+                _ = heartbeat.tick() => {
+                    // The relay is alive for every room
+                    let room = self.network_tx.room.take();
+                    self.send_heartbeat().await;
+                    self.network_tx.room = room;
+                    self.expire_locks(Instant::now()).await;
+                    continue;
+                }
+                _ = digests.tick() => {
+                    self.send_digests().await;
+                    continue;
+                }
+                _ = tokio::time::sleep_until(deadline), if coalescing => {
+                    self.flush_local_changes().await;
+                    continue;
+                }
+                _ = tokio::time::sleep_until(catch_up_deadline), if catching_up => {
+                    self.flush_remote_edits().await;
+                    continue;
+                }
+                permit = editor_tx.reserve(), if editor_backlog => {
+                    match permit {
+                        Ok(permit) => {
+                            if let Some(cmd) = self.next_buffered_for_editor() {
+                                permit.send(cmd);
+                            }
+                        }
+                        Err(_) => self.editor_buffer.clear(),
+                    }
+                    continue;
+                }
+            };
+            let Some(event) = event else {
+                self.enter_room(RoomId::new());
+                self.flush_local_changes().await;
+                self.flush_remote_edits().await;
+                break;
+            };
+            // Whatever peers name has to stay inside the project, checked once here
+            let Some(event) = vetted(event) else {
+                continue;
+            };
+            if let Event::PeerConnected { peer_id, room } = &event
+                && !room.is_empty()
+            {
+                if !self.headless {
+                    // Our editor's project is the only session here
+                    logger::warn(&format!(
+                        "!! [Core] Peer {} asked for room '{}', only a relay has rooms",
+                        peer_id, room
+                    ));
+                    let _ = self
+                        .network_tx
+                        .send(NetworkCommand::Disconnect {
+                            peer: *peer_id,
+                            reason: ByeReason::NoRooms,
+                        })
+                        .await;
+                }
+                logger::log(&format!(">> [Core] Peer {} joins room '{}'", peer_id, room));
+                self.peer_rooms.insert(*peer_id, room.clone());
+            }
+            let room = self.room_of(&event);
+            if !self.headless && !room.is_empty() {
+                // Refused above, whatever it sends until it is gone stays unanswered
+                if let Event::PeerDisconnected { peer_id } = event {
+                    self.peer_rooms.remove(&peer_id);
+                }
+                continue;
+            }
+            self.enter_room(room);
             match event {
                 Event::LocalChange { uri, changes } => {
                     self.handle_local_change(uri, changes).await;
                 }
-                Event::RemotePatch { uri, patch } => {
-                    self.handle_remote_patch(uri, patch).await;
+                Event::EditRejected { uri } => {
+                    // Held edits come after the refused one, they go out first
+                    // so the retry knows about them
+                    self.release_catch_up(&uri).await;
+                    let Some(doc) = self.workspace.documents.get_mut(&uri) else {
+                        continue;
+                    };
+                    if let Some(edits) = doc.rediff_rejected() {
+                        logger::log(&format!(
+                            ">> [Core] Editor refused an update for {}, retrying",
+                            uri
+                        ));
+                        self.send_to_editor(EditorCommand::ApplyEdits { uri, edits })
+                            .await;
+                    }
+                }
+                Event::RemotePatch {
+                    uri,
+                    patch,
+                    peer,
+                    agent_id,
+                } => {
+                    self.handle_remote_patch(uri, patch, peer, agent_id).await;
                 }
                 Event::LoadFromDisk { uri, content } => {
+                    if self.reject_oversized(&uri, content.len()).await {
+                        continue;
+                    }
                     // Just update state, don't load into editor
-                    self.workspace.get_or_create(uri, content);
+                    let doc = self.workspace.get_or_create(uri.clone(), content.clone());
+                    if doc.content != content.as_str() {
+                        logger::warn(&format!(
+                            "!! [Core] {} changed on disk since the saved session, keeping the saved history",
+                            uri
+                        ));
+                    }
                 }
                 Event::ClientDidOpen { uri, content } => {
-                    self.workspace.get_or_create(uri.clone(), content);
-                    self.workspace.mark_open(uri);
+                    self.handle_client_did_open(uri, content).await;
                 }
                 Event::ClientDidClose { uri } => {
                     self.workspace.mark_closed(&uri);
                 }
+                Event::LocalFileCreated { uri } => {
+                    self.handle_local_file_created(uri).await;
+                }
+                Event::LocalFileDeleted { uri } => {
+                    if self.suppressed("deletion", &uri) {
+                        continue;
+                    }
+                    self.workspace.remove_document(&uri);
+                    let _ = self
+                        .network_tx
+                        .send(NetworkCommand::BroadcastFileDeleted { uri, exclude: None })
+                        .await;
+                }
+                Event::LocalFileRenamed { from, to } => {
+                    if self.suppressed("rename", &from) || self.sync_ignored(&to) {
+                        continue;
+                    }
+                    logger::log(&format!(">> [Core] File renamed: {} -> {}", from, to));
+                    self.workspace.rename_document(&from, to.clone());
+                    let _ = self
+                        .network_tx
+                        .send(NetworkCommand::BroadcastFileRenamed {
+                            from,
+                            to,
+                            exclude: None,
+                        })
+                        .await;
+                }
+                Event::ExternalFileChange { uri, content } => {
+                    self.handle_external_change(uri, content).await;
+                }
+                Event::Checkpoint { uri, label } => {
+                    let Some(doc) = self.workspace.documents.get_mut(&uri) else {
+                        continue;
+                    };
+                    if doc.checkpoint(&label) {
+                        logger::log(&format!(">> [Core] Checkpoint '{}' of {}", label, uri));
+                    } else {
+                        logger::warn(&format!(
+                            "!! [Core] {} has no history to checkpoint (simple sync or a large file)",
+                            uri
+                        ));
+                    }
+                }
+                Event::Rollback { uri, label } => {
+                    self.handle_rollback(uri, label).await;
+                }
+                Event::RemoteFileCreated { uri, content, peer } => {
+                    self.handle_remote_file_created(uri, content, peer).await;
+                }
+                Event::RemoteFileDeleted { uri, peer } => {
+                    self.handle_remote_file_deleted(uri, peer).await;
+                }
+                Event::RemoteFileRenamed { from, to, peer } => {
+                    self.handle_remote_file_renamed(from, to, peer).await;
+                }
+                Event::ClientDidSave { uri } => {
+                    self.handle_local_save(uri).await;
+                }
+                Event::RemoteSaved { uri, peer } => {
+                    self.handle_remote_save(uri, peer).await;
+                }
                 Event::LocalCursorChange { uri, position } => {
                     let _ = self
                         .network_tx
                         .send(NetworkCommand::BroadcastCursor {
                             uri,
-                            position: (position.line, position.character),
+                            agent_id: self.workspace.local_agent_id.clone(),
+                            position: Some((position.line, position.character)),
                         })
                         .await;
                 }
-                Event::RemoteCursorChange { uri, position } => {
+                Event::RemoteCursor {
+                    uri,
+                    agent_id,
+                    position,
+                    peer,
+                } => {
+                    let cursors = self.presence.entry(peer).or_default();
+                    if position.is_some() {
+                        cursors.insert(agent_id.clone(), uri.clone());
+                    } else {
+                        cursors.remove(&agent_id);
+                    }
+                    self.send_to_editor(EditorCommand::RemoteCursor {
+                        uri,
+                        display_name: self.name_of(&agent_id),
+                        agent_id,
+                        position,
+                    })
+                    .await;
+                }
+                Event::LocalSelectionChange { uri, range } => {
                     let _ = self
-                        .editor_tx
-                        .send(EditorCommand::RemoteCursor { uri, position })
+                        .network_tx
+                        .send(NetworkCommand::BroadcastSelection {
+                            uri,
+                            agent_id: self.workspace.local_agent_id.clone(),
+                            range,
+                        })
                         .await;
                 }
-                Event::PeerRequestedSync => {
-                    crate::logger::log(">> [Core] Peer requested sync. Bundling state...");
-                    let snapshot = self
-                        .workspace
-                        .get_snapshot()
-                        .into_iter()
-                        .filter(|(uri, _)| !uri.is_empty() && uri != "/")
-                        .collect();
-
+                Event::RemoteSelection {
+                    uri,
+                    agent_id,
+                    range,
+                    peer,
+                } => {
+                    let selections = self.selections.entry(peer).or_default();
+                    if range.is_some() {
+                        selections.insert(agent_id.clone(), uri.clone());
+                    } else {
+                        selections.remove(&agent_id);
+                    }
+                    self.send_to_editor(EditorCommand::RemoteSelection {
+                        uri,
+                        display_name: self.name_of(&agent_id),
+                        agent_id,
+                        range,
+                    })
+                    .await;
+                }
+                Event::LocalLock { uri, range } => {
+                    self.handle_local_lock(uri, range).await;
+                }
+                Event::RemoteLock {
+                    uri,
+                    agent_id,
+                    range,
+                    peer,
+                } => {
+                    let key = (uri.clone(), agent_id.clone());
+                    match range.clone() {
+                        Some(range) => {
+                            self.locks.insert(
+                                key,
+                                RangeLock {
+                                    range,
+                                    peer: Some(peer),
+                                    claimed: Instant::now(),
+                                    warned: false,
+                                },
+                            );
+                        }
+                        None => {
+                            self.locks.remove(&key);
+                        }
+                    }
+                    self.send_to_editor(EditorCommand::RemoteLock {
+                        uri,
+                        display_name: self.name_of(&agent_id),
+                        agent_id,
+                        range,
+                    })
+                    .await;
+                }
+                Event::LocalViewportChange { uri, top_line } => {
                     let _ = self
                         .network_tx
-                        .send(NetworkCommand::SendFullSyncResponse { files: snapshot })
+                        .send(NetworkCommand::BroadcastViewport {
+                            uri,
+                            agent_id: self.workspace.local_agent_id.clone(),
+                            top_line,
+                        })
                         .await;
                 }
-                Event::RemoteFullSync { files } => {
-                    crate::logger::log(
-                        ">> [Core] Received Full Sync. Hydrating & Writing to Disk...",
-                    );
-
-                    let mut files_to_write = Vec::new();
-                    for (uri, patch) in files {
-                        // Check if we are actually tracking this file (User has it open)
-                        let is_open = self.workspace.documents.contains_key(&uri);
-
-                        // Hydrate Memory
-                        let doc = self.workspace.get_or_create_empty(uri.clone());
-                        let edits_opt = doc.apply_remote_patch(&patch);
-
+                Event::RemoteViewport {
+                    uri,
+                    agent_id,
+                    top_line,
+                    peer: _,
+                } => {
+                    self.viewports
+                        .insert(agent_id.clone(), (uri.clone(), top_line));
+                    if self.following.as_ref() == Some(&agent_id) {
+                        self.send_to_editor(EditorCommand::Reveal {
+                            uri,
+                            line: top_line,
+                        })
+                        .await;
+                    }
+                }
+                Event::Follow { agent_id } => {
+                    self.handle_follow(agent_id).await;
+                }
+                Event::JoinSession { addr, token } => {
+                    logger::log(&format!(">> [Core] Joining the session at {}", addr));
+                    let _ = self
+                        .network_tx
+                        .send(NetworkCommand::Connect { addr, token })
+                        .await;
+                }
+                Event::ResyncRequest { uri, id } => {
+                    // Replacing the whole buffer covers whatever was held back too
+                    if let Some(held) = self.catching_up.remove(&uri)
+                        && let Some(doc) = self.workspace.documents.get_mut(&uri)
+                    {
+                        doc.cancel_echoes(held.count);
+                    }
+                    let edit = self
+                        .workspace
+                        .documents
+                        .get_mut(&uri)
+                        .map(|doc| doc.resync_edit());
+                    let resynced = edit.is_some();
+                    if let Some(edit) = edit {
+                        logger::log(&format!(">> [Core] Resyncing {} in the editor", uri));
+                        self.send_to_editor(EditorCommand::ApplyEdits {
+                            uri,
+                            edits: vec![edit],
+                        })
+                        .await;
+                    }
+                    self.send_to_editor(EditorCommand::Resynced { id, resynced })
+                        .await;
+                }
+                Event::PeerRequestedSync { peer } => {
+                    crate::logger::log(">> [Core] Peer requested sync. Sending manifest...");
+                    let _ = self
+                        .network_tx
+                        .send(NetworkCommand::SendSyncManifest {
+                            peer,
+                            files: self
+                                .workspace
+                                .manifest()
+                                .into_iter()
+                                .filter(|(uri, _)| !self.left_out(uri))
+                                .collect(),
+                        })
+                        .await;
+                }
+                Event::RemoteSyncManifest { files, peer } => {
+                    self.handle_sync_manifest(files, peer).await;
+                }
+                Event::PeerRequestedFiles { uris, peer } => {
+                    crate::logger::log(&format!(
+                        ">> [Core] Peer requested {} file(s). Streaming...",
+                        uris.len()
+                    ));
+                    // One message per file, so a big project arrives bit by bit
+                    for file in self.workspace.get_files_snapshot(&uris) {
+                        let _ = self
+                            .network_tx
+                            .send(NetworkCommand::SendFullSyncResponse {
+                                peer,
+                                files: vec![file],
+                            })
+                            .await;
+                    }
+                    let _ = self
+                        .network_tx
+                        .send(NetworkCommand::SendResumeToken {
+                            peer,
+                            token: self.workspace.issue_resume_token(),
+                        })
+                        .await;
+                }
+                Event::PeerRequestedFile { uri, peer } => {
+                    self.handle_file_request(uri, peer).await;
+                }
+                Event::RemoteFileUnknown { uri, .. } => {
+                    self.handle_file_unknown(uri).await;
+                }
+                Event::RemoteRejected { uri, reason, peer } => {
+                    self.handle_rejected(uri, reason, peer).await;
+                }
+                Event::RemoteAck {
+                    uri,
+                    agent,
+                    frontier,
+                    peer,
+                } => {
+                    if let Some(doc) = self.workspace.documents.get_mut(&uri) {
+                        doc.record_ack(&agent, &frontier);
+                    }
+                    self.peer_agents.entry(peer).or_default().insert(agent);
+                    self.maybe_compact(uri).await;
+                }
+                Event::PeerConnected { peer_id, .. } => {
+                    self.connected.insert(peer_id);
+                    self.introduce_to(peer_id).await;
+                }
+                Event::StatusRequest { id } => {
+                    if self.connected.is_empty() {
+                        self.send_status(id, None).await;
+                    } else {
+                        // Answered by the first pong
+                        self.status_requests.push(id);
+                        let _ = self
+                            .network_tx
+                            .send(NetworkCommand::BroadcastPing {
+                                versions: self.workspace.version_summaries(),
+                            })
+                            .await;
+                    }
+                }
+                Event::RemotePing { versions, peer } => {
+                    let behind: usize = self
+                        .workspace
+                        .compare_versions(&versions)
+                        .iter()
+                        .map(|file| file.behind)
+                        .sum();
+                    logger::debug(&format!(
+                        "<- [Core] Ping from peer {}, we are {} op(s) behind it",
+                        peer, behind
+                    ));
+                    let _ = self
+                        .network_tx
+                        .send(NetworkCommand::SendPong {
+                            peer,
+                            versions: self.workspace.version_summaries(),
+                        })
+                        .await;
+                }
+                Event::RemoteDigest {
+                    uri,
+                    sha256,
+                    frontier,
+                    peer,
+                } => {
+                    self.handle_remote_digest(uri, sha256, frontier, peer).await;
+                }
+                Event::RemotePong { versions, peer } => {
+                    logger::debug(&format!("<- [Core] Pong from peer {}", peer));
+                    let files = self.workspace.compare_versions(&versions);
+                    for id in std::mem::take(&mut self.status_requests) {
+                        self.send_status(id, Some(files.clone())).await;
+                    }
+                }
+                Event::RemoteHello {
+                    agent_id,
+                    display_name,
+                    peer,
+                } => {
+                    logger::log(&format!(
+                        "<- [Core] {} is here (agent {}, peer {})",
+                        display_name, agent_id, peer
+                    ));
+                    self.greeted
+                        .entry(peer)
+                        .or_default()
+                        .insert(agent_id.clone());
+                    self.names.insert(agent_id, display_name);
+                }
+                Event::ControlStatus { reply } => {
+                    let _ = reply.send(self.control_status());
+                }
+                Event::PeerDisconnected { peer_id } => {
+                    self.connected.remove(&peer_id);
+                    if self.connected.is_empty() {
+                        // Nobody left to answer
+                        for id in std::mem::take(&mut self.status_requests) {
+                            self.send_status(id, None).await;
+                        }
+                    }
+                    logger::log(&format!(
+                        "<< [Core] Peer {} ({}) disconnected, cleaning up",
+                        peer_id,
+                        self.names_of(peer_id)
+                    ));
+                    let names = self.peer_names(peer_id);
+                    let who = if names.is_empty() {
+                        "a collaborator".to_string()
+                    } else {
+                        names.join(", ")
+                    };
+                    self.clear_presence_of(peer_id).await;
+                    self.liveness.forget_peer(peer_id);
+                    // Stale acks would hold every future delta back at their version
+                    for agent in self.peer_agents.remove(&peer_id).unwrap_or_default() {
+                        self.workspace.forget_agent(&agent);
+                    }
+                    self.greeted.remove(&peer_id);
+                    self.peer_rooms.remove(&peer_id);
+                    self.release_claims(peer_id).await;
+                    self.send_to_editor(EditorCommand::ShowMessage {
+                        kind: MessageKind::Warning,
+                        message: format!("JustSync: lost the connection to {}", who),
+                    })
+                    .await;
+                }
+                Event::OfflineQueueOverflowed => {
+                    let mut uris: Vec<String> = self.workspace.documents.keys().cloned().collect();
+                    uris.sort();
+                    for uri in uris {
+                        let patch = self.workspace.documents[&uri].outgoing_patch();
+                        let _ = self
+                            .network_tx
+                            .send(NetworkCommand::BroadcastPatch {
+                                uri,
+                                patch,
+                                agent_id: self.workspace.local_agent_id.clone(),
+                                exclude: None,
+                            })
+                            .await;
+                    }
+                }
+                Event::HostUnreachable { attempts, retry_in } => {
+                    // One popup, the retries after it only go to the editor's log
+                    let kind = if attempts == 1 {
+                        MessageKind::Error
+                    } else {
+                        MessageKind::Log
+                    };
+                    self.send_to_editor(EditorCommand::ShowMessage {
+                        kind,
+                        message: format!(
+                            "JustSync: can't reach the host, retrying in {}s (attempt {})",
+                            retry_in.as_secs_f32().ceil(),
+                            attempts
+                        ),
+                    })
+                    .await;
+                }
+                Event::RemoteHeartbeat { agent_id, peer } => {
+                    if agent_id != self.workspace.local_agent_id {
+                        self.liveness.beat(agent_id, peer, Instant::now());
+                    }
+                }
+                Event::PeerTimedOut { agent_id } => {
+                    logger::log(&format!(
+                        "<< [Core] {} went quiet, dropping their cursor",
+                        self.name_of(&agent_id)
+                    ));
+                    self.liveness.forget(&agent_id);
+                    self.clear_presence_of_agent(&agent_id).await;
+                }
+                Event::PeerRequestedResume { token, peer } => {
+                    crate::logger::log(">> [Core] Peer wants to resume. Bundling delta...");
+                    let snapshot = self.workspace.get_resume_snapshot(&token);
+                    self.send_sync_response(peer, snapshot).await;
+                }
+                Event::RemoteFullSync { files, peer } => {
+                    crate::logger::log(
+                        ">> [Core] Received Full Sync. Hydrating & Writing to Disk...",
+                    );
+
+                    let mut files_to_write = Vec::new();
+                    for (uri, patch) in files {
+                        if self.oversized.contains(&uri) {
+                            continue;
+                        }
+                        // Check if we are actually tracking this file (User has it open)
+                        let is_open = self.workspace.documents.contains_key(&uri);
+
+                        // Hydrate Memory
+                        self.hydrating.remove(&uri);
+                        self.opening.remove(&uri);
+                        let stale = self.stale_in_editor.remove(&uri);
+                        let revert = self.reverting.remove(&uri);
+                        let doc = self.workspace.get_or_create_empty(uri.clone());
+                        let base = doc.content.clone();
+                        let merged = if revert {
+                            doc.revert_to(&patch)
+                        } else {
+                            doc.apply_remote_patch(&patch)
+                        };
+                        if let Err(e) = &merged {
+                            // Asking again would get us the same state, nothing more to try
+                            logger::warn(&format!(
+                                "!! [Core] Full state of '{}' didn't merge: {}",
+                                uri, e
+                            ));
+                        }
+                        let edits_opt = merged.ok().filter(|edits| !edits.is_empty());
+                        let len = doc.content.len_bytes();
+                        if self.reject_oversized(&uri, len).await {
+                            self.workspace.documents.remove(&uri);
+                            continue;
+                        }
+                        let doc = self.workspace.get_or_create_empty(uri.clone());
+                        let frontier = doc.frontier();
+                        let rebased = doc.take_rebased().then(|| doc.outgoing_patch());
+                        self.acknowledge(peer, &uri, frontier).await;
+                        if let Some(patch) = rebased {
+                            let _ = self
+                                .network_tx
+                                .send(NetworkCommand::BroadcastPatch {
+                                    uri: uri.clone(),
+                                    patch,
+                                    agent_id: self.workspace.local_agent_id.clone(),
+                                    exclude: None,
+                                })
+                                .await;
+                        }
+                        let doc = self.workspace.get_or_create_empty(uri.clone());
+
                         // Capture for Disk
                         let content = doc.content.to_string();
                         files_to_write.push((uri.clone(), content));
 
                         // If it's not open, writing to disk (below) is sufficient.
-                        if is_open {
+                        if stale {
+                            // The editor shows its disk copy, not what we merged into
+                            if edits_opt.is_some() {
+                                doc.cancel_echoes(1);
+                            }
+                            let edit = doc.resync_edit();
+                            self.send_to_editor(EditorCommand::ApplyEdits {
+                                uri,
+                                edits: vec![edit],
+                            })
+                            .await;
+                        } else if is_open {
                             if let Some(edits) = edits_opt {
-                                let _ = self
-                                    .editor_tx
-                                    .send(EditorCommand::ApplyEdits { uri, edits })
-                                    .await;
+                                self.hold_for_catch_up(uri, base, edits).await;
                             }
                         } else if edits_opt.is_some() {
-                            doc.pending_remote_updates.fetch_sub(1, Ordering::SeqCst);
+                            doc.cancel_echoes(1);
                         }
                     }
 
                     // Write to Disk
-                    if let Err(e) = crate::fs::write_project_files(files_to_write) {
-                        crate::logger::log(&format!(
+                    if !self.writes_to_disk() {
+                        continue;
+                    }
+                    if let Err(e) = crate::fs::write_project_files(
+                        files_to_write,
+                        &self.sync_ignore,
+                        &self.share,
+                    ) {
+                        crate::logger::warn(&format!(
                             "!! [Disk] Failed to write synced files: {}",
                             e
                         ));
@@ -174,9 +1354,244 @@ impl Core {
                         crate::logger::log(">> [Disk] Full sync written to storage.");
                     }
                 }
-                Event::Shutdown => break,
+                Event::RemoteBye { reason } => {
+                    logger::log(&format!(
+                        "<< [Core] Connection closed by peer: {:?}",
+                        reason
+                    ));
+                    self.send_to_editor(EditorCommand::ShowMessage {
+                        kind: MessageKind::Warning,
+                        message: format!("JustSync: {}", reason.describe()),
+                    })
+                    .await;
+                }
+                Event::EditorReady => {
+                    self.flush_editor_buffer().await;
+                }
+                Event::AutosaveTick => self.autosave(),
+                Event::Shutdown => {
+                    self.flush_local_changes().await;
+                    // Save first, the process exits soon after the network is done
+                    if let Some(root) = &self.state_dir
+                        && let Err(e) = self.workspace.save_to_dir(root)
+                    {
+                        logger::warn(&format!("!! [Core] Could not save session: {}", e));
+                    }
+                    let _ = self.network_tx.send(NetworkCommand::Shutdown).await;
+                    break;
+                }
+            }
+        }
+    }
+
+    /// The editor's content founds the document, unless the host is still
+    /// sending us its version. Then that replaces the editor's once it arrives.
+    async fn handle_client_did_open(&mut self, uri: String, content: String) {
+        if self.reject_oversized(&uri, content.len()).await {
+            return;
+        }
+        self.workspace.mark_open(uri.clone());
+        if self.hydrating.contains(&uri) {
+            // The host's state is on its way, the disk copy would become a second base
+            logger::log(&format!(
+                ">> [Core] {} is still syncing, the editor gets the host's version",
+                uri
+            ));
+            self.workspace.get_or_create_empty(uri.clone());
+            self.stale_in_editor.insert(uri);
+            return;
+        }
+        if !self.compaction
+            && !self.workspace.documents.contains_key(&uri)
+            && !self.sync_ignored(&uri)
+            && let Some(&host) = self.connected.iter().next()
+        {
+            // Someone may have started this file already, a second start from
+            // our disk copy would conflict with theirs. The host knows.
+            logger::log(&format!(
+                ">> [Core] Asking the host whether {} has a history yet",
+                uri
+            ));
+            let _ = self
+                .network_tx
+                .send(NetworkCommand::SendRequestFile {
+                    peer: host,
+                    uri: uri.clone(),
+                })
+                .await;
+            self.workspace.get_or_create_empty(uri.clone());
+            self.hydrating.insert(uri.clone());
+            self.stale_in_editor.insert(uri.clone());
+            self.opening.insert(uri, content);
+            return;
+        }
+        self.workspace.get_or_create(uri, content);
+    }
+
+    /// A peer wants all of a file, to re-sync it or because its editor opened
+    /// it. A file nobody has yet is started once: by the host from its disk
+    /// copy, or else by the first peer to ask. Whoever asks meanwhile waits
+    /// for that start instead of starting a second history.
+    async fn handle_file_request(&mut self, uri: String, peer: PeerId) {
+        logger::log(&format!(">> [Core] Peer re-syncs '{}'", uri));
+        if self.compaction
+            && !self.workspace.documents.contains_key(&uri)
+            && !self.sync_ignored(&uri)
+        {
+            let on_disk = if self.headless {
+                None
+            } else {
+                crate::fs::read_project_file(&uri)
+            };
+            match on_disk {
+                Some(content) => {
+                    if self.reject_oversized(&uri, content.len()).await {
+                        return;
+                    }
+                    self.workspace.get_or_create(uri.clone(), content);
+                }
+                None => {
+                    let key = (self.room.clone(), uri.clone());
+                    if let Some((_, waiting)) = self.claims.get_mut(&key) {
+                        waiting.push(peer);
+                    } else {
+                        self.claims.insert(key, (peer, Vec::new()));
+                        let _ = self
+                            .network_tx
+                            .send(NetworkCommand::SendFileUnknown { peer, uri })
+                            .await;
+                    }
+                    return;
+                }
+            }
+        }
+        let files = self.workspace.get_files_snapshot(&[uri]);
+        if !files.is_empty() {
+            let _ = self
+                .network_tx
+                .send(NetworkCommand::SendFullSyncResponse { peer, files })
+                .await;
+        }
+    }
+
+    /// The host has no history of a file our editor opened, so the editor's
+    /// content starts it for everyone.
+    async fn handle_file_unknown(&mut self, uri: String) {
+        let Some(content) = self.opening.remove(&uri) else {
+            return;
+        };
+        logger::log(&format!(">> [Core] Nobody has {} yet, starting it", uri));
+        self.hydrating.remove(&uri);
+        self.stale_in_editor.remove(&uri);
+        self.workspace.documents.remove(&uri);
+        let patch = self
+            .workspace
+            .get_or_create(uri.clone(), content)
+            .outgoing_patch();
+        let _ = self
+            .network_tx
+            .send(NetworkCommand::BroadcastPatch {
+                uri,
+                patch,
+                agent_id: self.workspace.local_agent_id.clone(),
+                exclude: None,
+            })
+            .await;
+    }
+
+    /// The peers that waited for someone to start `uri` get its history now.
+    async fn settle_claim(&mut self, uri: &str) {
+        let Some((_, waiting)) = self.claims.remove(&(self.room.clone(), uri.to_string())) else {
+            return;
+        };
+        let files = self.workspace.get_files_snapshot(&[uri.to_string()]);
+        for peer in waiting {
+            let _ = self
+                .network_tx
+                .send(NetworkCommand::SendFullSyncResponse {
+                    peer,
+                    files: files.clone(),
+                })
+                .await;
+        }
+    }
+
+    /// A peer that left won't start the files it claimed, the next in line does.
+    async fn release_claims(&mut self, peer: PeerId) {
+        let mut promoted = Vec::new();
+        self.claims.retain(|(_, uri), (claimer, waiting)| {
+            waiting.retain(|&waiter| waiter != peer);
+            if *claimer != peer {
+                return true;
+            }
+            if waiting.is_empty() {
+                return false;
+            }
+            *claimer = waiting.remove(0);
+            promoted.push((*claimer, uri.clone()));
+            true
+        });
+        for (peer, uri) in promoted {
+            let _ = self
+                .network_tx
+                .send(NetworkCommand::SendFileUnknown { peer, uri })
+                .await;
+        }
+    }
+
+    /// A `didChange` for a file the editor never opened with us (some editors
+    /// send them after our own `applyEdit`). Range edits only land right on the
+    /// real content, not on an empty document, so that comes from the disk or
+    /// from a peer first. Returns whether the change can be applied now.
+    async fn base_for_unopened(
+        &mut self,
+        uri: &str,
+        changes: &[TextDocumentContentChangeEvent],
+    ) -> bool {
+        // A whole-buffer change brings its own base
+        if changes.first().is_some_and(|change| change.range.is_none()) {
+            return true;
+        }
+        let syncing = self.hydrating.contains(uri);
+        if !syncing
+            && !self.headless
+            && let Some(content) = crate::fs::read_project_file(uri)
+        {
+            if self.reject_oversized(uri, content.len()).await {
+                return false;
             }
+            logger::log(&format!(
+                ">> [Core] {} changed before it was opened, starting from the disk copy",
+                uri
+            ));
+            self.workspace.get_or_create(uri.to_string(), content);
+            return true;
+        }
+        if !syncing {
+            let Some(&peer) = self.connected.iter().next() else {
+                logger::warn(&format!(
+                    "!! [Core] Dropped an edit to {}, it was never opened and isn't on disk",
+                    uri
+                ));
+                return false;
+            };
+            let _ = self
+                .network_tx
+                .send(NetworkCommand::SendRequestFile {
+                    peer,
+                    uri: uri.to_string(),
+                })
+                .await;
+            self.hydrating.insert(uri.to_string());
         }
+        // Like an editor opening its outdated copy: the peer's version replaces it
+        logger::warn(&format!(
+            "!! [Core] Dropped an edit to {}, it is still syncing",
+            uri
+        ));
+        self.workspace.get_or_create_empty(uri.to_string());
+        self.stale_in_editor.insert(uri.to_string());
+        false
     }
 
     async fn handle_local_change(
@@ -184,11 +1599,53 @@ impl Core {
         uri: String,
         changes: Vec<TextDocumentContentChangeEvent>,
     ) {
+        if self.sync_ignored(&uri) {
+            return;
+        }
+        if self.stale_in_editor.contains(&uri) {
+            // Typed into the outdated copy, the host's version replaces all of it
+            logger::warn(&format!(
+                "!! [Core] Dropped an edit to {}, it is still syncing",
+                uri
+            ));
+            return;
+        }
+        if !self.workspace.documents.contains_key(&uri)
+            && !self.base_for_unopened(&uri, &changes).await
+        {
+            return;
+        }
+        if !self.observer {
+            self.touch_locks(&uri, &changes).await;
+        }
         // Get the document
         let doc = self.workspace.get_or_create_empty(uri.clone());
+        if self.observer {
+            // Our updates still come back from the editor, those aren't edits
+            if !doc.is_echo(&changes) {
+                self.suppressed("patch", &uri);
+            }
+            return;
+        }
+        let coalesce = doc.frontier().is_some();
 
         // Apply logic (The logic inside Document should return the binary patch if effective)
         if let Some(patch) = doc.apply_local_changes(changes) {
+            // The edit is in the CRDT already, one later patch carries it along
+            // with the ones to come. Other modes' patches don't add up like that.
+            if coalesce {
+                self.unsent.insert(uri);
+                self.flush_at
+                    .get_or_insert_with(|| Instant::now() + COALESCE_WINDOW);
+                return;
+            }
+            // Nobody to hear it, the network only keeps our last patch of the
+            // file for when we're back, so that one has to carry all of it
+            let patch = if self.connected.is_empty() {
+                doc.outgoing_patch()
+            } else {
+                patch
+            };
             crate::logger::log(&format!(
                 "-> [Core] Generated Patch for '{}' ({} bytes)",
                 uri,
@@ -196,610 +1653,5317 @@ impl Core {
             ));
             let _ = self
                 .network_tx
-                .send(NetworkCommand::BroadcastPatch { uri, patch })
+                .send(NetworkCommand::BroadcastPatch {
+                    uri,
+                    patch,
+                    agent_id: self.workspace.local_agent_id.clone(),
+                    exclude: None,
+                })
                 .await;
         }
     }
 
-    async fn handle_remote_patch(&mut self, uri: String, patch: Vec<u8>) {
-        crate::logger::log(&format!(
-            "<- [Core] Received Patch for '{}' ({} bytes)",
-            uri,
-            patch.len()
-        ));
-        let is_open = self.workspace.is_open(&uri);
+    /// Sends one patch per document with local edits that haven't gone out yet.
+    async fn flush_local_changes(&mut self) {
+        self.flush_at = None;
+        for uri in std::mem::take(&mut self.unsent) {
+            let Some(doc) = self.workspace.documents.get(&uri) else {
+                // Deleted in the meantime
+                continue;
+            };
+            let patch = doc.outgoing_patch();
+            crate::logger::log(&format!(
+                "-> [Core] Generated Patch for '{}' ({} bytes)",
+                uri,
+                patch.len()
+            ));
+            let _ = self
+                .network_tx
+                .send(NetworkCommand::BroadcastPatch {
+                    uri,
+                    patch,
+                    agent_id: self.workspace.local_agent_id.clone(),
+                    exclude: None,
+                })
+                .await;
+        }
+    }
+
+    /// Both sides start the new document from the same content (see `Document::with_mode`),
+    /// so later patches line up.
+    async fn handle_local_file_created(&mut self, uri: String) {
+        if self.suppressed("file creation", &uri) || self.sync_ignored(&uri) {
+            return;
+        }
+        let Some(content) = crate::fs::read_project_file(&uri) else {
+            return;
+        };
+        logger::log(&format!(">> [Core] File created: {}", uri));
+        let doc = self.workspace.get_or_create(uri.clone(), content);
+        let content = doc.content.to_string();
+        let _ = self
+            .network_tx
+            .send(NetworkCommand::BroadcastFileCreated {
+                uri,
+                content,
+                exclude: None,
+            })
+            .await;
+    }
+
+    async fn handle_external_change(&mut self, uri: String, content: String) {
+        if self.suppressed("disk change", &uri) || self.sync_ignored(&uri) {
+            return;
+        }
+        // The editor owns open files, it reloads them and reports the change itself
+        if self.workspace.is_open(&uri) {
+            logger::log(&format!(
+                ">> [Core] {} changed on disk while open, leaving it to the editor",
+                uri
+            ));
+            return;
+        }
+
+        // A file we didn't know yet is new for everyone else too
+        if !self.workspace.documents.contains_key(&uri) {
+            self.workspace.get_or_create(uri.clone(), content.clone());
+            let _ = self
+                .network_tx
+                .send(NetworkCommand::BroadcastFileCreated {
+                    uri,
+                    content,
+                    exclude: None,
+                })
+                .await;
+            return;
+        }
+
+        // Our own disk writes come back here unchanged and stop at the comparison
         let doc = self.workspace.get_or_create_empty(uri.clone());
-        let edits_opt = doc.apply_remote_patch(&patch);
+        if let Some(patch) = doc.apply_external_content(content) {
+            logger::log(&format!(
+                "-> [Core] Generated Patch for external change to '{}' ({} bytes)",
+                uri,
+                patch.len()
+            ));
+            let _ = self
+                .network_tx
+                .send(NetworkCommand::BroadcastPatch {
+                    uri,
+                    patch,
+                    agent_id: self.workspace.local_agent_id.clone(),
+                    exclude: None,
+                })
+                .await;
+        }
+    }
 
-        if is_open {
-            // Local editor has this file open, edits go to the editor
-            if let Some(edits) = edits_opt {
-                if let Err(e) = self
-                    .editor_tx
-                    .send(EditorCommand::ApplyEdits { uri, edits })
-                    .await
-                {
-                    logger::log(&format!("!! Failed to send edits to editor actor: {}", e));
-                }
-            }
-        } else {
-            // Local editor does not have this file open, so don't tell the editor, instead just write to disk.
-            if edits_opt.is_some() {
-                doc.pending_remote_updates.fetch_sub(1, Ordering::SeqCst);
-            }
+    /// Writes every document that changed since the last autosave to disk.
+    fn autosave(&mut self) {
+        if !self.writes_to_disk() {
+            return;
+        }
+        let changed: Vec<(String, String, u64)> = self
+            .workspace
+            .documents
+            .iter()
+            .filter_map(|(uri, doc)| {
+                let mut hasher = DefaultHasher::new();
+                doc.content.hash(&mut hasher);
+                let hash = hasher.finish();
+                (self.autosaved.get(uri) != Some(&hash))
+                    .then(|| (uri.clone(), doc.content.to_string(), hash))
+            })
+            .collect();
+        if changed.is_empty() {
+            return;
+        }
+        let files = changed
+            .iter()
+            .map(|(uri, content, _)| (uri.clone(), content.clone()))
+            .collect();
+        if let Err(e) = crate::fs::write_project_files(files, &self.sync_ignore, &self.share) {
+            logger::warn(&format!("!! [Disk] Autosave failed: {}", e));
+            return;
+        }
+        logger::log(&format!(">> [Disk] Autosaved {} file(s)", changed.len()));
+        for (uri, _, hash) in changed {
+            self.autosaved.insert(uri, hash);
+        }
+    }
 
-            let content = doc.content.to_string();
-            if let Err(e) = fs::write(&uri, content) {
-                logger::log(&format!("!! Failed to background-write to disk: {}", e));
-            } else {
-                logger::log(&format!(">> [Core] Background-wrote to disk: {}", uri));
-            }
+    /// Undoes everything since checkpoint `label`, for us and for the peers.
+    async fn handle_rollback(&mut self, uri: String, label: String) {
+        if self.suppressed("rollback", &uri) {
+            return;
+        }
+        let Some(doc) = self.workspace.documents.get_mut(&uri) else {
+            return;
+        };
+        let base = doc.content.clone();
+        let Some((patch, edits)) = doc.rollback_to(&label) else {
+            logger::warn(&format!(
+                "!! [Core] Nothing to roll back in {} to '{}'",
+                uri, label
+            ));
+            return;
+        };
+        logger::log(&format!(">> [Core] Rolled {} back to '{}'", uri, label));
+        let _ = self
+            .network_tx
+            .send(NetworkCommand::BroadcastPatch {
+                uri: uri.clone(),
+                patch,
+                agent_id: self.workspace.local_agent_id.clone(),
+                exclude: None,
+            })
+            .await;
+
+        if self.workspace.is_open(&uri) {
+            self.send_edits_to_editor(uri, base, edits).await;
+            return;
+        }
+        // Nobody shows it, so no echo is coming and the disk gets the content
+        let doc = self.workspace.get_or_create_empty(uri.clone());
+        doc.cancel_echoes(1);
+        let content = doc.content.to_string();
+        if self.writes_to_disk()
+            && let Err(e) = crate::fs::write_project_files(
+                vec![(uri.clone(), content)],
+                &self.sync_ignore,
+                &self.share,
+            )
+        {
+            logger::warn(&format!("!! [Disk] Failed to write {}: {}", uri, e));
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::lsp::{Position, Range, TextDocumentContentChangeEvent};
-    use std::time::Duration;
-    use tokio::sync::mpsc;
+    async fn handle_remote_file_created(&mut self, uri: String, content: String, peer: PeerId) {
+        if self.sync_ignored(&uri) || self.reject_oversized(&uri, content.len()).await {
+            return;
+        }
+        logger::log(&format!("<- [Core] {} was created by a peer", uri));
+        let is_open = self.workspace.is_open(&uri);
+        let doc = self.workspace.get_or_create(uri.clone(), content.clone());
+        let on_disk = doc.content.to_string();
+
+        // The editor owns open files, everything else lives on disk
+        if !is_open
+            && self.writes_to_disk()
+            && let Err(e) = crate::fs::write_project_files(
+                vec![(uri.clone(), on_disk)],
+                &self.sync_ignore,
+                &self.share,
+            )
+        {
+            logger::warn(&format!("!! [Disk] Failed to create {}: {}", uri, e));
+        }
+        if self.observer {
+            return;
+        }
+
+        let _ = self
+            .network_tx
+            .send(NetworkCommand::BroadcastFileCreated {
+                uri,
+                content,
+                exclude: Some(peer),
+            })
+            .await;
+    }
+
+    async fn handle_remote_file_deleted(&mut self, uri: String, peer: PeerId) {
+        logger::log(&format!("<- [Core] {} was deleted by a peer", uri));
+        let was_open = self.workspace.is_open(&uri);
+        self.workspace.remove_document(&uri);
+
+        if self.writes_to_disk()
+            && let Err(e) = crate::fs::remove_project_file(&uri)
+        {
+            logger::warn(&format!("!! [Disk] Failed to delete {}: {}", uri, e));
+        }
+        if was_open {
+            self.send_to_editor(EditorCommand::ShowMessage {
+                kind: MessageKind::Info,
+                message: format!("JustSync: {} was deleted by a collaborator", uri),
+            })
+            .await;
+        }
+        if self.observer {
+            return;
+        }
+
+        let _ = self
+            .network_tx
+            .send(NetworkCommand::BroadcastFileDeleted {
+                uri,
+                exclude: Some(peer),
+            })
+            .await;
+    }
+
+    async fn handle_remote_file_renamed(&mut self, from: String, to: String, peer: PeerId) {
+        if self.sync_ignored(&to) {
+            return;
+        }
+        logger::log(&format!(
+            "<- [Core] {} was renamed to {} by a peer",
+            from, to
+        ));
+        // Updates held for the old name belong to the buffer the editor still has
+        self.release_catch_up(&from).await;
+        let was_open = self.workspace.is_open(&from);
+        self.workspace.rename_document(&from, to.clone());
+        // The editor still shows the old name, its edits must not land on the new one
+        self.workspace.mark_closed(&to);
+
+        if self.writes_to_disk()
+            && let Err(e) = crate::fs::rename_project_file(&from, &to)
+        {
+            logger::warn(&format!(
+                "!! [Disk] Failed to rename {} to {}: {}",
+                from, to, e
+            ));
+        }
+        if was_open {
+            self.send_to_editor(EditorCommand::ShowMessage {
+                kind: MessageKind::Info,
+                message: format!("JustSync: {} was renamed to {} by a collaborator", from, to),
+            })
+            .await;
+        }
+        if self.observer {
+            return;
+        }
+
+        let _ = self
+            .network_tx
+            .send(NetworkCommand::BroadcastFileRenamed {
+                from,
+                to,
+                exclude: Some(peer),
+            })
+            .await;
+    }
+
+    /// The editor wrote its buffer, we write what the CRDT says, so the file on disk
+    /// is the synced content even if the two drifted apart.
+    async fn handle_local_save(&mut self, uri: String) {
+        if !self.workspace.documents.contains_key(&uri) || self.suppressed("save", &uri) {
+            return;
+        }
+        logger::log(&format!(">> [Core] {} saved", uri));
+        self.write_synced_content(&uri);
+
+        // Peers write the content they have, it has to include our last edits
+        self.flush_local_changes().await;
+        let _ = self
+            .network_tx
+            .send(NetworkCommand::BroadcastSaved { uri, exclude: None })
+            .await;
+    }
+
+    /// Writing a file the user has open would save their buffer behind their back,
+    /// so only closed ones are written.
+    async fn handle_remote_save(&mut self, uri: String, peer: PeerId) {
+        if !self.workspace.documents.contains_key(&uri) {
+            return;
+        }
+        logger::log(&format!("<- [Core] {} was saved by a peer", uri));
+        if !self.workspace.is_open(&uri) {
+            self.write_synced_content(&uri);
+        }
+        if self.observer {
+            return;
+        }
+        let _ = self
+            .network_tx
+            .send(NetworkCommand::BroadcastSaved {
+                uri,
+                exclude: Some(peer),
+            })
+            .await;
+    }
+
+    /// Writes the document's content to disk, unless it is there already.
+    fn write_synced_content(&self, uri: &str) {
+        let Some(doc) = self.workspace.documents.get(uri) else {
+            return;
+        };
+        if !self.writes_to_disk() {
+            return;
+        }
+        let content = doc.content.to_string();
+        if crate::fs::read_project_file(uri).as_deref() == Some(content.as_str()) {
+            return;
+        }
+        if let Err(e) = crate::fs::write_project_files(
+            vec![(uri.to_string(), content)],
+            &self.sync_ignore,
+            &self.share,
+        ) {
+            logger::warn(&format!("!! [Disk] Failed to write {}: {}", uri, e));
+        }
+    }
+
+    async fn maybe_compact(&mut self, uri: String) {
+        if !self.compaction {
+            return;
+        }
+        let Some(doc) = self.workspace.documents.get_mut(&uri) else {
+            return;
+        };
+        let agents = self.peer_agents.values().flatten().map(String::as_str);
+        if !doc.should_compact(agents) {
+            return;
+        }
+        if let Some(state) = doc.compact() {
+            let _ = self
+                .network_tx
+                .send(NetworkCommand::BroadcastPatch {
+                    uri,
+                    patch: state,
+                    agent_id: self.workspace.local_agent_id.clone(),
+                    exclude: None,
+                })
+                .await;
+        }
+    }
+
+    async fn handle_remote_patch(
+        &mut self,
+        uri: String,
+        patch: Vec<u8>,
+        peer: PeerId,
+        agent_id: String,
+    ) {
+        crate::logger::log(&format!(
+            "<- [Core] Received Patch for '{}' ({} bytes)",
+            uri,
+            patch.len()
+        ));
+        if self.sync_ignored(&uri) {
+            return;
+        }
+        if self.read_only.contains(&uri) {
+            logger::warn(&format!(
+                "!! [ReadOnly] Refused patch to '{}' from peer {}",
+                uri, peer
+            ));
+            let _ = self
+                .network_tx
+                .send(NetworkCommand::SendRejected {
+                    peer,
+                    uri,
+                    reason: "the host shares this file read-only".into(),
+                })
+                .await;
+            return;
+        }
+        let is_open = self.workspace.is_open(&uri);
+        let doc = self.workspace.get_or_create_empty(uri.clone());
+        let base = doc.content.clone();
+        let before = doc.frontier();
+        let merged = doc.apply_remote_patch(&patch);
+        let frontier = doc.frontier();
+        let overwritten = doc.take_overwrite_notice();
+        let merge_error = merged.as_ref().err().cloned();
+        let edits_opt = merged.ok().filter(|edits| !edits.is_empty());
+        // Our edits replayed onto a compacted history have to reach the sender too
+        let rebased = doc.take_rebased();
+
+        // Pass news on to everyone else (only the host has anyone else, and
+        // observers pass nothing on). CRDT patches are re-encoded so they fit
+        // what the other peers acked.
+        let relay = match &frontier {
+            _ if self.observer => None,
+            Some(_) if rebased || frontier != before => Some(doc.outgoing_patch()),
+            Some(_) => None,
+            None => edits_opt.is_some().then(|| patch.clone()),
+        };
+        self.acknowledge(peer, &uri, frontier).await;
+        if let Some(patch) = relay {
+            let _ = self
+                .network_tx
+                .send(NetworkCommand::BroadcastPatch {
+                    uri: uri.clone(),
+                    patch,
+                    agent_id: agent_id.clone(),
+                    exclude: (!rebased).then_some(peer),
+                })
+                .await;
+        }
+
+        if let Some(e) = merge_error {
+            // Dropping the patch would leave us diverged for good, the sender's
+            // full state has everything we missed
+            logger::warn(&format!(
+                "!! [Core] Merge conflict in '{}' ({}), re-syncing it from peer {}",
+                uri, e, peer
+            ));
+            let _ = self
+                .network_tx
+                .send(NetworkCommand::SendRequestFile {
+                    peer,
+                    uri: uri.clone(),
+                })
+                .await;
+            self.send_to_editor(EditorCommand::ShowMessage {
+                kind: MessageKind::Warning,
+                message: format!("JustSync: {} went out of sync, fetching it again", uri),
+            })
+            .await;
+        }
+
+        if overwritten {
+            logger::warn(&format!(
+                "!! [LWW] Local version of '{}' was overwritten",
+                uri
+            ));
+            self.send_to_editor(EditorCommand::ShowMessage {
+                kind: MessageKind::Warning,
+                message: format!(
+                    "JustSync: your version of {} was overwritten by a concurrent edit",
+                    uri
+                ),
+            })
+            .await;
+        }
+
+        if edits_opt.is_some() {
+            // A patch can carry other agents' ops too, the sender is who we name
+            self.attribute(&uri, agent_id).await;
+        }
+        self.settle_claim(&uri).await;
+
+        if is_open {
+            // Local editor has this file open, edits go to the editor
+            if let Some(edits) = edits_opt {
+                self.hold_for_catch_up(uri, base, edits).await;
+            }
+        } else {
+            // Local editor does not have this file open, so don't tell the editor, instead just write to disk.
+            let writes_to_disk = self.writes_to_disk();
+            let doc = self.workspace.get_or_create_empty(uri.clone());
+            if edits_opt.is_some() {
+                doc.cancel_echoes(1);
+            }
+
+            if !writes_to_disk {
+                return;
+            }
+            let content = doc.content.to_string();
+            if let Err(e) = crate::fs::write_project_file(&uri, &content) {
+                logger::warn(&format!("!! Failed to background-write to disk: {}", e));
+            } else {
+                logger::log(&format!(">> [Core] Background-wrote to disk: {}", uri));
+            }
+        }
+    }
+
+    /// Remembers `agent_id` as the last one to change `uri`, and tells the
+    /// editor when that is someone new.
+    async fn attribute(&mut self, uri: &str, agent_id: String) {
+        if agent_id.is_empty() || self.last_editor.get(uri) == Some(&agent_id) {
+            return;
+        }
+        self.last_editor.insert(uri.to_string(), agent_id.clone());
+        let display_name = self.name_of(&agent_id);
+        self.send_to_editor(EditorCommand::Attribution {
+            uri: uri.to_string(),
+            agent_id,
+            display_name,
+        })
+        .await;
+    }
+
+    /// The host's copy of `uri` hashes to `sha256` at `frontier`. If ours is at the
+    /// same version it must hash the same, otherwise we drifted apart without
+    /// anyone noticing (a dropped patch, an offset bug). The host's full state
+    /// replaces ours then.
+    async fn handle_remote_digest(
+        &mut self,
+        uri: String,
+        sha256: String,
+        frontier: Frontier,
+        peer: PeerId,
+    ) {
+        let Some(doc) = self.workspace.documents.get(&uri) else {
+            return;
+        };
+        let Some(ours) = doc.frontier() else {
+            return;
+        };
+        if !same_version(&ours, &frontier) || doc.digest() == sha256 {
+            return;
+        }
+        logger::warn(&format!("!! [Integrity] divergence on {}", uri));
+        if !self.reverting.insert(uri.clone()) {
+            return;
+        }
+        let _ = self
+            .network_tx
+            .send(NetworkCommand::SendRequestFile { peer, uri })
+            .await;
+    }
+
+    /// Our change never made it into the session. The host's full state
+    /// replaces ours once it arrives, which undoes the change in the editor too.
+    async fn handle_rejected(&mut self, uri: String, reason: String, peer: PeerId) {
+        logger::warn(&format!(
+            "!! [ReadOnly] Peer {} refused our change to '{}': {}",
+            peer, uri, reason
+        ));
+        if !self.reverting.insert(uri.clone()) {
+            // Already fetching it, one full state undoes all of them
+            return;
+        }
+        let _ = self
+            .network_tx
+            .send(NetworkCommand::SendRequestFile {
+                peer,
+                uri: uri.clone(),
+            })
+            .await;
+        self.send_to_editor(EditorCommand::ShowMessage {
+            kind: MessageKind::Warning,
+            message: format!("JustSync: your change to {} was undone, {}", uri, reason),
+        })
+        .await;
+    }
+
+    // =========================================================================
+    //  NETWORK OUTPUT
+    // =========================================================================
+
+    /// Tells the sender which version we now have, so it can send deltas from there.
+    async fn acknowledge(&mut self, peer: PeerId, uri: &str, frontier: Option<Frontier>) {
+        let Some(frontier) = frontier else {
+            return;
+        };
+        let _ = self
+            .network_tx
+            .send(NetworkCommand::SendAck {
+                peer,
+                uri: uri.to_string(),
+                agent: self.workspace.local_agent_id.clone(),
+                frontier,
+            })
+            .await;
+    }
+
+    /// Asks the host for the files we are missing or behind on. Whatever we already
+    /// have completely is acked instead, so the host sends deltas from there on.
+    async fn handle_sync_manifest(&mut self, files: Vec<(String, Option<Frontier>)>, peer: PeerId) {
+        let uris = self.workspace.missing_from(&files);
+        self.hydrating.extend(uris.iter().cloned());
+        logger::log(&format!(
+            "<- [Core] Host has {} file(s), fetching {}",
+            files.len(),
+            uris.len()
+        ));
+
+        for (uri, _) in &files {
+            if !uris.contains(uri) {
+                let frontier = self.workspace.documents.get(uri).and_then(|d| d.frontier());
+                self.acknowledge(peer, uri, frontier).await;
+            }
+        }
+        // Even when nothing is missing, so the host hands out a resume token
+        let _ = self
+            .network_tx
+            .send(NetworkCommand::SendRequestFiles { peer, uris })
+            .await;
+    }
+
+    /// Sends a (full or delta) snapshot to the peer, followed by a fresh resume token.
+    /// One message per file, like a full sync: what arrived before a drop stays merged.
+    async fn send_sync_response(&mut self, peer: PeerId, snapshot: Vec<(String, Vec<u8>)>) {
+        for file in snapshot
+            .into_iter()
+            .filter(|(uri, _)| !crate::fs::is_blank_path(uri))
+        {
+            let _ = self
+                .network_tx
+                .send(NetworkCommand::SendFullSyncResponse {
+                    peer,
+                    files: vec![file],
+                })
+                .await;
+        }
+        let _ = self
+            .network_tx
+            .send(NetworkCommand::SendResumeToken {
+                peer,
+                token: self.workspace.issue_resume_token(),
+            })
+            .await;
+    }
+
+    /// Starts or stops following someone. Starting jumps to where they were last seen.
+    async fn handle_follow(&mut self, agent_id: Option<String>) {
+        match &agent_id {
+            Some(agent) => logger::log(&format!(">> [Core] Following {}", agent)),
+            None => logger::log(">> [Core] Stopped following"),
+        }
+        let last_seen = agent_id
+            .as_ref()
+            .and_then(|agent| self.viewports.get(agent))
+            .cloned();
+        self.following = agent_id;
+        if let Some((uri, line)) = last_seen {
+            self.send_to_editor(EditorCommand::Reveal { uri, line })
+                .await;
+        }
+    }
+
+    /// Answers a status request. `None` when there was no peer to compare with.
+    async fn send_status(&mut self, id: serde_json::Value, files: Option<Vec<FileStatus>>) {
+        self.send_to_editor(EditorCommand::Status { id, files })
+            .await;
+    }
+
+    /// Tells a new connection who we are, and who else we know of,
+    /// so someone joining a running session can name everyone right away.
+    /// Make `room`'s workspace the current one, a room nobody used before
+    /// starts out empty. Only a relay with room peers has more than one.
+    fn enter_room(&mut self, room: RoomId) {
+        if room != self.room {
+            let workspace = self.rooms.remove(&room).unwrap_or_else(|| {
+                let mut workspace = Workspace::new(self.workspace.local_agent_id.clone());
+                workspace.mode = self.workspace.mode;
+                workspace
+            });
+            let left = std::mem::replace(&mut self.workspace, workspace);
+            self.rooms
+                .insert(std::mem::replace(&mut self.room, room), left);
+        }
+        let has_rooms = self.headless && !self.peer_rooms.is_empty();
+        self.network_tx.room = has_rooms.then(|| self.room.clone());
+    }
+
+    /// The room an event belongs to, the default one unless it came from a
+    /// peer in a room.
+    fn room_of(&self, event: &Event) -> RoomId {
+        let peer = match event {
+            Event::PeerConnected { room, .. } => return room.clone(),
+            Event::PeerTimedOut { agent_id } => {
+                self.liveness.last_seen.get(agent_id).map(|(peer, _)| *peer)
+            }
+            Event::RemotePatch { peer, .. }
+            | Event::RemoteFileCreated { peer, .. }
+            | Event::RemoteFileDeleted { peer, .. }
+            | Event::RemoteFileRenamed { peer, .. }
+            | Event::RemoteSaved { peer, .. }
+            | Event::RemoteCursor { peer, .. }
+            | Event::RemoteSelection { peer, .. }
+            | Event::RemoteLock { peer, .. }
+            | Event::RemoteViewport { peer, .. }
+            | Event::RemotePing { peer, .. }
+            | Event::RemotePong { peer, .. }
+            | Event::RemoteDigest { peer, .. }
+            | Event::PeerRequestedSync { peer }
+            | Event::RemoteAck { peer, .. }
+            | Event::RemoteHello { peer, .. }
+            | Event::PeerDisconnected { peer_id: peer }
+            | Event::RemoteHeartbeat { peer, .. }
+            | Event::PeerRequestedResume { peer, .. }
+            | Event::RemoteFileUnknown { peer, .. }
+            | Event::RemoteSyncManifest { peer, .. }
+            | Event::PeerRequestedFiles { peer, .. }
+            | Event::PeerRequestedFile { peer, .. }
+            | Event::RemoteRejected { peer, .. }
+            | Event::RemoteFullSync { peer, .. } => Some(*peer),
+            _ => None,
+        };
+        peer.and_then(|peer| self.peer_rooms.get(&peer))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    async fn introduce_to(&mut self, peer: PeerId) {
+        let own = (
+            self.workspace.local_agent_id.clone(),
+            self.display_name.clone(),
+        );
+        // On a relay, only the people in the same room
+        let room = self.peer_rooms.get(&peer);
+        let roommates: HashSet<&String> = self
+            .greeted
+            .iter()
+            .filter(|(other, _)| self.peer_rooms.get(*other) == room)
+            .flat_map(|(_, agents)| agents)
+            .collect();
+        let known = self
+            .names
+            .iter()
+            .filter(|(agent, _)| !self.headless || roommates.contains(agent))
+            .map(|(a, n)| (a.clone(), n.clone()));
+        let hellos: Vec<(String, String)> = std::iter::once(own).chain(known).collect();
+        for (agent_id, display_name) in hellos {
+            let _ = self
+                .network_tx
+                .send(NetworkCommand::SendHello {
+                    peer,
+                    agent_id,
+                    display_name,
+                })
+                .await;
+        }
+    }
+
+    /// What to call `agent_id`: its display name, or the id itself until it said hello.
+    fn name_of(&self, agent_id: &str) -> String {
+        self.names
+            .get(agent_id)
+            .cloned()
+            .unwrap_or_else(|| agent_id.to_string())
+    }
+
+    /// Everyone behind a connection, for the logs.
+    fn names_of(&self, peer: PeerId) -> String {
+        self.peer_names(peer).join(", ")
+    }
+
+    /// Everyone who acked or said hello over a connection, sorted.
+    fn peer_names(&self, peer: PeerId) -> Vec<String> {
+        let agents: HashSet<&String> = [&self.peer_agents, &self.greeted]
+            .into_iter()
+            .filter_map(|agents| agents.get(&peer))
+            .flatten()
+            .collect();
+        let mut names: Vec<String> = agents.into_iter().map(|a| self.name_of(a)).collect();
+        names.sort();
+        names
+    }
+
+    fn control_status(&self) -> CoreStatus {
+        let mut files: Vec<FileVersion> = self
+            .workspace
+            .documents
+            .iter()
+            .map(|(uri, doc)| FileVersion {
+                uri: uri.clone(),
+                ops: doc.version_summary().total(),
+                open: self.workspace.open_files.contains(uri),
+            })
+            .collect();
+        files.sort_by(|a, b| a.uri.cmp(&b.uri));
+        let names = self
+            .connected
+            .iter()
+            .map(|peer| (*peer, self.peer_names(*peer)))
+            .collect();
+        CoreStatus {
+            files,
+            names,
+            shared: self.share.clone(),
+        }
+    }
+
+    async fn handle_local_lock(&mut self, uri: String, range: Option<Range>) {
+        if self.observer {
+            self.suppressed("lock", &uri);
+            return;
+        }
+        let agent_id = self.workspace.local_agent_id.clone();
+        match range {
+            Some(range) => {
+                self.locks.insert(
+                    (uri.clone(), agent_id.clone()),
+                    RangeLock {
+                        range: range.clone(),
+                        peer: None,
+                        claimed: Instant::now(),
+                        warned: false,
+                    },
+                );
+                let _ = self
+                    .network_tx
+                    .send(NetworkCommand::BroadcastLock {
+                        uri,
+                        range,
+                        agent_id,
+                    })
+                    .await;
+            }
+            None => {
+                if self
+                    .locks
+                    .remove(&(uri.clone(), agent_id.clone()))
+                    .is_some()
+                {
+                    let _ = self
+                        .network_tx
+                        .send(NetworkCommand::BroadcastUnlock { uri, agent_id })
+                        .await;
+                }
+            }
+        }
+    }
+
+    /// Keeps our claim on `uri` alive while we edit it, and warns (once per
+    /// claim) when `changes` land inside someone else's.
+    async fn touch_locks(&mut self, uri: &str, changes: &[TextDocumentContentChangeEvent]) {
+        let now = Instant::now();
+        let mut refreshed = None;
+        let mut warnings = Vec::new();
+        for ((lock_uri, agent_id), lock) in self.locks.iter_mut() {
+            if lock_uri != uri {
+                continue;
+            }
+            if lock.peer.is_none() {
+                // Re-announced halfway, so the others' timers never run out while we type
+                if now.duration_since(lock.claimed) >= LOCK_TIMEOUT / 2 {
+                    refreshed = Some(lock.range.clone());
+                }
+                lock.claimed = now;
+            } else if !lock.warned
+                && changes.iter().any(|change| {
+                    change
+                        .range
+                        .as_ref()
+                        .is_none_or(|range| overlaps(range, &lock.range))
+                })
+            {
+                lock.warned = true;
+                warnings.push((agent_id.clone(), lock.range.clone()));
+            }
+        }
+        if let Some(range) = refreshed {
+            let _ = self
+                .network_tx
+                .send(NetworkCommand::BroadcastLock {
+                    uri: uri.to_string(),
+                    range,
+                    agent_id: self.workspace.local_agent_id.clone(),
+                })
+                .await;
+        }
+        for (agent_id, range) in warnings {
+            self.send_to_editor(EditorCommand::ShowMessage {
+                kind: MessageKind::Warning,
+                message: format!(
+                    "JustSync: {} is working on lines {}-{} of {}, your edit merges anyway",
+                    self.name_of(&agent_id),
+                    range.start.line + 1,
+                    range.end.line + 1,
+                    uri
+                ),
+            })
+            .await;
+        }
+    }
+
+    /// Releases every claim nobody touched for `LOCK_TIMEOUT`. Ours are
+    /// announced, the others' holders release theirs on their own.
+    async fn expire_locks(&mut self, now: Instant) {
+        let expired: Vec<((String, String), bool)> = self
+            .locks
+            .iter()
+            .filter(|(_, lock)| now.duration_since(lock.claimed) >= LOCK_TIMEOUT)
+            .map(|(key, lock)| (key.clone(), lock.peer.is_none()))
+            .collect();
+        for ((uri, agent_id), ours) in expired {
+            logger::log(&format!(
+                ">> [Core] The lock of {} on {} expired",
+                self.name_of(&agent_id),
+                uri
+            ));
+            self.release_lock(uri, agent_id, ours).await;
+        }
+    }
+
+    /// Releases the claims `matches` picks (by agent), locally and for the
+    /// peers we relay to.
+    async fn clear_locks(&mut self, matches: impl Fn(&str, &RangeLock) -> bool) {
+        let cleared: Vec<(String, String)> = self
+            .locks
+            .iter()
+            .filter(|((_, agent_id), lock)| matches(agent_id, lock))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for (uri, agent_id) in cleared {
+            self.release_lock(uri, agent_id, true).await;
+        }
+    }
+
+    async fn release_lock(&mut self, uri: String, agent_id: String, announce: bool) {
+        self.locks.remove(&(uri.clone(), agent_id.clone()));
+        if announce {
+            let _ = self
+                .network_tx
+                .send(NetworkCommand::BroadcastUnlock {
+                    uri: uri.clone(),
+                    agent_id: agent_id.clone(),
+                })
+                .await;
+        }
+        self.send_to_editor(EditorCommand::RemoteLock {
+            uri,
+            display_name: self.name_of(&agent_id),
+            agent_id,
+            range: None,
+        })
+        .await;
+    }
+
+    /// Removes every cursor and selection that came in over a dropped connection,
+    /// locally and for the peers we relay to (they can't tell the connection is gone).
+    async fn clear_presence_of(&mut self, peer: PeerId) {
+        self.clear_locks(|_, lock| lock.peer == Some(peer)).await;
+        for (agent_id, uri) in self.selections.remove(&peer).unwrap_or_default() {
+            self.clear_selection(agent_id, uri).await;
+        }
+        for (agent_id, uri) in self.presence.remove(&peer).unwrap_or_default() {
+            self.clear_cursor(agent_id, uri).await;
+        }
+    }
+
+    /// Like `clear_presence_of`, for one agent wherever we heard it from.
+    async fn clear_presence_of_agent(&mut self, agent_id: &str) {
+        self.clear_locks(|agent, lock| lock.peer.is_some() && agent == agent_id)
+            .await;
+        let selections: Vec<String> = self
+            .selections
+            .values_mut()
+            .filter_map(|selections| selections.remove(agent_id))
+            .collect();
+        for uri in selections {
+            self.clear_selection(agent_id.to_string(), uri).await;
+        }
+        let cursors: Vec<String> = self
+            .presence
+            .values_mut()
+            .filter_map(|cursors| cursors.remove(agent_id))
+            .collect();
+        for uri in cursors {
+            self.clear_cursor(agent_id.to_string(), uri).await;
+        }
+    }
+
+    async fn clear_selection(&mut self, agent_id: String, uri: String) {
+        let _ = self
+            .network_tx
+            .send(NetworkCommand::BroadcastSelection {
+                uri: uri.clone(),
+                agent_id: agent_id.clone(),
+                range: None,
+            })
+            .await;
+        self.send_to_editor(EditorCommand::RemoteSelection {
+            uri,
+            display_name: self.name_of(&agent_id),
+            agent_id,
+            range: None,
+        })
+        .await;
+    }
+
+    async fn clear_cursor(&mut self, agent_id: String, uri: String) {
+        let _ = self
+            .network_tx
+            .send(NetworkCommand::BroadcastCursor {
+                uri: uri.clone(),
+                agent_id: agent_id.clone(),
+                position: None,
+            })
+            .await;
+        self.send_to_editor(EditorCommand::RemoteCursor {
+            uri,
+            display_name: self.name_of(&agent_id),
+            agent_id,
+            position: None,
+        })
+        .await;
+    }
+
+    /// Lets everyone know we're still here. Observers stay invisible.
+    async fn send_heartbeat(&mut self) {
+        if self.observer || self.connected.is_empty() {
+            return;
+        }
+        let _ = self
+            .network_tx
+            .send(NetworkCommand::BroadcastHeartbeat {
+                agent_id: self.workspace.local_agent_id.clone(),
+            })
+            .await;
+    }
+
+    /// Tells the peers what our copy of each file hashes to, see `Event::RemoteDigest`.
+    /// Only the host does, it's the copy everybody syncs with. On a relay every
+    /// room gets the digests of its own files.
+    async fn send_digests(&mut self) {
+        if !self.compaction {
+            return;
+        }
+        let rooms: Vec<RoomId> = self.rooms.keys().cloned().collect();
+        self.send_room_digests().await;
+        for room in rooms {
+            self.enter_room(room);
+            self.send_room_digests().await;
+        }
+    }
+
+    async fn send_room_digests(&mut self) {
+        if self.connected.is_empty() {
+            return;
+        }
+        // Files with edits still in the coalesce window are ahead of everyone anyway
+        let digests: Vec<(String, String, Frontier)> = self
+            .workspace
+            .documents
+            .values()
+            .filter(|doc| !self.unsent.contains(&doc.uri))
+            .filter(|doc| !self.left_out(&doc.uri))
+            .filter_map(|doc| Some((doc.uri.clone(), doc.digest(), doc.frontier()?)))
+            .collect();
+        for (uri, sha256, frontier) in digests {
+            let _ = self
+                .network_tx
+                .send(NetworkCommand::BroadcastDigest {
+                    uri,
+                    sha256,
+                    frontier,
+                })
+                .await;
+        }
+    }
+
+    /// Whether `.justsyncignore` or `--ignore` keeps `uri` out of the session. Logs it if so.
+    /// `sync_ignored` without the logging, for whole lists of files.
+    fn left_out(&self, uri: &str) -> bool {
+        self.sync_ignore.is_ignored(uri, false) || !crate::fs::is_shared(uri, &self.share)
+    }
+
+    fn sync_ignored(&self, uri: &str) -> bool {
+        if self.oversized.contains(uri) {
+            logger::debug(&format!(
+                ">> [Core] {} is over --max-file-size, not syncing it",
+                uri
+            ));
+            return true;
+        }
+        if !crate::fs::is_shared(uri, &self.share) {
+            logger::debug(&format!(
+                ">> [Core] {} isn't in --share, not syncing it",
+                uri
+            ));
+            return true;
+        }
+        let ignored = self.sync_ignore.is_ignored(uri, false);
+        if ignored {
+            logger::debug(&format!(
+                ">> [Core] {} is in .justsyncignore or --ignore, not syncing it",
+                uri
+            ));
+        }
+        ignored
+    }
+
+    /// Whether `len` bytes are too many for `uri` to be synced. Such a file stays
+    /// out of the session from then on, the user hears about it once.
+    async fn reject_oversized(&mut self, uri: &str, len: usize) -> bool {
+        let max = self.max_file_len;
+        if len <= max {
+            return false;
+        }
+        if self.oversized.insert(uri.to_string()) {
+            logger::warn(&format!(
+                "!! [Core] {} has {} bytes, more than --max-file-size, not syncing it",
+                uri, len
+            ));
+            self.send_to_editor(EditorCommand::ShowMessage {
+                kind: MessageKind::Warning,
+                message: format!(
+                    "JustSync: {} is larger than {} KiB (--max-file-size) and won't be synced",
+                    uri,
+                    max / 1024
+                ),
+            })
+            .await;
+        }
+        true
+    }
+
+    /// Relays have no project and observers must not touch theirs.
+    fn writes_to_disk(&self) -> bool {
+        !self.headless && !self.observer
+    }
+
+    /// Whether a local action stays local because we only observe. Logs it if so.
+    fn suppressed(&self, what: &str, uri: &str) -> bool {
+        if self.observer {
+            logger::log(&format!(
+                ">> [Observer] suppressed outbound {} for {}",
+                what, uri
+            ));
+        }
+        self.observer
+    }
+
+    // =========================================================================
+    //  EDITOR OUTPUT (buffered until the editor is ready)
+    // =========================================================================
+
+    async fn send_to_editor(&mut self, cmd: EditorCommand) {
+        if self.headless {
+            return;
+        }
+        if let Err(cmd) = self.try_send_to_editor(cmd) {
+            self.buffer_for_editor(PendingEditorCommand::Other(cmd));
+        }
+    }
+
+    /// `base` is the document content the edits were computed against.
+    async fn send_edits_to_editor(&mut self, uri: String, base: Rope, edits: Vec<TextEdit>) {
+        if self.headless
+            || self
+                .try_send_to_editor(EditorCommand::ApplyEdits {
+                    uri: uri.clone(),
+                    edits,
+                })
+                .is_ok()
+        {
+            return;
+        }
+
+        // Fold into an already buffered batch for this file, keeping its original base
+        for pending in self.editor_buffer.iter_mut() {
+            if let PendingEditorCommand::Edits {
+                uri: pending_uri,
+                count,
+                ..
+            } = pending
+                && *pending_uri == uri
+            {
+                *count += 1;
+                return;
+            }
+        }
+        self.buffer_for_editor(PendingEditorCommand::Edits {
+            uri,
+            base,
+            count: 1,
+        });
+    }
+
+    /// Holds remote edits to an open document for `CATCH_UP_WINDOW`, together
+    /// with whatever else arrives for it until then.
+    async fn hold_for_catch_up(&mut self, uri: String, base: Rope, edits: Vec<TextEdit>) {
+        if !self.editor_ready {
+            // The editor buffer folds them together already
+            self.send_edits_to_editor(uri, base, edits).await;
+            return;
+        }
+        self.catching_up
+            .entry(uri)
+            .and_modify(|held| held.count += 1)
+            .or_insert(CatchUp {
+                base,
+                edits,
+                count: 1,
+            });
+        self.catch_up_at
+            .get_or_insert_with(|| Instant::now() + CATCH_UP_WINDOW);
+    }
+
+    /// Sends every held document to the editor, one update each.
+    async fn flush_remote_edits(&mut self) {
+        self.catch_up_at = None;
+        let uris: Vec<String> = self.catching_up.keys().cloned().collect();
+        for uri in uris {
+            self.release_catch_up(&uri).await;
+        }
+    }
+
+    /// Sends what is held for `uri`: one update from what the editor shows to our content.
+    async fn release_catch_up(&mut self, uri: &str) {
+        let Some(held) = self.catching_up.remove(uri) else {
+            return;
+        };
+        if self.catching_up.is_empty() {
+            self.catch_up_at = None;
+        }
+        if held.count == 1 {
+            self.send_edits_to_editor(uri.to_string(), held.base, held.edits)
+                .await;
+            return;
+        }
+        let Some(doc) = self.workspace.documents.get_mut(uri) else {
+            // Deleted in the meantime
+            return;
+        };
+        let edits = crate::diff::calculate_edits(&held.base, &doc.content);
+        logger::log(&format!(
+            ">> [Core] Caught up on {} updates to {} at once",
+            held.count, uri
+        ));
+        // `count` updates were announced to the echo guard, but they go out as one
+        doc.cancel_echoes(held.count);
+        if edits.is_empty() {
+            return;
+        }
+        doc.expect_echo(held.base.clone());
+        self.send_edits_to_editor(uri.to_string(), held.base, edits)
+            .await;
+    }
+
+    /// Hands `cmd` to the editor actor if it can take it right away. Gives it back
+    /// when it has to wait: the editor isn't ready, its channel is full, or older
+    /// commands are still waiting (they go first).
+    fn try_send_to_editor(&self, cmd: EditorCommand) -> Result<(), EditorCommand> {
+        if !self.editor_ready || !self.editor_buffer.is_empty() {
+            return Err(cmd);
+        }
+        match self.editor_tx.try_send(cmd) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(cmd)) => {
+                logger::warn("!! [Core] Editor is falling behind, holding back its updates");
+                Err(cmd)
+            }
+            Err(TrySendError::Closed(_)) => {
+                logger::warn("!! Failed to send command to editor actor: channel closed");
+                Ok(())
+            }
+        }
+    }
+
+    fn buffer_for_editor(&mut self, pending: PendingEditorCommand) {
+        if self.editor_buffer.len() >= self.editor_buffer_limit
+            && let Some(dropped) = self.editor_buffer.pop_front()
+        {
+            logger::warn(&format!(
+                "!! [Core] Editor buffer full ({} entries), dropping oldest",
+                self.editor_buffer_limit
+            ));
+            // The editor will never echo edits it never got
+            if let PendingEditorCommand::Edits { uri, count, .. } = dropped
+                && let Some(doc) = self.workspace.documents.get_mut(&uri)
+            {
+                doc.cancel_echoes(count);
+            }
+        }
+        self.editor_buffer.push_back(pending);
+    }
+
+    /// The run loop hands the buffered commands over as the editor takes them.
+    async fn flush_editor_buffer(&mut self) {
+        self.editor_ready = true;
+        if !self.editor_buffer.is_empty() {
+            logger::log(&format!(
+                ">> [Core] Editor ready, flushing {} buffered commands",
+                self.editor_buffer.len()
+            ));
+        }
+    }
+
+    /// The oldest buffered command, with buffered edits turned into one update.
+    fn next_buffered_for_editor(&mut self) -> Option<EditorCommand> {
+        while let Some(pending) = self.editor_buffer.pop_front() {
+            match pending {
+                PendingEditorCommand::Other(cmd) => return Some(cmd),
+                PendingEditorCommand::Edits { uri, base, count } => {
+                    let Some(doc) = self.workspace.documents.get_mut(&uri) else {
+                        continue;
+                    };
+                    let edits = crate::diff::calculate_edits(&base, &doc.content);
+
+                    // `count` updates were announced to the echo guard, but they go out as one
+                    doc.cancel_echoes(count);
+                    if !edits.is_empty() {
+                        doc.expect_echo(base);
+                        return Some(EditorCommand::ApplyEdits { uri, edits });
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+/// `event` without the files of a peer's that would lead outside the project,
+/// `None` if it is only about such a file.
+fn vetted(event: Event) -> Option<Event> {
+    match event {
+        Event::RemotePatch { ref uri, .. }
+        | Event::RemoteFileCreated { ref uri, .. }
+        | Event::RemoteFileDeleted { ref uri, .. }
+        | Event::RemoteSaved { ref uri, .. }
+        | Event::RemoteCursor { ref uri, .. }
+        | Event::RemoteSelection { ref uri, .. }
+        | Event::RemoteViewport { ref uri, .. }
+        | Event::RemoteLock { ref uri, .. }
+        | Event::RemoteDigest { ref uri, .. }
+        | Event::RemoteAck { ref uri, .. }
+        | Event::PeerRequestedFile { ref uri, .. }
+        | Event::RemoteRejected { ref uri, .. }
+        | Event::RemoteFileUnknown { ref uri, .. } => (!rejected_path(uri)).then_some(event),
+        Event::RemoteFileRenamed {
+            ref from, ref to, ..
+        } => (!rejected_path(from) && !rejected_path(to)).then_some(event),
+        Event::RemoteFullSync { files, peer } => Some(Event::RemoteFullSync {
+            files: files
+                .into_iter()
+                .filter(|(uri, _)| !rejected_path(uri))
+                .collect(),
+            peer,
+        }),
+        Event::RemoteSyncManifest { files, peer } => Some(Event::RemoteSyncManifest {
+            files: files
+                .into_iter()
+                .filter(|(uri, _)| !rejected_path(uri))
+                .collect(),
+            peer,
+        }),
+        Event::PeerRequestedFiles { uris, peer } => Some(Event::PeerRequestedFiles {
+            uris: uris.into_iter().filter(|uri| !rejected_path(uri)).collect(),
+            peer,
+        }),
+        Event::RemotePing { versions, peer } => Some(Event::RemotePing {
+            versions: versions
+                .into_iter()
+                .filter(|(uri, _)| !rejected_path(uri))
+                .collect(),
+            peer,
+        }),
+        Event::RemotePong { versions, peer } => Some(Event::RemotePong {
+            versions: versions
+                .into_iter()
+                .filter(|(uri, _)| !rejected_path(uri))
+                .collect(),
+            peer,
+        }),
+        event => Some(event),
+    }
+}
+
+/// Whether a peer's `uri` would lead outside the project. Logs the attempt.
+fn rejected_path(uri: &str) -> bool {
+    let rejected = !crate::fs::is_safe_relative_path(uri);
+    if rejected {
+        logger::warn(&format!("!! [Security] Rejected path: {}", uri));
+    }
+    rejected
+}
+
+/// Whether `a` and `b` share a position, touching ends count.
+fn overlaps(a: &Range, b: &Range) -> bool {
+    let key = |p: &Position| (p.line, p.character);
+    key(&a.start) <= key(&b.end) && key(&b.start) <= key(&a.end)
+}
+
+/// Whether two frontiers name the same version, in whatever order.
+fn same_version(a: &Frontier, b: &Frontier) -> bool {
+    let mut a = a.clone();
+    let mut b = b.clone();
+    a.sort();
+    b.sort();
+    a == b
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsp::{Position, Range, TextDocumentContentChangeEvent};
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn test_core_local_change_broadcasts() {
+        let (core_tx, core_rx) = mpsc::channel(10);
+        let (net_tx, mut net_rx) = mpsc::channel(10);
+        let (edit_tx, _edit_rx) = mpsc::channel(10);
+
+        let core = Core::new("test-agent".into(), net_tx, edit_tx);
+        tokio::spawn(async move {
+            core.run(core_rx).await;
+        });
+
+        let uri = "test.rs".to_string();
+        core_tx
+            .send(Event::ClientDidOpen {
+                uri: uri.clone(),
+                content: "initial".into(),
+            })
+            .await
+            .unwrap();
+
+        let change = TextDocumentContentChangeEvent {
+            range: Some(Range {
+                start: Position {
+                    line: 0,
+                    character: 7,
+                },
+                end: Position {
+                    line: 0,
+                    character: 7,
+                },
+            }),
+            text: " modified".to_string(),
+        };
+
+        // Trigger local change
+        core_tx
+            .send(Event::LocalChange {
+                uri: uri.clone(),
+                changes: vec![change],
+            })
+            .await
+            .unwrap();
+
+        // Verify broadcast
+        match tokio::time::timeout(Duration::from_millis(100), net_rx.recv()).await {
+            Ok(Some(NetworkCommand::BroadcastPatch {
+                uri: res_uri,
+                patch,
+                exclude: None,
+                ..
+            })) => {
+                assert_eq!(res_uri, uri);
+                assert!(!patch.is_empty());
+            }
+            _ => panic!("Expected BroadcastPatch"),
+        }
+
+        core_tx.send(Event::Shutdown).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_core_edits_to_open_files_stay_apart() {
+        let (core_tx, core_rx) = mpsc::channel(10);
+        let (net_tx, mut net_rx) = mpsc::channel(10);
+        let (edit_tx, _edit_rx) = mpsc::channel(10);
+        tokio::spawn(Core::new("local".into(), net_tx, edit_tx).run(core_rx));
+
+        // A peer that has both files as we opened them
+        let mut peer_docs = HashMap::new();
+        for (uri, content) in [("a.rs", "fn a() {}"), ("b.rs", "fn b() {}")] {
+            core_tx
+                .send(Event::ClientDidOpen {
+                    uri: uri.into(),
+                    content: content.into(),
+                })
+                .await
+                .unwrap();
+            peer_docs.insert(
+                uri.to_string(),
+                crate::state::Document::new(uri.into(), content.into(), "Peer"),
+            );
+        }
+
+        for (uri, text) in [("a.rs", "// a\n"), ("b.rs", "// b\n")] {
+            core_tx
+                .send(Event::LocalChange {
+                    uri: uri.into(),
+                    changes: vec![insert_at(0, 0, text)],
+                })
+                .await
+                .unwrap();
+        }
+
+        for _ in 0..2 {
+            match tokio::time::timeout(Duration::from_millis(200), net_rx.recv()).await {
+                Ok(Some(NetworkCommand::BroadcastPatch { uri, patch, .. })) => {
+                    peer_docs
+                        .get_mut(&uri)
+                        .unwrap()
+                        .apply_remote_patch(&patch)
+                        .unwrap();
+                }
+                other => panic!("Expected BroadcastPatch, got {:?}", other),
+            }
+        }
+        assert_eq!(peer_docs["a.rs"].content.to_string(), "// a\nfn a() {}");
+        assert_eq!(peer_docs["b.rs"].content.to_string(), "// b\nfn b() {}");
+
+        core_tx.send(Event::Shutdown).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_core_coalesces_fast_typing_into_one_patch() {
+        let (core_tx, core_rx) = mpsc::channel(20);
+        let (net_tx, mut net_rx) = mpsc::channel(20);
+        let (edit_tx, _edit_rx) = mpsc::channel(10);
+        tokio::spawn(Core::new("typist".into(), net_tx, edit_tx).run(core_rx));
+
+        let uri = "fast.rs".to_string();
+        core_tx
+            .send(Event::ClientDidOpen {
+                uri: uri.clone(),
+                content: String::new(),
+            })
+            .await
+            .unwrap();
+        let typed = |i: usize| TextDocumentContentChangeEvent {
+            range: Some(Range {
+                start: Position {
+                    line: 0,
+                    character: i,
+                },
+                end: Position {
+                    line: 0,
+                    character: i,
+                },
+            }),
+            text: i.to_string(),
+        };
+
+        // Ten keystrokes well within the window
+        for i in 0..10 {
+            core_tx
+                .send(Event::LocalChange {
+                    uri: uri.clone(),
+                    changes: vec![typed(i)],
+                })
+                .await
+                .unwrap();
+        }
+
+        let patch = match tokio::time::timeout(Duration::from_secs(1), net_rx.recv()).await {
+            Ok(Some(NetworkCommand::BroadcastPatch { patch, .. })) => patch,
+            res => panic!("Expected BroadcastPatch, got {:?}", res),
+        };
+        assert!(
+            tokio::time::timeout(COALESCE_WINDOW * 3, net_rx.recv())
+                .await
+                .is_err(),
+            "Only one patch for the whole burst"
+        );
+
+        // All of them, in order
+        let mut mirror = crate::state::Document::new(uri.clone(), String::new(), "mirror");
+        mirror.apply_remote_patch(&patch).unwrap();
+        assert_eq!(mirror.content.to_string(), "0123456789");
+
+        // A lone keystroke isn't stuck waiting for more to come
+        core_tx
+            .send(Event::LocalChange {
+                uri: uri.clone(),
+                changes: vec![typed(10)],
+            })
+            .await
+            .unwrap();
+        let patch = match tokio::time::timeout(Duration::from_secs(1), net_rx.recv()).await {
+            Ok(Some(NetworkCommand::BroadcastPatch { patch, .. })) => patch,
+            res => panic!("Expected BroadcastPatch, got {:?}", res),
+        };
+        mirror.apply_remote_patch(&patch).unwrap();
+        assert_eq!(mirror.content.to_string(), "012345678910");
+
+        core_tx.send(Event::Shutdown).await.unwrap();
+    }
+
+    #[test]
+    fn test_core_created_file_reaches_peer() {
+        crate::fs::tests::run_in_temp_dir(|| {
+            tokio::runtime::Runtime::new().unwrap().block_on(async {
+                let local_path = std::path::Path::new("new.rs");
+                let remote_path = std::path::Path::new("copy/new.rs");
+                std::fs::write(local_path, "fn new() {}").unwrap();
+                let uri = local_path.to_str().unwrap().to_string();
+                let remote_uri = remote_path.to_str().unwrap().to_string();
+
+                let (core_tx, core_rx) = mpsc::channel(10);
+                let (net_tx, mut net_rx) = mpsc::channel(10);
+                let (edit_tx, _edit_rx) = mpsc::channel(10);
+                tokio::spawn(Core::new("creator".into(), net_tx, edit_tx).run(core_rx));
+
+                core_tx
+                    .send(Event::LocalFileCreated { uri: uri.clone() })
+                    .await
+                    .unwrap();
+                let content =
+                    match tokio::time::timeout(Duration::from_millis(100), net_rx.recv()).await {
+                        Ok(Some(NetworkCommand::BroadcastFileCreated {
+                            uri: sent,
+                            content,
+                            exclude: None,
+                        })) => {
+                            assert_eq!(sent, uri);
+                            content
+                        }
+                        other => panic!("Expected BroadcastFileCreated, got {:?}", other),
+                    };
+                assert_eq!(content, "fn new() {}");
+
+                // The receiving side writes it and relays it to everyone else
+                let (peer_tx, peer_rx) = mpsc::channel(10);
+                let (peer_net_tx, mut peer_net_rx) = mpsc::channel(10);
+                let (peer_edit_tx, _peer_edit_rx) = mpsc::channel(10);
+                tokio::spawn(Core::new("receiver".into(), peer_net_tx, peer_edit_tx).run(peer_rx));
+
+                peer_tx
+                    .send(Event::RemoteFileCreated {
+                        uri: remote_uri.clone(),
+                        content,
+                        peer: 3,
+                    })
+                    .await
+                    .unwrap();
+                match tokio::time::timeout(Duration::from_millis(100), peer_net_rx.recv()).await {
+                    Ok(Some(NetworkCommand::BroadcastFileCreated {
+                        exclude: Some(3), ..
+                    })) => {}
+                    other => panic!("Expected relayed BroadcastFileCreated, got {:?}", other),
+                }
+                assert_eq!(std::fs::read_to_string(remote_path).unwrap(), "fn new() {}");
+
+                core_tx.send(Event::Shutdown).await.unwrap();
+                peer_tx.send(Event::Shutdown).await.unwrap();
+            })
+        });
+    }
+
+    #[tokio::test]
+    async fn test_core_external_change_becomes_patch() {
+        let (core_tx, core_rx) = mpsc::channel(10);
+        let (net_tx, mut net_rx) = mpsc::channel(10);
+        let (edit_tx, _edit_rx) = mpsc::channel(10);
+        let mut core = Core::new("agent".into(), net_tx, edit_tx);
+        core.workspace
+            .get_or_create("lib.rs".into(), "fn a() {}".into());
+        tokio::spawn(core.run(core_rx));
+
+        // Unchanged content (e.g. our own disk write) is not news
+        core_tx
+            .send(Event::ExternalFileChange {
+                uri: "lib.rs".into(),
+                content: "fn a() {}".into(),
+            })
+            .await
+            .unwrap();
+        core_tx
+            .send(Event::ExternalFileChange {
+                uri: "lib.rs".into(),
+                content: "fn a() {}\nfn b() {}".into(),
+            })
+            .await
+            .unwrap();
+
+        let patch = match tokio::time::timeout(Duration::from_millis(100), net_rx.recv()).await {
+            Ok(Some(NetworkCommand::BroadcastPatch {
+                uri,
+                patch,
+                exclude: None,
+                ..
+            })) if uri == "lib.rs" => patch,
+            other => panic!("Expected BroadcastPatch, got {:?}", other),
+        };
+        let mut peer = crate::state::Document::new("lib.rs".into(), "fn a() {}".into(), "peer");
+        peer.apply_remote_patch(&patch).unwrap();
+        assert_eq!(peer.content.to_string(), "fn a() {}\nfn b() {}");
+
+        core_tx.send(Event::Shutdown).await.unwrap();
+    }
+
+    #[test]
+    fn test_core_file_ops_outside_the_project_are_rejected() {
+        let outside = tempfile::tempdir().unwrap();
+        let secret = outside.path().join("id_ed25519");
+        std::fs::write(&secret, "key").unwrap();
+        let secret_uri = secret.to_str().unwrap().to_string();
+        let planted_uri = outside.path().join(".bashrc").to_str().unwrap().to_string();
+        crate::fs::tests::run_in_temp_dir(|| {
+            tokio::runtime::Runtime::new().unwrap().block_on(async {
+                std::fs::write("mine.rs", "fn mine() {}").unwrap();
+                let (core_tx, core_rx) = mpsc::channel(20);
+                let (net_tx, mut net_rx) = mpsc::channel(20);
+                let (edit_tx, _edit_rx) = mpsc::channel(20);
+                tokio::spawn(Core::new("agent".into(), net_tx, edit_tx).run(core_rx));
+
+                let mut events = vec![
+                    Event::RemoteFileDeleted {
+                        uri: secret_uri.clone(),
+                        peer: 2,
+                    },
+                    Event::RemoteFileRenamed {
+                        from: secret_uri.clone(),
+                        to: "stolen".into(),
+                        peer: 2,
+                    },
+                    Event::RemoteFileRenamed {
+                        from: "mine.rs".into(),
+                        to: "../justsync-moved.rs".into(),
+                        peer: 2,
+                    },
+                ];
+                for uri in [planted_uri.as_str(), "C:\\x", "../justsync-escaped.rs"] {
+                    events.push(Event::RemoteFileCreated {
+                        uri: uri.into(),
+                        content: "planted".into(),
+                        peer: 2,
+                    });
+                    events.push(Event::RemoteFileDeleted {
+                        uri: uri.into(),
+                        peer: 2,
+                    });
+                }
+                // One that's fine, so we know the rest were looked at
+                events.push(Event::RemoteFileCreated {
+                    uri: "ok.rs".into(),
+                    content: "fn ok() {}".into(),
+                    peer: 2,
+                });
+                for event in events {
+                    core_tx.send(event).await.unwrap();
+                }
+
+                match tokio::time::timeout(Duration::from_millis(200), net_rx.recv()).await {
+                    Ok(Some(NetworkCommand::BroadcastFileCreated { uri, .. })) => {
+                        assert_eq!(uri, "ok.rs")
+                    }
+                    other => panic!("Only ok.rs should be relayed, got {:?}", other),
+                }
+                assert!(std::path::Path::new("mine.rs").exists());
+                assert!(!std::path::Path::new("stolen").exists());
+                assert!(!std::path::Path::new("C:\\x").exists());
+                assert!(!std::path::Path::new("../justsync-escaped.rs").exists());
+                assert!(!std::path::Path::new("../justsync-moved.rs").exists());
+
+                core_tx.send(Event::Shutdown).await.unwrap();
+            })
+        });
+        assert_eq!(std::fs::read_to_string(&secret).unwrap(), "key");
+        assert!(!outside.path().join(".bashrc").exists());
+    }
+
+    #[test]
+    fn test_core_patches_outside_the_project_are_dropped() {
+        let outside = tempfile::tempdir().unwrap();
+        let target = outside.path().join("planted.rs");
+        let target_uri = target.to_str().unwrap().to_string();
+        crate::fs::tests::run_in_temp_dir(|| {
+            tokio::runtime::Runtime::new().unwrap().block_on(async {
+                let (core_tx, core_rx) = mpsc::channel(10);
+                let (net_tx, mut net_rx) = mpsc::channel(10);
+                let (edit_tx, _edit_rx) = mpsc::channel(10);
+                tokio::spawn(Core::new("agent".into(), net_tx, edit_tx).run(core_rx));
+
+                for uri in [target_uri.as_str(), "../justsync-patched.rs"] {
+                    let mut peer = crate::state::Document::new(uri.into(), String::new(), "peer");
+                    let patch = peer
+                        .apply_local_changes(vec![insert_at(0, 0, "planted")])
+                        .unwrap();
+                    core_tx
+                        .send(Event::RemotePatch {
+                            uri: uri.into(),
+                            patch,
+                            peer: 2,
+                            agent_id: "peer".into(),
+                        })
+                        .await
+                        .unwrap();
+                }
+
+                // Not written, not even asked about
+                let answer = tokio::time::timeout(Duration::from_millis(200), net_rx.recv()).await;
+                assert!(answer.is_err(), "Expected nothing, got {:?}", answer);
+                assert!(!std::path::Path::new("../justsync-patched.rs").exists());
+                core_tx.send(Event::Shutdown).await.unwrap();
+            })
+        });
+        assert!(!target.exists());
+    }
+
+    #[test]
+    fn test_core_deleted_file_is_removed_everywhere() {
+        crate::fs::tests::run_in_temp_dir(|| {
+            tokio::runtime::Runtime::new().unwrap().block_on(async {
+                let file_path = std::path::Path::new("old.rs");
+                std::fs::write(file_path, "fn old() {}").unwrap();
+                let uri = file_path.to_str().unwrap().to_string();
+
+                let (core_tx, core_rx) = mpsc::channel(10);
+                let (net_tx, mut net_rx) = mpsc::channel(10);
+                let (edit_tx, _edit_rx) = mpsc::channel(10);
+                let mut core = Core::new("agent".into(), net_tx, edit_tx);
+                core.workspace
+                    .get_or_create(uri.clone(), "fn old() {}".into());
+                tokio::spawn(core.run(core_rx));
+
+                // A peer deleted it: gone from disk, passed on to the others
+                core_tx
+                    .send(Event::RemoteFileDeleted {
+                        uri: uri.clone(),
+                        peer: 2,
+                    })
+                    .await
+                    .unwrap();
+                match tokio::time::timeout(Duration::from_millis(100), net_rx.recv()).await {
+                    Ok(Some(NetworkCommand::BroadcastFileDeleted {
+                        uri: sent,
+                        exclude: Some(2),
+                    })) => assert_eq!(sent, uri),
+                    other => panic!("Expected relayed BroadcastFileDeleted, got {:?}", other),
+                }
+                assert!(!file_path.exists());
+
+                // We deleted it ourselves: everyone hears about it
+                core_tx
+                    .send(Event::LocalFileDeleted { uri: uri.clone() })
+                    .await
+                    .unwrap();
+                match tokio::time::timeout(Duration::from_millis(100), net_rx.recv()).await {
+                    Ok(Some(NetworkCommand::BroadcastFileDeleted {
+                        uri: sent,
+                        exclude: None,
+                    })) => assert_eq!(sent, uri),
+                    other => panic!("Expected BroadcastFileDeleted, got {:?}", other),
+                }
+
+                core_tx.send(Event::Shutdown).await.unwrap();
+            })
+        });
+    }
+
+    #[test]
+    fn test_core_rename_keeps_the_history() {
+        crate::fs::tests::run_in_temp_dir(|| {
+            tokio::runtime::Runtime::new().unwrap().block_on(async {
+                let old_path = std::path::Path::new("a.rs");
+                let new_path = std::path::Path::new("moved/b.rs");
+                std::fs::write(old_path, "fn a() {}").unwrap();
+                let from = old_path.to_str().unwrap().to_string();
+                let to = new_path.to_str().unwrap().to_string();
+
+                let (core_tx, core_rx) = mpsc::channel(10);
+                let (net_tx, mut net_rx) = mpsc::channel(10);
+                let (edit_tx, _edit_rx) = mpsc::channel(10);
+                let mut core = Core::new("agent".into(), net_tx, edit_tx);
+                let state = core
+                    .workspace
+                    .get_or_create(from.clone(), "fn a() {}".into())
+                    .encode_state();
+                tokio::spawn(core.run(core_rx));
+
+                // A peer that has the history renames the file, then keeps typing in it
+                let mut peer = crate::state::Document::new(to.clone(), String::new(), "peer");
+                peer.apply_remote_patch(&state).unwrap();
+                core_tx
+                    .send(Event::RemoteFileRenamed {
+                        from: from.clone(),
+                        to: to.clone(),
+                        peer: 2,
+                    })
+                    .await
+                    .unwrap();
+                match tokio::time::timeout(Duration::from_millis(100), net_rx.recv()).await {
+                    Ok(Some(NetworkCommand::BroadcastFileRenamed {
+                        from: sent_from,
+                        to: sent_to,
+                        exclude: Some(2),
+                    })) => assert_eq!((sent_from, sent_to), (from.clone(), to.clone())),
+                    other => panic!("Expected relayed BroadcastFileRenamed, got {:?}", other),
+                }
+                assert!(!old_path.exists());
+                assert_eq!(std::fs::read_to_string(new_path).unwrap(), "fn a() {}");
+
+                let patch = peer
+                    .apply_local_changes(vec![insert_at(0, 9, "\nfn b() {}")])
+                    .unwrap();
+                core_tx
+                    .send(Event::RemotePatch {
+                        uri: to.clone(),
+                        patch,
+                        peer: 2,
+                        agent_id: String::new(),
+                    })
+                    .await
+                    .unwrap();
+                // The delta only merges onto the history it was made from
+                core_tx
+                    .send(Event::ClientDidSave { uri: to.clone() })
+                    .await
+                    .unwrap();
+                loop {
+                    match tokio::time::timeout(Duration::from_millis(200), net_rx.recv()).await {
+                        Ok(Some(NetworkCommand::BroadcastSaved { uri, .. })) => {
+                            assert_eq!(uri, to);
+                            break;
+                        }
+                        Ok(Some(_)) => {}
+                        other => panic!("Expected BroadcastSaved, got {:?}", other),
+                    }
+                }
+                assert_eq!(
+                    std::fs::read_to_string(new_path).unwrap(),
+                    "fn a() {}\nfn b() {}"
+                );
+
+                // We renamed it ourselves: everyone hears about it
+                core_tx
+                    .send(Event::LocalFileRenamed {
+                        from: to.clone(),
+                        to: from.clone(),
+                    })
+                    .await
+                    .unwrap();
+                match tokio::time::timeout(Duration::from_millis(100), net_rx.recv()).await {
+                    Ok(Some(NetworkCommand::BroadcastFileRenamed { exclude: None, .. })) => {}
+                    other => panic!("Expected BroadcastFileRenamed, got {:?}", other),
+                }
+
+                core_tx.send(Event::Shutdown).await.unwrap();
+            })
+        });
+    }
+
+    #[test]
+    fn test_core_client_close_behavior() {
+        crate::fs::tests::run_in_temp_dir(|| {
+            tokio::runtime::Runtime::new().unwrap().block_on(async {
+                let file_path = std::path::Path::new("closed_after_open.txt");
+                let uri = file_path.to_str().unwrap().to_string();
+
+                let (core_tx, core_rx) = mpsc::channel(10);
+                let (net_tx, _net_rx) = mpsc::channel(10);
+                let (edit_tx, mut edit_rx) = mpsc::channel(10);
+
+                let core = Core::new("test-agent".into(), net_tx, edit_tx);
+                tokio::spawn(async move {
+                    core.run(core_rx).await;
+                });
+
+                // 1. Open file
+                core_tx
+                    .send(Event::ClientDidOpen {
+                        uri: uri.clone(),
+                        content: "initial".into(),
+                    })
+                    .await
+                    .unwrap();
+
+                // 2. Close file
+                core_tx
+                    .send(Event::ClientDidClose { uri: uri.clone() })
+                    .await
+                    .unwrap();
+
+                // 3. Receive patch
+                let mut peer_doc =
+                    crate::state::Document::new(uri.clone(), "initial".into(), "Peer");
+                let patch = peer_doc
+                    .apply_local_changes(vec![TextDocumentContentChangeEvent {
+                        range: Some(Range {
+                            start: Position {
+                                line: 0,
+                                character: 7,
+                            },
+                            end: Position {
+                                line: 0,
+                                character: 7,
+                            },
+                        }),
+                        text: " updated".into(),
+                    }])
+                    .unwrap();
+
+                core_tx
+                    .send(Event::RemotePatch {
+                        uri: uri.clone(),
+                        patch,
+                        peer: 1,
+                        agent_id: String::new(),
+                    })
+                    .await
+                    .unwrap();
+
+                // 4. Verify NO editor update (because it's closed)
+                if let Ok(_) = tokio::time::timeout(Duration::from_millis(50), edit_rx.recv()).await
+                {
+                    panic!("Should not send editor command after file is closed");
+                }
+
+                // 5. Verify Disk Write
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                let content = std::fs::read_to_string(file_path).expect("File should exist");
+                assert_eq!(content, "initial updated");
+
+                core_tx.send(Event::Shutdown).await.unwrap();
+            })
+        });
+    }
+
+    #[tokio::test]
+    async fn test_core_remote_patch_applies_to_editor() {
+        let (core_tx, core_rx) = mpsc::channel(10);
+        let (net_tx, _net_rx) = mpsc::channel(10);
+        let (edit_tx, mut edit_rx) = mpsc::channel(10);
+
+        let core = Core::new("test-agent".into(), net_tx, edit_tx);
+        tokio::spawn(async move {
+            core.run(core_rx).await;
+        });
+
+        let uri = "test.rs".to_string();
+
+        // 1. Generate a valid patch from a "peer"
+        let mut peer_doc = crate::state::Document::new(uri.clone(), "hello".into(), "Peer");
+        let patch = peer_doc
+            .apply_local_changes(vec![TextDocumentContentChangeEvent {
+                range: Some(Range {
+                    start: Position {
+                        line: 0,
+                        character: 5,
+                    },
+                    end: Position {
+                        line: 0,
+                        character: 5,
+                    },
+                }),
+                text: " world".into(),
+            }])
+            .unwrap();
+
+        // 2. Open file locally
+        core_tx
+            .send(Event::ClientDidOpen {
+                uri: uri.clone(),
+                content: "hello".into(),
+            })
+            .await
+            .unwrap();
+
+        // 3. Receive remote patch
+        core_tx
+            .send(Event::RemotePatch {
+                uri: uri.clone(),
+                patch,
+                peer: 1,
+                agent_id: String::new(),
+            })
+            .await
+            .unwrap();
+
+        // 4. Verify editor update
+        match tokio::time::timeout(Duration::from_millis(100), edit_rx.recv()).await {
+            Ok(Some(EditorCommand::ApplyEdits {
+                uri: res_uri,
+                edits,
+            })) => {
+                assert_eq!(res_uri, uri);
+                assert!(!edits.is_empty());
+                assert_eq!(edits[0].new_text, " world");
+            }
+            _ => panic!("Expected ApplyEdits command"),
+        }
+
+        core_tx.send(Event::Shutdown).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_core_retries_a_refused_update_from_the_editors_buffer() {
+        let (core_tx, core_rx) = mpsc::channel(10);
+        let (net_tx, _net_rx) = mpsc::channel(10);
+        let (edit_tx, mut edit_rx) = mpsc::channel(10);
+        tokio::spawn(Core::new("test-agent".into(), net_tx, edit_tx).run(core_rx));
+
+        let uri = "test.rs".to_string();
+        let at = |character| Position { line: 0, character };
+        let mut peer_doc = crate::state::Document::new(uri.clone(), "hello".into(), "Peer");
+        let patch = peer_doc
+            .apply_local_changes(vec![TextDocumentContentChangeEvent {
+                range: Some(Range {
+                    start: at(5),
+                    end: at(5),
+                }),
+                text: " world".into(),
+            }])
+            .unwrap();
+
+        core_tx
+            .send(Event::ClientDidOpen {
+                uri: uri.clone(),
+                content: "hello".into(),
+            })
+            .await
+            .unwrap();
+        core_tx
+            .send(Event::RemotePatch {
+                uri: uri.clone(),
+                patch,
+                peer: 1,
+                agent_id: String::new(),
+            })
+            .await
+            .unwrap();
+        match tokio::time::timeout(Duration::from_millis(100), edit_rx.recv()).await {
+            Ok(Some(EditorCommand::ApplyEdits { edits, .. })) => {
+                assert_eq!(edits[0].range.start, at(5));
+            }
+            res => panic!("Expected ApplyEdits, got {:?}", res),
+        }
+
+        // The user types before the update lands, so the editor refuses it
+        core_tx
+            .send(Event::LocalChange {
+                uri: uri.clone(),
+                changes: vec![TextDocumentContentChangeEvent {
+                    range: Some(Range {
+                        start: at(0),
+                        end: at(0),
+                    }),
+                    text: "> ".into(),
+                }],
+            })
+            .await
+            .unwrap();
+        core_tx
+            .send(Event::EditRejected { uri: uri.clone() })
+            .await
+            .unwrap();
+
+        // Re-diffed against the buffer that has the keystroke
+        match tokio::time::timeout(Duration::from_millis(100), edit_rx.recv()).await {
+            Ok(Some(EditorCommand::ApplyEdits {
+                uri: res_uri,
+                edits,
+            })) => {
+                assert_eq!(res_uri, uri);
+                assert_eq!(edits.len(), 1);
+                assert_eq!(edits[0].range.start, at(7));
+                assert_eq!(edits[0].new_text, " world");
+            }
+            res => panic!("Expected ApplyEdits, got {:?}", res),
+        }
+
+        core_tx.send(Event::Shutdown).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_core_patch_without_edits_is_no_conflict() {
+        let (core_tx, core_rx) = mpsc::channel(10);
+        let (net_tx, mut net_rx) = mpsc::channel(10);
+        let (edit_tx, _edit_rx) = mpsc::channel(10);
+        tokio::spawn(Core::new("test-agent".into(), net_tx, edit_tx).run(core_rx));
+
+        let uri = "test.rs".to_string();
+        core_tx
+            .send(Event::ClientDidOpen {
+                uri: uri.clone(),
+                content: "hello".into(),
+            })
+            .await
+            .unwrap();
+        let mut peer_doc = crate::state::Document::new(uri.clone(), "hello".into(), "Peer");
+        let patch = peer_doc
+            .apply_local_changes(vec![insert_at(0, 5, " world")])
+            .unwrap();
+        // The relay echoes it back, the second copy changes nothing
+        for _ in 0..2 {
+            core_tx
+                .send(Event::RemotePatch {
+                    uri: uri.clone(),
+                    patch: patch.clone(),
+                    peer: 1,
+                    agent_id: String::new(),
+                })
+                .await
+                .unwrap();
+        }
+        core_tx.send(Event::Shutdown).await.unwrap();
+        loop {
+            match tokio::time::timeout(Duration::from_secs(1), net_rx.recv()).await {
+                Ok(Some(NetworkCommand::SendRequestFile { .. })) => {
+                    panic!("Re-synced after a patch that merged")
+                }
+                Ok(Some(NetworkCommand::Shutdown)) => break,
+                Ok(Some(_)) => continue,
+                res => panic!("Expected Shutdown, got {:?}", res),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_core_corrupt_patch_requests_resync() {
+        let (core_tx, core_rx) = mpsc::channel(10);
+        let (net_tx, mut net_rx) = mpsc::channel(10);
+        let (edit_tx, mut edit_rx) = mpsc::channel(10);
+
+        let core = Core::new("test-agent".into(), net_tx, edit_tx);
+        tokio::spawn(async move {
+            core.run(core_rx).await;
+        });
+
+        let uri = "test.rs".to_string();
+        core_tx
+            .send(Event::ClientDidOpen {
+                uri: uri.clone(),
+                content: "hello".into(),
+            })
+            .await
+            .unwrap();
+
+        // A patch cut short on the way
+        let mut peer_doc = crate::state::Document::new(uri.clone(), "hello".into(), "Peer");
+        let mut patch = peer_doc
+            .apply_local_changes(vec![TextDocumentContentChangeEvent {
+                range: None,
+                text: "hello world".into(),
+            }])
+            .unwrap();
+        patch.truncate(patch.len() / 2);
+        core_tx
+            .send(Event::RemotePatch {
+                uri: uri.clone(),
+                patch,
+                peer: 1,
+                agent_id: String::new(),
+            })
+            .await
+            .unwrap();
+
+        // Not dropped: we ask the sender for the whole file and tell the user
+        loop {
+            match tokio::time::timeout(Duration::from_secs(1), net_rx.recv()).await {
+                Ok(Some(NetworkCommand::SendRequestFile { peer, uri: asked })) => {
+                    assert_eq!(peer, 1);
+                    assert_eq!(asked, uri);
+                    break;
+                }
+                Ok(Some(_)) => continue,
+                res => panic!("Expected SendRequestFile, got {:?}", res),
+            }
+        }
+        match tokio::time::timeout(Duration::from_secs(1), edit_rx.recv()).await {
+            Ok(Some(EditorCommand::ShowMessage { message, .. })) => {
+                assert!(message.contains("test.rs"));
+            }
+            res => panic!("Expected ShowMessage, got {:?}", res),
+        }
+
+        // The other side of it: we answer with the full state of just that file
+        core_tx
+            .send(Event::PeerRequestedFile {
+                uri: uri.clone(),
+                peer: 1,
+            })
+            .await
+            .unwrap();
+        match tokio::time::timeout(Duration::from_secs(1), net_rx.recv()).await {
+            Ok(Some(NetworkCommand::SendFullSyncResponse { peer, files })) => {
+                assert_eq!(peer, 1);
+                assert_eq!(files.len(), 1);
+                assert_eq!(files[0].0, uri);
+            }
+            res => panic!("Expected SendFullSyncResponse, got {:?}", res),
+        }
+
+        core_tx.send(Event::Shutdown).await.unwrap();
+    }
+
+    #[test]
+    fn test_core_remote_patch_closed_file_writes_to_disk() {
+        crate::fs::tests::run_in_temp_dir(|| {
+            tokio::runtime::Runtime::new().unwrap().block_on(async {
+                let file_path = std::path::Path::new("closed.txt");
+                let uri = file_path.to_str().unwrap().to_string();
+
+                let (core_tx, core_rx) = mpsc::channel(10);
+                let (net_tx, _net_rx) = mpsc::channel(10);
+                let (edit_tx, mut edit_rx) = mpsc::channel(10);
+
+                let core = Core::new("test-agent".into(), net_tx, edit_tx);
+                tokio::spawn(async move {
+                    core.run(core_rx).await;
+                });
+
+                // 1. Generate patch
+                let mut peer_doc = crate::state::Document::new(uri.clone(), "start".into(), "Peer");
+                let patch = peer_doc
+                    .apply_local_changes(vec![TextDocumentContentChangeEvent {
+                        range: Some(Range {
+                            start: Position {
+                                line: 0,
+                                character: 5,
+                            },
+                            end: Position {
+                                line: 0,
+                                character: 5,
+                            },
+                        }),
+                        text: " finish".into(),
+                    }])
+                    .unwrap();
+
+                // 2. Receive remote patch (File NOT open)
+                core_tx
+                    .send(Event::RemotePatch {
+                        uri: uri.clone(),
+                        patch,
+                        peer: 1,
+                        agent_id: String::new(),
+                    })
+                    .await
+                    .unwrap();
+
+                // 3. Verify NO editor update
+                if let Ok(_) = tokio::time::timeout(Duration::from_millis(50), edit_rx.recv()).await
+                {
+                    panic!("Should not send editor command for closed file");
+                }
+
+                // 4. Verify Disk Write
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                let content = std::fs::read_to_string(file_path).expect("File should exist");
+                assert_eq!(content, "start finish");
+
+                core_tx.send(Event::Shutdown).await.unwrap();
+            })
+        });
+    }
+
+    #[test]
+    fn test_core_full_sync_logic() {
+        // Synced files are written relative to the working directory
+        crate::fs::tests::run_in_temp_dir(|| {
+            let rt = tokio::runtime::Runtime::new().unwrap();
+            rt.block_on(async {
+                // --- HOST SIDE ---
+
+                let (host_core_tx, host_core_rx) = mpsc::channel(10);
+                let (host_net_tx, mut host_net_rx) = mpsc::channel(10);
+                let (host_edit_tx, _) = mpsc::channel(10);
+                let mut host_core = Core::new("host".into(), host_net_tx, host_edit_tx);
+
+                // Pre-populate host workspace
+                host_core
+                    .workspace
+                    .get_or_create("doc1.txt".into(), "Host Content".into());
+
+                tokio::spawn(async move {
+                    host_core.run(host_core_rx).await;
+                });
+
+                // Request Sync
+                host_core_tx
+                    .send(Event::PeerRequestedSync { peer: 1 })
+                    .await
+                    .unwrap();
+
+                // The manifest comes first, the peer picks what it needs
+                match tokio::time::timeout(Duration::from_millis(100), host_net_rx.recv()).await {
+                    Ok(Some(NetworkCommand::SendSyncManifest { files, .. })) => {
+                        assert_eq!(files.len(), 1);
+                        assert_eq!(files[0].0, "doc1.txt");
+                    }
+                    _ => panic!("Expected SendSyncManifest"),
+                }
+                host_core_tx
+                    .send(Event::PeerRequestedFiles {
+                        uris: vec!["doc1.txt".into()],
+                        peer: 1,
+                    })
+                    .await
+                    .unwrap();
+
+                // Capture Response
+                let sync_files = match tokio::time::timeout(
+                    Duration::from_millis(100),
+                    host_net_rx.recv(),
+                )
+                .await
+                {
+                    Ok(Some(NetworkCommand::SendFullSyncResponse { files, .. })) => files,
+                    _ => panic!("Expected SendFullSyncResponse"),
+                };
+
+                assert_eq!(sync_files.len(), 1);
+                assert_eq!(sync_files[0].0, "doc1.txt");
+
+                // --- PEER SIDE ---
+
+                // A hostile host also tries to write outside the project
+                let outside = tempfile::tempdir().unwrap();
+                let outside_path = outside.path().join("evil.txt");
+                let mut payload = sync_files.clone();
+                payload.push((
+                    outside_path.to_str().unwrap().to_string(),
+                    sync_files[0].1.clone(),
+                ));
+                payload.push(("../evil.txt".into(), sync_files[0].1.clone()));
+
+                let (peer_core_tx, peer_core_rx) = mpsc::channel(10);
+                let (peer_net_tx, _) = mpsc::channel(10);
+                let (peer_edit_tx, _) = mpsc::channel(10);
+                let peer_core = Core::new("peer".into(), peer_net_tx, peer_edit_tx);
+
+                tokio::spawn(async move {
+                    peer_core.run(peer_core_rx).await;
+                });
+
+                // Receive Full Sync
+                peer_core_tx
+                    .send(Event::RemoteFullSync {
+                        files: payload,
+                        peer: 1,
+                    })
+                    .await
+                    .unwrap();
+
+                // Verify Disk
+                tokio::time::sleep(Duration::from_millis(100)).await;
+
+                let content =
+                    std::fs::read_to_string("doc1.txt").expect("Synced file should exist");
+                assert_eq!(content, "Host Content");
+                assert!(!outside_path.exists());
+                assert!(!std::path::Path::new("../evil.txt").exists());
+
+                host_core_tx.send(Event::Shutdown).await.unwrap();
+                peer_core_tx.send(Event::Shutdown).await.unwrap();
+            });
+        });
+    }
+
+    #[test]
+    fn test_core_save_writes_synced_content() {
+        crate::fs::tests::run_in_temp_dir(|| {
+            tokio::runtime::Runtime::new().unwrap().block_on(async {
+                let (core_tx, core_rx) = mpsc::channel(10);
+                let (net_tx, mut net_rx) = mpsc::channel(10);
+                let (edit_tx, _edit_rx) = mpsc::channel(10);
+                tokio::spawn(Core::new("saver".into(), net_tx, edit_tx).run(core_rx));
+
+                // The file on disk drifted from the session
+                std::fs::write("saved.txt", "stale").unwrap();
+                core_tx
+                    .send(Event::ClientDidOpen {
+                        uri: "saved.txt".into(),
+                        content: "draft".into(),
+                    })
+                    .await
+                    .unwrap();
+                core_tx
+                    .send(Event::LocalChange {
+                        uri: "saved.txt".into(),
+                        changes: vec![TextDocumentContentChangeEvent {
+                            range: None,
+                            text: "final draft".into(),
+                        }],
+                    })
+                    .await
+                    .unwrap();
+                core_tx
+                    .send(Event::ClientDidSave {
+                        uri: "saved.txt".into(),
+                    })
+                    .await
+                    .unwrap();
+
+                // Our edit goes out before the peers are told to write
+                assert!(matches!(
+                    net_rx.recv().await,
+                    Some(NetworkCommand::BroadcastPatch { .. })
+                ));
+                match net_rx.recv().await {
+                    Some(NetworkCommand::BroadcastSaved { uri, exclude }) => {
+                        assert_eq!(uri, "saved.txt");
+                        assert!(exclude.is_none());
+                    }
+                    other => panic!("Expected BroadcastSaved, got {:?}", other),
+                }
+                assert_eq!(std::fs::read_to_string("saved.txt").unwrap(), "final draft");
+
+                // A peer saving a file we don't have open writes our copy too
+                core_tx
+                    .send(Event::ClientDidClose {
+                        uri: "saved.txt".into(),
+                    })
+                    .await
+                    .unwrap();
+                std::fs::write("saved.txt", "stale again").unwrap();
+                core_tx
+                    .send(Event::RemoteSaved {
+                        uri: "saved.txt".into(),
+                        peer: 3,
+                    })
+                    .await
+                    .unwrap();
+                match net_rx.recv().await {
+                    Some(NetworkCommand::BroadcastSaved { exclude, .. }) => {
+                        assert_eq!(exclude, Some(3));
+                    }
+                    other => panic!("Expected BroadcastSaved, got {:?}", other),
+                }
+                assert_eq!(std::fs::read_to_string("saved.txt").unwrap(), "final draft");
+
+                core_tx.send(Event::Shutdown).await.unwrap();
+            });
+        });
+    }
+
+    #[test]
+    fn test_core_observer_never_sends_or_writes() {
+        crate::fs::tests::run_in_temp_dir(|| {
+            tokio::runtime::Runtime::new().unwrap().block_on(async {
+                let (core_tx, core_rx) = mpsc::channel(10);
+                let (net_tx, mut net_rx) = mpsc::channel(10);
+                let (edit_tx, mut edit_rx) = mpsc::channel(10);
+                let core = Core::new("observer".into(), net_tx, edit_tx).with_observer();
+                tokio::spawn(core.run(core_rx));
+
+                // The host's state of the session
+                let mut host_doc =
+                    crate::state::Document::new("notes.md".into(), "".into(), "Host");
+                let full = host_doc
+                    .apply_local_changes(vec![insert_at(0, 0, "hello")])
+                    .unwrap();
+                core_tx
+                    .send(Event::RemoteFullSync {
+                        files: vec![("notes.md".into(), full)],
+                        peer: 1,
+                    })
+                    .await
+                    .unwrap();
+                core_tx
+                    .send(Event::ClientDidOpen {
+                        uri: "notes.md".into(),
+                        content: "hello".into(),
+                    })
+                    .await
+                    .unwrap();
+
+                // Typing, saving and creating files stays local
+                core_tx
+                    .send(Event::LocalChange {
+                        uri: "notes.md".into(),
+                        changes: vec![insert_at(0, 5, "!")],
+                    })
+                    .await
+                    .unwrap();
+                core_tx
+                    .send(Event::ClientDidSave {
+                        uri: "notes.md".into(),
+                    })
+                    .await
+                    .unwrap();
+                std::fs::write("new.md", "mine").unwrap();
+                core_tx
+                    .send(Event::LocalFileCreated {
+                        uri: "new.md".into(),
+                    })
+                    .await
+                    .unwrap();
+
+                // Remote edits still reach the editor
+                let patch = host_doc
+                    .apply_local_changes(vec![insert_at(0, 0, "> ")])
+                    .unwrap();
+                core_tx
+                    .send(Event::RemotePatch {
+                        uri: "notes.md".into(),
+                        patch,
+                        peer: 1,
+                        agent_id: String::new(),
+                    })
+                    .await
+                    .unwrap();
+                loop {
+                    match tokio::time::timeout(Duration::from_millis(200), edit_rx.recv()).await {
+                        Ok(Some(EditorCommand::ApplyEdits { edits, .. })) => {
+                            assert_eq!(edits[0].new_text, "> ");
+                            break;
+                        }
+                        Ok(Some(_)) => continue,
+                        other => panic!("Expected ApplyEdits, got {:?}", other),
+                    }
+                }
+
+                // Outlast the coalescing window before looking at what went out
+                tokio::time::sleep(COALESCE_WINDOW * 3).await;
+                while let Ok(cmd) = net_rx.try_recv() {
+                    assert!(
+                        !matches!(
+                            cmd,
+                            NetworkCommand::BroadcastPatch { .. }
+                                | NetworkCommand::BroadcastSaved { .. }
+                                | NetworkCommand::BroadcastFileCreated { .. }
+                        ),
+                        "Observer sent {:?}",
+                        cmd
+                    );
+                }
+                assert!(!std::path::Path::new("notes.md").exists());
+
+                core_tx.send(Event::Shutdown).await.unwrap();
+            });
+        });
+    }
+
+    #[test]
+    fn test_core_read_only_file_bounces_peer_edit() {
+        crate::fs::tests::run_in_temp_dir(|| {
+            tokio::runtime::Runtime::new().unwrap().block_on(async {
+                let uri = "Cargo.lock".to_string();
+                let (host_tx, host_rx) = mpsc::channel(10);
+                let (host_net_tx, mut host_net_rx) = mpsc::channel(10);
+                let (host_edit_tx, _host_edit_rx) = mpsc::channel(10);
+                let host = Core::new("host".into(), host_net_tx, host_edit_tx)
+                    .with_read_only(HashSet::from([uri.clone()]));
+                tokio::spawn(host.run(host_rx));
+
+                let (peer_tx, peer_rx) = mpsc::channel(10);
+                let (peer_net_tx, mut peer_net_rx) = mpsc::channel(10);
+                let (peer_edit_tx, mut peer_edit_rx) = mpsc::channel(10);
+                tokio::spawn(Core::new("peer".into(), peer_net_tx, peer_edit_tx).run(peer_rx));
+
+                // Both start from the same content, so they share its history
+                for tx in [&host_tx, &peer_tx] {
+                    tx.send(Event::ClientDidOpen {
+                        uri: uri.clone(),
+                        content: "v1".into(),
+                    })
+                    .await
+                    .unwrap();
+                }
+
+                // The guest edits the lock file anyway
+                peer_tx
+                    .send(Event::LocalChange {
+                        uri: uri.clone(),
+                        changes: vec![insert_at(0, 2, " edited")],
+                    })
+                    .await
+                    .unwrap();
+                let patch = loop {
+                    match tokio::time::timeout(Duration::from_millis(200), peer_net_rx.recv()).await
+                    {
+                        Ok(Some(NetworkCommand::BroadcastPatch { patch, .. })) => break patch,
+                        Ok(Some(_)) => continue,
+                        other => panic!("Expected BroadcastPatch, got {:?}", other),
+                    }
+                };
+
+                // The host refuses it and keeps it to itself
+                host_tx
+                    .send(Event::RemotePatch {
+                        uri: uri.clone(),
+                        patch,
+                        peer: 1,
+                        agent_id: String::new(),
+                    })
+                    .await
+                    .unwrap();
+                let reason = loop {
+                    match tokio::time::timeout(Duration::from_millis(200), host_net_rx.recv()).await
+                    {
+                        Ok(Some(NetworkCommand::SendRejected {
+                            peer: 1,
+                            uri: rejected,
+                            reason,
+                        })) => {
+                            assert_eq!(rejected, uri);
+                            break reason;
+                        }
+                        Ok(Some(NetworkCommand::BroadcastPatch { .. })) => {
+                            panic!("Refused patch was relayed")
+                        }
+                        Ok(Some(_)) => continue,
+                        other => panic!("Expected SendRejected, got {:?}", other),
+                    }
+                };
+
+                // The guest fetches the host's state...
+                peer_tx
+                    .send(Event::RemoteRejected {
+                        uri: uri.clone(),
+                        reason,
+                        peer: 0,
+                    })
+                    .await
+                    .unwrap();
+                match tokio::time::timeout(Duration::from_millis(200), peer_net_rx.recv()).await {
+                    Ok(Some(NetworkCommand::SendRequestFile {
+                        peer: 0,
+                        uri: asked,
+                    })) => {
+                        assert_eq!(asked, uri)
+                    }
+                    other => panic!("Expected SendRequestFile, got {:?}", other),
+                }
+                host_tx
+                    .send(Event::PeerRequestedFile {
+                        uri: uri.clone(),
+                        peer: 1,
+                    })
+                    .await
+                    .unwrap();
+                let files = loop {
+                    match tokio::time::timeout(Duration::from_millis(200), host_net_rx.recv()).await
+                    {
+                        Ok(Some(NetworkCommand::SendFullSyncResponse { files, .. })) => {
+                            break files;
+                        }
+                        Ok(Some(_)) => continue,
+                        other => panic!("Expected SendFullSyncResponse, got {:?}", other),
+                    }
+                };
+
+                // ...and its buffer goes back to what the host has
+                peer_tx
+                    .send(Event::RemoteFullSync { files, peer: 0 })
+                    .await
+                    .unwrap();
+                let edits = loop {
+                    match tokio::time::timeout(Duration::from_millis(200), peer_edit_rx.recv())
+                        .await
+                    {
+                        Ok(Some(EditorCommand::ApplyEdits { edits, .. })) => break edits,
+                        Ok(Some(_)) => continue,
+                        other => panic!("Expected ApplyEdits, got {:?}", other),
+                    }
+                };
+                assert_eq!(edits.len(), 1);
+                assert_eq!(edits[0].new_text, "");
+                assert_eq!(edits[0].range.start.character, 2);
+                assert_eq!(edits[0].range.end.character, 9);
+
+                host_tx.send(Event::Shutdown).await.unwrap();
+                peer_tx.send(Event::Shutdown).await.unwrap();
+            });
+        });
+    }
+
+    #[tokio::test]
+
+    async fn test_core_resilience_change_without_open() {
+        // Scenario: The editor sends a didChange for a file we never saw a didOpen for.
+
+        // This happens with some aggressive LSP clients or plugins.
+
+        let (core_tx, core_rx) = mpsc::channel(10);
+
+        let (net_tx, _) = mpsc::channel(10);
+
+        let (edit_tx, _) = mpsc::channel(10);
+
+        let core = Core::new("resilient-agent".into(), net_tx, edit_tx);
+
+        tokio::spawn(async move {
+            core.run(core_rx).await;
+        });
+
+        let uri = "ghost_file.rs".to_string();
+
+        let change = TextDocumentContentChangeEvent {
+            range: Some(Range {
+                start: Position {
+                    line: 100,
+                    character: 0,
+                },
+
+                end: Position {
+                    line: 100,
+                    character: 0,
+                },
+            }),
+
+            text: "scary stuff".into(),
+        };
+
+        // Send Change WITHOUT Open
+
+        core_tx
+            .send(Event::LocalChange {
+                uri,
+                changes: vec![change],
+            })
+            .await
+            .unwrap();
+
+        // If we are here and the test hasn't panicked, the Core is still running.
+
+        // Let's send a Shutdown to confirm it processes the queue cleanly.
+
+        core_tx.send(Event::Shutdown).await.unwrap();
+    }
+
+    #[tokio::test]
+
+    async fn test_core_resilience_disk_write_failure() {
+        // Scenario: Remote patch received for a closed file, but we can't write to disk (permissions/invalid path).
+
+        // Core should NOT crash; it should log error and continue.
+
+        let (core_tx, core_rx) = mpsc::channel(10);
+
+        let (net_tx, _) = mpsc::channel(10);
+
+        let (edit_tx, _) = mpsc::channel(10);
+
+        let core = Core::new("io-agent".into(), net_tx, edit_tx);
+
+        tokio::spawn(async move {
+            core.run(core_rx).await;
+        });
+
+        // Use an invalid path that definitely cannot be written to (e.g., a directory or empty)
+
+        // On Linux, writing to a directory path usually fails.
+
+        let invalid_uri = if cfg!(target_os = "windows") {
+            "C:\\INVALID|<|*".to_string()
+        } else {
+            "/".to_string()
+        };
+
+        let mut peer_doc = crate::state::Document::new(invalid_uri.clone(), "".into(), "Peer");
+
+        let patch = peer_doc
+            .apply_local_changes(vec![TextDocumentContentChangeEvent {
+                range: Some(Range {
+                    start: Position {
+                        line: 0,
+                        character: 0,
+                    },
+                    end: Position {
+                        line: 0,
+                        character: 0,
+                    },
+                }),
+
+                text: "fail".into(),
+            }])
+            .unwrap();
+
+        // Send patch
+
+        core_tx
+            .send(Event::RemotePatch {
+                uri: invalid_uri,
+                patch,
+                peer: 1,
+                agent_id: String::new(),
+            })
+            .await
+            .unwrap();
+
+        // Give it a moment to try and fail
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // Core should still be alive
+
+        core_tx.send(Event::Shutdown).await.unwrap();
+    }
+
+    #[tokio::test]
+
+    async fn test_core_echo_guard_prevents_loop() {
+        // Scenario:
+
+        // 1. Remote Patch Arrives -> Edits sent to Editor.
+
+        // 2. Editor applies edits and (incorrectly) echoes them back as a LocalChange.
+
+        // 3. Core should REJECT this LocalChange to prevent infinite loop.
+
+        let (core_tx, core_rx) = mpsc::channel(10);
+
+        let (net_tx, mut net_rx) = mpsc::channel(10);
+
+        let (edit_tx, mut edit_rx) = mpsc::channel(10);
+
+        let core = Core::new("echo-agent".into(), net_tx, edit_tx);
+
+        tokio::spawn(async move {
+            core.run(core_rx).await;
+        });
+
+        let uri = "echo.rs".to_string();
+
+        core_tx
+            .send(Event::ClientDidOpen {
+                uri: uri.clone(),
+                content: "A".into(),
+            })
+            .await
+            .unwrap();
+
+        // 1. Remote Patch
+
+        let mut peer_doc = crate::state::Document::new(uri.clone(), "A".into(), "Peer");
+
+        let patch = peer_doc
+            .apply_local_changes(vec![TextDocumentContentChangeEvent {
+                range: Some(Range {
+                    start: Position {
+                        line: 0,
+                        character: 1,
+                    },
+                    end: Position {
+                        line: 0,
+                        character: 1,
+                    },
+                }),
+
+                text: "B".into(),
+            }])
+            .unwrap();
+
+        core_tx
+            .send(Event::RemotePatch {
+                uri: uri.clone(),
+                patch,
+                peer: 1,
+                agent_id: String::new(),
+            })
+            .await
+            .unwrap();
+
+        // Wait for Editor Command (proving remote patch was processed)
+
+        let _ = tokio::time::timeout(Duration::from_millis(100), edit_rx.recv())
+            .await
+            .unwrap();
+
+        // 2. Simulate Echo: The editor reports "AB" (which matches the remote update)
+
+        let echo_change = TextDocumentContentChangeEvent {
+            range: Some(Range {
+                start: Position {
+                    line: 0,
+                    character: 1,
+                },
+                end: Position {
+                    line: 0,
+                    character: 1,
+                },
+            }),
+
+            text: "B".into(),
+        };
+
+        core_tx
+            .send(Event::LocalChange {
+                uri: uri.clone(),
+                changes: vec![echo_change],
+            })
+            .await
+            .unwrap();
+
+        // 3. Verify NO Broadcast (Echo Guard worked)
+
+        // If the guard FAILED, we would see a BroadcastPatch here.
+
+        while let Ok(Some(cmd)) =
+            tokio::time::timeout(Duration::from_millis(100), net_rx.recv()).await
+        {
+            // Relaying the remote patch to other peers is fine, re-broadcasting the echo is not
+            if matches!(cmd, NetworkCommand::BroadcastPatch { exclude: None, .. }) {
+                panic!("Echo guard failed! Loop detected.");
+            }
+        }
+
+        core_tx.send(Event::Shutdown).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_core_simple_sync_concurrent_edits_pick_one_winner() {
+        let (core_tx, core_rx) = mpsc::channel(10);
+        let (net_tx, mut net_rx) = mpsc::channel(10);
+        let (edit_tx, mut edit_rx) = mpsc::channel(10);
+
+        let core = Core::new("alice".into(), net_tx, edit_tx).with_sync_mode(SyncMode::Simple);
+        tokio::spawn(async move {
+            core.run(core_rx).await;
+        });
+
+        let uri = "notes.md".to_string();
+        let append = |text: &str| TextDocumentContentChangeEvent {
+            range: Some(Range {
+                start: Position {
+                    line: 0,
+                    character: 4,
+                },
+                end: Position {
+                    line: 0,
+                    character: 4,
+                },
+            }),
+            text: text.into(),
+        };
+
+        core_tx
+            .send(Event::ClientDidOpen {
+                uri: uri.clone(),
+                content: "base".into(),
+            })
+            .await
+            .unwrap();
+
+        // Alice edits locally...
+        core_tx
+            .send(Event::LocalChange {
+                uri: uri.clone(),
+                changes: vec![append("A")],
+            })
+            .await
+            .unwrap();
+        let patch_from_alice =
+            match tokio::time::timeout(Duration::from_millis(100), net_rx.recv()).await {
+                Ok(Some(NetworkCommand::BroadcastPatch { patch, .. })) => patch,
+                _ => panic!("Expected BroadcastPatch"),
+            };
+
+        // ...while Bob edits the same file concurrently
+        let mut bob =
+            crate::state::Document::with_mode(uri.clone(), "base".into(), "bob", SyncMode::Simple);
+        let patch_from_bob = bob.apply_local_changes(vec![append("B")]).unwrap();
+
+        core_tx
+            .send(Event::RemotePatch {
+                uri: uri.clone(),
+                patch: patch_from_bob,
+                peer: 1,
+                agent_id: String::new(),
+            })
+            .await
+            .unwrap();
+
+        // Bob wins the tie-break, so Alice is told and her buffer gets replaced
+        let mut notified = false;
+        let mut replaced = false;
+        for _ in 0..2 {
+            match tokio::time::timeout(Duration::from_millis(100), edit_rx.recv()).await {
+                Ok(Some(EditorCommand::ShowMessage { message, .. })) => {
+                    assert!(message.contains("overwritten"));
+                    notified = true;
+                }
+                Ok(Some(EditorCommand::ApplyEdits { edits, .. })) => {
+                    assert!(!edits.is_empty());
+                    replaced = true;
+                }
+                res => panic!("Unexpected editor command: {:?}", res),
+            }
+        }
+        assert!(notified && replaced);
+
+        // Bob keeps his version when Alice's (losing) write reaches him
+        assert!(
+            bob.apply_remote_patch(&patch_from_alice)
+                .unwrap()
+                .is_empty()
+        );
+        assert!(!bob.take_overwrite_notice());
+        assert_eq!(bob.content.to_string(), "baseB");
+
+        core_tx.send(Event::Shutdown).await.unwrap();
+    }
+
+    fn insert_at(line: usize, character: usize, text: &str) -> TextDocumentContentChangeEvent {
+        TextDocumentContentChangeEvent {
+            range: Some(Range {
+                start: Position { line, character },
+                end: Position { line, character },
+            }),
+            text: text.into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_core_sync_fetches_only_missing_files() {
+        let (core_tx, core_rx) = mpsc::channel(100);
+        let (net_tx, mut net_rx) = mpsc::channel(100);
+        let (edit_tx, _edit_rx) = mpsc::channel(100);
+
+        // The host has ten files, the peer already has nine of them
+        let mut host = Workspace::new("host".into());
+        let mut core = Core::new("peer".into(), net_tx, edit_tx);
+        for i in 0..10 {
+            let uri = format!("src/file{}.rs", i);
+            host.get_or_create(uri.clone(), format!("fn f{}() {{}}", i));
+            if i != 7 {
+                core.workspace
+                    .get_or_create(uri, format!("fn f{}() {{}}", i));
+            }
+        }
+        tokio::spawn(async move {
+            core.run(core_rx).await;
+        });
+
+        core_tx
+            .send(Event::RemoteSyncManifest {
+                files: host.manifest(),
+                peer: 1,
+            })
+            .await
+            .unwrap();
+
+        // Acks for what we have, so the host knows where to send deltas from...
+        let mut acked = 0;
+        let uris = loop {
+            match net_rx.recv().await {
+                Some(NetworkCommand::SendAck { .. }) => acked += 1,
+                Some(NetworkCommand::SendRequestFiles { peer, uris }) => {
+                    assert_eq!(peer, 1);
+                    break uris;
+                }
+                other => panic!("Expected SendAck or SendRequestFiles, got {:?}", other),
+            }
+        };
+        // ...and just the one missing file is fetched
+        assert_eq!(acked, 9);
+        assert_eq!(uris, vec!["src/file7.rs".to_string()]);
+
+        core_tx.send(Event::Shutdown).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_core_seeded_host_serves_unopened_files() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("src")).unwrap();
+        std::fs::write(dir.path().join("src/lib.rs"), "pub fn lib() {}").unwrap();
+        std::fs::write(dir.path().join("notes.md"), "# Notes").unwrap();
+        std::fs::write(dir.path().join(".gitignore"), "*.log\n").unwrap();
+        std::fs::write(dir.path().join("build.log"), "noise").unwrap();
+        std::fs::write(dir.path().join("logo.png"), [0x89, b'P', 0, 0]).unwrap();
+
+        let (core_tx, core_rx) = mpsc::channel(10);
+        let (net_tx, mut net_rx) = mpsc::channel(10);
+        let (edit_tx, _edit_rx) = mpsc::channel(10);
+        tokio::spawn(Core::new("host".into(), net_tx, edit_tx).run(core_rx));
+
+        // What --seed-from-disk does on startup, nothing is opened in the editor
+        for (uri, content) in
+            crate::fs::scan_project(&dir.path().to_string_lossy(), &IgnoreRules::default(), &[])
+                .files
+        {
+            core_tx
+                .send(Event::LoadFromDisk { uri, content })
+                .await
+                .unwrap();
+        }
+
+        core_tx
+            .send(Event::PeerRequestedSync { peer: 1 })
+            .await
+            .unwrap();
+        let mut listed = match net_rx.recv().await {
+            Some(NetworkCommand::SendSyncManifest { files, .. }) => {
+                files.into_iter().map(|(uri, _)| uri).collect::<Vec<_>>()
+            }
+            other => panic!("Expected SendSyncManifest, got {:?}", other),
+        };
+        listed.sort();
+        assert_eq!(listed, vec!["notes.md", "src/lib.rs"]);
+
+        core_tx
+            .send(Event::PeerRequestedFiles {
+                uris: vec!["src/lib.rs".into()],
+                peer: 1,
+            })
+            .await
+            .unwrap();
+        match net_rx.recv().await {
+            Some(NetworkCommand::SendFullSyncResponse { files, .. }) => {
+                let mut peer =
+                    crate::state::Document::new(files[0].0.clone(), String::new(), "peer");
+                peer.apply_remote_patch(&files[0].1).unwrap();
+                assert_eq!(peer.content.to_string(), "pub fn lib() {}");
+            }
+            other => panic!("Expected SendFullSyncResponse, got {:?}", other),
+        }
+
+        core_tx.send(Event::Shutdown).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_core_reconnect_resumes_with_delta() {
+        let (core_tx, core_rx) = mpsc::channel(10);
+        let (net_tx, mut net_rx) = mpsc::channel(10);
+        let (edit_tx, _edit_rx) = mpsc::channel(10);
+
+        let mut core = Core::new("host".into(), net_tx, edit_tx);
+        let uri = "file:///big.txt".to_string();
+        let text: String = (0..2_000u64)
+            .map(|i| format!("line {:x}\n", i.wrapping_mul(0x9E37_79B9_7F4A_7C15)))
+            .collect();
+        core.workspace.get_or_create(uri.clone(), text);
+        tokio::spawn(async move {
+            core.run(core_rx).await;
+        });
+
+        async fn next_sync(
+            net_rx: &mut mpsc::Receiver<NetworkCommand>,
+        ) -> (Vec<(String, Vec<u8>)>, String) {
+            let files = match net_rx.recv().await {
+                Some(NetworkCommand::SendFullSyncResponse { files, .. }) => files,
+                other => panic!("Expected SendFullSyncResponse, got {:?}", other),
+            };
+            match net_rx.recv().await {
+                Some(NetworkCommand::SendResumeToken { token, .. }) => (files, token),
+                other => panic!("Expected SendResumeToken, got {:?}", other),
+            }
+        }
+
+        // First connection: full sync plus a token
+        core_tx
+            .send(Event::PeerRequestedFiles {
+                uris: vec![uri.clone()],
+                peer: 1,
+            })
+            .await
+            .unwrap();
+        let (full, token) = next_sync(&mut net_rx).await;
+        let mut peer = crate::state::Document::new(uri.clone(), String::new(), "peer");
+        peer.apply_remote_patch(&full[0].1).unwrap();
+
+        // Host edits while the peer is disconnected
+        core_tx
+            .send(Event::LocalChange {
+                uri: uri.clone(),
+                changes: vec![insert_at(0, 0, "missed ")],
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            net_rx.recv().await,
+            Some(NetworkCommand::BroadcastPatch { .. })
+        ));
+
+        // Reconnect: only the delta comes back, and it's enough to catch up
+        core_tx
+            .send(Event::PeerRequestedResume { token, peer: 1 })
+            .await
+            .unwrap();
+        let (delta, _fresh_token) = next_sync(&mut net_rx).await;
+        assert_eq!(delta.len(), 1);
+        assert!(delta[0].1.len() < full[0].1.len() / 10);
+
+        peer.apply_remote_patch(&delta[0].1).unwrap();
+        assert!(peer.content.to_string().starts_with("missed line 0"));
+
+        core_tx.send(Event::Shutdown).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_core_peer_disconnect_releases_its_acks() {
+        let (core_tx, core_rx) = mpsc::channel(10);
+        let (net_tx, mut net_rx) = mpsc::channel(10);
+        let (edit_tx, _edit_rx) = mpsc::channel(10);
+
+        let mut core = Core::new("host".into(), net_tx, edit_tx);
+        let uri = "file:///shared.txt".to_string();
+        core.workspace.get_or_create(uri.clone(), String::new());
+        tokio::spawn(async move {
+            core.run(core_rx).await;
+        });
+
+        async fn next_patch(net_rx: &mut mpsc::Receiver<NetworkCommand>) -> Vec<u8> {
+            match net_rx.recv().await {
+                Some(NetworkCommand::BroadcastPatch { patch, .. }) => patch,
+                other => panic!("Expected BroadcastPatch, got {:?}", other),
+            }
+        }
+
+        // Peer 2 only ever saw the empty document
+        core_tx
+            .send(Event::RemoteAck {
+                uri: uri.clone(),
+                agent: "slow".into(),
+                frontier: vec![],
+                peer: 2,
+            })
+            .await
+            .unwrap();
+
+        let text: String = (0..2_000u64)
+            .map(|i| format!("line {:x}\n", i.wrapping_mul(0x9E37_79B9_7F4A_7C15)))
+            .collect();
+        core_tx
+            .send(Event::LocalChange {
+                uri: uri.clone(),
+                changes: vec![insert_at(0, 0, &text)],
+            })
+            .await
+            .unwrap();
+        let mut mirror = crate::state::Document::new(uri.clone(), String::new(), "fast");
+        mirror
+            .apply_remote_patch(&next_patch(&mut net_rx).await)
+            .unwrap();
+
+        // Peer 1 is up to date
+        core_tx
+            .send(Event::RemoteAck {
+                uri: uri.clone(),
+                agent: "fast".into(),
+                frontier: mirror.frontier().unwrap(),
+                peer: 1,
+            })
+            .await
+            .unwrap();
+
+        // The slow peer still holds the delta back to the very beginning
+        core_tx
+            .send(Event::LocalChange {
+                uri: uri.clone(),
+                changes: vec![insert_at(0, 0, "a")],
+            })
+            .await
+            .unwrap();
+        let held_back = next_patch(&mut net_rx).await;
+        assert!(held_back.len() > text.len() / 10);
+
+        // Once it's gone, only the ops peer 1 is missing go out
+        core_tx
+            .send(Event::PeerDisconnected { peer_id: 2 })
+            .await
+            .unwrap();
+        core_tx
+            .send(Event::LocalChange {
+                uri: uri.clone(),
+                changes: vec![insert_at(0, 0, "b")],
+            })
+            .await
+            .unwrap();
+        let delta = next_patch(&mut net_rx).await;
+        assert!(delta.len() < held_back.len() / 10);
+
+        core_tx.send(Event::Shutdown).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_core_clears_cursors_of_disconnected_peer() {
+        let (core_tx, core_rx) = mpsc::channel(10);
+        let (net_tx, mut net_rx) = mpsc::channel(10);
+        let (edit_tx, mut edit_rx) = mpsc::channel(10);
+
+        let core = Core::new("host".into(), net_tx, edit_tx);
+        tokio::spawn(async move {
+            core.run(core_rx).await;
+        });
+
+        core_tx
+            .send(Event::RemoteCursor {
+                uri: "main.rs".into(),
+                agent_id: "bob".into(),
+                position: Some(Position {
+                    line: 3,
+                    character: 7,
+                }),
+                peer: 4,
+            })
+            .await
+            .unwrap();
+        match edit_rx.recv().await {
+            Some(EditorCommand::RemoteCursor {
+                agent_id, position, ..
+            }) => {
+                assert_eq!(agent_id, "bob");
+                assert_eq!(position.unwrap().line, 3);
+            }
+            other => panic!("Expected RemoteCursor, got {:?}", other),
+        }
+
+        core_tx
+            .send(Event::PeerDisconnected { peer_id: 4 })
+            .await
+            .unwrap();
+
+        // The other peers hear that bob's cursor is gone...
+        match net_rx.recv().await {
+            Some(NetworkCommand::BroadcastCursor {
+                uri,
+                agent_id,
+                position,
+            }) => {
+                assert_eq!(uri, "main.rs");
+                assert_eq!(agent_id, "bob");
+                assert!(position.is_none());
+            }
+            other => panic!("Expected BroadcastCursor, got {:?}", other),
+        }
+        // ...and so does our editor
+        match edit_rx.recv().await {
+            Some(EditorCommand::RemoteCursor {
+                agent_id, position, ..
+            }) => {
+                assert_eq!(agent_id, "bob");
+                assert!(position.is_none());
+            }
+            other => panic!("Expected RemoteCursor, got {:?}", other),
+        }
+        // The user is told someone left
+        match edit_rx.recv().await {
+            Some(EditorCommand::ShowMessage {
+                kind: MessageKind::Warning,
+                message,
+            }) => assert!(message.contains("lost the connection"), "{}", message),
+            other => panic!("Expected ShowMessage, got {:?}", other),
+        }
+
+        core_tx.send(Event::Shutdown).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_core_follow_mode_reveals_followed_viewport() {
+        let (core_tx, core_rx) = mpsc::channel(10);
+        let (net_tx, _net_rx) = mpsc::channel(10);
+        let (edit_tx, mut edit_rx) = mpsc::channel(10);
+
+        let core = Core::new("me".into(), net_tx, edit_tx);
+        tokio::spawn(async move {
+            core.run(core_rx).await;
+        });
+
+        let viewport = |agent: &str, uri: &str, top_line| Event::RemoteViewport {
+            uri: uri.into(),
+            agent_id: agent.into(),
+            top_line,
+            peer: 1,
+        };
+        let expect_reveal = |cmd: Option<EditorCommand>, want_uri: &str, want_line| match cmd {
+            Some(EditorCommand::Reveal { uri, line }) => {
+                assert_eq!(uri, want_uri);
+                assert_eq!(line, want_line);
+            }
+            other => panic!("Expected Reveal, got {:?}", other),
+        };
+
+        // Not following anyone yet: nothing moves
+        core_tx.send(viewport("bob", "a.rs", 10)).await.unwrap();
+        // Starting to follow jumps to where bob was last seen
+        core_tx
+            .send(Event::Follow {
+                agent_id: Some("bob".into()),
+            })
+            .await
+            .unwrap();
+        expect_reveal(edit_rx.recv().await, "a.rs", 10);
+
+        // Only bob's viewport is mirrored
+        core_tx.send(viewport("carol", "c.rs", 99)).await.unwrap();
+        core_tx.send(viewport("bob", "b.rs", 20)).await.unwrap();
+        expect_reveal(edit_rx.recv().await, "b.rs", 20);
+
+        // Toggled off: no more jumps
+        core_tx
+            .send(Event::Follow { agent_id: None })
+            .await
+            .unwrap();
+        core_tx.send(viewport("bob", "b.rs", 30)).await.unwrap();
+        core_tx.send(Event::Shutdown).await.unwrap();
+        assert!(edit_rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_core_clears_selections_of_disconnected_peer() {
+        let (core_tx, core_rx) = mpsc::channel(10);
+        let (net_tx, mut net_rx) = mpsc::channel(10);
+        let (edit_tx, mut edit_rx) = mpsc::channel(10);
+
+        let core = Core::new("host".into(), net_tx, edit_tx);
+        tokio::spawn(async move {
+            core.run(core_rx).await;
+        });
+
+        let range = |line| Range {
+            start: Position { line, character: 0 },
+            end: Position { line, character: 5 },
+        };
+        // A second selection replaces the first one, the editor is told each time
+        for line in [1, 4] {
+            core_tx
+                .send(Event::RemoteSelection {
+                    uri: "main.rs".into(),
+                    agent_id: "bob".into(),
+                    range: Some(range(line)),
+                    peer: 4,
+                })
+                .await
+                .unwrap();
+            match edit_rx.recv().await {
+                Some(EditorCommand::RemoteSelection {
+                    agent_id, range, ..
+                }) => {
+                    assert_eq!(agent_id, "bob");
+                    assert_eq!(range.unwrap().start.line, line);
+                }
+                other => panic!("Expected RemoteSelection, got {:?}", other),
+            }
+        }
+
+        core_tx
+            .send(Event::PeerDisconnected { peer_id: 4 })
+            .await
+            .unwrap();
+
+        // Cleared once, for the other peers and our editor
+        match net_rx.recv().await {
+            Some(NetworkCommand::BroadcastSelection {
+                agent_id, range, ..
+            }) => {
+                assert_eq!(agent_id, "bob");
+                assert!(range.is_none());
+            }
+            other => panic!("Expected BroadcastSelection, got {:?}", other),
+        }
+        match edit_rx.recv().await {
+            Some(EditorCommand::RemoteSelection {
+                agent_id, range, ..
+            }) => {
+                assert_eq!(agent_id, "bob");
+                assert!(range.is_none());
+            }
+            other => panic!("Expected RemoteSelection, got {:?}", other),
+        }
+
+        // Nothing else was left to clear
+        core_tx.send(Event::Shutdown).await.unwrap();
+        assert!(matches!(
+            net_rx.recv().await,
+            Some(NetworkCommand::Shutdown)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_core_introduces_itself_and_names_cursors() {
+        let (core_tx, core_rx) = mpsc::channel(10);
+        let (net_tx, mut net_rx) = mpsc::channel(10);
+        let (edit_tx, mut edit_rx) = mpsc::channel(10);
+
+        let core = Core::new("host-uuid".into(), net_tx, edit_tx).with_display_name("alice");
+        tokio::spawn(async move {
+            core.run(core_rx).await;
+        });
+
+        // bob connects: we say who we are
+        core_tx
+            .send(Event::PeerConnected {
+                peer_id: 4,
+                room: String::new(),
+            })
+            .await
+            .unwrap();
+        match net_rx.recv().await {
+            Some(NetworkCommand::SendHello {
+                peer,
+                agent_id,
+                display_name,
+            }) => {
+                assert_eq!(peer, 4);
+                assert_eq!(agent_id, "host-uuid");
+                assert_eq!(display_name, "alice");
+            }
+            other => panic!("Expected SendHello, got {:?}", other),
+        }
+
+        // bob's cursor carries his name once he said hello
+        core_tx
+            .send(Event::RemoteHello {
+                agent_id: "bob-uuid".into(),
+                display_name: "bob".into(),
+                peer: 4,
+            })
+            .await
+            .unwrap();
+        core_tx
+            .send(Event::RemoteCursor {
+                uri: "main.rs".into(),
+                agent_id: "bob-uuid".into(),
+                position: Some(Position {
+                    line: 1,
+                    character: 0,
+                }),
+                peer: 4,
+            })
+            .await
+            .unwrap();
+        match edit_rx.recv().await {
+            Some(EditorCommand::RemoteCursor {
+                agent_id,
+                display_name,
+                ..
+            }) => {
+                assert_eq!(agent_id, "bob-uuid");
+                assert_eq!(display_name, "bob");
+            }
+            other => panic!("Expected RemoteCursor, got {:?}", other),
+        }
+
+        // Someone joining later learns bob's name from us too
+        core_tx
+            .send(Event::PeerConnected {
+                peer_id: 7,
+                room: String::new(),
+            })
+            .await
+            .unwrap();
+        let mut introduced = Vec::new();
+        for _ in 0..2 {
+            match net_rx.recv().await {
+                Some(NetworkCommand::SendHello {
+                    peer, display_name, ..
+                }) => {
+                    assert_eq!(peer, 7);
+                    introduced.push(display_name);
+                }
+                other => panic!("Expected SendHello, got {:?}", other),
+            }
+        }
+        introduced.sort();
+        assert_eq!(introduced, vec!["alice", "bob"]);
+
+        core_tx.send(Event::Shutdown).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_core_status_reports_how_far_behind_we_are() {
+        let (core_tx, core_rx) = mpsc::channel(10);
+        let (net_tx, mut net_rx) = mpsc::channel(10);
+        let (edit_tx, mut edit_rx) = mpsc::channel(10);
+        tokio::spawn(Core::new("peer".into(), net_tx, edit_tx).run(core_rx));
+
+        // Alone there is nothing to compare with
+        core_tx
+            .send(Event::StatusRequest { id: 1.into() })
+            .await
+            .unwrap();
+        match edit_rx.recv().await {
+            Some(EditorCommand::Status { id, files }) => {
+                assert_eq!(id, 1);
+                assert!(files.is_none());
+            }
+            other => panic!("Expected Status, got {:?}", other),
+        }
+
+        let uri = "notes.md".to_string();
+        core_tx
+            .send(Event::ClientDidOpen {
+                uri: uri.clone(),
+                content: "base".into(),
+            })
+            .await
+            .unwrap();
+        core_tx
+            .send(Event::PeerConnected {
+                peer_id: 1,
+                room: String::new(),
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            net_rx.recv().await,
+            Some(NetworkCommand::SendHello { .. })
+        ));
+
+        // The host typed 7 chars we haven't received
+        let mut host = crate::state::Document::new(uri.clone(), "base".into(), "host");
+        host.apply_local_changes(vec![TextDocumentContentChangeEvent {
+            range: None,
+            text: "base + more".into(),
+        }])
+        .unwrap();
+
+        core_tx
+            .send(Event::StatusRequest { id: 2.into() })
+            .await
+            .unwrap();
+        match net_rx.recv().await {
+            Some(NetworkCommand::BroadcastPing { versions }) => {
+                assert_eq!(versions.len(), 1);
+                assert_eq!(versions[0].0, uri);
+            }
+            other => panic!("Expected BroadcastPing, got {:?}", other),
+        }
+        core_tx
+            .send(Event::RemotePong {
+                versions: vec![(uri.clone(), host.version_summary())],
+                peer: 1,
+            })
+            .await
+            .unwrap();
+        match edit_rx.recv().await {
+            Some(EditorCommand::Status { id, files }) => {
+                assert_eq!(id, 2);
+                let files = files.unwrap();
+                assert_eq!(files.len(), 1);
+                assert_eq!(files[0].uri, uri);
+                assert_eq!(files[0].behind, 7);
+                assert_eq!(files[0].ahead, 0);
+            }
+            other => panic!("Expected Status, got {:?}", other),
+        }
+
+        // Pinged ourselves, we answer with our versions
+        core_tx
+            .send(Event::RemotePing {
+                versions: vec![(uri.clone(), host.version_summary())],
+                peer: 1,
+            })
+            .await
+            .unwrap();
+        match net_rx.recv().await {
+            Some(NetworkCommand::SendPong { peer, versions }) => {
+                assert_eq!(peer, 1);
+                assert_eq!(host.version_summary().missing_from(&versions[0].1), 0);
+                assert_eq!(versions[0].1.missing_from(&host.version_summary()), 7);
+            }
+            other => panic!("Expected SendPong, got {:?}", other),
+        }
+
+        core_tx.send(Event::Shutdown).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_core_buffers_edits_until_editor_ready() {
+        let (core_tx, core_rx) = mpsc::channel(10);
+        let (net_tx, _net_rx) = mpsc::channel(10);
+        let (edit_tx, mut edit_rx) = mpsc::channel(10);
+
+        let core = Core::new("local".into(), net_tx, edit_tx).with_editor_buffer(8);
+        tokio::spawn(async move {
+            core.run(core_rx).await;
+        });
+
+        let uri = "early.rs".to_string();
+        core_tx
+            .send(Event::ClientDidOpen {
+                uri: uri.clone(),
+                content: "ab".into(),
+            })
+            .await
+            .unwrap();
+
+        // Three remote edits land before the editor is attached
+        let mut peer_doc = crate::state::Document::new(uri.clone(), "ab".into(), "Peer");
+        for (i, text) in ["1", "2", "3"].iter().enumerate() {
+            let patch = peer_doc
+                .apply_local_changes(vec![insert_at(0, 1 + i, text)])
+                .unwrap();
+            core_tx
+                .send(Event::RemotePatch {
+                    uri: uri.clone(),
+                    patch,
+                    peer: 1,
+                    agent_id: String::new(),
+                })
+                .await
+                .unwrap();
+        }
+
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), edit_rx.recv())
+                .await
+                .is_err(),
+            "Nothing may reach the editor before it is ready"
+        );
+
+        core_tx.send(Event::EditorReady).await.unwrap();
+
+        // One coalesced batch that takes the editor from "ab" to "a123b"
+        match tokio::time::timeout(Duration::from_millis(100), edit_rx.recv()).await {
+            Ok(Some(EditorCommand::ApplyEdits {
+                uri: res_uri,
+                edits,
+            })) => {
+                assert_eq!(res_uri, uri);
+                assert_eq!(edits.len(), 1);
+                assert_eq!(edits[0].new_text, "123");
+                assert_eq!(edits[0].range.start.character, 1);
+            }
+            res => panic!("Expected coalesced ApplyEdits, got {:?}", res),
+        }
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), edit_rx.recv())
+                .await
+                .is_err()
+        );
+
+        core_tx.send(Event::Shutdown).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_core_editor_buffer_drops_oldest_on_overflow() {
+        let (core_tx, core_rx) = mpsc::channel(10);
+        let (net_tx, _net_rx) = mpsc::channel(10);
+        let (edit_tx, mut edit_rx) = mpsc::channel(10);
+
+        let core = Core::new("local".into(), net_tx, edit_tx).with_editor_buffer(1);
+        tokio::spawn(async move {
+            core.run(core_rx).await;
+        });
+
+        for uri in ["first.rs", "second.rs"] {
+            core_tx
+                .send(Event::ClientDidOpen {
+                    uri: uri.into(),
+                    content: "x".into(),
+                })
+                .await
+                .unwrap();
+            let mut peer_doc = crate::state::Document::new(uri.into(), "x".into(), "Peer");
+            let patch = peer_doc
+                .apply_local_changes(vec![insert_at(0, 1, "y")])
+                .unwrap();
+            core_tx
+                .send(Event::RemotePatch {
+                    uri: uri.into(),
+                    patch,
+                    peer: 1,
+                    agent_id: String::new(),
+                })
+                .await
+                .unwrap();
+        }
+
+        core_tx.send(Event::EditorReady).await.unwrap();
+
+        match tokio::time::timeout(Duration::from_millis(100), edit_rx.recv()).await {
+            Ok(Some(EditorCommand::ApplyEdits { uri, .. })) => assert_eq!(uri, "second.rs"),
+            res => panic!("Expected ApplyEdits for the newest file, got {:?}", res),
+        }
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), edit_rx.recv())
+                .await
+                .is_err()
+        );
+
+        core_tx.send(Event::Shutdown).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_core_full_editor_channel_holds_edits_back() {
+        let (core_tx, core_rx) = mpsc::channel(10);
+        let (net_tx, mut net_rx) = mpsc::channel(10);
+        // Room for a single command, and nobody reading it yet
+        let (edit_tx, mut edit_rx) = mpsc::channel(1);
+        tokio::spawn(Core::new("local".into(), net_tx, edit_tx).run(core_rx));
+
+        core_tx
+            .send(Event::ClientDidOpen {
+                uri: "busy.rs".into(),
+                content: "x".into(),
+            })
+            .await
+            .unwrap();
+        let mut peer_doc = crate::state::Document::new("busy.rs".into(), "x".into(), "Peer");
+        for (i, text) in ["a", "b", "c", "d", "e"].into_iter().enumerate() {
+            let patch = peer_doc
+                .apply_local_changes(vec![insert_at(0, i + 1, text)])
+                .unwrap();
+            core_tx
+                .send(Event::RemotePatch {
+                    uri: "busy.rs".into(),
+                    patch,
+                    peer: 1,
+                    agent_id: String::new(),
+                })
+                .await
+                .unwrap();
+        }
+
+        // The core doesn't wait for the editor, local edits still go out
+        core_tx
+            .send(Event::ClientDidOpen {
+                uri: "other.rs".into(),
+                content: "".into(),
+            })
+            .await
+            .unwrap();
+        core_tx
+            .send(Event::LocalChange {
+                uri: "other.rs".into(),
+                changes: vec![insert_at(0, 0, "typed")],
+            })
+            .await
+            .unwrap();
+        loop {
+            match tokio::time::timeout(Duration::from_millis(500), net_rx.recv()).await {
+                Ok(Some(NetworkCommand::BroadcastPatch { uri, .. })) if uri == "other.rs" => break,
+                Ok(Some(_)) => continue,
+                other => panic!("Core is stuck behind the editor: {:?}", other),
+            }
+        }
+
+        // Once the editor catches up, every remote edit reaches it
+        let mut editor = "x".to_string();
+        while let Ok(Some(cmd)) =
+            tokio::time::timeout(Duration::from_millis(100), edit_rx.recv()).await
+        {
+            if let EditorCommand::ApplyEdits { edits, .. } = cmd {
+                editor = crate::diff::tests::apply_edits_to_string(&editor, &edits);
+            }
+        }
+        assert_eq!(editor, "xabcde");
+
+        core_tx.send(Event::Shutdown).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_core_resync_replaces_a_drifted_buffer() {
+        let (core_tx, core_rx) = mpsc::channel(10);
+        let (net_tx, mut net_rx) = mpsc::channel(10);
+        let (edit_tx, mut edit_rx) = mpsc::channel(10);
+        tokio::spawn(Core::new("local".into(), net_tx, edit_tx).run(core_rx));
+
+        core_tx
+            .send(Event::ClientDidOpen {
+                uri: "drift.rs".into(),
+                content: "fn main() {\n    run();\n}".into(),
+            })
+            .await
+            .unwrap();
+        core_tx
+            .send(Event::ResyncRequest {
+                uri: "drift.rs".into(),
+                id: serde_json::json!(4),
+            })
+            .await
+            .unwrap();
+
+        // Whatever the editor ended up with, one edit puts it right
+        let drifted = "fn main() {\n    rn();\n}\n}stray";
+        let edits = match tokio::time::timeout(Duration::from_secs(1), edit_rx.recv()).await {
+            Ok(Some(EditorCommand::ApplyEdits { uri, edits })) if uri == "drift.rs" => edits,
+            other => panic!("Expected the resync edit, got {:?}", other),
+        };
+        assert_eq!(edits.len(), 1);
+        assert_eq!(
+            crate::diff::tests::apply_edits_to_string(drifted, &edits),
+            "fn main() {\n    run();\n}"
+        );
+        match tokio::time::timeout(Duration::from_secs(1), edit_rx.recv()).await {
+            Ok(Some(EditorCommand::Resynced { id, resynced })) => {
+                assert_eq!(id, serde_json::json!(4));
+                assert!(resynced);
+            }
+            other => panic!("Expected the resync answer, got {:?}", other),
+        }
+
+        // The editor reports the replacement back, that's no news for the peers
+        core_tx
+            .send(Event::LocalChange {
+                uri: "drift.rs".into(),
+                changes: vec![TextDocumentContentChangeEvent {
+                    range: Some(Range {
+                        start: Position {
+                            line: 0,
+                            character: 0,
+                        },
+                        end: Position {
+                            line: 3,
+                            character: 6,
+                        },
+                    }),
+                    text: "fn main() {\n    run();\n}".into(),
+                }],
+            })
+            .await
+            .unwrap();
+        core_tx
+            .send(Event::LocalChange {
+                uri: "drift.rs".into(),
+                changes: vec![insert_at(2, 1, "\n")],
+            })
+            .await
+            .unwrap();
+        match tokio::time::timeout(Duration::from_secs(1), net_rx.recv()).await {
+            Ok(Some(NetworkCommand::BroadcastPatch { uri, .. })) => assert_eq!(uri, "drift.rs"),
+            other => panic!("Expected the typed newline, got {:?}", other),
+        }
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), net_rx.recv())
+                .await
+                .is_err()
+        );
+
+        // Nothing to resync a file we don't have with
+        core_tx
+            .send(Event::ResyncRequest {
+                uri: "unknown.rs".into(),
+                id: serde_json::json!(5),
+            })
+            .await
+            .unwrap();
+        match tokio::time::timeout(Duration::from_secs(1), edit_rx.recv()).await {
+            Ok(Some(EditorCommand::Resynced { resynced, .. })) => assert!(!resynced),
+            other => panic!("Expected the resync answer, got {:?}", other),
+        }
+
+        core_tx.send(Event::Shutdown).await.unwrap();
+    }
+
+    #[test]
+    fn test_silent_agent_times_out() {
+        let start = Instant::now();
+        let timeout = HEARTBEAT_INTERVAL * MISSED_HEARTBEATS;
+        let mut liveness = Liveness::default();
+        liveness.beat("bob".into(), 1, start);
+        liveness.beat("carol".into(), 1, start);
+
+        // Carol keeps beating, bob doesn't
+        for beat in 1..=MISSED_HEARTBEATS {
+            liveness.beat("carol".into(), 1, start + HEARTBEAT_INTERVAL * beat);
+        }
+        assert_eq!(
+            liveness.next_timeout(),
+            Some(("bob".to_string(), start + timeout))
+        );
+
+        liveness.forget("bob");
+        assert_eq!(
+            liveness.next_timeout(),
+            Some((
+                "carol".to_string(),
+                start + HEARTBEAT_INTERVAL * MISSED_HEARTBEATS + timeout
+            ))
+        );
+        liveness.forget_peer(1);
+        assert_eq!(liveness.next_timeout(), None);
+    }
+
+    #[tokio::test]
+    async fn test_core_timed_out_agent_loses_its_cursor() {
+        let (core_tx, core_rx) = mpsc::channel(10);
+        let (net_tx, mut net_rx) = mpsc::channel(10);
+        let (edit_tx, mut edit_rx) = mpsc::channel(10);
+        tokio::spawn(Core::new("local".into(), net_tx, edit_tx).run(core_rx));
+
+        for agent_id in ["bob", "carol"] {
+            core_tx
+                .send(Event::RemoteCursor {
+                    uri: "main.rs".into(),
+                    agent_id: agent_id.into(),
+                    position: Some(Position {
+                        line: 1,
+                        character: 0,
+                    }),
+                    peer: 1,
+                })
+                .await
+                .unwrap();
+            core_tx
+                .send(Event::RemoteHeartbeat {
+                    agent_id: agent_id.into(),
+                    peer: 1,
+                })
+                .await
+                .unwrap();
+            edit_rx.recv().await.unwrap();
+        }
+
+        core_tx
+            .send(Event::PeerTimedOut {
+                agent_id: "bob".into(),
+            })
+            .await
+            .unwrap();
+        match tokio::time::timeout(Duration::from_secs(1), edit_rx.recv()).await {
+            Ok(Some(EditorCommand::RemoteCursor {
+                agent_id, position, ..
+            })) => {
+                assert_eq!(agent_id, "bob");
+                assert!(position.is_none());
+            }
+            other => panic!("Expected bob's cursor to go, got {:?}", other),
+        }
+        // The peers we relay to drop it too
+        match tokio::time::timeout(Duration::from_secs(1), net_rx.recv()).await {
+            Ok(Some(NetworkCommand::BroadcastCursor {
+                agent_id, position, ..
+            })) => {
+                assert_eq!(agent_id, "bob");
+                assert!(position.is_none());
+            }
+            other => panic!("Expected bob's cursor to go, got {:?}", other),
+        }
+        // Carol is still here
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), edit_rx.recv())
+                .await
+                .is_err()
+        );
+
+        core_tx.send(Event::Shutdown).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_core_only_shared_files_are_synced() {
+        let (core_tx, core_rx) = mpsc::channel(10);
+        let (net_tx, mut net_rx) = mpsc::channel(10);
+        let (edit_tx, _edit_rx) = mpsc::channel(10);
+        tokio::spawn(
+            Core::new("local".into(), net_tx, edit_tx)
+                .with_share(vec!["src".into()])
+                .run(core_rx),
+        );
+
+        for uri in ["README.md", "src/lib.rs"] {
+            core_tx
+                .send(Event::ClientDidOpen {
+                    uri: uri.into(),
+                    content: "".into(),
+                })
+                .await
+                .unwrap();
+            core_tx
+                .send(Event::LocalChange {
+                    uri: uri.into(),
+                    changes: vec![insert_at(0, 0, "typed")],
+                })
+                .await
+                .unwrap();
+        }
+        core_tx
+            .send(Event::PeerRequestedSync { peer: 1 })
+            .await
+            .unwrap();
+
+        loop {
+            match tokio::time::timeout(Duration::from_secs(1), net_rx.recv()).await {
+                Ok(Some(NetworkCommand::BroadcastPatch { uri, .. })) => {
+                    assert_eq!(uri, "src/lib.rs")
+                }
+                Ok(Some(NetworkCommand::SendSyncManifest { files, .. })) => {
+                    let uris: Vec<&str> = files.iter().map(|(uri, _)| uri.as_str()).collect();
+                    assert_eq!(uris, vec!["src/lib.rs"]);
+                    break;
+                }
+                Ok(Some(_)) => continue,
+                other => panic!("Expected the manifest, got {:?}", other),
+            }
+        }
+
+        core_tx.send(Event::Shutdown).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_core_sync_ignored_files_stay_local() {
+        let (core_tx, core_rx) = mpsc::channel(10);
+        let (net_tx, mut net_rx) = mpsc::channel(10);
+        let (edit_tx, _edit_rx) = mpsc::channel(10);
+        let mut rules = IgnoreRules::default();
+        rules.add_patterns("", "*.env\n");
+        tokio::spawn(
+            Core::new("local".into(), net_tx, edit_tx)
+                .with_sync_ignore(rules)
+                .run(core_rx),
+        );
+
+        for uri in ["local.env", "main.rs"] {
+            core_tx
+                .send(Event::ClientDidOpen {
+                    uri: uri.into(),
+                    content: "".into(),
+                })
+                .await
+                .unwrap();
+            core_tx
+                .send(Event::LocalChange {
+                    uri: uri.into(),
+                    changes: vec![insert_at(0, 0, "typed")],
+                })
+                .await
+                .unwrap();
+        }
+        core_tx
+            .send(Event::PeerRequestedSync { peer: 1 })
+            .await
+            .unwrap();
+
+        loop {
+            match tokio::time::timeout(Duration::from_secs(1), net_rx.recv()).await {
+                Ok(Some(NetworkCommand::BroadcastPatch { uri, .. })) => {
+                    assert_eq!(uri, "main.rs")
+                }
+                Ok(Some(NetworkCommand::SendSyncManifest { files, .. })) => {
+                    let uris: Vec<&str> = files.iter().map(|(uri, _)| uri.as_str()).collect();
+                    assert_eq!(uris, vec!["main.rs"]);
+                    break;
+                }
+                Ok(Some(_)) => continue,
+                other => panic!("Expected the manifest, got {:?}", other),
+            }
+        }
+
+        core_tx.send(Event::Shutdown).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_core_burst_of_patches_reaches_the_editor_once() {
+        let (core_tx, core_rx) = mpsc::channel(64);
+        let (net_tx, mut net_rx) = mpsc::channel(64);
+        let (edit_tx, mut edit_rx) = mpsc::channel(64);
+        tokio::spawn(Core::new("local".into(), net_tx, edit_tx).run(core_rx));
+        tokio::spawn(async move { while net_rx.recv().await.is_some() {} });
+
+        core_tx
+            .send(Event::ClientDidOpen {
+                uri: "burst.rs".into(),
+                content: "x".into(),
+            })
+            .await
+            .unwrap();
+
+        // A reconnect delivers everything the peer typed meanwhile
+        let mut peer_doc = crate::state::Document::new("burst.rs".into(), "x".into(), "Peer");
+        let mut patches = Vec::new();
+        for i in 0..50 {
+            let patch = peer_doc
+                .apply_local_changes(vec![insert_at(0, 1 + i, "y")])
+                .unwrap();
+            patches.push(patch);
+        }
+        for patch in patches {
+            core_tx
+                .send(Event::RemotePatch {
+                    uri: "burst.rs".into(),
+                    patch,
+                    peer: 1,
+                    agent_id: String::new(),
+                })
+                .await
+                .unwrap();
+        }
+
+        let edits = match tokio::time::timeout(Duration::from_secs(1), edit_rx.recv()).await {
+            Ok(Some(EditorCommand::ApplyEdits { uri, edits })) if uri == "burst.rs" => edits,
+            res => panic!("Expected one ApplyEdits, got {:?}", res),
+        };
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits[0].new_text, "y".repeat(50));
+        assert_eq!(
+            edits[0].range.start,
+            Position {
+                line: 0,
+                character: 1
+            }
+        );
+        assert!(
+            tokio::time::timeout(CATCH_UP_WINDOW * 5, edit_rx.recv())
+                .await
+                .is_err()
+        );
+
+        core_tx.send(Event::Shutdown).await.unwrap();
+    }
+
+    #[test]
+    fn test_core_interrupted_sync_fetches_only_the_rest() {
+        crate::fs::tests::run_in_temp_dir(|| {
+            let rt = tokio::runtime::Runtime::new().unwrap();
+            rt.block_on(async {
+                let mut host = crate::state::Workspace::new("host".into());
+                for i in 0..5 {
+                    host.get_or_create(format!("f{}.md", i), format!("file {}", i));
+                }
+                let manifest = host.manifest();
+
+                let (core_tx, core_rx) = mpsc::channel(100);
+                let (net_tx, mut net_rx) = mpsc::channel(100);
+                let (edit_tx, _edit_rx) = mpsc::channel(100);
+                tokio::spawn(Core::new("peer".into(), net_tx, edit_tx).run(core_rx));
+
+                async fn requested(net_rx: &mut mpsc::Receiver<NetworkCommand>) -> Vec<String> {
+                    loop {
+                        match tokio::time::timeout(Duration::from_millis(200), net_rx.recv()).await
+                        {
+                            Ok(Some(NetworkCommand::SendRequestFiles { mut uris, .. })) => {
+                                uris.sort();
+                                return uris;
+                            }
+                            Ok(Some(_)) => {}
+                            other => panic!("Expected SendRequestFiles, got {:?}", other),
+                        }
+                    }
+                }
+
+                core_tx
+                    .send(Event::RemoteSyncManifest {
+                        files: manifest.clone(),
+                        peer: 1,
+                    })
+                    .await
+                    .unwrap();
+                let uris = requested(&mut net_rx).await;
+                assert_eq!(uris.len(), 5);
+
+                // Three files make it, then the connection drops
+                for file in host.get_files_snapshot(&uris[..3]) {
+                    core_tx
+                        .send(Event::RemoteFullSync {
+                            files: vec![file],
+                            peer: 1,
+                        })
+                        .await
+                        .unwrap();
+                }
+                core_tx
+                    .send(Event::PeerDisconnected { peer_id: 1 })
+                    .await
+                    .unwrap();
+
+                // The retry only asks for what is still missing
+                core_tx
+                    .send(Event::PeerConnected {
+                        peer_id: 2,
+                        room: String::new(),
+                    })
+                    .await
+                    .unwrap();
+                core_tx
+                    .send(Event::RemoteSyncManifest {
+                        files: manifest,
+                        peer: 2,
+                    })
+                    .await
+                    .unwrap();
+                assert_eq!(requested(&mut net_rx).await, uris[3..].to_vec());
+
+                core_tx.send(Event::Shutdown).await.unwrap();
+            });
+        });
+    }
 
     #[tokio::test]
-    async fn test_core_local_change_broadcasts() {
+    async fn test_relay_keeps_rooms_apart() {
         let (core_tx, core_rx) = mpsc::channel(10);
-        let (net_tx, mut net_rx) = mpsc::channel(10);
+        let (net_tx, mut net_rx) = mpsc::channel(20);
         let (edit_tx, _edit_rx) = mpsc::channel(10);
+        tokio::spawn(
+            Core::new("relay".into(), net_tx, edit_tx)
+                .with_headless()
+                .run(core_rx),
+        );
+
+        /// The next command that isn't an introduction, unwrapped from its room
+        async fn next(rx: &mut mpsc::Receiver<NetworkCommand>) -> (RoomId, NetworkCommand) {
+            loop {
+                match rx.recv().await {
+                    Some(NetworkCommand::InRoom { room, command }) => match *command {
+                        NetworkCommand::SendHello { .. } => {}
+                        command => return (room, command),
+                    },
+                    other => panic!("Expected a command in a room, got {:?}", other),
+                }
+            }
+        }
 
-        let core = Core::new("test-agent".into(), net_tx, edit_tx);
-        tokio::spawn(async move {
-            core.run(core_rx).await;
-        });
-
-        let uri = "test.rs".to_string();
+        for (peer_id, room) in [(1, "a"), (2, "a"), (3, "b")] {
+            core_tx
+                .send(Event::PeerConnected {
+                    peer_id,
+                    room: room.into(),
+                })
+                .await
+                .unwrap();
+        }
         core_tx
-            .send(Event::ClientDidOpen {
-                uri: uri.clone(),
-                content: "initial".into(),
+            .send(Event::RemoteFileCreated {
+                uri: "notes.md".into(),
+                content: "alpha".into(),
+                peer: 1,
             })
             .await
             .unwrap();
-
-        let change = TextDocumentContentChangeEvent {
-            range: Some(Range {
-                start: Position {
-                    line: 0,
-                    character: 7,
-                },
-                end: Position {
-                    line: 0,
-                    character: 7,
-                },
-            }),
-            text: " modified".to_string(),
-        };
-
-        // Trigger local change
+        match next(&mut net_rx).await {
+            (room, NetworkCommand::BroadcastFileCreated { uri, exclude, .. }) => {
+                assert_eq!(room, "a");
+                assert_eq!(uri, "notes.md");
+                assert_eq!(exclude, Some(1));
+            }
+            other => panic!("Expected BroadcastFileCreated, got {:?}", other),
+        }
         core_tx
-            .send(Event::LocalChange {
-                uri: uri.clone(),
-                changes: vec![change],
+            .send(Event::RemoteFileCreated {
+                uri: "todo.md".into(),
+                content: "beta".into(),
+                peer: 3,
             })
             .await
             .unwrap();
+        assert!(matches!(
+            next(&mut net_rx).await,
+            (room, NetworkCommand::BroadcastFileCreated { .. }) if room == "b"
+        ));
 
-        // Verify broadcast
-        match tokio::time::timeout(Duration::from_millis(100), net_rx.recv()).await {
-            Ok(Some(NetworkCommand::BroadcastPatch {
-                uri: res_uri,
-                patch,
-            })) => {
-                assert_eq!(res_uri, uri);
-                assert!(!patch.is_empty());
+        // Each room syncs only its own files
+        for (peer, expected) in [(2, "notes.md"), (3, "todo.md")] {
+            core_tx
+                .send(Event::PeerRequestedSync { peer })
+                .await
+                .unwrap();
+            match next(&mut net_rx).await {
+                (_, NetworkCommand::SendSyncManifest { peer: to, files }) => {
+                    assert_eq!(to, peer);
+                    let uris: Vec<&str> = files.iter().map(|(uri, _)| uri.as_str()).collect();
+                    assert_eq!(uris, vec![expected]);
+                }
+                other => panic!("Expected SendSyncManifest, got {:?}", other),
             }
-            _ => panic!("Expected BroadcastPatch"),
         }
 
         core_tx.send(Event::Shutdown).await.unwrap();
     }
 
     #[tokio::test]
-    async fn test_core_client_close_behavior() {
-        let temp_dir = tempfile::tempdir().unwrap();
-        let file_path = temp_dir.path().join("closed_after_open.txt");
-        let uri = file_path.to_str().unwrap().to_string();
-
+    async fn test_only_a_relay_has_rooms() {
         let (core_tx, core_rx) = mpsc::channel(10);
-        let (net_tx, _net_rx) = mpsc::channel(10);
-        let (edit_tx, mut edit_rx) = mpsc::channel(10);
-
-        let core = Core::new("test-agent".into(), net_tx, edit_tx);
-        tokio::spawn(async move {
-            core.run(core_rx).await;
-        });
+        let (net_tx, mut net_rx) = mpsc::channel(10);
+        let (edit_tx, _edit_rx) = mpsc::channel(10);
+        tokio::spawn(Core::new("host".into(), net_tx, edit_tx).run(core_rx));
 
-        // 1. Open file
         core_tx
-            .send(Event::ClientDidOpen {
-                uri: uri.clone(),
-                content: "initial".into(),
+            .send(Event::PeerConnected {
+                peer_id: 5,
+                room: "a".into(),
             })
             .await
             .unwrap();
-
-        // 2. Close file
+        assert!(matches!(
+            net_rx.recv().await,
+            Some(NetworkCommand::Disconnect {
+                peer: 5,
+                reason: ByeReason::NoRooms
+            })
+        ));
+        // Whatever it sends before it is gone is not part of our session
         core_tx
-            .send(Event::ClientDidClose { uri: uri.clone() })
+            .send(Event::PeerRequestedSync { peer: 5 })
             .await
             .unwrap();
-
-        // 3. Receive patch
-        let mut peer_doc = crate::state::Document::new(uri.clone(), "initial".into(), "Peer");
-        let patch = peer_doc
-            .apply_local_changes(vec![TextDocumentContentChangeEvent {
-                range: Some(Range {
-                    start: Position {
-                        line: 0,
-                        character: 7,
-                    },
-                    end: Position {
-                        line: 0,
-                        character: 7,
-                    },
-                }),
-                text: " updated".into(),
-            }])
-            .unwrap();
-
         core_tx
-            .send(Event::RemotePatch {
-                uri: uri.clone(),
-                patch,
-            })
+            .send(Event::PeerRequestedSync { peer: 6 })
             .await
             .unwrap();
+        assert!(matches!(
+            net_rx.recv().await,
+            Some(NetworkCommand::SendSyncManifest { peer: 6, .. })
+        ));
 
-        // 4. Verify NO editor update (because it's closed)
-        if let Ok(_) = tokio::time::timeout(Duration::from_millis(50), edit_rx.recv()).await {
-            panic!("Should not send editor command after file is closed");
-        }
+        core_tx.send(Event::Shutdown).await.unwrap();
+    }
+
+    #[test]
+    fn test_core_file_opened_while_syncing_gets_the_hosts_version() {
+        crate::fs::tests::run_in_temp_dir(|| {
+            tokio::runtime::Runtime::new().unwrap().block_on(async {
+                let (core_tx, core_rx) = mpsc::channel(10);
+                let (net_tx, mut net_rx) = mpsc::channel(10);
+                let (edit_tx, mut edit_rx) = mpsc::channel(10);
+                tokio::spawn(Core::new("peer".into(), net_tx, edit_tx).run(core_rx));
+                core_tx.send(Event::EditorReady).await.unwrap();
+
+                // Someone already edited the file in the session
+                let mut host = Workspace::new("host".into());
+                host.get_or_create("notes.md".into(), "edited by bob".into());
+                core_tx
+                    .send(Event::RemoteSyncManifest {
+                        files: host.manifest(),
+                        peer: 1,
+                    })
+                    .await
+                    .unwrap();
+                assert!(matches!(
+                    net_rx.recv().await,
+                    Some(NetworkCommand::SendRequestFiles { .. })
+                ));
+
+                // The editor opens it from an outdated disk copy before the state arrives
+                std::fs::write("notes.md", "old draft").unwrap();
+                core_tx
+                    .send(Event::ClientDidOpen {
+                        uri: "notes.md".into(),
+                        content: "old draft".into(),
+                    })
+                    .await
+                    .unwrap();
+                core_tx
+                    .send(Event::RemoteFullSync {
+                        files: host.get_snapshot(),
+                        peer: 1,
+                    })
+                    .await
+                    .unwrap();
 
-        // 5. Verify Disk Write
-        tokio::time::sleep(Duration::from_millis(100)).await;
-        let content = std::fs::read_to_string(&file_path).expect("File should exist");
-        assert_eq!(content, "initial updated");
+                match edit_rx.recv().await {
+                    Some(EditorCommand::ApplyEdits { uri, edits }) => {
+                        assert_eq!(uri, "notes.md");
+                        assert_eq!(edits.len(), 1);
+                        assert_eq!(edits[0].new_text, "edited by bob");
+                    }
+                    other => panic!("Expected ApplyEdits, got {:?}", other),
+                }
+                core_tx.send(Event::Shutdown).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                assert_eq!(
+                    std::fs::read_to_string("notes.md").unwrap(),
+                    "edited by bob"
+                );
+            });
+        });
+    }
 
-        core_tx.send(Event::Shutdown).await.unwrap();
+    #[test]
+    fn test_core_autosave_writes_changed_files_once() {
+        crate::fs::tests::run_in_temp_dir(|| {
+            tokio::runtime::Runtime::new().unwrap().block_on(async {
+                let (core_tx, core_rx) = mpsc::channel(10);
+                let (net_tx, _net_rx) = mpsc::channel(10);
+                let (edit_tx, _edit_rx) = mpsc::channel(10);
+                tokio::spawn(Core::new("saver".into(), net_tx, edit_tx).run(core_rx));
+
+                core_tx
+                    .send(Event::ClientDidOpen {
+                        uri: "notes.md".into(),
+                        content: "draft".into(),
+                    })
+                    .await
+                    .unwrap();
+                core_tx
+                    .send(Event::LocalChange {
+                        uri: "notes.md".into(),
+                        changes: vec![insert_at(0, 5, "!")],
+                    })
+                    .await
+                    .unwrap();
+                // The timer, ticking by hand
+                let tick = || async {
+                    core_tx.send(Event::AutosaveTick).await.unwrap();
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                };
+
+                tick().await;
+                assert_eq!(std::fs::read_to_string("notes.md").unwrap(), "draft!");
+
+                // Unchanged since, so it isn't written again
+                std::fs::remove_file("notes.md").unwrap();
+                tick().await;
+                assert!(!std::path::Path::new("notes.md").exists());
+
+                core_tx
+                    .send(Event::LocalChange {
+                        uri: "notes.md".into(),
+                        changes: vec![insert_at(0, 6, "?")],
+                    })
+                    .await
+                    .unwrap();
+                tick().await;
+                assert_eq!(std::fs::read_to_string("notes.md").unwrap(), "draft!?");
+
+                core_tx.send(Event::Shutdown).await.unwrap();
+            });
+        });
     }
 
     #[tokio::test]
-    async fn test_core_remote_patch_applies_to_editor() {
+    async fn test_core_unreachable_host_pops_up_once() {
         let (core_tx, core_rx) = mpsc::channel(10);
         let (net_tx, _net_rx) = mpsc::channel(10);
         let (edit_tx, mut edit_rx) = mpsc::channel(10);
 
-        let core = Core::new("test-agent".into(), net_tx, edit_tx);
+        let core = Core::new("peer".into(), net_tx, edit_tx);
         tokio::spawn(async move {
             core.run(core_rx).await;
         });
 
-        let uri = "test.rs".to_string();
-
-        // 1. Generate a valid patch from a "peer"
-        let mut peer_doc = crate::state::Document::new(uri.clone(), "hello".into(), "Peer");
-        let patch = peer_doc
-            .apply_local_changes(vec![TextDocumentContentChangeEvent {
-                range: Some(Range {
-                    start: Position {
-                        line: 0,
-                        character: 5,
-                    },
-                    end: Position {
-                        line: 0,
-                        character: 5,
-                    },
-                }),
-                text: " world".into(),
-            }])
-            .unwrap();
-
-        // 2. Open file locally
-        core_tx
-            .send(Event::ClientDidOpen {
-                uri: uri.clone(),
-                content: "hello".into(),
-            })
-            .await
-            .unwrap();
-
-        // 3. Receive remote patch
-        core_tx
-            .send(Event::RemotePatch {
-                uri: uri.clone(),
-                patch,
-            })
-            .await
-            .unwrap();
-
-        // 4. Verify editor update
-        match tokio::time::timeout(Duration::from_millis(100), edit_rx.recv()).await {
-            Ok(Some(EditorCommand::ApplyEdits {
-                uri: res_uri,
-                edits,
-            })) => {
-                assert_eq!(res_uri, uri);
-                assert!(!edits.is_empty());
-                assert_eq!(edits[0].new_text, " world");
+        for attempts in 1..=3 {
+            core_tx
+                .send(Event::HostUnreachable {
+                    attempts,
+                    retry_in: Duration::from_millis(500),
+                })
+                .await
+                .unwrap();
+        }
+        let mut kinds = Vec::new();
+        for _ in 0..3 {
+            match edit_rx.recv().await {
+                Some(EditorCommand::ShowMessage { kind, message }) => {
+                    assert!(message.contains("retrying in 1s"), "{}", message);
+                    kinds.push(kind);
+                }
+                other => panic!("Expected ShowMessage, got {:?}", other),
             }
-            _ => panic!("Expected ApplyEdits command"),
         }
+        // Only the first failure interrupts the user
+        assert_eq!(
+            kinds,
+            vec![MessageKind::Error, MessageKind::Log, MessageKind::Log]
+        );
 
         core_tx.send(Event::Shutdown).await.unwrap();
     }
 
     #[tokio::test]
-    async fn test_core_remote_patch_closed_file_writes_to_disk() {
-        let temp_dir = tempfile::tempdir().unwrap();
-        let file_path = temp_dir.path().join("closed.txt");
-        // Use absolute path as URI to target temp dir
-        let uri = file_path.to_str().unwrap().to_string();
-
+    async fn test_core_locks_are_broadcast_and_released() {
         let (core_tx, core_rx) = mpsc::channel(10);
-        let (net_tx, _net_rx) = mpsc::channel(10);
+        let (net_tx, mut net_rx) = mpsc::channel(10);
         let (edit_tx, mut edit_rx) = mpsc::channel(10);
 
-        let core = Core::new("test-agent".into(), net_tx, edit_tx);
+        let core = Core::new("alice".into(), net_tx, edit_tx);
         tokio::spawn(async move {
             core.run(core_rx).await;
         });
+        let lines = |from, to| Range {
+            start: Position {
+                line: from,
+                character: 0,
+            },
+            end: Position {
+                line: to,
+                character: 0,
+            },
+        };
 
-        // 1. Generate patch
-        let mut peer_doc = crate::state::Document::new(uri.clone(), "start".into(), "Peer");
-        let patch = peer_doc
-            .apply_local_changes(vec![TextDocumentContentChangeEvent {
-                range: Some(Range {
-                    start: Position {
-                        line: 0,
-                        character: 5,
-                    },
-                    end: Position {
-                        line: 0,
-                        character: 5,
-                    },
-                }),
-                text: " finish".into(),
-            }])
+        // Our own claim goes out, and so does its release
+        core_tx
+            .send(Event::LocalLock {
+                uri: "main.rs".into(),
+                range: Some(lines(2, 5)),
+            })
+            .await
             .unwrap();
+        match net_rx.recv().await {
+            Some(NetworkCommand::BroadcastLock {
+                uri,
+                range,
+                agent_id,
+            }) => {
+                assert_eq!(uri, "main.rs");
+                assert_eq!(agent_id, "alice");
+                assert_eq!(range.end.line, 5);
+            }
+            other => panic!("Expected BroadcastLock, got {:?}", other),
+        }
+        for _ in 0..2 {
+            core_tx
+                .send(Event::LocalLock {
+                    uri: "main.rs".into(),
+                    range: None,
+                })
+                .await
+                .unwrap();
+        }
+        match net_rx.recv().await {
+            Some(NetworkCommand::BroadcastUnlock { uri, agent_id }) => {
+                assert_eq!(uri, "main.rs");
+                assert_eq!(agent_id, "alice");
+            }
+            other => panic!("Expected BroadcastUnlock, got {:?}", other),
+        }
 
-        // 2. Receive remote patch (File NOT open)
+        // Bob's claim reaches the editor, typing inside it warns once
         core_tx
-            .send(Event::RemotePatch {
-                uri: uri.clone(),
-                patch,
+            .send(Event::RemoteLock {
+                uri: "main.rs".into(),
+                agent_id: "bob".into(),
+                range: Some(lines(0, 1)),
+                peer: 4,
             })
             .await
             .unwrap();
-
-        // 3. Verify NO editor update
-        if let Ok(_) = tokio::time::timeout(Duration::from_millis(50), edit_rx.recv()).await {
-            panic!("Should not send editor command for closed file");
+        match edit_rx.recv().await {
+            Some(EditorCommand::RemoteLock {
+                agent_id, range, ..
+            }) => {
+                assert_eq!(agent_id, "bob");
+                assert_eq!(range.unwrap().end.line, 1);
+            }
+            other => panic!("Expected RemoteLock, got {:?}", other),
+        }
+        core_tx
+            .send(Event::ClientDidOpen {
+                uri: "main.rs".into(),
+                content: "fn main() {}\n".into(),
+            })
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            core_tx
+                .send(Event::LocalChange {
+                    uri: "main.rs".into(),
+                    changes: vec![insert_at(0, 0, "x")],
+                })
+                .await
+                .unwrap();
+        }
+        match edit_rx.recv().await {
+            Some(EditorCommand::ShowMessage {
+                kind: MessageKind::Warning,
+                message,
+            }) => assert!(message.contains("lines 1-2"), "{}", message),
+            other => panic!("Expected ShowMessage, got {:?}", other),
         }
 
-        // 4. Verify Disk Write
-        tokio::time::sleep(Duration::from_millis(100)).await;
-        let content = std::fs::read_to_string(&file_path).expect("File should exist");
-        assert_eq!(content, "start finish");
+        // Bob's connection drops: his claim is gone here and for those we relay to
+        core_tx
+            .send(Event::PeerDisconnected { peer_id: 4 })
+            .await
+            .unwrap();
+        match edit_rx.recv().await {
+            Some(EditorCommand::RemoteLock {
+                agent_id, range, ..
+            }) => {
+                assert_eq!(agent_id, "bob");
+                assert!(range.is_none());
+            }
+            other => panic!("Expected the lock to be released, got {:?}", other),
+        }
+        loop {
+            match net_rx.recv().await {
+                Some(NetworkCommand::BroadcastUnlock { agent_id, .. }) => {
+                    assert_eq!(agent_id, "bob");
+                    break;
+                }
+                Some(_) => {}
+                None => panic!("Expected BroadcastUnlock"),
+            }
+        }
 
         core_tx.send(Event::Shutdown).await.unwrap();
     }
 
     #[tokio::test]
+    async fn test_core_lock_expires_without_edits() {
+        let (net_tx, mut net_rx) = mpsc::channel(10);
+        let (edit_tx, mut edit_rx) = mpsc::channel(10);
+        let mut core = Core::new("alice".into(), net_tx, edit_tx);
+
+        let range = Range {
+            start: Position {
+                line: 0,
+                character: 0,
+            },
+            end: Position {
+                line: 3,
+                character: 0,
+            },
+        };
+        core.handle_local_lock("main.rs".into(), Some(range)).await;
+        assert!(matches!(
+            net_rx.try_recv(),
+            Ok(NetworkCommand::BroadcastLock { .. })
+        ));
 
-    async fn test_core_full_sync_logic() {
-        // --- HOST SIDE ---
-
-        let (host_core_tx, host_core_rx) = mpsc::channel(10);
-
-        let (host_net_tx, mut host_net_rx) = mpsc::channel(10);
-
-        let (host_edit_tx, _) = mpsc::channel(10);
-
-        let mut host_core = Core::new("host".into(), host_net_tx, host_edit_tx);
-
-        // Pre-populate host workspace
-
-        host_core
-            .workspace
-            .get_or_create("file:///doc1.txt".into(), "Host Content".into());
-
-        tokio::spawn(async move {
-            host_core.run(host_core_rx).await;
-        });
-
-        // Request Sync
-
-        host_core_tx.send(Event::PeerRequestedSync).await.unwrap();
-
-        // Capture Response
-
-        let sync_files =
-            match tokio::time::timeout(Duration::from_millis(100), host_net_rx.recv()).await {
-                Ok(Some(NetworkCommand::SendFullSyncResponse { files })) => files,
-
-                _ => panic!("Expected SendFullSyncResponse"),
-            };
-
-        assert_eq!(sync_files.len(), 1);
-
-        assert_eq!(sync_files[0].0, "file:///doc1.txt");
-
-        // --- PEER SIDE ---
-
-        let temp_dir = tempfile::tempdir().unwrap();
-
-        let file_path = temp_dir.path().join("doc1.txt");
-
-        // Mock the payload to use our safe temp path
-
-        let safe_uri = file_path.to_str().unwrap().to_string();
+        core.expire_locks(Instant::now() + LOCK_TIMEOUT / 2).await;
+        assert!(net_rx.try_recv().is_err());
 
-        let safe_payload = vec![(safe_uri.clone(), sync_files[0].1.clone())];
+        core.expire_locks(Instant::now() + LOCK_TIMEOUT).await;
+        assert!(matches!(
+            net_rx.try_recv(),
+            Ok(NetworkCommand::BroadcastUnlock { .. })
+        ));
+        assert!(matches!(
+            edit_rx.try_recv(),
+            Ok(EditorCommand::RemoteLock { range: None, .. })
+        ));
+        assert!(core.locks.is_empty());
+    }
 
-        let (peer_core_tx, peer_core_rx) = mpsc::channel(10);
+    #[tokio::test]
+    async fn test_core_oversized_file_stays_out_of_the_session() {
+        let (net_tx, mut net_rx) = mpsc::channel(10);
+        let (edit_tx, mut edit_rx) = mpsc::channel(10);
+        let mut core = Core::new("local".into(), net_tx, edit_tx).with_max_file_len(8);
+
+        let huge = "x".repeat(9);
+        core.handle_client_did_open("bundle.min.js".into(), huge.clone())
+            .await;
+        assert!(!core.workspace.documents.contains_key("bundle.min.js"));
+        match edit_rx.try_recv() {
+            Ok(EditorCommand::ShowMessage {
+                kind: MessageKind::Warning,
+                message,
+            }) => assert!(message.contains("bundle.min.js"), "{}", message),
+            other => panic!("Expected ShowMessage, got {:?}", other),
+        }
 
-        let (peer_net_tx, _) = mpsc::channel(10);
+        // Typing in it goes nowhere, opening it again doesn't warn again
+        core.handle_local_change("bundle.min.js".into(), vec![insert_at(0, 0, "y")])
+            .await;
+        core.flush_local_changes().await;
+        core.handle_client_did_open("bundle.min.js".into(), huge)
+            .await;
+        assert!(core.workspace.documents.is_empty());
+        assert!(net_rx.try_recv().is_err());
+        assert!(edit_rx.try_recv().is_err());
+
+        // Files within the limit are synced as usual
+        core.handle_client_did_open("small.js".into(), "x".into())
+            .await;
+        assert!(core.workspace.documents.contains_key("small.js"));
+    }
 
-        let (peer_edit_tx, _) = mpsc::channel(10);
+    #[tokio::test]
+    async fn test_core_digest_mismatch_refetches_the_file() {
+        let uri = "test.rs".to_string();
 
-        let peer_core = Core::new("peer".into(), peer_net_tx, peer_edit_tx);
+        // The host tells its peers what its copy hashes to
+        let (host_net_tx, mut host_net_rx) = mpsc::channel(10);
+        let (host_edit_tx, _host_edit_rx) = mpsc::channel(10);
+        let mut host = Core::new("host".into(), host_net_tx, host_edit_tx).with_compaction();
+        host.workspace.get_or_create(uri.clone(), "hello".into());
+        host.connected.insert(1);
+        host.send_digests().await;
+        let (sha256, frontier) = match host_net_rx.try_recv() {
+            Ok(NetworkCommand::BroadcastDigest {
+                uri: sent,
+                sha256,
+                frontier,
+            }) => {
+                assert_eq!(sent, uri);
+                (sha256, frontier)
+            }
+            res => panic!("Expected BroadcastDigest, got {:?}", res),
+        };
 
+        // Same history on our side, but what we show drifted away from it
+        let (core_tx, core_rx) = mpsc::channel(10);
+        let (net_tx, mut net_rx) = mpsc::channel(10);
+        let (edit_tx, mut edit_rx) = mpsc::channel(10);
+        let mut core = Core::new("peer".into(), net_tx, edit_tx);
+        core.workspace
+            .get_or_create(uri.clone(), "hello".into())
+            .content = "hallo".into();
         tokio::spawn(async move {
-            peer_core.run(peer_core_rx).await;
+            core.run(core_rx).await;
         });
 
-        // Receive Full Sync
+        core_tx
+            .send(Event::RemoteDigest {
+                uri: uri.clone(),
+                sha256: sha256.clone(),
+                frontier: frontier.clone(),
+                peer: 1,
+            })
+            .await
+            .unwrap();
+        match tokio::time::timeout(Duration::from_secs(1), net_rx.recv()).await {
+            Ok(Some(NetworkCommand::SendRequestFile { peer, uri: asked })) => {
+                assert_eq!(peer, 1);
+                assert_eq!(asked, uri);
+            }
+            res => panic!("Expected SendRequestFile, got {:?}", res),
+        }
 
-        peer_core_tx
+        // The host's state replaces ours and fixes the editor
+        let state = host.workspace.documents[&uri].encode_state();
+        core_tx
             .send(Event::RemoteFullSync {
-                files: safe_payload,
+                files: vec![(uri.clone(), state)],
+                peer: 1,
             })
             .await
             .unwrap();
+        loop {
+            match tokio::time::timeout(Duration::from_secs(1), edit_rx.recv()).await {
+                Ok(Some(EditorCommand::ApplyEdits { edits, .. })) => {
+                    assert_eq!(edits.len(), 1);
+                    assert_eq!(edits[0].new_text, "e");
+                    break;
+                }
+                Ok(Some(_)) => continue,
+                res => panic!("Expected ApplyEdits, got {:?}", res),
+            }
+        }
 
-        // Verify Disk
-
-        tokio::time::sleep(Duration::from_millis(100)).await;
-
-        let content = std::fs::read_to_string(&file_path).expect("Synced file should exist");
-
-        assert_eq!(content, "Host Content");
-
-        host_core_tx.send(Event::Shutdown).await.unwrap();
-
-        peer_core_tx.send(Event::Shutdown).await.unwrap();
+        // Back in line, the next digest changes nothing
+        while net_rx.try_recv().is_ok() {}
+        core_tx
+            .send(Event::RemoteDigest {
+                uri: uri.clone(),
+                sha256,
+                frontier,
+                peer: 1,
+            })
+            .await
+            .unwrap();
+        core_tx.send(Event::Shutdown).await.unwrap();
+        loop {
+            match tokio::time::timeout(Duration::from_secs(1), net_rx.recv()).await {
+                Ok(Some(NetworkCommand::SendRequestFile { .. })) => {
+                    panic!("Re-fetched a file that matches")
+                }
+                Ok(Some(NetworkCommand::Shutdown)) => break,
+                Ok(Some(_)) => continue,
+                res => panic!("Expected Shutdown, got {:?}", res),
+            }
+        }
     }
 
     #[tokio::test]
-
-    async fn test_core_resilience_change_without_open() {
-        // Scenario: The editor sends a didChange for a file we never saw a didOpen for.
-
-        // This happens with some aggressive LSP clients or plugins.
-
+    async fn test_core_change_before_open_keeps_the_real_base() {
         let (core_tx, core_rx) = mpsc::channel(10);
+        let (net_tx, mut net_rx) = mpsc::channel(10);
+        let (edit_tx, _edit_rx) = mpsc::channel(10);
 
-        let (net_tx, _) = mpsc::channel(10);
-
-        let (edit_tx, _) = mpsc::channel(10);
-
-        let core = Core::new("resilient-agent".into(), net_tx, edit_tx);
-
+        let core = Core::new("test-agent".into(), net_tx, edit_tx);
         tokio::spawn(async move {
             core.run(core_rx).await;
         });
 
-        let uri = "ghost_file.rs".to_string();
-
-        let change = TextDocumentContentChangeEvent {
-            range: Some(Range {
-                start: Position {
-                    line: 100,
-                    character: 0,
-                },
-
-                end: Position {
-                    line: 100,
-                    character: 0,
-                },
-            }),
-
-            text: "scary stuff".into(),
+        // Tests run next to the manifest, it's on disk but was never opened.
+        // Applied to an empty document, the edit would make up the whole file.
+        core_tx
+            .send(Event::LocalChange {
+                uri: "Cargo.toml".into(),
+                changes: vec![insert_at(0, 0, "# edited\n")],
+            })
+            .await
+            .unwrap();
+        let patch = loop {
+            match tokio::time::timeout(Duration::from_secs(1), net_rx.recv()).await {
+                Ok(Some(NetworkCommand::BroadcastPatch { uri, patch, .. })) => {
+                    assert_eq!(uri, "Cargo.toml");
+                    break patch;
+                }
+                Ok(Some(_)) => continue,
+                res => panic!("Expected BroadcastPatch, got {:?}", res),
+            }
         };
+        let mut peer_doc = crate::state::Document::new("Cargo.toml".into(), "".into(), "peer");
+        peer_doc.apply_remote_patch(&patch).unwrap();
+        let on_disk = std::fs::read_to_string("Cargo.toml").unwrap();
+        assert_eq!(peer_doc.full_text(), format!("# edited\n{}", on_disk));
 
-        // Send Change WITHOUT Open
-
+        // Nowhere on disk: ask a peer instead of guessing
+        core_tx
+            .send(Event::PeerConnected {
+                peer_id: 1,
+                room: String::new(),
+            })
+            .await
+            .unwrap();
         core_tx
             .send(Event::LocalChange {
-                uri,
-                changes: vec![change],
+                uri: "not/on/disk.rs".into(),
+                changes: vec![insert_at(3, 2, "x")],
             })
             .await
             .unwrap();
-
-        // If we are here and the test hasn't panicked, the Core is still running.
-
-        // Let's send a Shutdown to confirm it processes the queue cleanly.
+        loop {
+            match tokio::time::timeout(Duration::from_secs(1), net_rx.recv()).await {
+                Ok(Some(NetworkCommand::SendRequestFile { peer, uri })) => {
+                    assert_eq!(peer, 1);
+                    assert_eq!(uri, "not/on/disk.rs");
+                    break;
+                }
+                Ok(Some(NetworkCommand::BroadcastPatch { uri, .. })) => {
+                    panic!("Sent a patch for {} without a base", uri)
+                }
+                Ok(Some(_)) => continue,
+                res => panic!("Expected SendRequestFile, got {:?}", res),
+            }
+        }
 
         core_tx.send(Event::Shutdown).await.unwrap();
     }
 
     #[tokio::test]
+    async fn test_core_single_file_resync_leaves_others_alone() {
+        // The host answers a request for one file with just that file
+        let (host_tx, host_rx) = mpsc::channel(10);
+        let (host_net_tx, mut host_net_rx) = mpsc::channel(10);
+        let (host_edit_tx, _host_edit_rx) = mpsc::channel(10);
+        let mut host = Core::new("host".into(), host_net_tx, host_edit_tx);
+        host.workspace
+            .get_or_create("a.rs".into(), "fn a() {}".into());
+        host.workspace
+            .get_or_create("b.rs".into(), "fn b() {}".into());
+        tokio::spawn(async move {
+            host.run(host_rx).await;
+        });
+        host_tx
+            .send(Event::PeerRequestedFile {
+                uri: "a.rs".into(),
+                peer: 1,
+            })
+            .await
+            .unwrap();
+        let files = match tokio::time::timeout(Duration::from_secs(1), host_net_rx.recv()).await {
+            Ok(Some(NetworkCommand::SendFullSyncResponse { peer, files })) => {
+                assert_eq!(peer, 1);
+                files
+            }
+            res => panic!("Expected SendFullSyncResponse, got {:?}", res),
+        };
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].0, "a.rs");
 
-    async fn test_core_resilience_disk_write_failure() {
-        // Scenario: Remote patch received for a closed file, but we can't write to disk (permissions/invalid path).
-
-        // Core should NOT crash; it should log error and continue.
-
+        // Our a.rs drifted, b.rs is fine
         let (core_tx, core_rx) = mpsc::channel(10);
-
-        let (net_tx, _) = mpsc::channel(10);
-
-        let (edit_tx, _) = mpsc::channel(10);
-
-        let core = Core::new("io-agent".into(), net_tx, edit_tx);
-
+        let (net_tx, mut net_rx) = mpsc::channel(10);
+        let (edit_tx, mut edit_rx) = mpsc::channel(10);
+        let mut core = Core::new("peer".into(), net_tx, edit_tx);
+        core.workspace
+            .get_or_create("a.rs".into(), "fn a() {}".into())
+            .content = "fn x() {}".into();
+        core.workspace
+            .get_or_create("b.rs".into(), "fn b() {}".into());
+        let healthy = crate::state::Document::new("a.rs".into(), "fn a() {}".into(), "host");
         tokio::spawn(async move {
             core.run(core_rx).await;
         });
 
-        // Use an invalid path that definitely cannot be written to (e.g., a directory or empty)
-
-        // On Linux, writing to a directory path usually fails.
+        core_tx
+            .send(Event::RemoteDigest {
+                uri: "a.rs".into(),
+                sha256: healthy.digest(),
+                frontier: healthy.frontier().unwrap(),
+                peer: 1,
+            })
+            .await
+            .unwrap();
+        match tokio::time::timeout(Duration::from_secs(1), net_rx.recv()).await {
+            Ok(Some(NetworkCommand::SendRequestFile { uri, .. })) => assert_eq!(uri, "a.rs"),
+            res => panic!("Expected SendRequestFile, got {:?}", res),
+        }
+        core_tx
+            .send(Event::RemoteFullSync { files, peer: 1 })
+            .await
+            .unwrap();
+        match tokio::time::timeout(Duration::from_secs(1), edit_rx.recv()).await {
+            Ok(Some(EditorCommand::ApplyEdits { uri, edits })) => {
+                assert_eq!(uri, "a.rs");
+                assert_eq!(edits[0].new_text, "a");
+            }
+            res => panic!("Expected ApplyEdits, got {:?}", res),
+        }
 
-        let invalid_uri = if cfg!(target_os = "windows") {
-            "C:\\INVALID|<|*".to_string()
-        } else {
-            "/".to_string()
-        };
+        // Nothing else was fetched or changed
+        core_tx.send(Event::Shutdown).await.unwrap();
+        loop {
+            match tokio::time::timeout(Duration::from_secs(1), net_rx.recv()).await {
+                Ok(Some(NetworkCommand::SendRequestFile { uri, .. })) => {
+                    panic!("Fetched {} as well", uri)
+                }
+                Ok(Some(NetworkCommand::Shutdown)) => break,
+                Ok(Some(_)) => continue,
+                res => panic!("Expected Shutdown, got {:?}", res),
+            }
+        }
+        while let Ok(cmd) = edit_rx.try_recv() {
+            if let EditorCommand::ApplyEdits { uri, .. } = cmd {
+                assert_ne!(uri, "b.rs");
+            }
+        }
+    }
 
-        let mut peer_doc = crate::state::Document::new(invalid_uri.clone(), "".into(), "Peer");
+    #[tokio::test]
+    async fn test_core_attributes_remote_changes_to_their_sender() {
+        let (core_tx, core_rx) = mpsc::channel(10);
+        let (net_tx, _net_rx) = mpsc::channel(10);
+        let (edit_tx, mut edit_rx) = mpsc::channel(10);
 
-        let patch = peer_doc
-            .apply_local_changes(vec![TextDocumentContentChangeEvent {
-                range: Some(Range {
-                    start: Position {
-                        line: 0,
-                        character: 0,
-                    },
-                    end: Position {
-                        line: 0,
-                        character: 0,
-                    },
-                }),
+        let core = Core::new("test-agent".into(), net_tx, edit_tx);
+        tokio::spawn(async move {
+            core.run(core_rx).await;
+        });
 
-                text: "fail".into(),
-            }])
+        let uri = "test.rs".to_string();
+        core_tx
+            .send(Event::RemoteHello {
+                agent_id: "bob-uuid".into(),
+                display_name: "bob".into(),
+                peer: 1,
+            })
+            .await
             .unwrap();
-
-        // Send patch
-
         core_tx
-            .send(Event::RemotePatch {
-                uri: invalid_uri,
-                patch,
+            .send(Event::ClientDidOpen {
+                uri: uri.clone(),
+                content: "hello".into(),
             })
             .await
             .unwrap();
 
-        // Give it a moment to try and fail
-
-        tokio::time::sleep(Duration::from_millis(50)).await;
+        // bob types twice, then carol's edit comes relayed over the same connection
+        let mut peer_doc = crate::state::Document::new(uri.clone(), "hello".into(), "Peer");
+        for (text, agent_id) in [("!", "bob-uuid"), ("?", "bob-uuid"), (".", "carol-uuid")] {
+            let patch = peer_doc
+                .apply_local_changes(vec![insert_at(0, 0, text)])
+                .unwrap();
+            core_tx
+                .send(Event::RemotePatch {
+                    uri: uri.clone(),
+                    patch,
+                    peer: 1,
+                    agent_id: agent_id.into(),
+                })
+                .await
+                .unwrap();
+        }
 
-        // Core should still be alive
+        let mut attributions = Vec::new();
+        while let Ok(Some(cmd)) =
+            tokio::time::timeout(Duration::from_millis(200), edit_rx.recv()).await
+        {
+            if let EditorCommand::Attribution {
+                uri: res_uri,
+                agent_id,
+                display_name,
+            } = cmd
+            {
+                assert_eq!(res_uri, uri);
+                attributions.push((agent_id, display_name));
+            }
+        }
+        // Only a change of editor is news
+        assert_eq!(
+            attributions,
+            vec![
+                ("bob-uuid".to_string(), "bob".to_string()),
+                ("carol-uuid".to_string(), "carol-uuid".to_string())
+            ]
+        );
 
         core_tx.send(Event::Shutdown).await.unwrap();
     }
 
-    #[tokio::test]
-
-    async fn test_core_echo_guard_prevents_loop() {
-        // Scenario:
-
-        // 1. Remote Patch Arrives -> Edits sent to Editor.
+    #[test]
+    fn test_core_peers_opening_the_same_new_file_share_one_history() {
+        crate::fs::tests::run_in_temp_dir(|| {
+            tokio::runtime::Runtime::new().unwrap().block_on(async {
+                let uri = "new.md".to_string();
+                let (host_net_tx, mut host_net_rx) = mpsc::channel(10);
+                let (host_edit_tx, _host_edit_rx) = mpsc::channel(10);
+                let mut host = Core::new("host".into(), host_net_tx, host_edit_tx)
+                    .with_compaction()
+                    .with_headless();
+                host.connected.extend([1, 2]);
+
+                // Both peers open the file with their own, different copies
+                let (alice_net_tx, mut alice_net_rx) = mpsc::channel(10);
+                let (alice_edit_tx, _alice_edit_rx) = mpsc::channel(10);
+                let mut alice = Core::new("alice".into(), alice_net_tx, alice_edit_tx);
+                alice.connected.insert(0);
+                alice
+                    .handle_client_did_open(uri.clone(), "alpha".into())
+                    .await;
+
+                let (bob_tx, bob_rx) = mpsc::channel(10);
+                let (bob_net_tx, mut bob_net_rx) = mpsc::channel(10);
+                let (bob_edit_tx, mut bob_edit_rx) = mpsc::channel(10);
+                let mut bob = Core::new("bob".into(), bob_net_tx, bob_edit_tx);
+                bob.connected.insert(0);
+                tokio::spawn(bob.run(bob_rx));
+                bob_tx
+                    .send(Event::ClientDidOpen {
+                        uri: uri.clone(),
+                        content: "beta".into(),
+                    })
+                    .await
+                    .unwrap();
+
+                // Neither starts a history of its own, they ask the host first
+                for net_rx in [&mut alice_net_rx, &mut bob_net_rx] {
+                    match tokio::time::timeout(Duration::from_secs(1), net_rx.recv()).await {
+                        Ok(Some(NetworkCommand::SendRequestFile { peer, uri: asked })) => {
+                            assert_eq!(peer, 0);
+                            assert_eq!(asked, uri);
+                        }
+                        res => panic!("Expected SendRequestFile, got {:?}", res),
+                    }
+                }
 
-        // 2. Editor applies edits and (incorrectly) echoes them back as a LocalChange.
+                // Alice asked first, so she starts it. Bob waits for her.
+                host.handle_file_request(uri.clone(), 1).await;
+                host.handle_file_request(uri.clone(), 2).await;
+                match host_net_rx.try_recv() {
+                    Ok(NetworkCommand::SendFileUnknown { peer, .. }) => assert_eq!(peer, 1),
+                    res => panic!("Expected SendFileUnknown, got {:?}", res),
+                }
+                assert!(host_net_rx.try_recv().is_err());
+
+                alice.handle_file_unknown(uri.clone()).await;
+                let patch = match alice_net_rx.try_recv() {
+                    Ok(NetworkCommand::BroadcastPatch { patch, .. }) => patch,
+                    res => panic!("Expected BroadcastPatch, got {:?}", res),
+                };
+                host.handle_remote_patch(uri.clone(), patch, 1, "alice".into())
+                    .await;
+                let files = loop {
+                    match host_net_rx.try_recv() {
+                        Ok(NetworkCommand::SendFullSyncResponse { peer, files }) => {
+                            assert_eq!(peer, 2);
+                            break files;
+                        }
+                        Ok(_) => continue,
+                        res => panic!("Expected SendFullSyncResponse, got {:?}", res),
+                    }
+                };
 
-        // 3. Core should REJECT this LocalChange to prevent infinite loop.
+                // Bob's editor drops its copy for Alice's, and he acks her history
+                bob_tx
+                    .send(Event::RemoteFullSync { files, peer: 0 })
+                    .await
+                    .unwrap();
+                match tokio::time::timeout(Duration::from_secs(1), bob_edit_rx.recv()).await {
+                    Ok(Some(EditorCommand::ApplyEdits { edits, .. })) => {
+                        assert_eq!(edits[0].new_text, "alpha");
+                    }
+                    res => panic!("Expected ApplyEdits, got {:?}", res),
+                }
+                let frontier = loop {
+                    match tokio::time::timeout(Duration::from_secs(1), bob_net_rx.recv()).await {
+                        Ok(Some(NetworkCommand::SendAck { frontier, .. })) => break frontier,
+                        Ok(Some(_)) => continue,
+                        res => panic!("Expected SendAck, got {:?}", res),
+                    }
+                };
+                assert_eq!(
+                    Some(frontier.clone()),
+                    host.workspace.documents[&uri].frontier()
+                );
+                assert_eq!(Some(frontier), alice.workspace.documents[&uri].frontier());
+                bob_tx.send(Event::Shutdown).await.unwrap();
+            });
+        });
+    }
 
+    #[tokio::test]
+    async fn test_core_multi_cursor_change_is_one_patch() {
         let (core_tx, core_rx) = mpsc::channel(10);
+        let (net_tx, mut net_rx) = mpsc::channel(40);
+        let (edit_tx, _edit_rx) = mpsc::channel(10);
 
-        let (net_tx, mut net_rx) = mpsc::channel(10);
-
-        let (edit_tx, mut edit_rx) = mpsc::channel(10);
-
-        let core = Core::new("echo-agent".into(), net_tx, edit_tx);
-
+        let core = Core::new("test-agent".into(), net_tx, edit_tx);
         tokio::spawn(async move {
             core.run(core_rx).await;
         });
 
-        let uri = "echo.rs".to_string();
-
+        let uri = "multi.rs".to_string();
         core_tx
             .send(Event::ClientDidOpen {
                 uri: uri.clone(),
-                content: "A".into(),
+                content: "\n".repeat(20),
             })
             .await
             .unwrap();
 
-        // 1. Remote Patch
-
-        let mut peer_doc = crate::state::Document::new(uri.clone(), "A".into(), "Peer");
-
-        let patch = peer_doc
-            .apply_local_changes(vec![TextDocumentContentChangeEvent {
+        // One cursor on each line, all typing at once
+        let changes = (0..20)
+            .map(|line| TextDocumentContentChangeEvent {
                 range: Some(Range {
-                    start: Position {
-                        line: 0,
-                        character: 1,
-                    },
-                    end: Position {
-                        line: 0,
-                        character: 1,
-                    },
+                    start: Position { line, character: 0 },
+                    end: Position { line, character: 0 },
                 }),
-
-                text: "B".into(),
-            }])
-            .unwrap();
-
-        core_tx
-            .send(Event::RemotePatch {
-                uri: uri.clone(),
-                patch,
+                text: "x".to_string(),
             })
-            .await
-            .unwrap();
-
-        // Wait for Editor Command (proving remote patch was processed)
-
-        let _ = tokio::time::timeout(Duration::from_millis(100), edit_rx.recv())
-            .await
-            .unwrap();
-
-        // 2. Simulate Echo: The editor reports "AB" (which matches the remote update)
-
-        let echo_change = TextDocumentContentChangeEvent {
-            range: Some(Range {
-                start: Position {
-                    line: 0,
-                    character: 1,
-                },
-                end: Position {
-                    line: 0,
-                    character: 1,
-                },
-            }),
-
-            text: "B".into(),
-        };
-
+            .collect();
         core_tx
             .send(Event::LocalChange {
                 uri: uri.clone(),
-                changes: vec![echo_change],
+                changes,
             })
             .await
             .unwrap();
 
-        // 3. Verify NO Broadcast (Echo Guard worked)
-
-        // If the guard FAILED, we would see a BroadcastPatch here.
-
-        if let Ok(_) = tokio::time::timeout(Duration::from_millis(100), net_rx.recv()).await {
-            panic!("Echo guard failed! Loop detected.");
+        let mut patches = 0;
+        while let Ok(Some(command)) = tokio::time::timeout(COALESCE_WINDOW * 5, net_rx.recv()).await
+        {
+            if let NetworkCommand::BroadcastPatch { uri: sent, .. } = command {
+                assert_eq!(sent, uri);
+                patches += 1;
+            }
         }
+        assert_eq!(patches, 1, "all 20 changes go out in one patch");
 
         core_tx.send(Event::Shutdown).await.unwrap();
     }
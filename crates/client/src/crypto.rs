@@ -1,8 +1,12 @@
 use rcgen::generate_simple_self_signed;
 use ring::digest::{SHA256, digest};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{hmac, pbkdf2};
 use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{WebPkiSupportedAlgorithms, verify_tls12_signature, verify_tls13_signature};
 use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
 use rustls::{DigitallySignedStruct, Error, SignatureScheme};
+use std::num::NonZeroU32;
 use std::sync::Arc;
 
 pub fn generate_cert_and_token() -> (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>, String) {
@@ -24,10 +28,106 @@ pub fn generate_cert_and_token() -> (Vec<CertificateDer<'static>>, PrivateKeyDer
     (cert_chain, PrivateKeyDer::Pkcs8(priv_key), token)
 }
 
+/// Checks that `token` looks like what the host prints (hex SHA256, 64 chars).
+pub fn validate_token(token: &str) -> Result<(), String> {
+    match hex::decode(token) {
+        Ok(bytes) if bytes.len() == 32 => Ok(()),
+        _ => Err(format!(
+            "'{}' is not a valid token, copy the 64 hex characters the host printed",
+            token
+        )),
+    }
+}
+
+/// The iterations are what makes guessing expensive.
+const PASSWORD_ITERATIONS: u32 = 100_000;
+
+/// The password given with `--password`. We accept connections with a key
+/// salted once at startup and tell each peer the salt, when we connect we
+/// derive a key from the salt the host sends.
+pub struct SessionPassword {
+    password: String,
+    own: PasswordKey,
+}
+
+impl SessionPassword {
+    /// Slow on purpose, see `PasswordKey::derive`.
+    pub fn new(password: &str) -> Self {
+        Self {
+            password: password.to_string(),
+            own: PasswordKey::derive(password, &nonce()),
+        }
+    }
+
+    /// The key peers connecting to us prove themselves with.
+    pub fn own(&self) -> &PasswordKey {
+        &self.own
+    }
+
+    /// The key for a host that salted the password with `salt`.
+    pub fn for_salt(&self, salt: &[u8]) -> PasswordKey {
+        PasswordKey::derive(&self.password, salt)
+    }
+}
+
+/// A key derived from the password and a salt. The host and a peer prove to
+/// each other they hold it without sending it (see `network::check_password_of_peer`).
+pub struct PasswordKey {
+    salt: Vec<u8>,
+    key: hmac::Key,
+}
+
+impl PasswordKey {
+    pub fn derive(password: &str, salt: &[u8]) -> Self {
+        let mut secret = [0u8; 32];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            NonZeroU32::new(PASSWORD_ITERATIONS).unwrap(),
+            salt,
+            password.as_bytes(),
+            &mut secret,
+        );
+        Self {
+            salt: salt.to_vec(),
+            key: hmac::Key::new(hmac::HMAC_SHA256, &secret),
+        }
+    }
+
+    pub fn salt(&self) -> &[u8] {
+        &self.salt
+    }
+
+    /// Proof that `role` knows the password, for the other side's `nonce`.
+    /// `session` ties it to one TLS session, so a relay can't pass it on.
+    pub fn prove(&self, role: &[u8], session: &[u8], nonce: &[u8]) -> Vec<u8> {
+        hmac::sign(&self.key, &proof_input(role, session, nonce))
+            .as_ref()
+            .to_vec()
+    }
+
+    pub fn verify(&self, role: &[u8], session: &[u8], nonce: &[u8], proof: &[u8]) -> bool {
+        hmac::verify(&self.key, &proof_input(role, session, nonce), proof).is_ok()
+    }
+}
+
+fn proof_input(role: &[u8], session: &[u8], nonce: &[u8]) -> Vec<u8> {
+    [role, b":", session, nonce].concat()
+}
+
+/// Fresh randomness for a password challenge.
+pub fn nonce() -> [u8; 32] {
+    let mut nonce = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .expect("the OS has no randomness");
+    nonce
+}
+
 /// Own special verifier for the peer
 #[derive(Debug)]
 pub struct TokenVerifier {
     expected_hash: Vec<u8>,
+    algorithms: WebPkiSupportedAlgorithms,
 }
 
 impl TokenVerifier {
@@ -35,10 +135,30 @@ impl TokenVerifier {
         let bytes = hex::decode(token_hex).expect("Invalid token format, expected hash");
         Arc::new(Self {
             expected_hash: bytes,
+            algorithms: signature_algorithms(),
+        })
+    }
+}
+
+/// Accepts whatever certificate the host presents. Only for peers started
+/// without a token: the connection is encrypted, but anyone could be on the other end.
+#[derive(Debug)]
+pub struct InsecureVerifier {
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl InsecureVerifier {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            algorithms: signature_algorithms(),
         })
     }
 }
 
+fn signature_algorithms() -> WebPkiSupportedAlgorithms {
+    rustls::crypto::ring::default_provider().signature_verification_algorithms
+}
+
 impl ServerCertVerifier for TokenVerifier {
     fn verify_server_cert(
         &self,
@@ -60,34 +180,62 @@ impl ServerCertVerifier for TokenVerifier {
         }
     }
 
-    // Following methods are just boilerplate, to work around the signature check
-    // Note: We don't have to signature check, as we trust the hash
+    // The certificate is public, so the host still has to prove it holds the key
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+impl ServerCertVerifier for InsecureVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
     fn verify_tls12_signature(
         &self,
-        _message: &[u8],
-        _cert: &CertificateDer<'_>,
-        _dss: &DigitallySignedStruct,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
     ) -> Result<HandshakeSignatureValid, Error> {
-        Ok(HandshakeSignatureValid::assertion())
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
     }
 
     fn verify_tls13_signature(
         &self,
-        _message: &[u8],
-        _cert: &CertificateDer<'_>,
-        _dss: &DigitallySignedStruct,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
     ) -> Result<HandshakeSignatureValid, Error> {
-        Ok(HandshakeSignatureValid::assertion())
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
     }
 
     fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
-        // Wir akzeptieren alle gängigen Schemata
-        vec![
-            SignatureScheme::RSA_PSS_SHA256,
-            SignatureScheme::RSA_PKCS1_SHA256,
-            SignatureScheme::ECDSA_NISTP256_SHA256,
-            SignatureScheme::ED25519,
-        ]
+        self.algorithms.supported_schemes()
     }
 }
 
@@ -112,6 +260,14 @@ mod tests {
         assert!(result.is_ok(), "Verifier rejected a valid token/cert pair!");
     }
 
+    #[test]
+    fn test_validate_token() {
+        let (_, _, token) = generate_cert_and_token();
+        assert!(validate_token(&token).is_ok());
+        assert!(validate_token("not-hex").is_err());
+        assert!(validate_token("abcd").is_err());
+    }
+
     #[test]
     fn test_token_verification_failure() {
         // 1. Generate a valid cert/token pair
@@ -138,4 +294,32 @@ mod tests {
             err
         );
     }
+
+    #[test]
+    fn test_password_proofs() {
+        let key = PasswordKey::derive("correct horse", b"salt");
+        let proof = key.prove(b"host", b"session", b"nonce");
+        assert!(key.verify(b"host", b"session", b"nonce", &proof));
+
+        // Bound to the role, the session and the password
+        assert!(!key.verify(b"peer", b"session", b"nonce", &proof));
+        assert!(!key.verify(b"host", b"other session", b"nonce", &proof));
+        assert!(
+            !PasswordKey::derive("battery staple", b"salt")
+                .verify(b"host", b"session", b"nonce", &proof)
+        );
+    }
+
+    #[test]
+    fn test_every_session_salts_its_password() {
+        let first = SessionPassword::new("correct horse");
+        let second = SessionPassword::new("correct horse");
+        assert_ne!(first.own().salt(), second.own().salt());
+
+        // A peer told the salt ends up with the host's key
+        let proof = first.own().prove(b"host", b"session", b"nonce");
+        let peer_key = second.for_salt(first.own().salt());
+        assert!(peer_key.verify(b"host", b"session", b"nonce", &proof));
+        assert!(!second.own().verify(b"host", b"session", b"nonce", &proof));
+    }
 }
@@ -1,8 +1,32 @@
+use std::collections::HashMap;
+
+use crate::grapheme;
 use crate::lsp::{Position, Range, TextEdit};
 use dissimilar::Chunk;
 use ropey::Rope;
 
+/// A dirty middle spanning more lines than this is diffed line by line.
+/// A char diff of a reformatted file is thousands of one-char edits.
+pub const LINE_DIFF_THRESHOLD: usize = 16;
+
+/// A dirty middle of up to this many chars is replaced by one edit when the
+/// char diff cuts it into several: a changed word isn't worth five fragments.
+pub const SMALL_MIDDLE: usize = 64;
+
+/// A char diff with more edits than this is replaced by one edit over the
+/// whole dirty middle.
+pub const MAX_FRAGMENTS: usize = 8;
+
+#[cfg(test)]
+thread_local! {
+    /// How often this thread ran `calculate_edits`, for tests of the paths that shouldn't.
+    pub static FULL_DIFFS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
 pub fn calculate_edits(old: &Rope, new: &Rope) -> Vec<TextEdit> {
+    #[cfg(test)]
+    FULL_DIFFS.with(|count| count.set(count.get() + 1));
+
     // Fast pointer comparison or deep comparison if pointers differ.
     if old == new {
         return Vec::new();
@@ -13,11 +37,15 @@ pub fn calculate_edits(old: &Rope, new: &Rope) -> Vec<TextEdit> {
 
     // Prefix Scan (Optimization)
     // Find how many characters at the start are identical.
-    let prefix_len = old
+    let mut prefix_len = old
         .chars()
         .zip(new.chars())
         .take_while(|(a, b)| a == b)
         .count();
+    // A shared first half of an emoji or accented letter isn't shared text
+    while !(grapheme::is_boundary(old, prefix_len) && grapheme::is_boundary(new, prefix_len)) {
+        prefix_len -= 1;
+    }
 
     // Suffix Scan (Optimization)
     // Find how many characters at the end are identical.
@@ -32,8 +60,13 @@ pub fn calculate_edits(old: &Rope, new: &Rope) -> Vec<TextEdit> {
 
     // Calculate the "Dirty Middle" Boundaries
     let start = prefix_len;
-    let old_end = len_old - common_suffix_len;
-    let new_end = len_new - common_suffix_len;
+    let mut old_end = len_old - common_suffix_len;
+    let mut new_end = len_new - common_suffix_len;
+    // Same for the suffix, the ends move into it until neither splits a cluster
+    while !(grapheme::is_boundary(old, old_end) && grapheme::is_boundary(new, new_end)) {
+        old_end += 1;
+        new_end += 1;
+    }
 
     // Fast Path: Pure Insertion or Deletion
     // If the middle of one side is empty, it's a simple insert/delete.
@@ -67,9 +100,40 @@ pub fn calculate_edits(old: &Rope, new: &Rope) -> Vec<TextEdit> {
     // Fallback: The "Dirty Middle" Diff
     // Used for replacements, disjoint edits, or complex changes.
 
+    let spanned_lines = old.char_to_line(old_end) - old.char_to_line(start);
+    if spanned_lines.max(new.char_to_line(new_end) - new.char_to_line(start)) > LINE_DIFF_THRESHOLD
+        && let Some(edits) = diff_lines(old, new, start, old_end, new_end)
+    {
+        return edits;
+    }
+    let edits = diff_chars(old, new, start, old_end, new_end);
+    let middle = (old_end - start).max(new_end - start);
+    if edits.len() > 1 && (middle <= SMALL_MIDDLE || edits.len() > MAX_FRAGMENTS) {
+        let new_middle = new.slice(start..new_end).to_string();
+        return vec![replace_edit(old, (start, old_end, new_middle))];
+    }
+    edits
+}
+
+/// Char-level diff of `old[start..old_end]` against `new[start..new_end]`.
+fn diff_chars(
+    old: &Rope,
+    new: &Rope,
+    start: usize,
+    old_end: usize,
+    new_end: usize,
+) -> Vec<TextEdit> {
     let old_middle = old.slice(start..old_end).to_string();
     let new_middle = new.slice(start..new_end).to_string();
 
+    // Clusters of several chars are compared as a whole, so no edit lands inside one
+    let old_clusters = grapheme::clusters(&old_middle);
+    let new_clusters = grapheme::clusters(&new_middle);
+    if old_clusters.len() != old_end - start || new_clusters.len() != new_end - start {
+        return diff_units(old, start, &old_clusters, &new_clusters)
+            .unwrap_or_else(|| vec![replace_edit(old, (start, old_end, new_middle.clone()))]);
+    }
+
     let chunks = dissimilar::diff(&old_middle, &new_middle);
 
     let mut edits = Vec::new();
@@ -117,19 +181,125 @@ pub fn calculate_edits(old: &Rope, new: &Rope) -> Vec<TextEdit> {
     edits
 }
 
+/// Line-level diff of the dirty middle, widened to whole lines.
+/// `None` if there are too many distinct lines.
+fn diff_lines(
+    old: &Rope,
+    new: &Rope,
+    start: usize,
+    old_end: usize,
+    new_end: usize,
+) -> Option<Vec<TextEdit>> {
+    // The prefix and suffix are equal on both sides, so widening
+    // by the same amount keeps the two regions comparable
+    let start = old.line_to_char(old.char_to_line(start));
+    let to_line_end = old
+        .chars_at(old_end)
+        .position(|c| c == '\n')
+        .map_or(old.len_chars() - old_end, |i| i + 1);
+    let old_text = old.slice(start..old_end + to_line_end).to_string();
+    let new_text = new.slice(start..new_end + to_line_end).to_string();
+    diff_units(old, start, &split_lines(&old_text), &split_lines(&new_text))
+}
+
+/// Diff of two texts (starting at `start` in `old`) cut into units that are
+/// never split, like lines or grapheme clusters. Every unit becomes one char
+/// so the char diff can do the work, changed runs of units come out as one
+/// replacing edit each. `None` if there are too many distinct units.
+fn diff_units(
+    old: &Rope,
+    start: usize,
+    old_units: &[&str],
+    new_units: &[&str],
+) -> Option<Vec<TextEdit>> {
+    let mut ids = HashMap::new();
+    let old_ids = encode_units(old_units, &mut ids)?;
+    let new_ids = encode_units(new_units, &mut ids)?;
+
+    let mut edits: Vec<TextEdit> = Vec::new();
+    let (mut old_unit, mut new_unit) = (0, 0);
+    let mut current_pos = start;
+    // Grows while deleted and inserted runs follow each other
+    let mut pending: Option<(usize, usize, String)> = None;
+
+    for chunk in dissimilar::diff(&old_ids, &new_ids) {
+        let count = chunk_text(&chunk).chars().count();
+        match chunk {
+            Chunk::Equal(_) => {
+                if let Some(edit) = pending.take() {
+                    edits.push(replace_edit(old, edit));
+                }
+                current_pos += old_units[old_unit..old_unit + count]
+                    .iter()
+                    .map(|unit| unit.chars().count())
+                    .sum::<usize>();
+                old_unit += count;
+                new_unit += count;
+            }
+            Chunk::Delete(_) => {
+                let len: usize = old_units[old_unit..old_unit + count]
+                    .iter()
+                    .map(|unit| unit.chars().count())
+                    .sum();
+                let edit = pending.get_or_insert((current_pos, current_pos, String::new()));
+                edit.1 += len;
+                current_pos += len;
+                old_unit += count;
+            }
+            Chunk::Insert(_) => {
+                let edit = pending.get_or_insert((current_pos, current_pos, String::new()));
+                edit.2
+                    .extend(new_units[new_unit..new_unit + count].iter().copied());
+                new_unit += count;
+            }
+        }
+    }
+    if let Some(edit) = pending {
+        edits.push(replace_edit(old, edit));
+    }
+    Some(edits)
+}
+
+/// One char per unit, the same unit always gets the same char.
+/// Counts up from the private use area, so we run out only past ~1M distinct units.
+fn encode_units<'a>(units: &[&'a str], ids: &mut HashMap<&'a str, char>) -> Option<String> {
+    units
+        .iter()
+        .map(|unit| {
+            let next = char::from_u32(0xE000 + ids.len() as u32)?;
+            Some(*ids.entry(unit).or_insert(next))
+        })
+        .collect()
+}
+
+/// Lines including their `\n`, so joining them gives back the text.
+fn split_lines(text: &str) -> Vec<&str> {
+    text.split_inclusive('\n').collect()
+}
+
+fn chunk_text<'a>(chunk: &Chunk<'a>) -> &'a str {
+    match chunk {
+        Chunk::Equal(text) | Chunk::Delete(text) | Chunk::Insert(text) => text,
+    }
+}
+
+fn replace_edit(old: &Rope, (start, end, new_text): (usize, usize, String)) -> TextEdit {
+    TextEdit {
+        range: Range {
+            start: offset_to_position(old, start),
+            end: offset_to_position(old, end),
+        },
+        new_text,
+    }
+}
+
 fn offset_to_position(rope: &Rope, char_idx: usize) -> Position {
     // Ropey handles this log(N)
-    let line_idx = rope.char_to_line(char_idx);
-    let line_start_char = rope.line_to_char(line_idx);
-    let col = char_idx - line_start_char;
-    Position {
-        line: line_idx,
-        character: col,
-    }
+    crate::lsp::char_to_position(rope, char_idx)
 }
 
 #[cfg(test)]
-mod tests {
+pub mod tests {
     use super::*;
     use proptest::prelude::*;
 
@@ -143,7 +313,7 @@ mod tests {
     }
 
     // Helper to apply edits to a string
-    fn apply_edits_to_string(original: &str, edits: &[TextEdit]) -> String {
+    pub fn apply_edits_to_string(original: &str, edits: &[TextEdit]) -> String {
         // apply edits from bottom to top so indices don't shift!
         let mut sorted_edits = edits.to_vec();
         sorted_edits.sort_by_key(|e| std::cmp::Reverse(e.range.start.line));
@@ -169,8 +339,7 @@ mod tests {
     }
 
     fn position_to_offset(rope: &Rope, pos: &Position) -> usize {
-        let line_char = rope.line_to_char(pos.line);
-        line_char + pos.character
+        crate::lsp::utf16_to_char_offset(rope, pos.line, pos.character)
     }
 
     proptest! {
@@ -200,6 +369,80 @@ mod tests {
         }
     }
 
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(300))]
+
+        // Long multi-line texts out of a few distinct lines, so the line diff kicks in
+        #[test]
+        fn test_line_diff_correctness_invariant(
+            old_lines in proptest::collection::vec("(fn a\\(\\) \\{\\}|    x|let ü = 1;|)", 0..60),
+            new_lines in proptest::collection::vec("(fn a\\(\\) \\{\\}|    x|let ü = 1;|\\}|)", 0..60),
+            trailing_newline in proptest::bool::ANY,
+        ) {
+            let mut old_text = old_lines.join("\n");
+            let mut new_text = new_lines.join("\n");
+            if trailing_newline {
+                old_text.push('\n');
+                new_text.push('\n');
+            }
+
+            let edits = calculate_edits(&Rope::from_str(&old_text), &Rope::from_str(&new_text));
+            let reconstructed = apply_edits_to_string(&old_text, &edits);
+            prop_assert_eq!(&reconstructed, &new_text, "\nEdits: {:?}\n", edits);
+        }
+    }
+
+    #[test]
+    fn test_reformat_produces_line_edits() {
+        // A formatter touching every line of a 500 line file
+        let old_text: String = (0..500)
+            .map(|i| format!("fn f{}(a:u32,b:u32)->u32{{a+b}}\n", i))
+            .collect();
+        let new_text: String = (0..500)
+            .map(|i| format!("fn f{}(a: u32, b: u32) -> u32 {{ a + b }}\n", i))
+            .collect();
+        let old = Rope::from_str(&old_text);
+        let new = Rope::from_str(&new_text);
+
+        let by_char = diff_chars(&old, &new, 0, old.len_chars(), new.len_chars());
+        let edits = calculate_edits(&old, &new);
+
+        assert!(edits.len() * 10 <= by_char.len());
+        assert_eq!(apply_edits_to_string(&old_text, &edits), new_text);
+    }
+
+    #[test]
+    fn test_word_replacement_is_one_edit() {
+        let old = Rope::from_str("fn main() {\n    println!(\"hello world\");\n}\n");
+        let new = Rope::from_str("fn main() {\n    println!(\"hello there\");\n}\n");
+        let start = "fn main() {\n    println!(\"hello ".chars().count();
+        let fragments = diff_chars(&old, &new, start, start + 5, start + 5);
+        assert!(fragments.len() > 1, "{:?}", fragments);
+
+        let edits = calculate_edits(&old, &new);
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits[0].range.start, pos!(1, 20));
+        assert_eq!(edits[0].range.end, pos!(1, 25));
+        assert_eq!(edits[0].new_text, "there");
+
+        // Scattered changes in a long line are still fragments
+        let old_text = format!("{}a{}b{}", "x".repeat(40), "y".repeat(80), "z".repeat(40));
+        let new_text = format!("{}A{}B{}", "x".repeat(40), "y".repeat(80), "z".repeat(40));
+        let edits = calculate_edits(&Rope::from_str(&old_text), &Rope::from_str(&new_text));
+        assert!(edits.len() > 1);
+        assert!(edits.len() <= MAX_FRAGMENTS);
+        assert_eq!(apply_edits_to_string(&old_text, &edits), new_text);
+    }
+
+    #[test]
+    fn test_small_replacement_stays_char_level() {
+        let old = Rope::from_str("let a = 1;\nlet b = 2;\n");
+        let new = Rope::from_str("let a = 10;\nlet b = 2;\n");
+        let edits = calculate_edits(&old, &new);
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits[0].new_text, "0");
+    }
+
     #[test]
     fn test_offset_to_position_mapping() {
         // Arrange
@@ -257,11 +500,65 @@ mod tests {
                 prop_assert!(pos.line < rope.len_lines(), "Line index out of bounds");
 
                 // Check the reverse math (Roundtrip)
-                let line_start = rope.line_to_char(pos.line);
-                let calculated_offset = line_start + pos.character;
+                let calculated_offset = position_to_offset(&rope, &pos);
 
                 prop_assert_eq!(calculated_offset, offset, "Roundtrip failed!");
             }
         }
     }
+
+    #[test]
+    fn test_edits_never_split_a_grapheme_cluster() {
+        let family = "👨\u{200D}👩\u{200D}👧\u{200D}👦";
+        let cases = [
+            // Dropping the last member, a char diff would cut after "👧"
+            (
+                format!("a{}b", family),
+                "a👨\u{200D}👩\u{200D}👧b".to_string(),
+            ),
+            // Text right before, inside and after the sequence
+            (format!("a{}b", family), format!("ax{}b", family)),
+            (family.to_string(), format!("{}!", family)),
+            (
+                format!("x {} y", family),
+                format!("x {}{} y", family, family),
+            ),
+            // A skin tone, a flag and a combining accent added to existing chars
+            ("ok 👍 ok".to_string(), "ok 👍🏽 ok".to_string()),
+            ("🇩🇪🇫🇷".to_string(), "🇩🇪🇮🇹".to_string()),
+            ("cafe".to_string(), "cafe\u{301}".to_string()),
+            (
+                format!("a {} b c", family),
+                format!("a {}\u{200D}👦 b d", family),
+            ),
+        ];
+
+        for (old_text, new_text) in cases {
+            let old = Rope::from_str(&old_text);
+            let new = Rope::from_str(&new_text);
+            let edits = calculate_edits(&old, &new);
+            assert_eq!(apply_edits_to_string(&old_text, &edits), new_text);
+
+            // Edits come in order, so where one lands in the new text is its
+            // old position shifted by what the earlier ones added
+            let mut shift = 0isize;
+            for edit in &edits {
+                let start = position_to_offset(&old, &edit.range.start);
+                let end = position_to_offset(&old, &edit.range.end);
+                let new_start = start.checked_add_signed(shift).unwrap();
+                let new_end = new_start + edit.new_text.chars().count();
+                shift += (new_end - new_start) as isize - (end - start) as isize;
+
+                let split = !grapheme::is_boundary(&old, start)
+                    || !grapheme::is_boundary(&old, end)
+                    || !grapheme::is_boundary(&new, new_start)
+                    || !grapheme::is_boundary(&new, new_end);
+                assert!(
+                    !split,
+                    "{:?} -> {:?}: {:?} splits a cluster",
+                    old_text, new_text, edit
+                );
+            }
+        }
+    }
 }
@@ -0,0 +1,792 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+use crate::core::{self, Core, Event};
+use crate::crypto;
+use crate::handler::EditorCommand;
+use crate::logger;
+use crate::lsp::{TextDocumentContentChangeEvent, TextEdit};
+use crate::network::{
+    self, Limits, NetworkCommand, NetworkConfig, NetworkError, Pairing, RoomId, Timeouts,
+};
+use crate::state::SyncMode;
+use crate::transport::InMemoryTransport;
+
+/// The port hosts listen on and peers connect to unless told otherwise.
+pub const DEFAULT_PORT: u16 = 4444;
+
+/// How long `Engine::shutdown` waits for the network to say goodbye to the peers.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(3);
+
+/// Entry point for running JustSync inside another program:
+///
+/// ```no_run
+/// # async fn demo() -> Result<(), String> {
+/// let engine = justsync::JustSync::builder()
+///     .host(4444)
+///     .on_edit(|edit| println!("{} changed", edit.uri))
+///     .start()
+///     .await?;
+/// engine.open_document("notes.md", "hello").await;
+/// engine.shutdown().await;
+/// # Ok(())
+/// # }
+/// ```
+pub struct JustSync;
+
+impl JustSync {
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+}
+
+/// An update from a peer, as edits against the local copy of `uri`.
+#[derive(Debug, Clone)]
+pub struct RemoteEdit {
+    pub uri: String,
+    pub edits: Vec<TextEdit>,
+}
+
+type EditCallback = Box<dyn Fn(RemoteEdit) + Send + 'static>;
+type ErrorCallback = Box<dyn FnOnce(NetworkError) + Send + 'static>;
+
+enum Role {
+    Host,
+    /// Without an address the peer waits for `Event::JoinSession`.
+    Peer {
+        remote_ip: Option<String>,
+    },
+    /// Another daemon on this machine, met on a local socket named after `session`.
+    Pair {
+        session: String,
+    },
+    /// One end of an `InMemoryTransport::pair`, the listening end hosts.
+    InMemory {
+        transport: std::sync::Arc<InMemoryTransport>,
+    },
+}
+
+/// Settings for an `Engine`, see `JustSync::builder`.
+/// Paths are relative to the working directory, like everything the engine writes.
+pub struct Builder {
+    role: Role,
+    port: u16,
+    token: Option<String>,
+    password: Option<String>,
+    room: RoomId,
+    sync_mode: SyncMode,
+    name: Option<String>,
+    editor_buffer: Option<usize>,
+    max_file_len: usize,
+    limits: Limits,
+    timeouts: Timeouts,
+    max_message_len: usize,
+    state_dir: Option<PathBuf>,
+    agent_id: Option<String>,
+    read_only: Vec<String>,
+    ignore: Vec<String>,
+    share: Vec<String>,
+    seed_from_disk: bool,
+    watch: bool,
+    autosave: Option<Duration>,
+    headless: bool,
+    observer: bool,
+    control_socket: bool,
+    on_edit: Option<EditCallback>,
+    on_error: Option<ErrorCallback>,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self {
+            role: Role::Peer { remote_ip: None },
+            port: DEFAULT_PORT,
+            token: None,
+            password: None,
+            room: RoomId::new(),
+            sync_mode: SyncMode::Crdt,
+            name: None,
+            editor_buffer: None,
+            max_file_len: core::DEFAULT_MAX_FILE_LEN,
+            limits: Limits::default(),
+            timeouts: Timeouts::DEFAULT,
+            max_message_len: crate::lsp::DEFAULT_MAX_MESSAGE_LEN,
+            state_dir: None,
+            agent_id: None,
+            read_only: Vec::new(),
+            ignore: Vec::new(),
+            share: Vec::new(),
+            seed_from_disk: false,
+            watch: false,
+            autosave: None,
+            headless: false,
+            observer: false,
+            control_socket: false,
+            on_edit: None,
+            on_error: None,
+        }
+    }
+}
+
+impl Builder {
+    /// Hosts a session on `port`. The token peers need is `Engine::token`.
+    pub fn host(mut self, port: u16) -> Self {
+        self.role = Role::Host;
+        self.port = port;
+        self
+    }
+
+    /// Joins the session at `remote_ip` (`port` is used if it has none), or
+    /// waits for `Event::JoinSession` when there is no address yet.
+    pub fn peer(mut self, remote_ip: Option<String>, port: u16) -> Self {
+        self.role = Role::Peer { remote_ip };
+        self.port = port;
+        self
+    }
+
+    /// Syncs with the other daemon started with the same `session` on this
+    /// machine, over a local socket instead of the network. The first one
+    /// hosts, the second joins it.
+    pub fn pair(mut self, session: impl Into<String>) -> Self {
+        self.role = Role::Pair {
+            session: session.into(),
+        };
+        self
+    }
+
+    /// Syncs over one end of `InMemoryTransport::pair` instead of the network,
+    /// for tests. The listening end hosts.
+    pub fn in_memory(mut self, transport: InMemoryTransport) -> Self {
+        self.role = Role::InMemory {
+            transport: std::sync::Arc::new(transport),
+        };
+        self
+    }
+
+    /// The host's token (peer only, without it the host is not verified).
+    pub fn token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// A password both sides give instead of (or on top of) the token.
+    pub fn password(mut self, password: impl Into<String>) -> Self {
+        self.password = Some(password.into());
+        self
+    }
+
+    /// The room to join on a relay (peer only), each room is its own session.
+    pub fn room(mut self, room: impl Into<RoomId>) -> Self {
+        self.room = room.into();
+        self
+    }
+
+    pub fn sync_mode(mut self, mode: SyncMode) -> Self {
+        self.sync_mode = mode;
+        self
+    }
+
+    /// The name the others see next to our cursor, defaults to the agent id.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Holds back up to `limit` remote updates until the editor sends
+    /// `Event::EditorReady`.
+    pub fn editor_buffer(mut self, limit: usize) -> Self {
+        self.editor_buffer = Some(limit);
+        self
+    }
+
+    /// Leaves files over `bytes` out of the session.
+    pub fn max_file_len(mut self, bytes: usize) -> Self {
+        self.max_file_len = bytes;
+        self
+    }
+
+    /// Refuses patches over `bytes` and asks for the whole file instead.
+    pub fn max_patch_len(mut self, bytes: usize) -> Self {
+        self.limits.max_patch_len = bytes;
+        self
+    }
+
+    /// Refuses full syncs of a file over `bytes`, `network::MIN_MAX_BULK_LEN` at the least.
+    pub fn max_bulk_len(mut self, bytes: usize) -> Self {
+        self.limits.max_bulk_len = bytes.max(network::MIN_MAX_BULK_LEN);
+        self
+    }
+
+    /// How often QUIC connections are pinged and how long they may stay silent.
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Editor messages over `bytes` end the session, see `Engine::max_message_len`.
+    pub fn max_message_len(mut self, bytes: usize) -> Self {
+        self.max_message_len = bytes;
+        self
+    }
+
+    /// The CRDT agent id to edit as, instead of the one saved in the state dir.
+    pub fn agent_id(mut self, id: impl Into<String>) -> Self {
+        self.agent_id = Some(id.into());
+        self
+    }
+
+    /// Restores the session saved below `root` and saves it there on shutdown.
+    pub fn state_dir(mut self, root: impl Into<PathBuf>) -> Self {
+        self.state_dir = Some(root.into());
+        self
+    }
+
+    /// Files matching these patterns (gitignore syntax) are read-only for guests (host only).
+    pub fn read_only(mut self, globs: Vec<String>) -> Self {
+        self.read_only = globs;
+        self
+    }
+
+    /// Keeps files matching these patterns (gitignore syntax) out of the session,
+    /// on top of `.gitignore` and `.justsyncignore`.
+    pub fn ignore(mut self, globs: Vec<String>) -> Self {
+        self.ignore = globs;
+        self
+    }
+
+    /// Syncs only files at or under these paths (relative to the project root),
+    /// the whole project without any. The ignore rules still apply within them.
+    pub fn share(mut self, paths: Vec<String>) -> Self {
+        self.share = paths;
+        self
+    }
+
+    /// Shares every file of the project, not only the opened ones (host only).
+    pub fn seed_from_disk(mut self, enabled: bool) -> Self {
+        self.seed_from_disk = enabled;
+        self
+    }
+
+    /// Also syncs changes made to the files outside the editor.
+    pub fn watch(mut self, enabled: bool) -> Self {
+        self.watch = enabled;
+        self
+    }
+
+    /// Writes the documents to disk every `every`, whether or not anyone saves.
+    pub fn autosave(mut self, every: Duration) -> Self {
+        self.autosave = Some(every);
+        self
+    }
+
+    /// Relays without an editor, documents are only kept in memory (host only).
+    pub fn headless(mut self, enabled: bool) -> Self {
+        self.headless = enabled;
+        self
+    }
+
+    /// Follows the session read-only (peer only).
+    pub fn observer(mut self, enabled: bool) -> Self {
+        self.observer = enabled;
+        self
+    }
+
+    /// Answers `justsync status` from the working directory.
+    pub fn control_socket(mut self) -> Self {
+        self.control_socket = true;
+        self
+    }
+
+    /// Called for every update a peer makes to an open document. Apply the
+    /// edits and report the result through `Engine::apply_local_change` like
+    /// any other change, the engine recognizes its own edits and doesn't send
+    /// them back. Setting this consumes what `subscribe_remote_edits` returns.
+    pub fn on_edit(mut self, callback: impl Fn(RemoteEdit) + Send + 'static) -> Self {
+        self.on_edit = Some(Box::new(callback));
+        self
+    }
+
+    /// Called once if the network can't run at all (port taken, TLS broken, ...).
+    /// Without it the error is only logged.
+    pub fn on_error(mut self, callback: impl FnOnce(NetworkError) + Send + 'static) -> Self {
+        self.on_error = Some(Box::new(callback));
+        self
+    }
+
+    /// Spawns the core and the network on the current tokio runtime.
+    pub async fn start(self) -> Result<Engine, String> {
+        // Fails when the embedder already picked one, which is fine too
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let pair = match &self.role {
+            Role::Pair { session } => {
+                Some(Pairing::Socket(crate::transport::pair_socket_path(session)))
+            }
+            Role::InMemory { transport } => Some(Pairing::Transport(transport.clone())),
+            _ => None,
+        };
+        let is_host = match (&self.role, &pair) {
+            (Role::InMemory { transport }, _) => transport.is_listener(),
+            (_, Some(Pairing::Socket(path))) => {
+                !crate::transport::LocalTransport::reachable(path).await
+            }
+            _ => matches!(self.role, Role::Host),
+        };
+
+        // Host - generate everything from scratch. Peer - just take the token
+        let (server_cert, server_key, token, active_token) = if pair.is_some() {
+            // Nothing to verify, the socket is all there is
+            (None, None, None, None)
+        } else if is_host {
+            let (cert, key, token) = crypto::generate_cert_and_token();
+            (Some(cert), Some(key), Some(token), None)
+        } else {
+            if let Some(token) = &self.token {
+                crypto::validate_token(token)?;
+            }
+            (None, None, None, self.token)
+        };
+        // Slow on purpose, so do it once before anyone connects
+        let password = self.password.as_deref().map(crypto::SessionPassword::new);
+
+        // Core Inbox
+        let (core_tx, core_rx) = mpsc::channel::<Event>(100);
+        // Network Outbox
+        let (net_out_tx, net_out_rx) = mpsc::channel::<NetworkCommand>(100);
+        // Editor Outbox
+        let (editor_out_tx, editor_out_rx) = mpsc::channel(100);
+
+        let control = self.control_socket.then(|| {
+            tokio::spawn(crate::control::serve(
+                crate::control::socket_path(Path::new(".")),
+                core_tx.clone(),
+                net_out_tx.clone(),
+            ))
+        });
+
+        // A saved session keeps its agent, so its restored history isn't split in two
+        let agent_id = match (self.agent_id, &self.state_dir) {
+            (Some(id), _) => id,
+            (None, Some(root)) if !self.headless && !self.observer => {
+                crate::state::saved_agent_id(root)
+            }
+            (None, _) => Uuid::new_v4().to_string(),
+        };
+        let mut core = Core::new(agent_id, net_out_tx, editor_out_tx)
+            .with_sync_mode(self.sync_mode)
+            .with_max_file_len(self.max_file_len);
+        if let Some(name) = self.name {
+            core = core.with_display_name(name);
+        }
+        if self.headless {
+            // A relay starts empty and learns every document from the peers
+            core = core.with_headless();
+        } else if let Some(limit) = self.editor_buffer {
+            core = core.with_editor_buffer(limit);
+        }
+        if self.observer {
+            // Nothing gets persisted, so there is no state to keep either
+            core = core.with_observer();
+        } else if !self.headless
+            && let Some(root) = self.state_dir
+        {
+            core = core.with_state_dir(root);
+        }
+        if is_host {
+            let read_only = crate::fs::matching_files(Path::new("."), &self.read_only);
+            if !read_only.is_empty() {
+                logger::log(&format!(
+                    ">> [Host] {} file(s) are read-only for guests",
+                    read_only.len()
+                ));
+            }
+            core = core.with_compaction().with_read_only(read_only);
+        }
+        let sync_ignore = crate::fs::sync_ignore_rules(Path::new("."), &self.ignore);
+        let share = crate::fs::share_paths(&self.share);
+        let core = core
+            .with_sync_ignore(sync_ignore.clone())
+            .with_share(share.clone());
+
+        // Host: Share the project on disk, not just what the user opens
+        if is_host && self.seed_from_disk {
+            logger::log(">> [Host] Scanning workspace files...");
+            let scan = crate::fs::scan_project(".", &sync_ignore, &share);
+            if scan.skipped > 0 {
+                logger::warn(&format!(
+                    "!! [Host] {} binary or non-UTF-8 file(s) will not be synced",
+                    scan.skipped
+                ));
+            }
+            for (uri, content) in scan.files {
+                let _ = core_tx.send(Event::LoadFromDisk { uri, content }).await;
+            }
+        }
+
+        if self.watch {
+            tokio::spawn(crate::watcher::run(
+                ".".into(),
+                sync_ignore,
+                share,
+                core_tx.clone(),
+            ));
+        }
+        if let Some(every) = self.autosave {
+            tokio::spawn(autosave(core_tx.clone(), every));
+        }
+
+        tokio::spawn(core.run(core_rx));
+
+        let mode = if is_host { "host" } else { "peer" };
+        let remote_ip = match self.role {
+            Role::Peer { remote_ip } => remote_ip,
+            Role::Host | Role::Pair { .. } | Role::InMemory { .. } => None,
+        };
+        let config = NetworkConfig {
+            mode: mode.to_string(),
+            remote_ip,
+            port: self.port,
+            token: active_token,
+            password,
+            room: self.room,
+            server_certs: server_cert,
+            server_key,
+            pair,
+            limits: self.limits,
+            timeouts: self.timeouts,
+        };
+        let net_core_tx = core_tx.clone();
+        let on_error = self.on_error;
+        let network = tokio::spawn(async move {
+            let result = crate::network::run(
+                config,
+                net_core_tx, // Send to Core
+                net_out_rx,  // Receive from Core
+            )
+            .await;
+            if let Err(e) = result {
+                match on_error {
+                    Some(callback) => callback(e),
+                    None => logger::log_at(logger::Level::Error, &format!("!! [Net] {}", e)),
+                }
+            }
+        });
+
+        let editor_rx = if self.headless {
+            // Nobody edits here
+            drop(editor_out_rx);
+            None
+        } else if let Some(on_edit) = self.on_edit {
+            tokio::spawn(forward_edits(editor_out_rx, on_edit));
+            None
+        } else {
+            Some(editor_out_rx)
+        };
+
+        Ok(Engine {
+            core_tx,
+            editor_rx,
+            network,
+            control,
+            token,
+            max_message_len: self.max_message_len,
+        })
+    }
+}
+
+/// A host and a peer syncing over an `InMemoryTransport`, for tests that need
+/// two engines but no network. Like any engine they write synced files to the
+/// working directory.
+pub async fn spawn_paired_engines() -> Result<(Engine, Engine), String> {
+    let (listener, dialer) = InMemoryTransport::pair();
+    let host = JustSync::builder().in_memory(listener).start().await?;
+    let peer = JustSync::builder().in_memory(dialer).start().await?;
+    Ok((host, peer))
+}
+
+/// Hands `ApplyEdits` to the embedder, the rest is meant for an LSP editor.
+async fn forward_edits(mut rx: mpsc::Receiver<EditorCommand>, on_edit: EditCallback) {
+    while let Some(cmd) = rx.recv().await {
+        if let EditorCommand::ApplyEdits { uri, edits } = cmd {
+            on_edit(RemoteEdit { uri, edits });
+        }
+    }
+}
+
+/// Asks the core for an autosave every `every`, until it is gone.
+async fn autosave(core_tx: mpsc::Sender<Event>, every: Duration) {
+    let mut timer = tokio::time::interval_at(tokio::time::Instant::now() + every, every);
+    timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        timer.tick().await;
+        if core_tx.send(Event::AutosaveTick).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// A running engine, from `Builder::start`.
+pub struct Engine {
+    core_tx: mpsc::Sender<Event>,
+    editor_rx: Option<mpsc::Receiver<EditorCommand>>,
+    network: JoinHandle<()>,
+    control: Option<JoinHandle<()>>,
+    token: Option<String>,
+    max_message_len: usize,
+}
+
+impl Engine {
+    /// The token peers need to verify this host, `None` on a peer.
+    pub fn token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+
+    /// The largest editor message to take, for `handler::run`.
+    pub fn max_message_len(&self) -> usize {
+        self.max_message_len
+    }
+
+    /// The core's inbox, for feeding it events the methods below don't cover.
+    pub fn events(&self) -> mpsc::Sender<Event> {
+        self.core_tx.clone()
+    }
+
+    /// Starts syncing `uri` with `content` as the local copy.
+    pub async fn open_document(&self, uri: impl Into<String>, content: impl Into<String>) {
+        let _ = self
+            .core_tx
+            .send(Event::ClientDidOpen {
+                uri: uri.into(),
+                content: content.into(),
+            })
+            .await;
+    }
+
+    /// Reports a change to an open document, with the same ranges as an LSP `didChange`.
+    pub async fn apply_local_change(
+        &self,
+        uri: impl Into<String>,
+        changes: Vec<TextDocumentContentChangeEvent>,
+    ) {
+        let _ = self
+            .core_tx
+            .send(Event::LocalChange {
+                uri: uri.into(),
+                changes,
+            })
+            .await;
+    }
+
+    /// Everything the engine wants shown in the editor: remote edits, but also
+    /// cursors and messages. There is only one stream, so this is `None` after
+    /// the first call, with `on_edit` and on a headless host.
+    pub fn subscribe_remote_edits(&mut self) -> Option<mpsc::Receiver<EditorCommand>> {
+        self.editor_rx.take()
+    }
+
+    /// Saves the session, tells the peers we are leaving and waits (briefly)
+    /// for the network to finish.
+    pub async fn shutdown(self) {
+        if let Some(control) = self.control {
+            control.abort();
+            let _ = control.await;
+        }
+        let _ = self.core_tx.send(Event::Shutdown).await;
+        let _ = tokio::time::timeout(SHUTDOWN_GRACE, self.network).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsp::{Position, Range};
+
+    /// A port nothing listens on right now, over UDP (QUIC) and TCP.
+    fn free_port() -> u16 {
+        loop {
+            let udp = std::net::UdpSocket::bind("0.0.0.0:0").unwrap();
+            let port = udp.local_addr().unwrap().port();
+            if std::net::TcpListener::bind(("0.0.0.0", port)).is_ok() {
+                return port;
+            }
+        }
+    }
+
+    #[test]
+    fn test_engine_syncs_without_an_editor() {
+        crate::fs::tests::run_in_temp_dir(|| {
+            tokio::runtime::Runtime::new().unwrap().block_on(async {
+                let port = free_port();
+                let host = JustSync::builder().host(port).start().await.unwrap();
+                assert!(host.token().is_some());
+                host.open_document("notes.txt", "hello").await;
+
+                let (edit_tx, mut edit_rx) = mpsc::unbounded_channel();
+                let peer = JustSync::builder()
+                    .peer(Some(format!("127.0.0.1:{}", port)), port)
+                    .token(host.token().unwrap())
+                    .on_edit(move |edit| {
+                        let _ = edit_tx.send(edit);
+                    })
+                    .start()
+                    .await
+                    .unwrap();
+
+                // The initial sync writes the file, then we open it like an editor would
+                let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+                while std::fs::read_to_string("notes.txt").ok().as_deref() != Some("hello") {
+                    assert!(tokio::time::Instant::now() < deadline, "Sync never arrived");
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+                peer.open_document("notes.txt", "hello").await;
+
+                let end = Position {
+                    line: 0,
+                    character: 5,
+                };
+                host.apply_local_change(
+                    "notes.txt",
+                    vec![TextDocumentContentChangeEvent {
+                        range: Some(Range {
+                            start: end.clone(),
+                            end,
+                        }),
+                        text: " world".into(),
+                    }],
+                )
+                .await;
+
+                let edit = tokio::time::timeout(Duration::from_secs(5), edit_rx.recv())
+                    .await
+                    .expect("The peer never saw the edit")
+                    .unwrap();
+                assert_eq!(edit.uri, "notes.txt");
+                let texts: Vec<&str> = edit.edits.iter().map(|e| e.new_text.as_str()).collect();
+                assert_eq!(texts, vec![" world"]);
+
+                peer.shutdown().await;
+                host.shutdown().await;
+            });
+        });
+    }
+
+    #[tokio::test]
+    async fn test_engine_rejects_a_bad_token() {
+        let result = JustSync::builder()
+            .peer(None, DEFAULT_PORT)
+            .token("not-a-token")
+            .start()
+            .await;
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_paired_engines_sync_over_a_local_socket() {
+        crate::fs::tests::run_in_temp_dir(|| {
+            tokio::runtime::Runtime::new().unwrap().block_on(async {
+                let session = format!("test-{}", Uuid::new_v4());
+                let first = JustSync::builder().pair(&session).start().await.unwrap();
+                first.open_document("notes.txt", "hello").await;
+
+                // The second one only joins once the first listens
+                let path = crate::transport::pair_socket_path(&session);
+                let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+                while !crate::transport::LocalTransport::reachable(&path).await {
+                    assert!(tokio::time::Instant::now() < deadline, "Nobody listens");
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+                let (edit_tx, mut edit_rx) = mpsc::unbounded_channel();
+                let second = JustSync::builder()
+                    .pair(&session)
+                    .on_edit(move |edit| {
+                        let _ = edit_tx.send(edit);
+                    })
+                    .start()
+                    .await
+                    .unwrap();
+                // The initial sync writes the file, then we open it like an editor would
+                while std::fs::read_to_string("notes.txt").ok().as_deref() != Some("hello") {
+                    assert!(tokio::time::Instant::now() < deadline, "Sync never arrived");
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+                second.open_document("notes.txt", "hello").await;
+
+                let end = Position {
+                    line: 0,
+                    character: 5,
+                };
+                first
+                    .apply_local_change(
+                        "notes.txt",
+                        vec![TextDocumentContentChangeEvent {
+                            range: Some(Range {
+                                start: end.clone(),
+                                end,
+                            }),
+                            text: " world".into(),
+                        }],
+                    )
+                    .await;
+
+                let edit = tokio::time::timeout(Duration::from_secs(5), edit_rx.recv())
+                    .await
+                    .expect("The second daemon never saw the edit")
+                    .unwrap();
+                assert_eq!(edit.uri, "notes.txt");
+                let texts: Vec<&str> = edit.edits.iter().map(|e| e.new_text.as_str()).collect();
+                assert_eq!(texts, vec![" world"]);
+
+                second.shutdown().await;
+                first.shutdown().await;
+            });
+        });
+    }
+
+    #[test]
+    fn test_in_memory_engines_sync_an_edit() {
+        crate::fs::tests::run_in_temp_dir(|| {
+            tokio::runtime::Runtime::new().unwrap().block_on(async {
+                let (host, mut peer) = spawn_paired_engines().await.unwrap();
+                let mut edits = peer.subscribe_remote_edits().unwrap();
+                host.open_document("notes.txt", "hello").await;
+                peer.open_document("notes.txt", "hello").await;
+
+                let end = Position {
+                    line: 0,
+                    character: 5,
+                };
+                host.apply_local_change(
+                    "notes.txt",
+                    vec![TextDocumentContentChangeEvent {
+                        range: Some(Range {
+                            start: end.clone(),
+                            end,
+                        }),
+                        text: " world".into(),
+                    }],
+                )
+                .await;
+
+                // However the peer's copy started, the edit ends up in it
+                loop {
+                    match tokio::time::timeout(Duration::from_secs(5), edits.recv()).await {
+                        Ok(Some(EditorCommand::ApplyEdits { uri, edits })) => {
+                            assert_eq!(uri, "notes.txt");
+                            if edits.iter().any(|e| e.new_text.ends_with(" world")) {
+                                break;
+                            }
+                        }
+                        Ok(Some(_)) => continue,
+                        res => panic!("The peer never saw the edit: {:?}", res),
+                    }
+                }
+
+                peer.shutdown().await;
+                host.shutdown().await;
+            });
+        });
+    }
+}
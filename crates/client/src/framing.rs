@@ -0,0 +1,114 @@
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Largest frame we accept. Anything bigger is a broken or hostile peer,
+/// and we'd rather drop the connection than buffer it.
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// Writes one `[4-byte big-endian length][payload]` frame.
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    write_frame_up_to(writer, payload, MAX_FRAME_LEN).await
+}
+
+/// `write_frame` for streams whose frames may be larger than `MAX_FRAME_LEN`.
+pub async fn write_frame_up_to<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    payload: &[u8],
+    max_len: usize,
+) -> io::Result<()> {
+    let len = u32::try_from(payload.len())
+        .ok()
+        .filter(|len| *len as usize <= max_len)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "frame too large"))?;
+    writer.write_all(&len.to_be_bytes()).await?;
+    writer.write_all(payload).await
+}
+
+/// Reads the next frame. Returns `None` when the stream ended cleanly between frames.
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    read_frame_up_to(reader, MAX_FRAME_LEN).await
+}
+
+/// `read_frame` for streams whose frames may be larger than `MAX_FRAME_LEN`.
+pub async fn read_frame_up_to<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_len: usize,
+) -> io::Result<Option<Vec<u8>>> {
+    let mut len_bytes = [0u8; 4];
+    match reader.read_exact(&mut len_bytes).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let len = u32::from_be_bytes(len_bytes) as usize;
+    if len > max_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {} bytes exceeds the limit", len),
+        ));
+    }
+
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload).await?;
+    Ok(Some(payload))
+}
+
+/// `#[serde(with = "crate::framing::base64")]` for bytes inside JSON payloads,
+/// which would otherwise spell out every byte as a number.
+pub mod base64 {
+    use base64::{Engine, engine::general_purpose::STANDARD};
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        STANDARD.decode(text).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_frames_roundtrip_in_order() {
+        let (mut a, mut b) = tokio::io::duplex(64);
+
+        let writer = tokio::spawn(async move {
+            for i in 0..1000u32 {
+                write_frame(&mut a, format!("patch {}", i).as_bytes())
+                    .await
+                    .unwrap();
+            }
+            write_frame(&mut a, b"").await.unwrap();
+        });
+
+        for i in 0..1000u32 {
+            let frame = read_frame(&mut b).await.unwrap().unwrap();
+            assert_eq!(frame, format!("patch {}", i).as_bytes());
+        }
+        assert_eq!(read_frame(&mut b).await.unwrap().unwrap(), b"");
+        writer.await.unwrap();
+
+        // Writer is gone: clean end of stream
+        assert!(read_frame(&mut b).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_oversized_frame_is_rejected() {
+        let mut input: &[u8] = &((MAX_FRAME_LEN as u32) + 1).to_be_bytes();
+        let err = read_frame(&mut input).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_frame_is_an_error() {
+        let mut input: &[u8] = &[0, 0, 0, 10, b'h', b'i'];
+        assert!(read_frame(&mut input).await.is_err());
+    }
+}
@@ -1,16 +1,67 @@
-use std::{fs, path::Path};
+use std::{collections::HashSet, fs, path::Path};
 
-use crate::logger;
+use crate::{
+    ignore::{IgnoreCache, IgnoreRules},
+    logger,
+};
 
-pub fn to_relative_path(uri: &str, root: &str) -> String {
-    let clean_uri = uri.replace("%20", " ");
-    let clean_root = root.replace("%20", " ");
+/// Bytes that stay as they are in the path of a `file://` URI (RFC 3986 `pchar` and `/`).
+/// Everything else, spaces, `#`, `?`, `%` and non-ASCII included, is percent-encoded.
+fn is_uri_safe(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:@/".contains(&byte)
+}
+
+fn percent_encode_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for &byte in path.as_bytes() {
+        if is_uri_safe(byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
 
-    let path_str = clean_uri.strip_prefix("file://").unwrap_or(&clean_uri);
-    let root_str = clean_root.strip_prefix("file://").unwrap_or(&clean_root);
+/// Decodes `%XX` escapes. Broken escapes are kept as they are, and so are
+/// byte sequences that don't decode to UTF-8.
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = (bytes[i] == b'%')
+            .then(|| bytes.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escape {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).unwrap_or_else(|_| text.to_string())
+}
+
+/// A `file://` URI (or a path cut out of one) as a decoded path with forward
+/// slashes. `file:///C:/x` becomes `C:/x`, not `/C:/x`.
+fn uri_to_path(uri: &str) -> String {
+    let path = percent_decode(uri.strip_prefix("file://").unwrap_or(uri));
+    let path = path.replace('\\', "/");
+    match path.as_bytes() {
+        [b'/', drive, b':', ..] if drive.is_ascii_alphabetic() => path[1..].to_string(),
+        _ => path,
+    }
+}
 
-    let path_norm = path_str.replace('\\', "/");
-    let root_norm = root_str.replace('\\', "/");
+pub fn to_relative_path(uri: &str, root: &str) -> String {
+    let path_norm = uri_to_path(uri);
+    let root_norm = uri_to_path(root);
 
     let path = Path::new(&path_norm);
     let root = Path::new(&root_norm);
@@ -35,44 +86,255 @@ pub fn to_relative_path(uri: &str, root: &str) -> String {
         }
     }
 
+    // `./`, `..` or a symlink on either side: compare where both really are
+    if is_absolute_path(&path_norm)
+        && let (Some(path), Some(root)) = (canonical(&path_norm), canonical(&root_norm))
+        && let Ok(relative) = path.strip_prefix(&root)
+    {
+        return relative.to_string_lossy().replace('\\', "/");
+    }
+
     path_norm
 }
 
+/// Where `path` really is, for a file that doesn't exist (yet) where its directory is.
+fn canonical(path: &str) -> Option<std::path::PathBuf> {
+    let path = Path::new(path);
+    fs::canonicalize(path).ok().or_else(|| {
+        let parent = fs::canonicalize(path.parent()?).ok()?;
+        Some(parent.join(path.file_name()?))
+    })
+}
+
+/// The project root named by the editor's `rootUri`: absolute, without `.` and
+/// `..` parts or a trailing slash. Symlinks stay, the editor names files through
+/// them too. Errs with the reason if it isn't an existing directory.
+pub fn project_root(raw: &str) -> Result<String, String> {
+    let path = uri_to_path(raw);
+    let absolute = if is_absolute_path(&path) {
+        path
+    } else {
+        let cwd = std::env::current_dir().map_err(|e| e.to_string())?;
+        format!("{}/{}", cwd.to_string_lossy().replace('\\', "/"), path)
+    };
+    let root = lexically_normalized(&absolute);
+    match fs::metadata(&root) {
+        Ok(meta) if meta.is_dir() => Ok(root),
+        Ok(_) => Err("is not a directory".to_string()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err("doesn't exist".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// `path` without empty, `.` and `..` parts. A `..` never climbs above a drive.
+fn lexically_normalized(path: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                if parts.last().is_some_and(|last| !last.ends_with(':')) {
+                    parts.pop();
+                }
+            }
+            part => parts.push(part),
+        }
+    }
+    let joined = parts.join("/");
+    if path.starts_with('/') {
+        format!("/{}", joined)
+    } else {
+        joined
+    }
+}
+
+/// The project-relative path of a URI the editor sent, `None` when it names
+/// no file (empty, whitespace, `/` or the project root itself) or one outside
+/// the project. Those aren't synced, a peer has nowhere to put them.
+pub fn normalize_uri(raw: &str, root: &str) -> Option<String> {
+    let uri = to_relative_path(raw, root);
+    if is_blank_path(&uri) {
+        return None;
+    }
+    if !is_safe_relative_path(&uri) {
+        logger::log(&format!(
+            ">> [FS] Not syncing {}, it's outside the project",
+            raw
+        ));
+        return None;
+    }
+    Some(uri)
+}
+
+/// A path that names no file: empty, whitespace or only slashes.
+pub fn is_blank_path(path: &str) -> bool {
+    path.trim().trim_matches(['/', '\\']).trim().is_empty()
+}
+
 pub fn to_absolute_uri(rel_path: &str, root: &str) -> String {
     // Already a URI
     if rel_path.starts_with("file://") {
         return rel_path.replace('\\', "/");
     }
 
-    // Windows Absolute Path (C:\...)
-    if rel_path.len() > 1 && rel_path.chars().nth(1) == Some(':') {
-        // FIX: Windows URIs need 3 slashes: file:///C:/...
-        return format!("file:///{}", rel_path.replace('\\', "/"));
+    let rel_norm = rel_path.replace('\\', "/");
+    let full_path = if is_absolute_path(&rel_norm) {
+        rel_norm
+    } else {
+        // Relative Path -> Join with Root
+        let root_norm = uri_to_path(root);
+        Path::new(&root_norm)
+            .join(&rel_norm)
+            .to_string_lossy()
+            .replace('\\', "/")
+    };
+
+    // Windows URIs need 3 slashes: file:///C:/...
+    if full_path.starts_with('/') {
+        format!("file://{}", percent_encode_path(&full_path))
+    } else {
+        format!("file:///{}", percent_encode_path(&full_path))
     }
+}
 
-    // Unix Absolute Path (/usr/...)
-    if rel_path.starts_with('/') || rel_path.starts_with('\\') {
-        return format!("file://{}", rel_path.replace('\\', "/"));
-    }
+/// `/usr/...` or `C:/...` (forward slashes only).
+fn is_absolute_path(path: &str) -> bool {
+    path.starts_with('/')
+        || matches!(path.as_bytes(), [drive, b':', ..] if drive.is_ascii_alphabetic())
+}
 
-    // Relative Path -> Join with Root
-    let clean_root = root.trim_start_matches("file://");
-    let root_norm = clean_root.replace('\\', "/");
-    let rel_norm = rel_path.replace('\\', "/");
+/// Sync-specific exclusions at the project root, gitignore syntax. Merged with
+/// `.gitignore` when scanning, and also keeps files we receive off the disk.
+pub const SYNC_IGNORE_FILE: &str = ".justsyncignore";
+
+/// `--share` paths as `is_shared` wants them: relative to the project root,
+/// without `./` or slashes around them. Empty shares the whole project.
+pub fn share_paths(paths: &[String]) -> Vec<String> {
+    paths
+        .iter()
+        .map(|path| {
+            let path = path.replace('\\', "/");
+            let path = path.strip_prefix("./").unwrap_or(&path);
+            path.trim_matches('/').to_string()
+        })
+        .filter(|path| !path.is_empty() && path != ".")
+        .collect()
+}
+
+/// Whether `uri` is one of the `share` paths or under one. Without any, everything is.
+pub fn is_shared(uri: &str, share: &[String]) -> bool {
+    share.is_empty() || share.iter().any(|path| uri == path || is_under(uri, path))
+}
+
+/// Whether `uri` is inside the directory `dir`.
+fn is_under(uri: &str, dir: &str) -> bool {
+    uri.strip_prefix(dir)
+        .is_some_and(|rest| rest.starts_with('/'))
+}
 
-    let path = Path::new(&root_norm).join(&rel_norm);
-    let full_path = path.to_string_lossy().replace('\\', "/");
+/// How many leading bytes are checked for a NUL when telling text from binary.
+const BINARY_SNIFF_LEN: usize = 8 * 1024;
 
-    format!("file://{}", full_path)
+/// Result of walking the project.
+#[derive(Debug, Default)]
+pub struct ProjectScan {
+    /// (Relative URI, Content) of every text file.
+    pub files: Vec<(String, String)>,
+    /// Files left out because they are binary or not UTF-8.
+    pub skipped: usize,
 }
 
-/// Recursively reads all files in a directory, returning (Relative URI, Content).
-/// Skips hidden files (starting with .) and common build artifacts.
+/// Same as `scan_project` of the whole project without `--ignore`, but only the text files.
 pub fn scan_project_directory(root: &str) -> Vec<(String, String)> {
-    let mut results = Vec::new();
-    let root_path = Path::new(root);
+    scan_project(root, &sync_ignore_rules(Path::new(root), &[]), &[]).files
+}
+
+/// Whether `bytes` look like a binary file (a NUL byte near the start, like git does).
+pub fn is_binary(bytes: &[u8]) -> bool {
+    bytes.iter().take(BINARY_SNIFF_LEN).any(|&b| b == 0)
+}
+
+/// Recursively reads all files in a directory, returning (Relative URI, Content).
+/// Skips hidden files (starting with .), gitignored paths or those `sync_ignore`
+/// matches (or common build artifacts without a .gitignore), binary files and,
+/// with `share` paths (see `is_shared`), everything outside them.
+pub fn scan_project(root: &str, sync_ignore: &IgnoreRules, share: &[String]) -> ProjectScan {
+    let mut results = ProjectScan::default();
+    let Ok(canonical_root) = fs::canonicalize(root) else {
+        return results;
+    };
+    walk_project(Path::new(root), sync_ignore, share, &mut |uri, path| {
+        // A symlink to somewhere else isn't part of the project
+        if !ensure_within_root(path, &canonical_root) {
+            logger::warn(&format!(
+                "!! [FS] Skipped {}, it leads outside the project",
+                uri
+            ));
+            return;
+        }
+        let Ok(bytes) = fs::read(path) else {
+            return;
+        };
+        // Binary content can't go through the text CRDT
+        if is_binary(&bytes) {
+            logger::warn(&format!("!! [FS] Skipped binary file: {}", uri));
+            results.skipped += 1;
+            return;
+        }
+        match String::from_utf8(bytes) {
+            Ok(content) => {
+                logger::debug(&format!("Found file {}", &uri));
+                results.files.push((uri, content));
+            }
+            Err(_) => {
+                logger::warn(&format!("!! [FS] Skipped non-UTF-8 file: {}", uri));
+                results.skipped += 1;
+            }
+        }
+    });
+    results
+}
+
+/// Calls `on_file` with (Relative URI, Path) of every file `scan_project` would look at.
+pub fn walk_project(
+    root: &Path,
+    sync_ignore: &IgnoreRules,
+    share: &[String],
+    on_file: &mut dyn FnMut(String, &Path),
+) {
+    walk_project_cached(
+        root,
+        sync_ignore,
+        share,
+        &mut IgnoreCache::default(),
+        on_file,
+    );
+}
 
-    fn visit(dir: &Path, root: &Path, results: &mut Vec<(String, String)>) {
+/// `walk_project` for walking the same project again and again: the `.gitignore`
+/// files are only parsed again when they changed since the last walk with `cache`.
+pub fn walk_project_cached(
+    root: &Path,
+    sync_ignore: &IgnoreRules,
+    share: &[String],
+    cache: &mut IgnoreCache,
+    on_file: &mut dyn FnMut(String, &Path),
+) {
+    // Without a .gitignore we fall back to skipping the usual build directories
+    let mut rules = IgnoreRules::default();
+    let has_gitignore = rules.add_cached_file(cache, "", &root.join(".gitignore"));
+    // Added last, so it can also take back what .gitignore excludes
+    rules.extend(sync_ignore);
+
+    fn visit(
+        dir: &Path,
+        root: &Path,
+        rules: &mut IgnoreRules,
+        share: &[String],
+        cache: &mut IgnoreCache,
+        has_gitignore: bool,
+        on_file: &mut dyn FnMut(String, &Path),
+    ) {
         if let Ok(entries) = fs::read_dir(dir) {
             for entry in entries.flatten() {
                 let path = entry.path();
@@ -83,73 +345,271 @@ pub fn scan_project_directory(root: &str) -> Vec<(String, String)> {
                     None => continue,
                 };
 
-                if file_name.starts_with('.')
-                    || file_name == "target"
-                    || file_name == "node_modules"
-                    || file_name == "dist"
-                    || file_name == "_build"
+                if file_name.starts_with('.') {
+                    continue;
+                }
+                if !has_gitignore
+                    && (file_name == "target"
+                        || file_name == "node_modules"
+                        || file_name == "dist"
+                        || file_name == "_build")
                 {
                     continue;
                 }
 
+                // Safely attempt to strip the prefix.
+                // If it fails (e.g. root is "." and path is "src/main.rs"),
+                // we likely just want the path as is.
+                let relative_path_cow = path
+                    .strip_prefix(root)
+                    .unwrap_or(&path) // Fallback to original path if strip fails
+                    .to_string_lossy();
+
+                // Convert Cow<str> to String
+                let relative_path = relative_path_cow.into_owned();
+
+                let uri = relative_path.replace("\\", "/");
+
+                if rules.is_ignored(&uri, is_dir) {
+                    continue;
+                }
+
                 if is_dir {
-                    visit(&path, root, results);
-                } else if let Ok(content) = fs::read_to_string(&path) {
-                    // Safely attempt to strip the prefix.
-                    // If it fails (e.g. root is "." and path is "src/main.rs"),
-                    // we likely just want the path as is.
-                    let relative_path_cow = path
-                        .strip_prefix(root)
-                        .unwrap_or(&path) // Fallback to original path if strip fails
-                        .to_string_lossy();
-
-                    // Convert Cow<str> to String
-                    let relative_path = relative_path_cow.into_owned();
-
-                    let uri = relative_path.replace("\\", "/");
-
-                    logger::log(&format!("Found file {}", &uri));
-                    results.push((uri, content));
+                    // Only directories with something shared in them are worth a look
+                    if !is_shared(&uri, share) && !share.iter().any(|path| is_under(path, &uri)) {
+                        continue;
+                    }
+                    // Nested ignore files only affect their own subtree
+                    rules.add_cached_file(cache, &uri, &path.join(".gitignore"));
+                    visit(&path, root, rules, share, cache, has_gitignore, on_file);
+                } else if is_shared(&uri, share) {
+                    on_file(uri, &path);
                 }
             }
         }
     }
 
-    visit(root_path, root_path, &mut results);
-    results
+    visit(root, root, &mut rules, share, cache, has_gitignore, on_file);
 }
 
-pub fn write_project_files(files: Vec<(String, String)>) -> anyhow::Result<()> {
-    for (path_str, content) in files {
-        if path_str.trim().is_empty() || path_str == "/" {
-            logger::log("Ignoring empty file path");
-            continue;
+/// The `.justsyncignore` rules of the project at `root` and the `--ignore`
+/// `patterns` (gitignore syntax, relative to the root), empty without either.
+pub fn sync_ignore_rules(root: &Path, patterns: &[String]) -> IgnoreRules {
+    let mut rules = IgnoreRules::default();
+    rules.add_file("", &root.join(SYNC_IGNORE_FILE));
+    rules.add_patterns("", &patterns.join("\n"));
+    rules
+}
+
+/// Relative URIs of the project files matching any of `patterns`
+/// (gitignore syntax, e.g. `Cargo.lock` or `ci/**`).
+pub fn matching_files(root: &Path, patterns: &[String]) -> HashSet<String> {
+    let mut rules = IgnoreRules::default();
+    rules.add_patterns("", &patterns.join("\n"));
+    let mut matched = HashSet::new();
+    walk_project(root, &sync_ignore_rules(root, &[]), &[], &mut |uri, _| {
+        if rules.is_ignored(&uri, false) {
+            matched.insert(uri);
+        }
+    });
+    matched
+}
+
+/// Whether `path` climbs out of the project (e.g. "../../../etc/passwd").
+fn escapes_project(path: &Path) -> bool {
+    path.components()
+        .any(|c| matches!(c, std::path::Component::ParentDir))
+}
+
+/// Whether `path` really lies under `canonical_root` once symlinks are
+/// followed. A path that doesn't exist yet is judged by the deepest part of it
+/// that does, so a new file in a linked-out directory counts as outside too.
+pub fn ensure_within_root(path: &Path, canonical_root: &Path) -> bool {
+    let mut existing = path;
+    let mut missing = Vec::new();
+    let real = loop {
+        let probe = if existing.as_os_str().is_empty() {
+            Path::new(".")
         } else {
-            logger::log(&format!(">> [FS DEBUG] Found file: {}", path_str));
+            existing
+        };
+        match fs::canonicalize(probe) {
+            Ok(real) => break real,
+            // There but unresolvable, e.g. a symlink to nowhere: writing would follow it
+            Err(_) if fs::symlink_metadata(probe).is_ok() => return false,
+            Err(_) => {
+                let (Some(parent), Some(name)) = (existing.parent(), existing.file_name()) else {
+                    return false;
+                };
+                missing.push(name);
+                existing = parent;
+            }
         }
+    };
+    let real = missing
+        .into_iter()
+        .rev()
+        .fold(real, |path, name| path.join(name));
+    real.starts_with(canonical_root)
+}
 
-        // Ensure we are writing relatively to CWD
-        let path = Path::new(&path_str);
+/// `ensure_within_root` for a path relative to the project we run in. Logs escapes.
+/// Absolute paths never are, files outside the root aren't synced.
+fn within_project(path_str: &str) -> bool {
+    within(Path::new("."), path_str)
+}
 
-        // Safety check: Prevent writing outside project (e.g. "../../../etc/passwd")
-        if path
-            .components()
-            .any(|c| matches!(c, std::path::Component::ParentDir))
-        {
-            crate::logger::log(&format!("!! [FS] Skipped unsafe path: {}", path_str));
-            continue;
+/// `within_project` for the project at `root`.
+fn within(root: &Path, path_str: &str) -> bool {
+    let path = Path::new(path_str);
+    let within = !path.is_absolute()
+        && fs::canonicalize(root)
+            .is_ok_and(|canonical| ensure_within_root(&root.join(path), &canonical));
+    if !within {
+        logger::warn(&format!(
+            "!! [FS] Skipped {}, it leads outside the project",
+            path_str
+        ));
+    }
+    within
+}
+
+/// Whether a path a peer sent us stays inside the project once joined to it.
+/// Rejects absolute paths, drive letters (`C:\`, `C:foo`), UNC paths and `..`,
+/// with either kind of slash, no matter which OS we're running on.
+pub fn is_safe_relative_path(path: &str) -> bool {
+    if is_blank_path(path) || path.starts_with('/') || path.starts_with('\\') {
+        return false;
+    }
+    let mut chars = path.chars();
+    if chars.next().is_some_and(|c| c.is_ascii_alphabetic()) && chars.next() == Some(':') {
+        return false;
+    }
+    !path.split(['/', '\\']).any(|part| part == "..") && !Path::new(path).has_root()
+}
+
+/// Reads a file the user just created so peers can get it.
+/// `None` for unsafe paths and files that aren't text.
+pub fn read_project_file(path_str: &str) -> Option<String> {
+    read_project_file_in(Path::new("."), path_str)
+}
+
+/// `read_project_file` for the project at `root`, `path_str` is relative to it.
+pub fn read_project_file_in(root: &Path, path_str: &str) -> Option<String> {
+    if escapes_project(Path::new(path_str)) {
+        logger::warn(&format!("!! [FS] Skipped unsafe path: {}", path_str));
+        return None;
+    }
+    if !within(root, path_str) {
+        return None;
+    }
+    let bytes = fs::read(root.join(path_str)).ok()?;
+    if is_binary(&bytes) {
+        logger::warn(&format!("!! [FS] Skipped binary file: {}", path_str));
+        return None;
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Deletes a file a peer deleted. A file that is already gone is fine.
+pub fn remove_project_file(path_str: &str) -> anyhow::Result<()> {
+    let path = Path::new(path_str);
+    if is_blank_path(path_str) || escapes_project(path) {
+        logger::warn(&format!("!! [FS] Skipped unsafe path: {}", path_str));
+        return Ok(());
+    }
+    if !within_project(path_str) {
+        return Ok(());
+    }
+    match fs::remove_file(path) {
+        Ok(()) => {
+            logger::log(&format!(">> [FS] Removed: {}", path_str));
+            Ok(())
         }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
 
-        if let Some(parent) = path.parent() {
-            fs::create_dir_all(parent)?;
+/// Moves a file a peer renamed. A file that is already gone is fine.
+pub fn rename_project_file(from: &str, to: &str) -> anyhow::Result<()> {
+    for path_str in [from, to] {
+        if is_blank_path(path_str) || escapes_project(Path::new(path_str)) {
+            logger::warn(&format!("!! [FS] Skipped unsafe path: {}", path_str));
+            return Ok(());
         }
+        if !within_project(path_str) {
+            return Ok(());
+        }
+    }
+    if let Some(parent) = Path::new(to).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::rename(from, to) {
+        Ok(()) => {
+            logger::log(&format!(">> [FS] Renamed: {} -> {}", from, to));
+            Ok(())
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
 
-        fs::write(path, content)?;
-        crate::logger::log(&format!(">> [FS] Wrote: {}", path_str));
+/// Writes what peers sent, except for files `sync_ignore` matches and those outside `share`.
+pub fn write_project_files(
+    files: Vec<(String, String)>,
+    sync_ignore: &IgnoreRules,
+    share: &[String],
+) -> anyhow::Result<()> {
+    for (path_str, content) in files {
+        if is_blank_path(&path_str) {
+            logger::log("Ignoring empty file path");
+            continue;
+        } else if sync_ignore.is_ignored(&path_str, false) {
+            logger::log(&format!(
+                ">> [FS] Skipped {}, it is in {} or --ignore",
+                path_str, SYNC_IGNORE_FILE
+            ));
+            continue;
+        } else if !is_shared(&path_str, share) {
+            logger::log(&format!(
+                ">> [FS] Skipped {}, it isn't in --share",
+                path_str
+            ));
+            continue;
+        } else {
+            logger::debug(&format!(">> [FS DEBUG] Found file: {}", path_str));
+        }
+        write_project_file(&path_str, &content)?;
     }
     Ok(())
 }
 
+/// Writes one file of the project, creating its directories.
+/// Paths that lead outside the project are skipped.
+pub fn write_project_file(path_str: &str, content: &str) -> anyhow::Result<()> {
+    // Ensure we are writing relatively to CWD
+    let path = Path::new(path_str);
+
+    // Safety check: Prevent writing outside project (e.g. "../../../etc/passwd"),
+    // also through a symlink inside it
+    if escapes_project(path) {
+        crate::logger::warn(&format!("!! [FS] Skipped unsafe path: {}", path_str));
+        return Ok(());
+    }
+    if !within_project(path_str) {
+        return Ok(());
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    fs::write(path, content)?;
+    crate::logger::log(&format!(">> [FS] Wrote: {}", path_str));
+    Ok(())
+}
+
 #[cfg(test)]
 pub mod tests {
     use super::*;
@@ -278,6 +738,44 @@ pub mod tests {
         assert_eq!(result, "file:///C:/Users/src/modules/logic.rs");
     }
 
+    #[test]
+    fn test_special_characters_roundtrip() {
+        let rel = "src/my file#2/café.rs";
+
+        let root = "file:///home/user/project";
+        let uri = to_absolute_uri(rel, root);
+        assert_eq!(
+            uri,
+            "file:///home/user/project/src/my%20file%232/caf%C3%A9.rs"
+        );
+        assert_eq!(to_relative_path(&uri, root), rel);
+
+        // VS Code on Windows encodes the drive colon too
+        let root = "file:///c%3A/Users/Dev/Project";
+        let uri = to_absolute_uri(rel, root);
+        assert_eq!(
+            uri,
+            "file:///c:/Users/Dev/Project/src/my%20file%232/caf%C3%A9.rs"
+        );
+        assert_eq!(to_relative_path(&uri, root), rel);
+        assert_eq!(
+            to_relative_path(
+                "file:///C:/Users/Dev/Project/a%25b.rs",
+                "C:/Users/Dev/Project"
+            ),
+            "a%b.rs"
+        );
+    }
+
+    #[test]
+    fn test_broken_escapes_are_kept() {
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+        assert_eq!(percent_decode("%41%62"), "Ab");
+        // Not UTF-8 once decoded
+        assert_eq!(percent_decode("%FF"), "%FF");
+    }
+
     // =========================================================================
     //  scan_project_directory
     // =========================================================================
@@ -374,6 +872,96 @@ pub mod tests {
         assert_eq!(results[0].0, "src/main.rs");
     }
 
+    #[test]
+    fn test_scan_skips_binary_files() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+
+        fs::write(
+            temp_dir.path().join("logo.png"),
+            b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR",
+        )
+        .unwrap();
+        fs::write(temp_dir.path().join("latin1.txt"), b"caf\xe9").unwrap();
+        create_file(&temp_dir, "main.rs", "fn main() {}");
+
+        let scan = scan_project(
+            temp_dir.path().to_str().unwrap(),
+            &IgnoreRules::default(),
+            &[],
+        );
+
+        assert_eq!(scan.files.len(), 1);
+        assert_eq!(scan.files[0].0, "main.rs");
+        assert_eq!(scan.skipped, 2);
+    }
+
+    #[test]
+    fn test_binary_sniff_only_looks_at_the_start() {
+        assert!(is_binary(b"text\0more"));
+        assert!(!is_binary("plain text, even with ümlauts".as_bytes()));
+
+        let mut late_nul = vec![b'a'; BINARY_SNIFF_LEN];
+        late_nul.push(0);
+        assert!(!is_binary(&late_nul));
+    }
+
+    #[test]
+    fn test_scan_respects_gitignore() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+
+        // create_file writes debug-quoted content, ignore files need the raw text
+        fs::write(
+            temp_dir.path().join(".gitignore"),
+            "secret.txt\n/generated/\n",
+        )
+        .unwrap();
+        create_file(&temp_dir, "secret.txt", "API_KEY=123");
+        create_file(&temp_dir, "config/secret.txt", "API_KEY=456");
+        create_file(&temp_dir, "generated/schema.rs", "huge");
+        // Nested ignore files apply to their own directory
+        create_file(&temp_dir, "web/app.js.map", "{}");
+        fs::write(temp_dir.path().join("web/.gitignore"), "*.map\n").unwrap();
+        create_file(&temp_dir, "web/app.js", "code");
+        create_file(&temp_dir, "src/main.rs", "code");
+
+        let root_str = temp_dir.path().to_str().unwrap();
+        let found: std::collections::HashSet<_> = scan_project_directory(root_str)
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect();
+
+        assert_eq!(
+            found,
+            ["src/main.rs", "web/app.js"]
+                .into_iter()
+                .map(String::from)
+                .collect()
+        );
+    }
+
+    #[test]
+    fn test_matching_files_use_glob_patterns() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        create_file(&temp_dir, "Cargo.lock", "lock");
+        create_file(&temp_dir, "crates/app/Cargo.lock", "lock");
+        create_file(&temp_dir, "ci/deploy/pipeline.yml", "steps");
+        create_file(&temp_dir, "src/main.rs", "code");
+
+        let patterns = ["Cargo.lock".to_string(), "ci/**".to_string()];
+        let matched = matching_files(temp_dir.path(), &patterns);
+        assert_eq!(
+            matched,
+            [
+                "Cargo.lock",
+                "crates/app/Cargo.lock",
+                "ci/deploy/pipeline.yml"
+            ]
+            .into_iter()
+            .map(String::from)
+            .collect()
+        );
+    }
+
     #[test]
     fn test_handles_binary_files_gracefully() {
         // fs::read_to_string returns an Error if the file is not valid UTF-8.
@@ -399,7 +987,7 @@ pub mod tests {
 
     // This function runs a closure inside a temporary directory.
     // It guarantees we don't accidentally write to your real hard drive.
-    fn run_in_temp_dir<F>(test_fn: F)
+    pub fn run_in_temp_dir<F>(test_fn: F)
     where
         F: FnOnce(),
     {
@@ -424,6 +1012,112 @@ pub mod tests {
         }
     }
 
+    #[test]
+    fn test_ignore_flag_keeps_matching_files_out() {
+        run_in_temp_dir(|| {
+            fs::write("main.rs", "fn main() {}").unwrap();
+            fs::write("debug.log", "noise").unwrap();
+            let ignore = sync_ignore_rules(Path::new("."), &["*.log".to_string()]);
+
+            let found: Vec<String> = scan_project(".", &ignore, &[])
+                .files
+                .into_iter()
+                .map(|(path, _)| path)
+                .collect();
+            write_project_files(
+                vec![("logs/trace.log".to_string(), "more".to_string())],
+                &ignore,
+                &[],
+            )
+            .unwrap();
+            let received = Path::new("logs/trace.log").exists();
+
+            assert_eq!(found, vec!["main.rs".to_string()]);
+            assert!(!received);
+        });
+    }
+
+    #[test]
+    fn test_share_flag_only_includes_those_paths() {
+        run_in_temp_dir(|| {
+            fs::create_dir_all("src/net").unwrap();
+            fs::create_dir_all("docs").unwrap();
+            fs::create_dir_all("src-old").unwrap();
+            fs::write("src/main.rs", "fn main() {}").unwrap();
+            fs::write("src/net/peer.rs", "// peer").unwrap();
+            fs::write("src/keys.env", "SECRET=1").unwrap();
+            fs::write("src-old/main.rs", "// gone").unwrap();
+            fs::write("docs/notes.md", "private").unwrap();
+            fs::write("README.md", "private too").unwrap();
+            let share = share_paths(&["./src/".to_string()]);
+            assert_eq!(share, vec!["src"]);
+            // Ignoring wins over sharing
+            let ignore = sync_ignore_rules(Path::new("."), &["*.env".to_string()]);
+
+            let mut found: Vec<String> = scan_project(".", &ignore, &share)
+                .files
+                .into_iter()
+                .map(|(path, _)| path)
+                .collect();
+            found.sort();
+            write_project_files(
+                vec![
+                    ("src/new.rs".to_string(), "// new".to_string()),
+                    ("docs/new.md".to_string(), "sent anyway".to_string()),
+                ],
+                &ignore,
+                &share,
+            )
+            .unwrap();
+
+            assert_eq!(found, vec!["src/main.rs", "src/net/peer.rs"]);
+            assert!(Path::new("src/new.rs").exists());
+            assert!(!Path::new("docs/new.md").exists());
+        });
+    }
+
+    #[test]
+    fn test_is_shared() {
+        let share = vec!["src".to_string(), "Cargo.toml".to_string()];
+        assert!(is_shared("src/main.rs", &share));
+        assert!(is_shared("Cargo.toml", &share));
+        assert!(!is_shared("src-old/main.rs", &share));
+        assert!(!is_shared("Cargo.lock", &share));
+        assert!(is_shared("anything.rs", &[]));
+        assert!(share_paths(&[".".to_string(), "/".to_string()]).is_empty());
+    }
+
+    #[test]
+    fn test_justsyncignore_keeps_env_files_out() {
+        run_in_temp_dir(|| {
+            fs::write(".gitignore", "*.log\n").unwrap();
+            fs::write(SYNC_IGNORE_FILE, "*.env\n").unwrap();
+            fs::write("main.rs", "fn main() {}").unwrap();
+            fs::write("local.env", "SECRET=1").unwrap();
+            fs::write("build.log", "noise").unwrap();
+
+            // Both ignore files apply to what we share
+            let found: Vec<String> = scan_project_directory(".")
+                .into_iter()
+                .map(|(path, _)| path)
+                .collect();
+            assert_eq!(found, vec!["main.rs".to_string()]);
+
+            // And what we receive
+            write_project_files(
+                vec![
+                    ("config/prod.env".to_string(), "SECRET=2".to_string()),
+                    ("lib.rs".to_string(), "pub fn lib() {}".to_string()),
+                ],
+                &sync_ignore_rules(Path::new("."), &[]),
+                &[],
+            )
+            .unwrap();
+            assert!(!Path::new("config/prod.env").exists());
+            assert!(Path::new("lib.rs").exists());
+        });
+    }
+
     #[test]
     fn test_write_simple_files() {
         run_in_temp_dir(|| {
@@ -432,7 +1126,7 @@ pub mod tests {
                 ("Cargo.toml".to_string(), "[package]".to_string()),
             ];
 
-            let result = write_project_files(files);
+            let result = write_project_files(files, &IgnoreRules::default(), &[]);
             assert!(result.is_ok());
 
             // Verify files exist in the (temp) CWD
@@ -452,7 +1146,7 @@ pub mod tests {
                 "pub fn add() {}".to_string(),
             )];
 
-            write_project_files(files).unwrap();
+            write_project_files(files, &IgnoreRules::default(), &[]).unwrap();
 
             // Verify directory structure was created
             assert!(Path::new("src").is_dir());
@@ -470,7 +1164,7 @@ pub mod tests {
                 ("src/../../oops.txt".to_string(), "hacked".to_string()),
             ];
 
-            let result = write_project_files(files);
+            let result = write_project_files(files, &IgnoreRules::default(), &[]);
             assert!(result.is_ok()); // Function returns Ok, but skips unsafe files
 
             // Verify files were NOT written
@@ -478,6 +1172,159 @@ pub mod tests {
         });
     }
 
+    #[test]
+    fn test_remove_project_file_stays_inside_project() {
+        run_in_temp_dir(|| {
+            fs::create_dir("project").unwrap();
+            fs::write("outside.txt", "keep me").unwrap();
+            std::env::set_current_dir("project").unwrap();
+            fs::write("gone.rs", "").unwrap();
+
+            remove_project_file("gone.rs").unwrap();
+            remove_project_file("gone.rs").unwrap(); // Already gone
+            remove_project_file("../outside.txt").unwrap();
+
+            assert!(!Path::new("gone.rs").exists());
+            assert!(Path::new("../outside.txt").exists());
+            assert_eq!(read_project_file("../outside.txt"), None);
+        });
+    }
+
+    #[test]
+    fn test_safe_relative_paths() {
+        for safe in [
+            "src/main.rs",
+            "./src/lib.rs",
+            ".gitignore",
+            "a/..b/c..",
+            "dir\\file.rs",
+        ] {
+            assert!(is_safe_relative_path(safe), "{} should be safe", safe);
+        }
+        for unsafe_path in [
+            "../evil.txt",
+            "src/../../oops.txt",
+            "src\\..\\..\\oops.txt",
+            "..",
+            "/etc/passwd",
+            "\\Windows\\System32",
+            "C:\\Windows\\System32\\driver.sys",
+            "c:/Users/evil",
+            "C:relative.txt",
+            "\\\\server\\share\\file.txt",
+            "//server/share/file.txt",
+            "",
+            "   ",
+        ] {
+            assert!(
+                !is_safe_relative_path(unsafe_path),
+                "{} should be rejected",
+                unsafe_path
+            );
+        }
+    }
+
+    #[test]
+    fn test_project_root_is_normalized_and_checked() {
+        let dir = tempfile::tempdir().unwrap();
+        let real = fs::canonicalize(dir.path()).unwrap();
+        let real = real.to_string_lossy();
+        fs::create_dir(dir.path().join("project")).unwrap();
+
+        let messy = format!("file://{}/./project/../project/", real);
+        assert_eq!(project_root(&messy), Ok(format!("{}/project", real)));
+        assert_eq!(
+            project_root(&format!("{}/missing", real)),
+            Err("doesn't exist".to_string())
+        );
+        fs::write(dir.path().join("file.txt"), "x").unwrap();
+        assert_eq!(
+            project_root(&format!("{}/file.txt", real)),
+            Err("is not a directory".to_string())
+        );
+        assert_eq!(lexically_normalized("C:/a/../../b"), "C:/b");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_relative_path_through_a_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let real = fs::canonicalize(dir.path()).unwrap().join("project");
+        fs::create_dir_all(real.join("src")).unwrap();
+        fs::write(real.join("src/main.rs"), "").unwrap();
+        let link = dir.path().join("link");
+        std::os::unix::fs::symlink(&real, &link).unwrap();
+
+        // The editor's root and its file URIs spell the project differently
+        let root = link.to_string_lossy();
+        let uri = format!("file://{}/src/main.rs", real.display());
+        assert_eq!(to_relative_path(&uri, &root), "src/main.rs");
+        let uri = format!("file://{}/src/new.rs", real.display());
+        assert_eq!(to_relative_path(&uri, &root), "src/new.rs");
+        let root = format!("{}/./project", dir.path().display());
+        let uri = format!("file://{}/src/main.rs", real.display());
+        assert_eq!(to_relative_path(&uri, &root), "src/main.rs");
+    }
+
+    #[test]
+    fn test_normalize_uri() {
+        let root = "/tmp/project";
+        assert_eq!(
+            normalize_uri("file:///tmp/project/src/main.rs", root).as_deref(),
+            Some("src/main.rs")
+        );
+        assert_eq!(
+            normalize_uri("file:///tmp/project/my%20notes.md", root).as_deref(),
+            Some("my notes.md")
+        );
+        for blank in [
+            "",
+            "/",
+            "   ",
+            "\t\n",
+            " / ",
+            "\\",
+            "file:///tmp/project",
+            "file:///tmp/project/",
+            "file:///tmp/project/%20%20",
+        ] {
+            assert_eq!(
+                normalize_uri(blank, root),
+                None,
+                "{:?} names no file",
+                blank
+            );
+        }
+    }
+
+    #[test]
+    fn test_uris_above_the_root_are_not_synced() {
+        let root = "/tmp/project";
+        for outside in [
+            "file:///tmp/deps/lib.rs",
+            "file:///tmp/project/../deps/lib.rs",
+            "file:///tmp/project_old/lib.rs",
+            "file:///etc/hosts",
+            "/tmp/deps/lib.rs",
+        ] {
+            assert_eq!(
+                normalize_uri(outside, root),
+                None,
+                "{:?} is outside the project",
+                outside
+            );
+        }
+        // Editors send normalized URIs, a `..` that happens to stay inside isn't worth guessing at
+        assert_eq!(
+            normalize_uri("file:///tmp/project/src/../lib.rs", root).as_deref(),
+            None,
+        );
+        assert_eq!(
+            normalize_uri("file:///tmp/project/lib.rs", root).as_deref(),
+            Some("lib.rs")
+        );
+    }
+
     #[test]
     fn test_security_allows_safe_dots() {
         run_in_temp_dir(|| {
@@ -487,7 +1334,7 @@ pub mod tests {
                 ("./src/lib.rs".to_string(), "// code".to_string()),
             ];
 
-            write_project_files(files).unwrap();
+            write_project_files(files, &IgnoreRules::default(), &[]).unwrap();
 
             assert!(Path::new(".gitignore").exists());
             assert!(Path::new("src/lib.rs").exists());
@@ -503,7 +1350,7 @@ pub mod tests {
                 ("/".to_string(), "ignore root".to_string()),
             ];
 
-            let result = write_project_files(files);
+            let result = write_project_files(files, &IgnoreRules::default(), &[]);
             assert!(result.is_ok());
 
             // Ensure nothing weird was created
@@ -523,11 +1370,75 @@ pub mod tests {
                 "config.json".to_string(),
                 "{ \"updated\": true }".to_string(),
             )];
-            write_project_files(files).unwrap();
+            write_project_files(files, &IgnoreRules::default(), &[]).unwrap();
 
             // Verify new content
             let content = fs::read_to_string("config.json").unwrap();
             assert_eq!(content, "{ \"updated\": true }");
         });
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinks_out_of_the_project_are_refused() {
+        let outside = tempfile::tempdir().unwrap();
+        fs::write(outside.path().join("secret.txt"), "secret").unwrap();
+        run_in_temp_dir(|| {
+            let root = fs::canonicalize(".").unwrap();
+            std::os::unix::fs::symlink(outside.path().join("secret.txt"), "secret.txt").unwrap();
+            std::os::unix::fs::symlink(outside.path(), "elsewhere").unwrap();
+            std::os::unix::fs::symlink(outside.path().join("gone"), "dangling").unwrap();
+            fs::write("notes.md", "mine").unwrap();
+            std::os::unix::fs::symlink("notes.md", "alias.md").unwrap();
+
+            assert!(ensure_within_root(Path::new("notes.md"), &root));
+            assert!(ensure_within_root(Path::new("alias.md"), &root));
+            assert!(ensure_within_root(Path::new("src/new.rs"), &root));
+            assert!(!ensure_within_root(Path::new("secret.txt"), &root));
+            assert!(!ensure_within_root(Path::new("elsewhere/new.rs"), &root));
+            assert!(!ensure_within_root(Path::new("dangling"), &root));
+
+            // Neither read nor written through
+            assert_eq!(read_project_file("secret.txt"), None);
+            write_project_files(
+                vec![
+                    ("secret.txt".into(), "overwritten".into()),
+                    ("elsewhere/new.rs".into(), "planted".into()),
+                    ("dangling".into(), "planted".into()),
+                    ("src/new.rs".into(), "fine".into()),
+                ],
+                &IgnoreRules::default(),
+                &[],
+            )
+            .unwrap();
+            let scanned: Vec<String> = scan_project_directory(".")
+                .into_iter()
+                .map(|(uri, _)| uri)
+                .collect();
+            assert!(!scanned.contains(&"secret.txt".to_string()));
+            assert_eq!(fs::read_to_string("src/new.rs").unwrap(), "fine");
+        });
+        assert_eq!(
+            fs::read_to_string(outside.path().join("secret.txt")).unwrap(),
+            "secret"
+        );
+        assert!(!outside.path().join("new.rs").exists());
+        assert!(!outside.path().join("gone").exists());
+    }
+
+    #[test]
+    fn test_absolute_paths_are_refused() {
+        let outside = tempfile::tempdir().unwrap();
+        let secret = outside.path().join("secret.txt");
+        fs::write(&secret, "secret").unwrap();
+        let secret_str = secret.to_str().unwrap().to_string();
+        run_in_temp_dir(|| {
+            assert_eq!(read_project_file(&secret_str), None);
+            write_project_file(&secret_str, "overwritten").unwrap();
+            rename_project_file(&secret_str, "stolen.txt").unwrap();
+            remove_project_file(&secret_str).unwrap();
+            assert!(!Path::new("stolen.txt").exists());
+        });
+        assert_eq!(fs::read_to_string(&secret).unwrap(), "secret");
+    }
 }
@@ -0,0 +1,186 @@
+use ropey::Rope;
+
+const ZWJ: char = '\u{200D}';
+
+/// Where a user-perceived character (grapheme cluster) may end, so edits never
+/// land inside an emoji sequence, a flag or a letter with combining marks.
+/// Follows the rules of UAX #29 that show up in text files (CR LF, extending
+/// and spacing marks, emoji ZWJ sequences, regional indicator pairs, Hangul
+/// syllables), the character classes are the common blocks, not the full tables.
+pub fn is_boundary(rope: &Rope, char_idx: usize) -> bool {
+    if char_idx == 0 || char_idx >= rope.len_chars() {
+        return true;
+    }
+    let before = rope.char(char_idx - 1);
+    let after = rope.char(char_idx);
+    let indicators = if is_regional_indicator(before) {
+        rope.chars_at(char_idx)
+            .reversed()
+            .take_while(|&c| is_regional_indicator(c))
+            .count()
+    } else {
+        0
+    };
+    breaks(before, after, indicators)
+}
+
+/// `text` split into grapheme clusters, see `is_boundary`.
+pub fn clusters(text: &str) -> Vec<&str> {
+    let mut clusters = Vec::new();
+    let mut start = 0;
+    let mut previous: Option<char> = None;
+    // Regional indicators in a row up to `previous`
+    let mut indicators = 0;
+    for (index, c) in text.char_indices() {
+        if let Some(before) = previous
+            && breaks(before, c, indicators)
+        {
+            clusters.push(&text[start..index]);
+            start = index;
+        }
+        indicators = if is_regional_indicator(c) {
+            indicators + 1
+        } else {
+            0
+        };
+        previous = Some(c);
+    }
+    if start < text.len() {
+        clusters.push(&text[start..]);
+    }
+    clusters
+}
+
+/// Whether a cluster ends between `before` and `after`. `indicators` is how
+/// many regional indicators directly precede `after`, flags are pairs of them.
+fn breaks(before: char, after: char, indicators: usize) -> bool {
+    if before == '\r' && after == '\n' {
+        return false;
+    }
+    if before.is_control() || after.is_control() {
+        return true;
+    }
+    if let (Some(before), Some(after)) = (hangul(before), hangul(after))
+        && before.joins(after)
+    {
+        return false;
+    }
+    if is_extend(after) || after == ZWJ || is_spacing_mark(after) {
+        return false;
+    }
+    if before == ZWJ && is_pictographic(after) {
+        return false;
+    }
+    !(is_regional_indicator(after) && indicators % 2 == 1)
+}
+
+fn is_regional_indicator(c: char) -> bool {
+    matches!(c, '\u{1F1E6}'..='\u{1F1FF}')
+}
+
+fn is_extend(c: char) -> bool {
+    matches!(c,
+        '\u{0300}'..='\u{036F}' // Combining diacritical marks
+        | '\u{0483}'..='\u{0489}'
+        | '\u{0591}'..='\u{05BD}' | '\u{05BF}' | '\u{05C1}'..='\u{05C2}' | '\u{05C4}'..='\u{05C5}' | '\u{05C7}'
+        | '\u{0610}'..='\u{061A}' | '\u{064B}'..='\u{065F}' | '\u{0670}' | '\u{06D6}'..='\u{06DC}' | '\u{06DF}'..='\u{06E4}'
+        | '\u{0900}'..='\u{0902}' | '\u{093A}' | '\u{093C}' | '\u{0941}'..='\u{0948}' | '\u{094D}' | '\u{0951}'..='\u{0957}'
+        | '\u{0962}'..='\u{0963}'
+        | '\u{0E31}' | '\u{0E34}'..='\u{0E3A}' | '\u{0E47}'..='\u{0E4E}' // Thai
+        | '\u{1AB0}'..='\u{1AFF}'
+        | '\u{1DC0}'..='\u{1DFF}'
+        | '\u{200C}' // Zero width non-joiner
+        | '\u{20D0}'..='\u{20FF}' // Combining marks for symbols (keycaps)
+        | '\u{3099}'..='\u{309A}'
+        | '\u{FE00}'..='\u{FE0F}' // Variation selectors
+        | '\u{FE20}'..='\u{FE2F}'
+        | '\u{1F3FB}'..='\u{1F3FF}' // Skin tones
+        | '\u{E0020}'..='\u{E007F}' // Tags (subdivision flags)
+        | '\u{E0100}'..='\u{E01EF}'
+    )
+}
+
+fn is_spacing_mark(c: char) -> bool {
+    matches!(c,
+        '\u{0903}' | '\u{093B}' | '\u{093E}'..='\u{0940}' | '\u{0949}'..='\u{094C}' | '\u{094E}'..='\u{094F}'
+        | '\u{0E33}'
+    )
+}
+
+fn is_pictographic(c: char) -> bool {
+    matches!(c,
+        '\u{00A9}' | '\u{00AE}' | '\u{203C}' | '\u{2049}' | '\u{2122}' | '\u{2139}'
+        | '\u{2194}'..='\u{21AA}'
+        | '\u{231A}'..='\u{23FF}'
+        | '\u{25AA}'..='\u{25FE}'
+        | '\u{2600}'..='\u{27BF}'
+        | '\u{2934}'..='\u{2935}'
+        | '\u{2B05}'..='\u{2B55}'
+        | '\u{3030}' | '\u{303D}' | '\u{3297}' | '\u{3299}'
+        | '\u{1F000}'..='\u{1FAFF}'
+    )
+}
+
+/// The parts a Hangul syllable is made of.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Jamo {
+    Leading,
+    Vowel,
+    Trailing,
+    /// A precomposed syllable without a trailing consonant
+    Syllable,
+    /// A precomposed syllable with one
+    SyllableWithTrailing,
+}
+
+impl Jamo {
+    fn joins(self, after: Jamo) -> bool {
+        use Jamo::*;
+        match self {
+            Leading => after != Trailing,
+            Vowel | Syllable => matches!(after, Vowel | Trailing),
+            Trailing | SyllableWithTrailing => after == Trailing,
+        }
+    }
+}
+
+fn hangul(c: char) -> Option<Jamo> {
+    match c {
+        '\u{1100}'..='\u{115F}' | '\u{A960}'..='\u{A97C}' => Some(Jamo::Leading),
+        '\u{1160}'..='\u{11A7}' | '\u{D7B0}'..='\u{D7C6}' => Some(Jamo::Vowel),
+        '\u{11A8}'..='\u{11FF}' | '\u{D7CB}'..='\u{D7FB}' => Some(Jamo::Trailing),
+        '\u{AC00}'..='\u{D7A3}' if (c as u32 - 0xAC00).is_multiple_of(28) => Some(Jamo::Syllable),
+        '\u{AC00}'..='\u{D7A3}' => Some(Jamo::SyllableWithTrailing),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clusters_keep_sequences_together() {
+        let family = "👨\u{200D}👩\u{200D}👧\u{200D}👦";
+        assert_eq!(clusters(family), vec![family]);
+        assert_eq!(clusters("a👍🏽b"), vec!["a", "👍🏽", "b"]);
+        assert_eq!(clusters("🇩🇪🇫🇷🇮"), vec!["🇩🇪", "🇫🇷", "🇮"]);
+        assert_eq!(clusters("e\u{301}x\r\n"), vec!["e\u{301}", "x", "\r\n"]);
+        assert_eq!(clusters("한국"), vec!["한", "국"]);
+        assert_eq!(
+            clusters("\u{1100}\u{1161}\u{11A8}"),
+            vec!["\u{1100}\u{1161}\u{11A8}"]
+        );
+        assert!(clusters("").is_empty());
+    }
+
+    #[test]
+    fn test_boundaries_in_a_rope() {
+        let rope = Rope::from_str("a🇩🇪🇫🇷\u{301}");
+        let boundaries: Vec<usize> = (0..=rope.len_chars())
+            .filter(|&i| is_boundary(&rope, i))
+            .collect();
+        // A combining mark after a flag still belongs to it
+        assert_eq!(boundaries, vec![0, 1, 3, 6]);
+    }
+}
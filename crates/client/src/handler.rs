@@ -1,40 +1,209 @@
+use std::collections::HashMap;
+
 use crate::core::Event;
 use crate::logger;
 use crate::lsp::{
-    self, CursorPositionParams, DidChangeParams, DidCloseParams, DidOpenParams, LspHeader,
-    Position, TextEdit,
+    self, ApplyWorkspaceEditResult, CheckpointParams, CursorPositionParams, DidChangeParams,
+    DidCloseParams, DidOpenParams, DidSaveParams, FileOperationParams, FollowParams, JoinParams,
+    LspHeader, Position, RenameFilesParams, ResyncParams, SelectionParams, TextEdit,
+    ViewportParams,
 };
+use crate::state::FileStatus;
 use serde_json::json;
-use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::sync::mpsc;
 
 #[derive(Debug)]
 pub enum EditorCommand {
-    ApplyEdits { uri: String, edits: Vec<TextEdit> },
-    RemoteCursor { uri: String, position: Position },
+    ApplyEdits {
+        uri: String,
+        edits: Vec<TextEdit>,
+    },
+    /// `None` removes the cursor of `agent_id`.
+    RemoteCursor {
+        uri: String,
+        agent_id: String,
+        display_name: String,
+        position: Option<Position>,
+    },
+    /// Replaces the selection shown for `agent_id`, `None` removes it.
+    RemoteSelection {
+        uri: String,
+        agent_id: String,
+        display_name: String,
+        range: Option<lsp::Range>,
+    },
+    /// Replaces the range claimed by `agent_id`, `None` releases it.
+    RemoteLock {
+        uri: String,
+        agent_id: String,
+        display_name: String,
+        range: Option<lsp::Range>,
+    },
+    /// `agent_id` sent the latest change to `uri`, for showing who is editing it.
+    Attribution {
+        uri: String,
+        agent_id: String,
+        display_name: String,
+    },
+    /// Scroll to `line` of `uri`, opening it if needed (follow mode).
+    Reveal {
+        uri: String,
+        line: usize,
+    },
+    /// Tell the user something, see `notify_editor`.
+    ShowMessage {
+        kind: MessageKind,
+        message: String,
+    },
+    /// Answer to a `$/justsync/status` request. `files: None` means no peer to compare with.
+    Status {
+        id: serde_json::Value,
+        files: Option<Vec<FileStatus>>,
+    },
+    /// Answer to a `$/justsync/resync` request, `resynced: false` when we don't know the file.
+    Resynced {
+        id: serde_json::Value,
+        resynced: bool,
+    },
+}
+
+/// How serious a message for the user is, the LSP `MessageType`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    Error = 1,
+    Warning = 2,
+    Info = 3,
+    /// Only goes to the editor's log, nothing pops up
+    Log = 4,
+}
+
+/// Editor-side bookkeeping that lives next to the stdio loop.
+#[derive(Debug, Default)]
+pub struct EditorState {
+    /// Whether the editor accepts versioned `documentChanges` edits.
+    pub use_document_changes: bool,
+    /// Latest document version reported by the editor, keyed by relative URI.
+    pub versions: HashMap<String, i32>,
+    /// In-flight `workspace/applyEdit` requests: request id -> (URI, version sent, if any).
+    pending_edits: HashMap<i64, (String, Option<i32>)>,
+    /// Refused edits in a row, per URI. We stop retrying at `MAX_EDIT_RETRIES`.
+    rejections: HashMap<String, u32>,
+    /// Where `next_request_id` was when the editor last reported a version, per URI.
+    /// Edits sent before that don't get to roll the version back.
+    reported_at: HashMap<String, i64>,
+    next_request_id: i64,
+}
+
+/// How often in a row we re-send an update the editor refused.
+pub const MAX_EDIT_RETRIES: u32 = 3;
+
+impl EditorState {
+    fn track_version(&mut self, uri: &str, version: i32) {
+        self.versions.insert(uri.to_string(), version);
+        self.reported_at
+            .insert(uri.to_string(), self.next_request_id);
+    }
+
+    /// The editor confirmed one of our edits. We already counted it when sending,
+    /// but a `didChange` may have moved the version since, hence the `max`.
+    fn confirm_edit(&mut self, request_id: i64) {
+        let Some((uri, sent_version)) = self.pending_edits.remove(&request_id) else {
+            return;
+        };
+        self.rejections.remove(&uri);
+        if let Some(sent_version) = sent_version {
+            let version = self.versions.entry(uri).or_insert(sent_version);
+            *version = (*version).max(sent_version + 1);
+        }
+    }
+
+    /// The editor refused one of our edits. Returns the URI if it's worth another try.
+    fn reject_edit(&mut self, request_id: i64) -> Option<String> {
+        let (uri, sent_version) = self.pending_edits.remove(&request_id)?;
+        // The editor is still where it was, unless it told us otherwise since
+        let reported_since = self
+            .reported_at
+            .get(&uri)
+            .is_some_and(|&at| at > request_id);
+        if let Some(sent_version) = sent_version
+            && !reported_since
+        {
+            let version = self.versions.entry(uri.clone()).or_insert(sent_version);
+            *version = (*version).min(sent_version);
+        }
+        let rejections = self.rejections.entry(uri.clone()).or_default();
+        *rejections += 1;
+        if *rejections > MAX_EDIT_RETRIES {
+            logger::warn(&format!(
+                "!! [Handler] Editor keeps refusing updates for {}, giving up until it takes one",
+                uri
+            ));
+            return None;
+        }
+        Some(uri)
+    }
 }
 
 /// The main IO loop for the Editor.
 /// It bridges the gap between "JSON on Stdin" and "Events in Rust Channels".
-pub async fn run(core_tx: mpsc::Sender<Event>, mut editor_rx: mpsc::Receiver<EditorCommand>) {
+/// Messages from the editor above `max_message_len` bytes end the session.
+pub async fn run(
+    core_tx: mpsc::Sender<Event>,
+    editor_rx: mpsc::Receiver<EditorCommand>,
+    max_message_len: usize,
+) {
     // Setup Stdin/Stdout
-    let stdin = tokio::io::stdin();
-    let mut reader = BufReader::new(stdin);
-    let mut stdout = tokio::io::stdout();
+    let reader = BufReader::new(tokio::io::stdin());
+    serve(
+        reader,
+        tokio::io::stdout(),
+        core_tx,
+        editor_rx,
+        max_message_len,
+    )
+    .await;
+}
 
+/// `run` on any pair of streams.
+async fn serve<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    mut reader: BufReader<R>,
+    mut stdout: W,
+    core_tx: mpsc::Sender<Event>,
+    mut editor_rx: mpsc::Receiver<EditorCommand>,
+    max_message_len: usize,
+) {
     // Initial Handshake (blocking/sequential part)
     // We need to establish the "root" and tell the editor we are ready.
-    let (root_dir, _) = perform_initialization_handshake(&mut reader, &mut stdout).await;
+    let (root_dir, capabilities) =
+        match perform_initialization_handshake(&mut reader, &mut stdout, max_message_len).await {
+            Ok(init) => init,
+            Err(e) => {
+                logger::warn(&format!(
+                    "!! [Handler] Handshake with the editor failed: {}",
+                    e
+                ));
+                let _ = core_tx.send(Event::Shutdown).await;
+                return;
+            }
+        };
+    let mut state = EditorState {
+        use_document_changes: capabilities.supports_document_changes(),
+        ..Default::default()
+    };
+    let _ = core_tx.send(Event::EditorReady).await;
 
     // The Main Event Loop
     loop {
         tokio::select! {
             // --- INBOUND: From Editor (User Typed) ---
-            read_res = lsp::read_message(&mut reader) => {
+            read_res = lsp::read_message(&mut reader, max_message_len) => {
                 match read_res {
                     Ok(Some(body)) => {
                         // Parse JSON and convert to Event
-                        process_editor_message(&body, &core_tx, &root_dir).await;
+                        if let Some(reply) = process_editor_message(&body, &core_tx, &root_dir, &mut state).await {
+                            write_rpc(&mut stdout, &reply.to_string()).await;
+                        }
                     }
                     Ok(None) => {
                         // EOF: Editor closed the pipe. We shut down.
@@ -42,7 +211,10 @@ pub async fn run(core_tx: mpsc::Sender<Event>, mut editor_rx: mpsc::Receiver<Edi
                         break;
                     }
                     Err(e) => {
+                        // E.g. the editor died mid-message. Nothing of the message
+                        // was applied, it is only ever handled once read whole.
                         eprintln!("!! Stdin Error: {}", e);
+                        let _ = core_tx.send(Event::Shutdown).await;
                         break;
                     }
                 }
@@ -52,10 +224,32 @@ pub async fn run(core_tx: mpsc::Sender<Event>, mut editor_rx: mpsc::Receiver<Edi
             Some(cmd) = editor_rx.recv() => {
                 match cmd {
                     EditorCommand::ApplyEdits { uri, edits } => {
-                         send_edits_to_editor(&mut stdout, &uri, edits, &root_dir).await;
+                         send_edits_to_editor(&mut stdout, &mut state, &uri, edits, &root_dir).await;
+                    }
+                    EditorCommand::RemoteCursor { uri, agent_id, display_name, position } => {
+                        send_cursor_to_editor(&mut stdout, &uri, &agent_id, &display_name, position, &root_dir).await;
+                    }
+                    EditorCommand::RemoteSelection { uri, agent_id, display_name, range } => {
+                        send_range_to_editor(&mut stdout, "$/justsync/selection", &uri, &agent_id, &display_name, range, &root_dir).await;
+                    }
+                    EditorCommand::RemoteLock { uri, agent_id, display_name, range } => {
+                        send_range_to_editor(&mut stdout, "$/justsync/lock", &uri, &agent_id, &display_name, range, &root_dir).await;
+                    }
+                    EditorCommand::Attribution { uri, agent_id, display_name } => {
+                        send_attribution_to_editor(&mut stdout, &uri, &agent_id, &display_name, &root_dir).await;
+                    }
+                    EditorCommand::Reveal { uri, line } => {
+                        send_reveal_to_editor(&mut stdout, &uri, line, &root_dir).await;
                     }
-                    EditorCommand::RemoteCursor { uri, position } => {
-                        send_cursor_to_editor(&mut stdout, &uri, position, &root_dir).await;
+                    EditorCommand::ShowMessage { kind, message } => {
+                        notify_editor(&mut stdout, kind, &message).await;
+                    }
+                    EditorCommand::Status { id, files } => {
+                        send_status_to_editor(&mut stdout, id, files, &root_dir).await;
+                    }
+                    EditorCommand::Resynced { id, resynced } => {
+                        let msg = json!({ "jsonrpc": "2.0", "id": id, "result": { "resynced": resynced } });
+                        write_rpc(&mut stdout, &msg.to_string()).await;
                     }
                 }
             }
@@ -63,97 +257,375 @@ pub async fn run(core_tx: mpsc::Sender<Event>, mut editor_rx: mpsc::Receiver<Edi
     }
 }
 
-async fn process_editor_message(body: &str, tx: &mpsc::Sender<Event>, root_dir: &str) {
-    if let Ok(header) = serde_json::from_str::<LspHeader>(body) {
-        if let Some(method) = header.method {
-            logger::log(&format!(">> [Handler] Method: {}", method));
-            match method.as_str() {
-                "textDocument/didOpen" => {
-                    if let Some(params_val) = header.params {
-                        if let Ok(params) = serde_json::from_value::<DidOpenParams>(params_val) {
-                            let uri =
-                                crate::fs::to_relative_path(&params.text_document.uri, root_dir);
-
-                            logger::log(&format!(">> [Handler] didOpen URI: '{}'", uri));
-
-                            if uri.is_empty() || uri == "/" {
-                                return;
-                            }
-
-                            // Convert to Event
-                            let event = Event::ClientDidOpen {
-                                uri,
-                                content: params.text_document.text,
-                            };
-                            let _ = tx.send(event).await;
-                        }
+/// Turns one message from the editor into events for the core. Returns the
+/// answer to a request nobody else will answer, so the editor isn't left waiting.
+async fn process_editor_message(
+    body: &str,
+    tx: &mpsc::Sender<Event>,
+    root_dir: &str,
+    state: &mut EditorState,
+) -> Option<serde_json::Value> {
+    let Ok(header) = serde_json::from_str::<LspHeader>(body) else {
+        return None;
+    };
+
+    let Some(method) = header.method else {
+        // A response. Only the ids we handed out for `workspace/applyEdit` are
+        // ours, anything else is dropped without touching the pending edits.
+        // An error response counts as refused.
+        let Some(id) = header
+            .id
+            .as_ref()
+            .and_then(|v| v.as_i64())
+            .filter(|id| state.pending_edits.contains_key(id))
+        else {
+            logger::log(&format!(
+                ">> [Handler] Ignoring response to unknown request {:?}",
+                header.id
+            ));
+            return None;
+        };
+        let applied = header
+            .result
+            .and_then(|result| serde_json::from_value::<ApplyWorkspaceEditResult>(result).ok())
+            .is_some_and(|res| res.applied);
+        if applied {
+            state.confirm_edit(id);
+        } else if let Some(uri) = state.reject_edit(id) {
+            let _ = tx.send(Event::EditRejected { uri }).await;
+        }
+        return None;
+    };
+
+    logger::log(&format!(">> [Handler] Method: {}", method));
+    match method.as_str() {
+        "textDocument/didOpen" => {
+            if let Some(params_val) = header.params {
+                if let Ok(params) = serde_json::from_value::<DidOpenParams>(params_val) {
+                    let Some(uri) = crate::fs::normalize_uri(&params.text_document.uri, root_dir)
+                    else {
+                        return outside_project(header.id, &params.text_document.uri);
+                    };
+
+                    logger::log(&format!(">> [Handler] didOpen URI: '{}'", uri));
+
+                    if let Some(version) = params.text_document.version {
+                        state.track_version(&uri, version);
                     }
+
+                    // Convert to Event
+                    let event = Event::ClientDidOpen {
+                        uri,
+                        content: params.text_document.text,
+                    };
+                    let _ = tx.send(event).await;
                 }
-                "textDocument/didChange" => {
-                    if let Some(params_val) = header.params {
-                        if let Ok(params) = serde_json::from_value::<DidChangeParams>(params_val) {
-                            let uri =
-                                crate::fs::to_relative_path(&params.text_document.uri, root_dir);
-
-                            logger::log(&format!(">> [Handler] didChange URI: '{}'", uri));
-
-                            if uri.is_empty() || uri == "/" {
-                                return;
-                            }
-
-                            // Convert to Event
-                            let event = Event::LocalChange {
-                                uri,
-                                changes: params.content_changes,
-                            };
-                            let _ = tx.send(event).await;
-                        }
-                    }
+            }
+        }
+        "textDocument/didChange" => {
+            if let Some(params_val) = header.params {
+                if let Ok(params) = serde_json::from_value::<DidChangeParams>(params_val) {
+                    let Some(uri) = crate::fs::normalize_uri(&params.text_document.uri, root_dir)
+                    else {
+                        return outside_project(header.id, &params.text_document.uri);
+                    };
+
+                    logger::log(&format!(">> [Handler] didChange URI: '{}'", uri));
+
+                    state.track_version(&uri, params.text_document.version);
+
+                    // Convert to Event
+                    let event = Event::LocalChange {
+                        uri,
+                        changes: params.content_changes,
+                    };
+                    let _ = tx.send(event).await;
                 }
-                "textDocument/didClose" => {
-                    if let Some(params_val) = header.params {
-                        if let Ok(params) = serde_json::from_value::<DidCloseParams>(params_val) {
-                            let uri =
-                                crate::fs::to_relative_path(&params.text_document.uri, root_dir);
-                            let _ = tx.send(Event::ClientDidClose { uri }).await;
-                        }
-                    }
+            }
+        }
+        "textDocument/didClose" => {
+            if let Some(params_val) = header.params {
+                if let Ok(params) = serde_json::from_value::<DidCloseParams>(params_val) {
+                    let Some(uri) = crate::fs::normalize_uri(&params.text_document.uri, root_dir)
+                    else {
+                        return outside_project(header.id, &params.text_document.uri);
+                    };
+                    state.versions.remove(&uri);
+                    let _ = tx.send(Event::ClientDidClose { uri }).await;
                 }
-                "$/justsync/cursor" => {
-                    if let Some(params_val) = header.params {
-                        if let Ok(params) =
-                            serde_json::from_value::<CursorPositionParams>(params_val)
-                        {
-                            let uri =
-                                crate::fs::to_relative_path(&params.text_document.uri, root_dir);
-                            let _ = tx
-                                .send(Event::LocalCursorChange {
-                                    uri,
-                                    position: params.position,
-                                })
-                                .await;
-                        }
+            }
+        }
+        "textDocument/didSave" => {
+            if let Some(params_val) = header.params
+                && let Ok(params) = serde_json::from_value::<DidSaveParams>(params_val)
+            {
+                let Some(uri) = crate::fs::normalize_uri(&params.text_document.uri, root_dir)
+                else {
+                    return outside_project(header.id, &params.text_document.uri);
+                };
+                let _ = tx.send(Event::ClientDidSave { uri }).await;
+            }
+        }
+        "workspace/didCreateFiles" => {
+            if let Some(params_val) = header.params
+                && let Ok(params) = serde_json::from_value::<FileOperationParams>(params_val)
+            {
+                for file in params.files {
+                    let Some(uri) = crate::fs::normalize_uri(&file.uri, root_dir) else {
+                        continue;
+                    };
+                    let _ = tx.send(Event::LocalFileCreated { uri }).await;
+                }
+            }
+        }
+        "workspace/didDeleteFiles" => {
+            if let Some(params_val) = header.params
+                && let Ok(params) = serde_json::from_value::<FileOperationParams>(params_val)
+            {
+                for file in params.files {
+                    let Some(uri) = crate::fs::normalize_uri(&file.uri, root_dir) else {
+                        continue;
+                    };
+                    state.versions.remove(&uri);
+                    let _ = tx.send(Event::LocalFileDeleted { uri }).await;
+                }
+            }
+        }
+        "workspace/didRenameFiles" => {
+            if let Some(params_val) = header.params
+                && let Ok(params) = serde_json::from_value::<RenameFilesParams>(params_val)
+            {
+                for file in params.files {
+                    let (Some(from), Some(to)) = (
+                        crate::fs::normalize_uri(&file.old_uri, root_dir),
+                        crate::fs::normalize_uri(&file.new_uri, root_dir),
+                    ) else {
+                        continue;
+                    };
+                    // Same buffer under a new name, its version carries over
+                    if let Some(version) = state.versions.remove(&from) {
+                        state.versions.insert(to.clone(), version);
                     }
+                    let _ = tx.send(Event::LocalFileRenamed { from, to }).await;
+                }
+            }
+        }
+        "$/justsync/cursor" => {
+            if let Some(params_val) = header.params {
+                if let Ok(params) = serde_json::from_value::<CursorPositionParams>(params_val) {
+                    let Some(uri) = crate::fs::normalize_uri(&params.text_document.uri, root_dir)
+                    else {
+                        return outside_project(header.id, &params.text_document.uri);
+                    };
+                    let _ = tx
+                        .send(Event::LocalCursorChange {
+                            uri,
+                            position: params.position,
+                        })
+                        .await;
                 }
-                _ => { /* Ignore other LSP messages */ }
+            }
+        }
+        // Selections, both directions use `$/justsync/selection`:
+        //   editor -> us  { textDocument: { uri }, range }, `range: null` when nothing is selected
+        //   us -> editor  { uri, agentId, displayName, range, color }
+        //                 draw `range` in `color` ("#rrggbb", the same for an agent everywhere),
+        //                 replacing that agent's previous selection. `range: null` removes it.
+        "$/justsync/selection" => {
+            if let Some(params_val) = header.params
+                && let Ok(params) = serde_json::from_value::<SelectionParams>(params_val)
+            {
+                let Some(uri) = crate::fs::normalize_uri(&params.text_document.uri, root_dir)
+                else {
+                    return outside_project(header.id, &params.text_document.uri);
+                };
+                let _ = tx
+                    .send(Event::LocalSelectionChange {
+                        uri,
+                        range: params.range,
+                    })
+                    .await;
+            }
+        }
+        // Locks, both directions use `$/justsync/lock`, shaped like selections:
+        //   editor -> us  { textDocument: { uri }, range }, `range: null` releases it
+        //   us -> editor  { uri, agentId, displayName, range, color }
+        //                 mark `range` as claimed by that agent, replacing its previous
+        //                 claim on `uri`. `range: null` removes it. Advisory only, edits
+        //                 inside still merge (we warn the user once per claim).
+        "$/justsync/lock" => {
+            if let Some(params_val) = header.params
+                && let Ok(params) = serde_json::from_value::<SelectionParams>(params_val)
+            {
+                let Some(uri) = crate::fs::normalize_uri(&params.text_document.uri, root_dir)
+                else {
+                    return outside_project(header.id, &params.text_document.uri);
+                };
+                let _ = tx
+                    .send(Event::LocalLock {
+                        uri,
+                        range: params.range,
+                    })
+                    .await;
+            }
+        }
+        // Follow mode, all notifications:
+        //   editor -> us  `$/justsync/viewport` { textDocument: { uri }, topLine }
+        //                 whenever the first visible line changes
+        //   editor -> us  `$/justsync/follow` { agentId }, or `{ agentId: null }` to stop
+        //   us -> editor  `$/justsync/reveal` { uri, line }
+        //                 open `uri` if it isn't and scroll `line` to the top, never edit anything
+        // The agent ids to follow are the ones `$/justsync/remoteCursor` reports.
+        "$/justsync/viewport" => {
+            if let Some(params_val) = header.params
+                && let Ok(params) = serde_json::from_value::<ViewportParams>(params_val)
+            {
+                let Some(uri) = crate::fs::normalize_uri(&params.text_document.uri, root_dir)
+                else {
+                    return outside_project(header.id, &params.text_document.uri);
+                };
+                let _ = tx
+                    .send(Event::LocalViewportChange {
+                        uri,
+                        top_line: params.top_line,
+                    })
+                    .await;
+            }
+        }
+        "$/justsync/follow" => {
+            if let Some(params_val) = header.params
+                && let Ok(params) = serde_json::from_value::<FollowParams>(params_val)
+            {
+                let _ = tx
+                    .send(Event::Follow {
+                        agent_id: params.agent_id,
+                    })
+                    .await;
+            }
+        }
+        // Joining a session, a notification:
+        //   editor -> us  `$/justsync/join` { ip, token }
+        //                 leave the current host (if any) and connect to this one. `ip` may
+        //                 leave out the port, `--port` is used then. Peers only.
+        "$/justsync/join" => {
+            if let Some(params_val) = header.params
+                && let Ok(params) = serde_json::from_value::<JoinParams>(params_val)
+            {
+                let _ = tx
+                    .send(Event::JoinSession {
+                        addr: params.ip,
+                        token: params.token,
+                    })
+                    .await;
+            }
+        }
+        // Sync status, a request:
+        //   editor -> us  `$/justsync/status` (no params)
+        //   us -> editor  { connected, inSync, files: [{ uri, behind, ahead }] }
+        //                 `behind`/`ahead` count edits (ops) the other side has and we don't,
+        //                 and the other way round. Only documents that differ are listed.
+        "$/justsync/status" => {
+            if let Some(id) = header.id {
+                let _ = tx.send(Event::StatusRequest { id }).await;
+            }
+        }
+        // Starting a document over, a request:
+        //   editor -> us  `$/justsync/resync` { textDocument: { uri } }
+        //   us -> editor  `workspace/applyEdit` replacing the whole buffer, then { resynced }
+        //                 For a buffer that drifted from the session and can't be trusted
+        //                 with incremental edits any more.
+        "$/justsync/resync" => {
+            let id = header.id?;
+            let params = header
+                .params
+                .and_then(|params| serde_json::from_value::<ResyncParams>(params).ok());
+            let Some(params) = params else {
+                return Some(json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "error": { "code": INVALID_PARAMS, "message": "expected { textDocument: { uri } }" }
+                }));
+            };
+            // A request still gets its answer: no such document, nothing resynced
+            let uri =
+                crate::fs::normalize_uri(&params.text_document.uri, root_dir).unwrap_or_default();
+            let _ = tx.send(Event::ResyncRequest { uri, id }).await;
+        }
+        // Checkpoints, both notifications:
+        //   editor -> us  `$/justsync/checkpoint` { textDocument: { uri }, label }
+        //                 remember the document's current version as `label`
+        //   editor -> us  `$/justsync/rollback` { textDocument: { uri }, label }
+        //                 undo everything since, for everyone. Arrives as a
+        //                 `workspace/applyEdit` like any remote change.
+        "$/justsync/checkpoint" | "$/justsync/rollback" => {
+            if let Some(params_val) = header.params
+                && let Ok(params) = serde_json::from_value::<CheckpointParams>(params_val)
+            {
+                let Some(uri) = crate::fs::normalize_uri(&params.text_document.uri, root_dir)
+                else {
+                    return outside_project(header.id, &params.text_document.uri);
+                };
+                let label = params.label;
+                let event = if method == "$/justsync/checkpoint" {
+                    Event::Checkpoint { uri, label }
+                } else {
+                    Event::Rollback { uri, label }
+                };
+                let _ = tx.send(event).await;
+            }
+        }
+        // The core answers `$/justsync/status` and `$/justsync/resync` later,
+        // but they can't be cancelled: they run to completion and still get
+        // their response. Everything else is answered right away.
+        "$/cancelRequest" => {}
+        "shutdown" => {
+            return header
+                .id
+                .map(|id| json!({ "jsonrpc": "2.0", "id": id, "result": null }));
+        }
+        _ => {
+            // Other notifications are none of our business, but a request
+            // always gets an answer
+            if let Some(id) = header.id {
+                return Some(json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "error": { "code": -32601, "message": format!("Unsupported method: {}", method) }
+                }));
             }
         }
     }
+    None
 }
 
-async fn send_cursor_to_editor(
-    stdout: &mut tokio::io::Stdout,
+/// The answer to a request about a file outside the project, `None` for a notification.
+fn outside_project(id: Option<serde_json::Value>, uri: &str) -> Option<serde_json::Value> {
+    let id = id?;
+    Some(json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": INVALID_PARAMS, "message": format!("{} is outside the project", uri) }
+    }))
+}
+
+async fn send_cursor_to_editor<W: AsyncWrite + Unpin>(
+    stdout: &mut W,
     uri: &str,
-    position: Position,
+    agent_id: &str,
+    display_name: &str,
+    position: Option<Position>,
     root_dir: &str,
 ) {
     let abs_uri = crate::fs::to_absolute_uri(uri, root_dir);
 
+    // A null position tells the editor to drop that agent's marker
     let msg = json!({
         "jsonrpc": "2.0",
         "method": "$/justsync/remoteCursor",
         "params": {
             "uri": abs_uri,
+            "agentId": agent_id,
+            "displayName": display_name,
             "position": position
         }
     });
@@ -161,8 +633,139 @@ async fn send_cursor_to_editor(
     write_rpc(stdout, &msg.to_string()).await;
 }
 
-async fn send_edits_to_editor(
-    stdout: &mut tokio::io::Stdout,
+/// Colors readable on light and dark themes alike.
+const AGENT_COLORS: [&str; 8] = [
+    "#e06c75", "#61afef", "#98c379", "#c678dd", "#e5c07b", "#56b6c2", "#d19a66", "#be5046",
+];
+
+/// The color an agent is drawn in. Derived from the id with FNV-1a, so every
+/// peer picks the same one without having to agree on anything.
+pub fn agent_color(agent_id: &str) -> &'static str {
+    let hash = agent_id
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+        });
+    AGENT_COLORS[(hash % AGENT_COLORS.len() as u64) as usize]
+}
+
+/// `$/justsync/selection` or `$/justsync/lock`, they look the same.
+async fn send_range_to_editor<W: AsyncWrite + Unpin>(
+    stdout: &mut W,
+    method: &str,
+    uri: &str,
+    agent_id: &str,
+    display_name: &str,
+    range: Option<lsp::Range>,
+    root_dir: &str,
+) {
+    let msg = json!({
+        "jsonrpc": "2.0",
+        "method": method,
+        "params": {
+            "uri": crate::fs::to_absolute_uri(uri, root_dir),
+            "agentId": agent_id,
+            "displayName": display_name,
+            "range": range,
+            "color": agent_color(agent_id)
+        }
+    });
+
+    write_rpc(stdout, &msg.to_string()).await;
+}
+
+async fn send_status_to_editor<W: AsyncWrite + Unpin>(
+    stdout: &mut W,
+    id: serde_json::Value,
+    files: Option<Vec<FileStatus>>,
+    root_dir: &str,
+) {
+    let connected = files.is_some();
+    let files: Vec<serde_json::Value> = files
+        .unwrap_or_default()
+        .into_iter()
+        .map(|file| {
+            json!({
+                "uri": crate::fs::to_absolute_uri(&file.uri, root_dir),
+                "behind": file.behind,
+                "ahead": file.ahead
+            })
+        })
+        .collect();
+    let msg = json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "result": {
+            "connected": connected,
+            "inSync": connected && files.is_empty(),
+            "files": files
+        }
+    });
+
+    write_rpc(stdout, &msg.to_string()).await;
+}
+
+async fn send_attribution_to_editor<W: AsyncWrite + Unpin>(
+    stdout: &mut W,
+    uri: &str,
+    agent_id: &str,
+    display_name: &str,
+    root_dir: &str,
+) {
+    let msg = json!({
+        "jsonrpc": "2.0",
+        "method": "$/justsync/attribution",
+        "params": {
+            "uri": crate::fs::to_absolute_uri(uri, root_dir),
+            "agentId": agent_id,
+            "displayName": display_name,
+            "color": agent_color(agent_id)
+        }
+    });
+
+    write_rpc(stdout, &msg.to_string()).await;
+}
+
+async fn send_reveal_to_editor<W: AsyncWrite + Unpin>(
+    stdout: &mut W,
+    uri: &str,
+    line: usize,
+    root_dir: &str,
+) {
+    let msg = json!({
+        "jsonrpc": "2.0",
+        "method": "$/justsync/reveal",
+        "params": {
+            "uri": crate::fs::to_absolute_uri(uri, root_dir),
+            "line": line
+        }
+    });
+
+    write_rpc(stdout, &msg.to_string()).await;
+}
+
+/// Pops up a message in the editor (`window/showMessage`), or only logs it
+/// there (`window/logMessage`) for `MessageKind::Log`.
+pub async fn notify_editor<W: AsyncWrite + Unpin>(stdout: &mut W, kind: MessageKind, text: &str) {
+    let method = match kind {
+        MessageKind::Log => "window/logMessage",
+        _ => "window/showMessage",
+    };
+    let msg = json!({
+        "jsonrpc": "2.0",
+        "method": method,
+        "params": {
+            "type": kind as u8,
+            "message": text
+        }
+    });
+
+    write_rpc(stdout, &msg.to_string()).await;
+}
+
+async fn send_edits_to_editor<W: AsyncWrite + Unpin>(
+    stdout: &mut W,
+    state: &mut EditorState,
     uri: &str,
     edits: Vec<TextEdit>,
     root_dir: &str,
@@ -172,17 +775,47 @@ async fn send_edits_to_editor(
     }
 
     let abs_uri = crate::fs::to_absolute_uri(uri, root_dir);
-    let mut changes = serde_json::Map::new();
-    changes.insert(abs_uri, serde_json::to_value(edits).unwrap());
+    let edits = serde_json::to_value(edits).unwrap();
+
+    let request_id = state.next_request_id;
+    state.next_request_id += 1;
+
+    // Strict editors reject edits against a stale version, so prefer the
+    // versioned form whenever the editor supports it and we know its version.
+    let tracked_version = state.versions.get(uri).copied();
+    let versioned = tracked_version.filter(|_| state.use_document_changes);
+    state
+        .pending_edits
+        .insert(request_id, (uri.to_string(), versioned));
+    // The next edit may go out before the editor answers this one, it has to
+    // target the version this one will produce
+    if let Some(version) = versioned {
+        state.versions.insert(uri.to_string(), version + 1);
+    }
+    let edit = match versioned {
+        Some(version) => {
+            json!({
+                "documentChanges": [{
+                    "textDocument": { "uri": abs_uri, "version": version },
+                    "edits": edits
+                }]
+            })
+        }
+        _ => {
+            let mut changes = serde_json::Map::new();
+            changes.insert(abs_uri, edits);
+            json!({ "changes": changes })
+        }
+    };
 
     // Construct the workspace/applyEdit JSON
     let msg = json!({
         "jsonrpc": "2.0",
-        "id": 1,
+        "id": request_id,
         "method": "workspace/applyEdit",
         "params": {
             "label": "JustSync Remote Update",
-            "edit": { "changes": changes }
+            "edit": edit
         }
     });
 
@@ -190,43 +823,143 @@ async fn send_edits_to_editor(
 }
 
 // Simple helper to write Content-Length headers
-async fn write_rpc(stdout: &mut tokio::io::Stdout, msg: &str) {
+async fn write_rpc<W: AsyncWrite + Unpin>(stdout: &mut W, msg: &str) {
     let _ = stdout
         .write_all(format!("Content-Length: {}\r\n\r\n{}", msg.len(), msg).as_bytes())
         .await;
     let _ = stdout.flush().await;
 }
 
-async fn perform_initialization_handshake(
-    reader: &mut BufReader<tokio::io::Stdin>,
-    stdout: &mut tokio::io::Stdout,
-) -> (String, ()) {
-    // Wait for "initialize" request
-    let body = lsp::read_message(reader)
-        .await
-        .expect("Failed to read init")
-        .unwrap();
-    let header: LspHeader = serde_json::from_str(&body).unwrap();
-
-    // Extract Root URI
-    let params: crate::lsp::InitializeParams =
-        serde_json::from_value(header.params.unwrap()).unwrap();
-    let raw_root = params.root_uri.unwrap_or_else(|| ".".to_string());
-    let root_dir = raw_root.replace("file://", "");
-
-    // Send "initialize" response
+/// JSON-RPC error codes we answer the handshake (and bad requests) with.
+const INVALID_PARAMS: i32 = -32602;
+const SERVER_NOT_INITIALIZED: i32 = -32002;
+
+async fn send_error_response<W: AsyncWrite + Unpin>(
+    stdout: &mut W,
+    id: Option<serde_json::Value>,
+    code: i32,
+    message: &str,
+) {
+    let response = json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message }
+    });
+    write_rpc(stdout, &response.to_string()).await;
+}
+
+/// Waits for `initialize` and answers it. Returns the project root and what the
+/// editor can do. On error the editor already got an error response (if it
+/// asked for something) and the daemon should stop.
+async fn perform_initialization_handshake<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    reader: &mut BufReader<R>,
+    stdout: &mut W,
+    max_message_len: usize,
+) -> anyhow::Result<(String, lsp::ClientCapabilities)> {
+    // Wait for "initialize" request, anything before it is premature
+    let header = loop {
+        let body = lsp::read_message(reader, max_message_len)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Editor closed the connection before initialize"))?;
+        let Ok(header) = serde_json::from_str::<LspHeader>(&body) else {
+            logger::warn("!! [Handler] Ignoring unparsable message before initialize");
+            continue;
+        };
+        match header.method.as_deref() {
+            Some("initialize") => break header,
+            Some("exit") => anyhow::bail!("Editor exited before initialize"),
+            _ if header.id.is_some() => {
+                send_error_response(
+                    stdout,
+                    header.id,
+                    SERVER_NOT_INITIALIZED,
+                    "Server not initialized",
+                )
+                .await;
+            }
+            // Notifications before initialize are dropped, as the spec says
+            _ => {}
+        }
+    };
+
+    let params = header
+        .params
+        .ok_or_else(|| "missing params".to_string())
+        .and_then(|params| {
+            serde_json::from_value::<lsp::InitializeParams>(params).map_err(|e| e.to_string())
+        });
+    let params = match params {
+        Ok(params) => params,
+        Err(e) => {
+            send_error_response(
+                stdout,
+                header.id,
+                INVALID_PARAMS,
+                &format!("Invalid params: {}", e),
+            )
+            .await;
+            anyhow::bail!("Invalid initialize params: {}", e);
+        }
+    };
+
+    // Extract Root URI, editors without a workspace (or with a broken one) get
+    // the directory we run in
+    let cwd = || {
+        std::env::current_dir()
+            .map(|dir| dir.to_string_lossy().into_owned())
+            .unwrap_or_else(|_| ".".to_string())
+    };
+    let mut bad_root = None;
+    let root_dir = match params.root_uri {
+        Some(raw_root) => crate::fs::project_root(&raw_root).unwrap_or_else(|e| {
+            let fallback = cwd();
+            logger::warn(&format!(
+                "!! [Handler] Root {} {}, using {}",
+                raw_root, e, fallback
+            ));
+            bad_root = Some(format!(
+                "JustSync: the workspace folder {} {}, syncing {} instead",
+                raw_root, e, fallback
+            ));
+            fallback
+        }),
+        None => cwd(),
+    };
+
+    // Send "initialize" response. Full-text changes are diffed by the document either way.
+    let sync_kind = params.capabilities.text_document_sync_kind();
+    logger::log(&format!(
+        ">> [Handler] Advertising textDocumentSync {}",
+        sync_kind
+    ));
+    // Editors only report file operations we registered for
+    let all_files = json!({ "filters": [{ "pattern": { "glob": "**/*" } }] });
     let response = json!({
         "jsonrpc": "2.0",
         "id": header.id,
         "result": {
             "capabilities": {
-                "textDocumentSync": 2 // Incremental Sync
+                "textDocumentSync": {
+                    "openClose": true,
+                    "change": sync_kind, // 1 = full, 2 = incremental
+                    "save": { "includeText": false }
+                },
+                "workspace": {
+                    "fileOperations": {
+                        "didCreate": all_files,
+                        "didDelete": all_files,
+                        "didRename": all_files
+                    }
+                }
             }
         }
     });
     write_rpc(stdout, &response.to_string()).await;
+    if let Some(message) = bad_root {
+        notify_editor(stdout, MessageKind::Error, &message).await;
+    }
 
-    (root_dir, ())
+    Ok((root_dir, params.capabilities))
 }
 
 #[cfg(test)]
@@ -259,7 +992,7 @@ mod tests {
         })
         .to_string();
 
-        process_editor_message(&msg, &tx, root_dir).await;
+        process_editor_message(&msg, &tx, root_dir, &mut EditorState::default()).await;
 
         match tokio::time::timeout(Duration::from_millis(100), rx.recv()).await {
             Ok(Some(Event::ClientDidOpen { uri, content })) => {
@@ -298,7 +1031,7 @@ mod tests {
         })
         .to_string();
 
-        process_editor_message(&msg, &tx, root_dir).await;
+        process_editor_message(&msg, &tx, root_dir, &mut EditorState::default()).await;
 
         match tokio::time::timeout(Duration::from_millis(100), rx.recv()).await {
             Ok(Some(Event::LocalChange { uri, changes })) => {
@@ -309,4 +1042,920 @@ mod tests {
             _ => panic!("Expected LocalChange"),
         }
     }
+
+    #[tokio::test]
+    async fn test_handler_full_sync_did_change() {
+        let (tx, mut rx) = mpsc::channel(10);
+
+        let msg = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didChange",
+            "params": {
+                "textDocument": {
+                    "uri": "file:///tmp/project/src/lib.rs",
+                    "version": 3
+                },
+                "contentChanges": [{ "text": "pub fn main() {}" }]
+            }
+        })
+        .to_string();
+
+        process_editor_message(&msg, &tx, "/tmp/project", &mut EditorState::default()).await;
+
+        let changes = match tokio::time::timeout(Duration::from_millis(100), rx.recv()).await {
+            Ok(Some(Event::LocalChange { changes, .. })) => changes,
+            _ => panic!("Expected LocalChange"),
+        };
+        assert!(changes[0].range.is_none());
+
+        // The document turns the whole-text change into a minimal CRDT edit
+        let mut doc = crate::state::Document::new("src/lib.rs".into(), "fn main() {}".into(), "A");
+        let mut peer = crate::state::Document::new("src/lib.rs".into(), "fn main() {}".into(), "B");
+        let patch = doc.apply_local_changes(changes).unwrap();
+        assert_eq!(doc.content.to_string(), "pub fn main() {}");
+
+        let edits = peer.apply_remote_patch(&patch).unwrap();
+        assert_eq!(peer.content.to_string(), "pub fn main() {}");
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits[0].new_text, "pub ");
+    }
+
+    #[tokio::test]
+    async fn test_handler_file_operations() {
+        let (tx, mut rx) = mpsc::channel(10);
+        let mut state = EditorState::default();
+        state.track_version("src/old.rs", 4);
+
+        for method in ["workspace/didCreateFiles", "workspace/didDeleteFiles"] {
+            let uri = if method.contains("Create") {
+                "new"
+            } else {
+                "old"
+            };
+            let msg = json!({
+                "jsonrpc": "2.0",
+                "method": method,
+                "params": { "files": [{ "uri": format!("file:///tmp/project/src/{}.rs", uri) }] }
+            })
+            .to_string();
+            process_editor_message(&msg, &tx, "/tmp/project", &mut state).await;
+        }
+
+        match rx.recv().await {
+            Some(Event::LocalFileCreated { uri }) => assert_eq!(uri, "src/new.rs"),
+            other => panic!("Expected LocalFileCreated, got {:?}", other),
+        }
+        match rx.recv().await {
+            Some(Event::LocalFileDeleted { uri }) => assert_eq!(uri, "src/old.rs"),
+            other => panic!("Expected LocalFileDeleted, got {:?}", other),
+        }
+        assert!(state.versions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_handler_did_rename_files() {
+        let (tx, mut rx) = mpsc::channel(10);
+        let mut state = EditorState::default();
+        state.track_version("src/a.rs", 7);
+
+        let msg = json!({
+            "jsonrpc": "2.0",
+            "method": "workspace/didRenameFiles",
+            "params": { "files": [{
+                "oldUri": "file:///tmp/project/src/a.rs",
+                "newUri": "file:///tmp/project/src/b.rs"
+            }] }
+        })
+        .to_string();
+        process_editor_message(&msg, &tx, "/tmp/project", &mut state).await;
+
+        match rx.recv().await {
+            Some(Event::LocalFileRenamed { from, to }) => {
+                assert_eq!(from, "src/a.rs");
+                assert_eq!(to, "src/b.rs");
+            }
+            other => panic!("Expected LocalFileRenamed, got {:?}", other),
+        }
+        assert_eq!(state.versions.get("src/b.rs"), Some(&7));
+        assert!(!state.versions.contains_key("src/a.rs"));
+    }
+
+    #[tokio::test]
+    async fn test_handler_did_save() {
+        let (tx, mut rx) = mpsc::channel(10);
+        let mut state = EditorState::default();
+        let msg = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didSave",
+            "params": { "textDocument": { "uri": "file:///tmp/project/src/main.rs" } }
+        });
+        process_editor_message(&msg.to_string(), &tx, "/tmp/project", &mut state).await;
+        match rx.recv().await {
+            Some(Event::ClientDidSave { uri }) => assert_eq!(uri, "src/main.rs"),
+            other => panic!("Expected ClientDidSave, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handler_follow_mode() {
+        let (tx, mut rx) = mpsc::channel(10);
+        let mut state = EditorState::default();
+
+        let messages = [
+            json!({
+                "jsonrpc": "2.0",
+                "method": "$/justsync/viewport",
+                "params": { "textDocument": { "uri": "file:///tmp/project/src/main.rs" }, "topLine": 40 }
+            }),
+            json!({ "jsonrpc": "2.0", "method": "$/justsync/follow", "params": { "agentId": "bob" } }),
+            json!({ "jsonrpc": "2.0", "method": "$/justsync/follow", "params": { "agentId": null } }),
+        ];
+        for msg in messages {
+            process_editor_message(&msg.to_string(), &tx, "/tmp/project", &mut state).await;
+        }
+
+        match rx.recv().await {
+            Some(Event::LocalViewportChange { uri, top_line }) => {
+                assert_eq!(uri, "src/main.rs");
+                assert_eq!(top_line, 40);
+            }
+            other => panic!("Expected LocalViewportChange, got {:?}", other),
+        }
+        match rx.recv().await {
+            Some(Event::Follow { agent_id }) => assert_eq!(agent_id.as_deref(), Some("bob")),
+            other => panic!("Expected Follow, got {:?}", other),
+        }
+        match rx.recv().await {
+            Some(Event::Follow { agent_id }) => assert!(agent_id.is_none()),
+            other => panic!("Expected Follow, got {:?}", other),
+        }
+
+        let mut out = Vec::new();
+        send_reveal_to_editor(&mut out, "src/lib.rs", 12, "/tmp/project").await;
+        let msg = parse_rpc(&out);
+        assert_eq!(msg["method"], "$/justsync/reveal");
+        assert_eq!(msg["params"]["uri"], "file:///tmp/project/src/lib.rs");
+        assert_eq!(msg["params"]["line"], 12);
+    }
+
+    #[tokio::test]
+    async fn test_handler_join_request() {
+        let (tx, mut rx) = mpsc::channel(10);
+        let mut state = EditorState::default();
+
+        let msg = json!({
+            "jsonrpc": "2.0",
+            "method": "$/justsync/join",
+            "params": { "ip": "10.0.0.5", "token": "abc" }
+        });
+        process_editor_message(&msg.to_string(), &tx, "/tmp/project", &mut state).await;
+
+        match rx.recv().await {
+            Some(Event::JoinSession { addr, token }) => {
+                assert_eq!(addr, "10.0.0.5");
+                assert_eq!(token.as_deref(), Some("abc"));
+            }
+            other => panic!("Expected JoinSession, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handler_resync_request() {
+        let (tx, mut rx) = mpsc::channel(10);
+        let mut state = EditorState::default();
+
+        let msg = json!({
+            "jsonrpc": "2.0",
+            "id": 3,
+            "method": "$/justsync/resync",
+            "params": { "textDocument": { "uri": "file:///tmp/project/src/lib.rs" } }
+        });
+        process_editor_message(&msg.to_string(), &tx, "/tmp/project", &mut state).await;
+
+        match rx.recv().await {
+            Some(Event::ResyncRequest { uri, id }) => {
+                assert_eq!(uri, "src/lib.rs");
+                assert_eq!(id, json!(3));
+            }
+            other => panic!("Expected ResyncRequest, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handler_selections() {
+        let (tx, mut rx) = mpsc::channel(10);
+        let mut state = EditorState::default();
+        let msg = json!({
+            "jsonrpc": "2.0",
+            "method": "$/justsync/selection",
+            "params": {
+                "textDocument": { "uri": "file:///tmp/project/main.rs" },
+                "range": { "start": { "line": 1, "character": 0 }, "end": { "line": 2, "character": 4 } }
+            }
+        });
+        process_editor_message(&msg.to_string(), &tx, "/tmp/project", &mut state).await;
+        match rx.recv().await {
+            Some(Event::LocalSelectionChange { uri, range }) => {
+                assert_eq!(uri, "main.rs");
+                assert_eq!(range.unwrap().end.character, 4);
+            }
+            other => panic!("Expected LocalSelectionChange, got {:?}", other),
+        }
+
+        let mut out = Vec::new();
+        send_range_to_editor(
+            &mut out,
+            "$/justsync/selection",
+            "main.rs",
+            "bob",
+            "Bob",
+            None,
+            "/tmp/project",
+        )
+        .await;
+        let msg = parse_rpc(&out);
+        assert_eq!(msg["method"], "$/justsync/selection");
+        assert_eq!(msg["params"]["agentId"], "bob");
+        assert!(msg["params"]["range"].is_null());
+        assert_eq!(msg["params"]["color"], agent_color("bob"));
+    }
+
+    #[tokio::test]
+    async fn test_handler_status_request() {
+        let (tx, mut rx) = mpsc::channel(10);
+        let mut state = EditorState::default();
+        let msg = json!({ "jsonrpc": "2.0", "id": 9, "method": "$/justsync/status" });
+        process_editor_message(&msg.to_string(), &tx, "/tmp/project", &mut state).await;
+        match rx.recv().await {
+            Some(Event::StatusRequest { id }) => assert_eq!(id, 9),
+            other => panic!("Expected StatusRequest, got {:?}", other),
+        }
+
+        let mut out = Vec::new();
+        let files = vec![FileStatus {
+            uri: "main.rs".into(),
+            behind: 3,
+            ahead: 0,
+        }];
+        send_status_to_editor(&mut out, json!(9), Some(files), "/tmp/project").await;
+        let msg = parse_rpc(&out);
+        assert_eq!(msg["id"], 9);
+        assert_eq!(msg["result"]["connected"], true);
+        assert_eq!(msg["result"]["inSync"], false);
+        assert_eq!(
+            msg["result"]["files"][0]["uri"],
+            "file:///tmp/project/main.rs"
+        );
+        assert_eq!(msg["result"]["files"][0]["behind"], 3);
+
+        let mut out = Vec::new();
+        send_status_to_editor(&mut out, json!(10), None, "/tmp/project").await;
+        let msg = parse_rpc(&out);
+        assert_eq!(msg["result"]["connected"], false);
+        assert_eq!(msg["result"]["inSync"], false);
+    }
+
+    #[test]
+    fn test_agent_colors_are_stable() {
+        assert_eq!(agent_color("alice"), agent_color("alice"));
+        assert!(agent_color("alice").starts_with('#'));
+        // Not everyone ends up with the same color
+        let colors: std::collections::HashSet<_> = (0..32)
+            .map(|i| agent_color(&format!("agent-{}", i)))
+            .collect();
+        assert!(colors.len() > 1);
+    }
+
+    fn frame(msg: serde_json::Value) -> String {
+        let body = msg.to_string();
+        format!("Content-Length: {}\r\n\r\n{}", body.len(), body)
+    }
+
+    /// Every JSON-RPC message written to `out`.
+    fn parse_all_rpc(out: &[u8]) -> Vec<serde_json::Value> {
+        let mut text = std::str::from_utf8(out).unwrap();
+        let mut messages = Vec::new();
+        while let Some((header, rest)) = text.split_once("\r\n\r\n") {
+            let len: usize = header
+                .trim_start_matches("Content-Length: ")
+                .parse()
+                .unwrap();
+            messages.push(serde_json::from_str(&rest[..len]).unwrap());
+            text = &rest[len..];
+        }
+        messages
+    }
+
+    async fn handshake(input: String) -> (anyhow::Result<String>, Vec<serde_json::Value>) {
+        let mut reader = BufReader::new(input.as_bytes());
+        let mut out = Vec::new();
+        let result =
+            perform_initialization_handshake(&mut reader, &mut out, lsp::DEFAULT_MAX_MESSAGE_LEN)
+                .await;
+        (result.map(|(root, _)| root), parse_all_rpc(&out))
+    }
+
+    #[tokio::test]
+    async fn test_handshake_rejects_missing_params() {
+        for params in [json!(null), json!(42), json!({ "rootUri": 7 })] {
+            let msg =
+                json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": params });
+            let (result, out) = handshake(frame(msg)).await;
+            assert!(result.is_err());
+            assert_eq!(out.len(), 1);
+            assert_eq!(out[0]["id"], 1);
+            assert_eq!(out[0]["error"]["code"], -32602);
+            assert!(out[0].get("result").is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handshake_defaults_root_to_cwd() {
+        let msg = json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {} });
+        let (result, out) = handshake(frame(msg)).await;
+        // Other tests move the CWD around, so only check it's a real directory
+        assert!(std::path::Path::new(&result.unwrap()).is_absolute());
+        assert!(out[0]["result"]["capabilities"].is_object());
+        // Editors only send didSave when asked to
+        assert!(out[0]["result"]["capabilities"]["textDocumentSync"]["save"].is_object());
+    }
+
+    #[tokio::test]
+    async fn test_handshake_falls_back_from_a_bad_root() {
+        let project = tempfile::tempdir().unwrap();
+        let file = project.path().join("not-a-dir.txt");
+        std::fs::write(&file, "x").unwrap();
+        let missing = project.path().join("missing");
+        for (root, why) in [(missing, "doesn't exist"), (file, "is not a directory")] {
+            let msg = json!({
+                "jsonrpc": "2.0", "id": 1, "method": "initialize",
+                "params": { "rootUri": format!("file://{}", root.display()) }
+            });
+            let (result, out) = handshake(frame(msg)).await;
+            // Still initialized, in the directory we run in
+            assert!(std::path::Path::new(&result.unwrap()).is_absolute());
+            assert!(out[0]["result"]["capabilities"].is_object());
+            assert_eq!(out[1]["method"], "window/showMessage");
+            assert_eq!(out[1]["params"]["type"], 1);
+            let message = out[1]["params"]["message"].as_str().unwrap();
+            assert!(message.contains(why), "{}", message);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handshake_survives_early_and_broken_messages() {
+        let project = tempfile::tempdir().unwrap();
+        let root = project.path().to_string_lossy().into_owned();
+        let input = [
+            "Content-Length: 8\r\n\r\nnot json".to_string(),
+            frame(json!({ "jsonrpc": "2.0", "method": "$/justsync/cursor", "params": {} })),
+            frame(json!({ "jsonrpc": "2.0", "id": 1, "method": "textDocument/hover" })),
+            frame(json!({
+                "jsonrpc": "2.0", "id": 2, "method": "initialize",
+                "params": { "rootUri": format!("file://{}", root) }
+            })),
+        ]
+        .concat();
+        let (result, out) = handshake(input).await;
+        assert_eq!(result.unwrap(), root);
+        // The early request is turned down, then initialize succeeds
+        assert_eq!(out.len(), 2);
+        assert_eq!(out[0]["id"], 1);
+        assert_eq!(out[0]["error"]["code"], -32002);
+        assert_eq!(out[1]["id"], 2);
+        assert!(out[1]["result"].is_object());
+
+        // An editor that hangs up early is an error, not a panic
+        let (result, out) = handshake(String::new()).await;
+        assert!(result.is_err());
+        assert!(out.is_empty());
+    }
+
+    fn parse_rpc(out: &[u8]) -> serde_json::Value {
+        let text = std::str::from_utf8(out).unwrap();
+        let (_, body) = text.split_once("\r\n\r\n").unwrap();
+        serde_json::from_str(body).unwrap()
+    }
+
+    fn sample_edit() -> TextEdit {
+        TextEdit {
+            range: lsp::Range {
+                start: Position {
+                    line: 0,
+                    character: 0,
+                },
+                end: Position {
+                    line: 0,
+                    character: 0,
+                },
+            },
+            new_text: "x".into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_apply_edit_carries_tracked_version() {
+        let (tx, _rx) = mpsc::channel(10);
+        let root_dir = "/tmp/project";
+        let mut state = EditorState {
+            use_document_changes: true,
+            ..Default::default()
+        };
+
+        let change = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didChange",
+            "params": {
+                "textDocument": { "uri": "file:///tmp/project/src/lib.rs", "version": 7 },
+                "contentChanges": []
+            }
+        })
+        .to_string();
+        process_editor_message(&change, &tx, root_dir, &mut state).await;
+
+        let mut out = Vec::new();
+        send_edits_to_editor(
+            &mut out,
+            &mut state,
+            "src/lib.rs",
+            vec![sample_edit()],
+            root_dir,
+        )
+        .await;
+
+        let msg = parse_rpc(&out);
+        let doc_change = &msg["params"]["edit"]["documentChanges"][0];
+        assert_eq!(doc_change["textDocument"]["version"], 7);
+        assert_eq!(
+            doc_change["textDocument"]["uri"],
+            "file:///tmp/project/src/lib.rs"
+        );
+        assert!(msg["params"]["edit"].get("changes").is_none());
+
+        // Editor confirms the edit -> our tracked version moves ahead
+        let response = json!({
+            "jsonrpc": "2.0",
+            "id": msg["id"],
+            "result": { "applied": true }
+        })
+        .to_string();
+        process_editor_message(&response, &tx, root_dir, &mut state).await;
+        assert_eq!(state.versions.get("src/lib.rs"), Some(&8));
+
+        // A late didChange echo with the same version must not push it further
+        state.track_version("src/lib.rs", 8);
+        let mut out = Vec::new();
+        send_edits_to_editor(
+            &mut out,
+            &mut state,
+            "src/lib.rs",
+            vec![sample_edit()],
+            root_dir,
+        )
+        .await;
+        let msg = parse_rpc(&out);
+        assert_eq!(
+            msg["params"]["edit"]["documentChanges"][0]["textDocument"]["version"],
+            8
+        );
+    }
+
+    #[tokio::test]
+    async fn test_back_to_back_edits_target_successive_versions() {
+        let (tx, _rx) = mpsc::channel(10);
+        let root_dir = "/tmp/project";
+        let mut state = EditorState {
+            use_document_changes: true,
+            ..Default::default()
+        };
+        state.track_version("src/lib.rs", 7);
+
+        // Both go out before the editor answers either
+        let mut sent = Vec::new();
+        for _ in 0..2 {
+            let mut out = Vec::new();
+            send_edits_to_editor(
+                &mut out,
+                &mut state,
+                "src/lib.rs",
+                vec![sample_edit()],
+                root_dir,
+            )
+            .await;
+            sent.push(parse_rpc(&out));
+        }
+        let versions: Vec<_> = sent
+            .iter()
+            .map(|msg| {
+                msg["params"]["edit"]["documentChanges"][0]["textDocument"]["version"].clone()
+            })
+            .collect();
+        assert_eq!(versions, vec![json!(7), json!(8)]);
+
+        // The editor applies the first and refuses the second
+        let applied =
+            json!({ "jsonrpc": "2.0", "id": sent[0]["id"], "result": { "applied": true } });
+        let refused =
+            json!({ "jsonrpc": "2.0", "id": sent[1]["id"], "result": { "applied": false } });
+        process_editor_message(&applied.to_string(), &tx, root_dir, &mut state).await;
+        process_editor_message(&refused.to_string(), &tx, root_dir, &mut state).await;
+        assert_eq!(state.versions.get("src/lib.rs"), Some(&8));
+    }
+
+    #[tokio::test]
+    async fn test_apply_edit_falls_back_to_changes() {
+        let root_dir = "/tmp/project";
+        let mut state = EditorState::default();
+        state.track_version("src/lib.rs", 3);
+
+        let mut out = Vec::new();
+        send_edits_to_editor(
+            &mut out,
+            &mut state,
+            "src/lib.rs",
+            vec![sample_edit()],
+            root_dir,
+        )
+        .await;
+
+        let msg = parse_rpc(&out);
+        assert!(msg["params"]["edit"].get("documentChanges").is_none());
+        assert!(msg["params"]["edit"]["changes"]["file:///tmp/project/src/lib.rs"].is_array());
+    }
+
+    #[tokio::test]
+    async fn test_refused_apply_edit_makes_the_core_resend() {
+        use crate::lsp::{Range, TextDocumentContentChangeEvent};
+
+        let (core_tx, core_rx) = mpsc::channel(10);
+        let (net_tx, _net_rx) = mpsc::channel(10);
+        let (edit_tx, mut edit_rx) = mpsc::channel(10);
+        tokio::spawn(crate::core::Core::new("me".into(), net_tx, edit_tx).run(core_rx));
+        let root_dir = "/tmp/project";
+        let mut state = EditorState::default();
+
+        let at = |character| Position { line: 0, character };
+        let mut peer = crate::state::Document::new("a.rs".into(), "hello".into(), "peer");
+        let patch = peer
+            .apply_local_changes(vec![TextDocumentContentChangeEvent {
+                range: Some(Range {
+                    start: at(5),
+                    end: at(5),
+                }),
+                text: " world".into(),
+            }])
+            .unwrap();
+        core_tx
+            .send(Event::ClientDidOpen {
+                uri: "a.rs".into(),
+                content: "hello".into(),
+            })
+            .await
+            .unwrap();
+        core_tx
+            .send(Event::RemotePatch {
+                uri: "a.rs".into(),
+                patch,
+                peer: 1,
+                agent_id: String::new(),
+            })
+            .await
+            .unwrap();
+
+        // The editor refuses the update it is sent
+        let Ok(Some(EditorCommand::ApplyEdits { uri, edits })) =
+            tokio::time::timeout(Duration::from_secs(1), edit_rx.recv()).await
+        else {
+            panic!("Expected ApplyEdits");
+        };
+        let mut out = Vec::new();
+        send_edits_to_editor(&mut out, &mut state, &uri, edits, root_dir).await;
+        let refusal = json!({ "jsonrpc": "2.0", "id": parse_rpc(&out)["id"], "result": { "applied": false } });
+        process_editor_message(&refusal.to_string(), &core_tx, root_dir, &mut state).await;
+
+        // So it gets the missing text again
+        match tokio::time::timeout(Duration::from_secs(1), edit_rx.recv()).await {
+            Ok(Some(EditorCommand::ApplyEdits { uri, edits })) => {
+                assert_eq!(uri, "a.rs");
+                assert_eq!(edits[0].new_text, " world");
+            }
+            res => panic!("Expected ApplyEdits again, got {:?}", res),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_refused_apply_edit_is_retried() {
+        let (tx, mut rx) = mpsc::channel(10);
+        let root_dir = "/tmp/project";
+        let mut state = EditorState {
+            use_document_changes: true,
+            ..Default::default()
+        };
+        state.track_version("src/lib.rs", 7);
+
+        let mut sent = Vec::new();
+        for _ in 0..=MAX_EDIT_RETRIES {
+            let mut out = Vec::new();
+            send_edits_to_editor(
+                &mut out,
+                &mut state,
+                "src/lib.rs",
+                vec![sample_edit()],
+                root_dir,
+            )
+            .await;
+            sent.push(parse_rpc(&out)["id"].clone());
+        }
+
+        // The user typed meanwhile, so the editor refuses. Errors count the same.
+        let refusals = sent.iter().enumerate().map(|(i, id)| {
+            if i % 2 == 0 {
+                json!({ "jsonrpc": "2.0", "id": id, "result": { "applied": false } })
+            } else {
+                json!({ "jsonrpc": "2.0", "id": id, "error": { "code": -32800, "message": "stale" } })
+            }
+        });
+        for refusal in refusals.take(MAX_EDIT_RETRIES as usize) {
+            process_editor_message(&refusal.to_string(), &tx, root_dir, &mut state).await;
+            match rx.try_recv() {
+                Ok(Event::EditRejected { uri }) => assert_eq!(uri, "src/lib.rs"),
+                res => panic!("Expected EditRejected, got {:?}", res),
+            }
+        }
+        // Refused too often: give up instead of looping
+        let last = json!({ "jsonrpc": "2.0", "id": sent[MAX_EDIT_RETRIES as usize], "result": { "applied": false } });
+        process_editor_message(&last.to_string(), &tx, root_dir, &mut state).await;
+        assert!(rx.try_recv().is_err());
+        // Refusals don't move the version, nor do responses we didn't ask for
+        assert_eq!(state.versions.get("src/lib.rs"), Some(&7));
+        let stray = json!({ "jsonrpc": "2.0", "id": 99, "result": { "applied": false } });
+        process_editor_message(&stray.to_string(), &tx, root_dir, &mut state).await;
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_handler_drops_uris_that_name_no_file() {
+        let (tx, mut rx) = mpsc::channel(10);
+        let mut state = EditorState::default();
+        for uri in ["", "/", "   ", "file:///tmp/project/"] {
+            let msg = json!({
+                "jsonrpc": "2.0",
+                "method": "textDocument/didOpen",
+                "params": { "textDocument": { "uri": uri, "version": 1, "text": "x" } }
+            })
+            .to_string();
+            process_editor_message(&msg, &tx, "/tmp/project", &mut state).await;
+        }
+        let msg = json!({
+            "jsonrpc": "2.0",
+            "method": "workspace/didCreateFiles",
+            "params": { "files": [{ "uri": " " }, { "uri": "file:///tmp/project/a.rs" }] }
+        })
+        .to_string();
+        process_editor_message(&msg, &tx, "/tmp/project", &mut state).await;
+
+        match rx.recv().await {
+            Some(Event::LocalFileCreated { uri }) => assert_eq!(uri, "a.rs"),
+            other => panic!("Expected only LocalFileCreated, got {:?}", other),
+        }
+        assert!(rx.try_recv().is_err());
+        assert!(state.versions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_handler_checkpoint_and_rollback() {
+        let (tx, mut rx) = mpsc::channel(10);
+        let mut state = EditorState::default();
+        for method in ["$/justsync/checkpoint", "$/justsync/rollback"] {
+            let msg = json!({
+                "jsonrpc": "2.0",
+                "method": method,
+                "params": {
+                    "textDocument": { "uri": "file:///tmp/project/src/main.rs" },
+                    "label": "before refactor"
+                }
+            })
+            .to_string();
+            process_editor_message(&msg, &tx, "/tmp/project", &mut state).await;
+        }
+
+        match rx.recv().await {
+            Some(Event::Checkpoint { uri, label }) => {
+                assert_eq!(uri, "src/main.rs");
+                assert_eq!(label, "before refactor");
+            }
+            other => panic!("Expected Checkpoint, got {:?}", other),
+        }
+        assert!(matches!(
+            rx.recv().await,
+            Some(Event::Rollback { uri, label }) if uri == "src/main.rs" && label == "before refactor"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_notify_editor_payload() {
+        let mut out = Vec::new();
+        notify_editor(
+            &mut out,
+            MessageKind::Warning,
+            "JustSync: lost the connection to bob",
+        )
+        .await;
+        let msg = parse_rpc(&out);
+        assert_eq!(msg["jsonrpc"], "2.0");
+        assert_eq!(msg["method"], "window/showMessage");
+        assert_eq!(msg["params"]["type"], 2);
+        assert_eq!(
+            msg["params"]["message"],
+            "JustSync: lost the connection to bob"
+        );
+        assert!(msg.get("id").is_none());
+
+        let mut out = Vec::new();
+        notify_editor(&mut out, MessageKind::Log, "retrying").await;
+        let msg = parse_rpc(&out);
+        assert_eq!(msg["method"], "window/logMessage");
+        assert_eq!(msg["params"]["type"], 4);
+    }
+
+    #[tokio::test]
+    async fn test_unrelated_responses_and_requests() {
+        let (tx, mut rx) = mpsc::channel(10);
+        let root_dir = "/tmp/project";
+        let mut state = EditorState::default();
+        state.track_version("src/lib.rs", 3);
+
+        let mut out = Vec::new();
+        send_edits_to_editor(
+            &mut out,
+            &mut state,
+            "src/lib.rs",
+            vec![sample_edit()],
+            root_dir,
+        )
+        .await;
+        let id = parse_rpc(&out)["id"].clone();
+
+        // A refusal for a request that isn't ours arrives first and changes nothing
+        let other = id.as_i64().unwrap() + 1;
+        let unrelated = json!({ "jsonrpc": "2.0", "id": other, "error": { "code": -32800, "message": "cancelled" } });
+        let reply = process_editor_message(&unrelated.to_string(), &tx, root_dir, &mut state).await;
+        assert!(reply.is_none());
+        assert!(rx.try_recv().is_err());
+
+        let cancel =
+            json!({ "jsonrpc": "2.0", "method": "$/cancelRequest", "params": { "id": 4 } });
+        let reply = process_editor_message(&cancel.to_string(), &tx, root_dir, &mut state).await;
+        assert!(reply.is_none());
+
+        // Our own response is still matched
+        let ours = json!({ "jsonrpc": "2.0", "id": id, "result": { "applied": true } });
+        process_editor_message(&ours.to_string(), &tx, root_dir, &mut state).await;
+        assert!(state.pending_edits.is_empty());
+        assert!(rx.try_recv().is_err());
+
+        // Requests we don't serve are answered instead of left hanging
+        let hover =
+            json!({ "jsonrpc": "2.0", "id": "h1", "method": "textDocument/hover", "params": {} });
+        let reply = process_editor_message(&hover.to_string(), &tx, root_dir, &mut state)
+            .await
+            .unwrap();
+        assert_eq!(reply["id"], "h1");
+        assert_eq!(reply["error"]["code"], -32601);
+
+        let shutdown = json!({ "jsonrpc": "2.0", "id": 5, "method": "shutdown" });
+        let reply = process_editor_message(&shutdown.to_string(), &tx, root_dir, &mut state)
+            .await
+            .unwrap();
+        assert_eq!(reply["id"], 5);
+        assert!(reply["result"].is_null());
+        assert!(reply.get("error").is_none());
+
+        // So are requests about files outside the project, notifications aren't
+        let params = json!({ "textDocument": { "uri": "file:///etc/passwd" }, "label": "x" });
+        let outside = json!({ "jsonrpc": "2.0", "id": 6, "method": "$/justsync/checkpoint", "params": params });
+        let reply = process_editor_message(&outside.to_string(), &tx, root_dir, &mut state)
+            .await
+            .unwrap();
+        assert_eq!(reply["id"], 6);
+        assert_eq!(reply["error"]["code"], INVALID_PARAMS);
+        let outside =
+            json!({ "jsonrpc": "2.0", "method": "$/justsync/checkpoint", "params": params });
+        let reply = process_editor_message(&outside.to_string(), &tx, root_dir, &mut state).await;
+        assert!(reply.is_none());
+        assert!(rx.try_recv().is_err());
+
+        // And a resync we can't make sense of
+        let broken =
+            json!({ "jsonrpc": "2.0", "id": 7, "method": "$/justsync/resync", "params": {} });
+        let reply = process_editor_message(&broken.to_string(), &tx, root_dir, &mut state)
+            .await
+            .unwrap();
+        assert_eq!(reply["id"], 7);
+        assert_eq!(reply["error"]["code"], INVALID_PARAMS);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_truncated_did_change_is_dropped_whole() {
+        let project = tempfile::tempdir().unwrap();
+        let root = project.path().to_string_lossy().into_owned();
+        let uri = format!("file://{}/notes.md", root);
+        let change = frame(json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didChange",
+            "params": {
+                "textDocument": { "uri": uri, "version": 2 },
+                "contentChanges": [{ "text": "hello world" }]
+            }
+        }));
+        // The editor dies halfway through the body
+        let truncated = &change[..change.len() - 20];
+        let input = [
+            frame(json!({
+                "jsonrpc": "2.0", "id": 1, "method": "initialize",
+                "params": { "rootUri": format!("file://{}", root) }
+            })),
+            frame(json!({
+                "jsonrpc": "2.0",
+                "method": "textDocument/didOpen",
+                "params": {
+                    "textDocument": { "uri": uri, "languageId": "markdown", "version": 1, "text": "hello" }
+                }
+            })),
+            truncated.to_string(),
+        ]
+        .concat();
+
+        let (core_tx, core_rx) = mpsc::channel(10);
+        let (net_tx, mut net_rx) = mpsc::channel(10);
+        let (edit_tx, edit_rx) = mpsc::channel(10);
+        let core =
+            tokio::spawn(crate::core::Core::new("editor".into(), net_tx, edit_tx).run(core_rx));
+        serve(
+            BufReader::new(input.as_bytes()),
+            tokio::io::sink(),
+            core_tx,
+            edit_rx,
+            lsp::DEFAULT_MAX_MESSAGE_LEN,
+        )
+        .await;
+
+        // The core is told to stop, and never sees a patch to send
+        tokio::time::timeout(Duration::from_secs(1), core)
+            .await
+            .expect("The core wasn't shut down")
+            .unwrap();
+        while let Ok(cmd) = net_rx.try_recv() {
+            assert!(
+                !matches!(cmd, crate::network::NetworkCommand::BroadcastPatch { .. }),
+                "A partial change went out"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handler_ignores_files_outside_the_root() {
+        let (tx, mut rx) = mpsc::channel(10);
+        let root_dir = "/tmp/project";
+        let mut state = EditorState::default();
+
+        // e.g. a dependency opened through go-to-definition
+        let open = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": {
+                "textDocument": {
+                    "uri": "file:///tmp/deps/serde/src/lib.rs",
+                    "languageId": "rust",
+                    "version": 1,
+                    "text": "pub trait Serialize {}"
+                }
+            }
+        })
+        .to_string();
+        let change = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didChange",
+            "params": {
+                "textDocument": { "uri": "file:///tmp/deps/serde/src/lib.rs", "version": 2 },
+                "contentChanges": [{
+                    "range": {
+                        "start": { "line": 0, "character": 0 },
+                        "end": { "line": 0, "character": 0 }
+                    },
+                    "text": "// "
+                }]
+            }
+        })
+        .to_string();
+
+        process_editor_message(&open, &tx, root_dir, &mut state).await;
+        process_editor_message(&change, &tx, root_dir, &mut state).await;
+
+        assert!(
+            rx.try_recv().is_err(),
+            "nothing outside the root reaches the core"
+        );
+    }
 }
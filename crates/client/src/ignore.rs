@@ -0,0 +1,264 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// One line of a `.gitignore`.
+#[derive(Debug, Clone)]
+struct Rule {
+    /// Directory of the ignore file, relative to the project root ("" for the root).
+    base: String,
+    pattern: Vec<char>,
+    negated: bool,
+    dir_only: bool,
+    /// Contains a slash, so it's matched against the whole path below `base`
+    /// instead of just the file name.
+    anchored: bool,
+}
+
+/// Ignore files parsed on an earlier walk of the project, by path, with the
+/// modification time they had then.
+#[derive(Debug, Default)]
+pub struct IgnoreCache {
+    files: HashMap<PathBuf, (Option<SystemTime>, Option<IgnoreRules>)>,
+}
+
+/// Gitignore rules collected while walking the project.
+/// Later rules win over earlier ones, like in git.
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreRules {
+    rules: Vec<Rule>,
+}
+
+impl IgnoreRules {
+    /// Adds `other`'s rules after ours, so they win.
+    pub fn extend(&mut self, other: &IgnoreRules) {
+        self.rules.extend(other.rules.iter().cloned());
+    }
+
+    /// Reads the ignore file at `path`. Returns `false` if there is none.
+    /// `base` is the directory it lives in, relative to the project root.
+    pub fn add_file(&mut self, base: &str, path: &Path) -> bool {
+        match std::fs::read_to_string(path) {
+            Ok(text) => {
+                self.add_patterns(base, &text);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// `add_file`, parsing the file only if it changed since `cache` last saw it.
+    pub fn add_cached_file(&mut self, cache: &mut IgnoreCache, base: &str, path: &Path) -> bool {
+        let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        let fresh = cache
+            .files
+            .get(path)
+            .is_some_and(|(seen, _)| *seen == modified);
+        if !fresh {
+            let mut parsed = IgnoreRules::default();
+            let found = parsed.add_file(base, path).then_some(parsed);
+            cache.files.insert(path.to_path_buf(), (modified, found));
+        }
+        match &cache.files[path].1 {
+            Some(parsed) => {
+                self.extend(parsed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn add_patterns(&mut self, base: &str, text: &str) {
+        for line in text.lines() {
+            if let Some(rule) = parse_line(base, line) {
+                self.rules.push(rule);
+            }
+        }
+    }
+
+    /// `rel_path` is relative to the project root and uses forward slashes.
+    pub fn is_ignored(&self, rel_path: &str, is_dir: bool) -> bool {
+        let mut ignored = false;
+        for rule in &self.rules {
+            if rule.dir_only && !is_dir {
+                continue;
+            }
+            let below_base = if rule.base.is_empty() {
+                rel_path
+            } else {
+                match rel_path
+                    .strip_prefix(rule.base.as_str())
+                    .and_then(|rest| rest.strip_prefix('/'))
+                {
+                    Some(rest) => rest,
+                    None => continue,
+                }
+            };
+            let subject = if rule.anchored {
+                below_base
+            } else {
+                below_base.rsplit('/').next().unwrap_or(below_base)
+            };
+            let subject: Vec<char> = subject.chars().collect();
+            if glob_match(&rule.pattern, &subject) {
+                ignored = !rule.negated;
+            }
+        }
+        ignored
+    }
+}
+
+fn parse_line(base: &str, line: &str) -> Option<Rule> {
+    let line = line.trim_end();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+
+    let (negated, line) = match line.strip_prefix('!') {
+        Some(rest) => (true, rest),
+        None => (false, line.strip_prefix('\\').unwrap_or(line)),
+    };
+    let (dir_only, line) = match line.strip_suffix('/') {
+        Some(rest) => (true, rest),
+        None => (false, line),
+    };
+    let anchored = line.contains('/');
+    let line = line.strip_prefix('/').unwrap_or(line);
+    if line.is_empty() {
+        return None;
+    }
+
+    Some(Rule {
+        base: base.to_string(),
+        pattern: line.chars().collect(),
+        negated,
+        dir_only,
+        anchored,
+    })
+}
+
+/// Gitignore-style glob: `*` and `?` stay within one path segment, `**` crosses them.
+fn glob_match(pattern: &[char], text: &[char]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some('*') if pattern.get(1) == Some(&'*') => {
+            let rest = &pattern[2..];
+            // "**/" also matches zero directories
+            if let Some(after_slash) = rest.strip_prefix(&['/'])
+                && glob_match(after_slash, text)
+            {
+                return true;
+            }
+            (0..=text.len()).any(|i| glob_match(rest, &text[i..]))
+        }
+        Some('*') => {
+            let rest = &pattern[1..];
+            for i in 0..=text.len() {
+                if glob_match(rest, &text[i..]) {
+                    return true;
+                }
+                if i < text.len() && text[i] == '/' {
+                    break;
+                }
+            }
+            false
+        }
+        Some('?') => {
+            matches!(text.first(), Some(c) if *c != '/') && glob_match(&pattern[1..], &text[1..])
+        }
+        Some('[') => match (text.first(), match_class(&pattern[1..], text.first())) {
+            (Some(_), Some((true, rest))) => glob_match(rest, &text[1..]),
+            // An unclosed bracket is just a literal '['
+            (Some('['), None) => glob_match(&pattern[1..], &text[1..]),
+            _ => false,
+        },
+        Some('\\') if pattern.len() > 1 => {
+            text.first() == Some(&pattern[1]) && glob_match(&pattern[2..], &text[1..])
+        }
+        Some(c) => text.first() == Some(c) && glob_match(&pattern[1..], &text[1..]),
+    }
+}
+
+/// Matches `ch` against a `[...]` class (`pattern` starts after the '[').
+/// Returns whether it matched and the pattern after the closing ']',
+/// or `None` if the class is never closed.
+fn match_class<'a>(pattern: &'a [char], ch: Option<&char>) -> Option<(bool, &'a [char])> {
+    let ch = *ch?;
+    let (negated, mut i) = match pattern.first() {
+        Some('!') | Some('^') => (true, 1),
+        _ => (false, 0),
+    };
+    let mut matched = false;
+    let mut first = true;
+    while i < pattern.len() {
+        let c = pattern[i];
+        if c == ']' && !first {
+            return Some((matched != negated && ch != '/', &pattern[i + 1..]));
+        }
+        first = false;
+        if pattern.get(i + 1) == Some(&'-') && pattern.get(i + 2).is_some_and(|e| *e != ']') {
+            if (c..=pattern[i + 2]).contains(&ch) {
+                matched = true;
+            }
+            i += 3;
+        } else {
+            if c == ch {
+                matched = true;
+            }
+            i += 1;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(text: &str) -> IgnoreRules {
+        let mut rules = IgnoreRules::default();
+        rules.add_patterns("", text);
+        rules
+    }
+
+    #[test]
+    fn test_name_patterns_match_at_any_depth() {
+        let rules = rules("*.log\nsecret.txt\n");
+        assert!(rules.is_ignored("debug.log", false));
+        assert!(rules.is_ignored("logs/deep/trace.log", false));
+        assert!(rules.is_ignored("config/secret.txt", false));
+        assert!(!rules.is_ignored("src/main.rs", false));
+    }
+
+    #[test]
+    fn test_anchored_and_directory_patterns() {
+        let rules = rules("/build\nout/\ndocs/*.pdf\n**/cache/**\n");
+        assert!(rules.is_ignored("build", true));
+        assert!(!rules.is_ignored("src/build", true));
+        assert!(rules.is_ignored("nested/out", true));
+        assert!(!rules.is_ignored("out", false));
+        assert!(rules.is_ignored("docs/manual.pdf", false));
+        assert!(!rules.is_ignored("docs/sub/manual.pdf", false));
+        assert!(rules.is_ignored("a/cache/b/c.bin", false));
+    }
+
+    #[test]
+    fn test_negation_and_classes() {
+        let rules = rules("# comment\n*.env\n!example.env\nfile[0-9].txt\n");
+        assert!(rules.is_ignored("prod.env", false));
+        assert!(!rules.is_ignored("example.env", false));
+        assert!(rules.is_ignored("file7.txt", false));
+        assert!(!rules.is_ignored("fileX.txt", false));
+    }
+
+    #[test]
+    fn test_nested_rules_only_apply_below_their_directory() {
+        let mut rules = IgnoreRules::default();
+        rules.add_patterns("web", "/dist\n");
+        assert!(rules.is_ignored("web/dist", true));
+        assert!(!rules.is_ignored("dist", true));
+        assert!(!rules.is_ignored("webapp/dist", true));
+    }
+}
@@ -0,0 +1,27 @@
+//! The JustSync sync engine. `main.rs` is a CLI over `engine`, embedders start
+//! with `JustSync::builder()`.
+
+// Module definitions
+pub mod audit;
+pub mod chunked;
+pub mod compact;
+pub mod control;
+pub mod core;
+pub mod crypto;
+pub mod diff;
+pub mod engine;
+pub mod framing;
+pub mod fs;
+pub mod grapheme;
+pub mod handler;
+pub mod ignore;
+pub mod logger;
+pub mod lsp;
+pub mod lww;
+pub mod network;
+pub mod resume;
+pub mod state;
+pub mod transport;
+pub mod watcher;
+
+pub use engine::{Builder, Engine, JustSync, RemoteEdit};
@@ -1,33 +1,291 @@
 use std::fs::OpenOptions;
 use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-static LOG_FILE: OnceLock<String> = OnceLock::new();
+static LOG_FILE: OnceLock<PathBuf> = OnceLock::new();
+// Set once we told stderr the log file can't be written
+static FILE_UNAVAILABLE: AtomicBool = AtomicBool::new(false);
+static MAX_LEVEL: OnceLock<Level> = OnceLock::new();
+static VERBOSITY: OnceLock<Verbosity> = OnceLock::new();
+
+/// How important a message is. Set `JUSTSYNC_LOG` to one of these (e.g.
+/// `JUSTSYNC_LOG=debug`) to see everything up to that level, the default is `info`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    /// Parses a `JUSTSYNC_LOG` value, case doesn't matter.
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "error" => Some(Level::Error),
+            "warn" | "warning" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            _ => None,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+        }
+    }
+}
+
+/// How much of the log also goes to stderr (`--quiet`, `--verbose`). The log
+/// file gets everything up to `JUSTSYNC_LOG` either way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Verbosity {
+    /// Errors only
+    Quiet,
+    /// Errors and warnings
+    #[default]
+    Normal,
+    /// Everything the log file gets
+    Verbose,
+}
+
+impl Verbosity {
+    /// The most detailed level stderr gets, when the file gets up to `file`.
+    fn stderr_level(self, file: Level) -> Level {
+        match self {
+            Verbosity::Quiet => Level::Error,
+            Verbosity::Normal => Level::Warn,
+            Verbosity::Verbose => file,
+        }
+    }
+}
+
+/// Call before the first message, later calls are ignored.
+pub fn set_verbosity(verbosity: Verbosity) {
+    let _ = VERBOSITY.set(verbosity);
+}
 
 pub fn init(is_host: bool) {
     let suffix = if is_host { "host" } else { "peer" };
-    // Separate log files
-    LOG_FILE
-        .set(format!("/tmp/lsp_proxy_{}.log", suffix))
-        .unwrap();
+    let path = log_path(
+        suffix,
+        std::env::var_os("JUSTSYNC_LOG_FILE").map(PathBuf::from),
+    );
+    let _ = LOG_FILE.set(path);
 }
 
+/// `JUSTSYNC_LOG_FILE` if set, otherwise one file per role in the OS temp dir
+/// (`/tmp` isn't a thing on Windows).
+fn log_path(suffix: &str, env_override: Option<PathBuf>) -> PathBuf {
+    env_override
+        .filter(|path| !path.as_os_str().is_empty())
+        .unwrap_or_else(|| std::env::temp_dir().join(format!("lsp_proxy_{}.log", suffix)))
+}
+
+fn max_level() -> Level {
+    *MAX_LEVEL.get_or_init(|| {
+        std::env::var("JUSTSYNC_LOG")
+            .ok()
+            .and_then(|value| Level::parse(&value))
+            .unwrap_or(Level::Info)
+    })
+}
+
+/// Logs at `Level::Info`.
 pub fn log(msg: &str) {
-    let unknown_path = "/tmp/lsp_proxy_unknown.log".to_string();
-    let path = LOG_FILE.get().unwrap_or(&unknown_path);
+    log_at(Level::Info, msg);
+}
+
+pub fn warn(msg: &str) {
+    log_at(Level::Warn, msg);
+}
+
+pub fn debug(msg: &str) {
+    log_at(Level::Debug, msg);
+}
+
+pub fn log_at(level: Level, msg: &str) {
+    let path = LOG_FILE.get_or_init(|| log_path("unknown", None));
+    emit(
+        path,
+        max_level(),
+        &mut std::io::stderr(),
+        VERBOSITY
+            .get_or_init(Verbosity::default)
+            .stderr_level(max_level()),
+        level,
+        msg,
+    );
+}
+
+/// Writes `msg` to the file at `path` up to `max`, and to `stderr` up to its own level.
+fn emit(
+    path: &Path,
+    max: Level,
+    stderr: &mut dyn Write,
+    stderr_max: Level,
+    level: Level,
+    msg: &str,
+) {
+    if level > max && level > stderr_max {
+        return;
+    }
 
     // Get PID
     let pid = std::process::id();
+    let line = format!("[{}] [{}] {}", pid, level.label(), msg);
 
     // Print to stderr (captured by VS Code output panel usually)
-    eprintln!("[{}] {}", pid, msg);
+    if level <= stderr_max {
+        let _ = writeln!(stderr, "{}", line);
+    }
+    if level > max {
+        return;
+    }
+
+    // An unwritable log file must not take the daemon down, stderr has to do then
+    match OpenOptions::new().create(true).append(true).open(path) {
+        Ok(mut file) => {
+            let _ = writeln!(file, "{}", line);
+        }
+        Err(e) => {
+            if !FILE_UNAVAILABLE.swap(true, Ordering::Relaxed) {
+                eprintln!(
+                    "[{}] [WARN] Can't write log file {}: {}",
+                    pid,
+                    path.display(),
+                    e
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_levels_parse_from_env_values() {
+        assert_eq!(Level::parse("DEBUG"), Some(Level::Debug));
+        assert_eq!(Level::parse(" warn "), Some(Level::Warn));
+        assert_eq!(Level::parse("error"), Some(Level::Error));
+        assert_eq!(Level::parse("loud"), None);
+    }
+
+    #[test]
+    fn test_debug_is_suppressed_at_info() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.log");
+        let path = path.as_path();
+
+        let quiet = &mut std::io::sink();
+        emit(
+            path,
+            Level::Info,
+            quiet,
+            Level::Warn,
+            Level::Debug,
+            "hidden detail",
+        );
+        emit(path, Level::Info, quiet, Level::Warn, Level::Info, "shown");
+        emit(
+            path,
+            Level::Info,
+            quiet,
+            Level::Warn,
+            Level::Error,
+            "shown too",
+        );
+
+        let written = std::fs::read_to_string(path).unwrap();
+        assert!(!written.contains("hidden detail"));
+        assert!(written.contains("[INFO] shown"));
+        assert!(written.contains("[ERROR] shown too"));
+    }
+
+    #[test]
+    fn test_log_file_follows_env_override() {
+        let dir = tempfile::tempdir().unwrap();
+        let custom = dir.path().join("custom.log");
+
+        let path = log_path("host", Some(custom.clone()));
+        assert_eq!(path, custom);
+        emit(
+            &path,
+            Level::Info,
+            &mut std::io::sink(),
+            Level::Warn,
+            Level::Info,
+            "hello from the env",
+        );
+        let written = std::fs::read_to_string(&custom).unwrap();
+        assert!(written.contains("hello from the env"));
+
+        // Unset or empty: the OS temp dir
+        assert_eq!(
+            log_path("peer", None),
+            std::env::temp_dir().join("lsp_proxy_peer.log")
+        );
+        assert_eq!(
+            log_path("peer", Some(PathBuf::new())),
+            log_path("peer", None)
+        );
+    }
+
+    #[test]
+    fn test_unwritable_log_file_does_not_panic() {
+        let dir = tempfile::tempdir().unwrap();
+        // A directory can't be opened for appending
+        emit(
+            dir.path(),
+            Level::Info,
+            &mut std::io::sink(),
+            Level::Warn,
+            Level::Info,
+            "still alive",
+        );
+    }
+
+    #[test]
+    fn test_info_stays_off_stderr_by_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.log");
+        let stderr_max = Verbosity::default().stderr_level(Level::Info);
+
+        let mut stderr = Vec::new();
+        emit(
+            &path,
+            Level::Info,
+            &mut stderr,
+            stderr_max,
+            Level::Info,
+            "routine",
+        );
+        emit(
+            &path,
+            Level::Info,
+            &mut stderr,
+            stderr_max,
+            Level::Warn,
+            "careful",
+        );
+        let printed = String::from_utf8(stderr).unwrap();
+        assert!(!printed.contains("routine"));
+        assert!(printed.contains("[WARN] careful"));
 
-    let mut file = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(path)
-        .unwrap();
+        // The file still gets both
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert!(written.contains("[INFO] routine"));
+        assert!(written.contains("[WARN] careful"));
 
-    // Write with PID prefix
-    let _ = writeln!(file, "[{}] {}", pid, msg);
+        assert_eq!(Verbosity::Quiet.stderr_level(Level::Debug), Level::Error);
+        assert_eq!(Verbosity::Verbose.stderr_level(Level::Debug), Level::Debug);
+    }
 }
@@ -1,4 +1,5 @@
 use anyhow::{Context, Result, anyhow};
+use ropey::Rope;
 use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
 
@@ -8,6 +9,7 @@ pub struct LspHeader {
     pub method: Option<String>,
     pub id: Option<serde_json::Value>,
     pub params: Option<serde_json::Value>,
+    pub result: Option<serde_json::Value>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -20,6 +22,7 @@ pub struct DidOpenParams {
 pub struct TextDocumentItem {
     pub uri: String,
     pub text: String,
+    pub version: Option<i32>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -36,6 +39,12 @@ pub struct DidCloseParams {
     pub text_document: TextDocumentIdentifier,
 }
 
+#[derive(serde::Deserialize)]
+pub struct DidSaveParams {
+    #[serde(rename = "textDocument")]
+    pub text_document: TextDocumentIdentifier,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct TextDocumentIdentifier {
     pub uri: String,
@@ -59,12 +68,44 @@ pub struct Range {
     pub end: Position,
 }
 
+/// `character` counts UTF-16 code units, as the LSP spec demands.
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct Position {
     pub line: usize,
     pub character: usize,
 }
 
+/// Absolute char index of the UTF-16 column `utf16_col` in `line`.
+/// Out-of-range lines and columns are clamped to the end of the document.
+pub fn utf16_to_char_offset(rope: &Rope, line: usize, utf16_col: usize) -> usize {
+    // Past the last line is the end of the document, LSP range ends may point there
+    if line >= rope.len_lines() {
+        return rope.len_chars();
+    }
+    // Columns past the end of a line stay on it, in front of its line break
+    let text = rope.line(line);
+    let mut len = text.len_chars();
+    if len > 0 && text.char(len - 1) == '\n' {
+        len -= 1;
+    }
+    if len > 0 && text.char(len - 1) == '\r' {
+        len -= 1;
+    }
+    let utf16_col = utf16_col.min(text.slice(..len).len_utf16_cu());
+    let line_start = rope.char_to_utf16_cu(rope.line_to_char(line));
+    rope.utf16_cu_to_char(line_start + utf16_col)
+}
+
+/// The LSP position of an absolute char index.
+pub fn char_to_position(rope: &Rope, char_idx: usize) -> Position {
+    let line = rope.char_to_line(char_idx);
+    let line_start = rope.line_to_char(line);
+    Position {
+        line,
+        character: rope.char_to_utf16_cu(char_idx) - rope.char_to_utf16_cu(line_start),
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TextEdit {
     pub range: Range,
@@ -79,10 +120,138 @@ pub struct CursorPositionParams {
     pub position: Position,
 }
 
+/// Params of `$/justsync/selection` from the editor. `None` clears it.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SelectionParams {
+    #[serde(rename = "textDocument")]
+    pub text_document: TextDocumentIdentifier,
+    pub range: Option<Range>,
+}
+
+/// Params of `$/justsync/viewport`: the first line visible in the editor.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ViewportParams {
+    #[serde(rename = "textDocument")]
+    pub text_document: TextDocumentIdentifier,
+    #[serde(rename = "topLine")]
+    pub top_line: usize,
+}
+
+/// Params of `$/justsync/join`: the host's address (the port is optional) and its token.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct JoinParams {
+    pub ip: String,
+    pub token: Option<String>,
+}
+
+/// Params of `$/justsync/resync`: the document whose editor buffer we should overwrite.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ResyncParams {
+    #[serde(rename = "textDocument")]
+    pub text_document: TextDocumentIdentifier,
+}
+
+/// Params of `$/justsync/checkpoint` and `$/justsync/rollback`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CheckpointParams {
+    #[serde(rename = "textDocument")]
+    pub text_document: TextDocumentIdentifier,
+    pub label: String,
+}
+
+/// Params of `$/justsync/follow`. `None` stops following.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FollowParams {
+    #[serde(rename = "agentId")]
+    pub agent_id: Option<String>,
+}
+
+/// Params of `workspace/didCreateFiles` and `workspace/didDeleteFiles`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FileOperationParams {
+    pub files: Vec<FileOperation>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FileOperation {
+    pub uri: String,
+}
+
+/// Params of `workspace/didRenameFiles`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RenameFilesParams {
+    pub files: Vec<FileRename>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FileRename {
+    #[serde(rename = "oldUri")]
+    pub old_uri: String,
+    #[serde(rename = "newUri")]
+    pub new_uri: String,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct InitializeParams {
     #[serde(rename = "rootUri")]
     pub root_uri: Option<String>,
+    #[serde(default)]
+    pub capabilities: ClientCapabilities,
+}
+
+/// The subset of the editor's capabilities we actually care about.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ClientCapabilities {
+    pub workspace: Option<WorkspaceClientCapabilities>,
+    pub experimental: Option<ExperimentalClientCapabilities>,
+}
+
+/// Editors that can't produce ranged changes opt out with
+/// `"experimental": { "incrementalSync": false }`.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ExperimentalClientCapabilities {
+    #[serde(rename = "incrementalSync")]
+    pub incremental_sync: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct WorkspaceClientCapabilities {
+    #[serde(rename = "workspaceEdit")]
+    pub workspace_edit: Option<WorkspaceEditClientCapabilities>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct WorkspaceEditClientCapabilities {
+    #[serde(rename = "documentChanges")]
+    pub document_changes: Option<bool>,
+}
+
+impl ClientCapabilities {
+    /// Whether the editor accepts versioned `documentChanges` in a `WorkspaceEdit`.
+    pub fn supports_document_changes(&self) -> bool {
+        self.workspace
+            .as_ref()
+            .and_then(|w| w.workspace_edit.as_ref())
+            .and_then(|e| e.document_changes)
+            .unwrap_or(false)
+    }
+
+    /// The `textDocumentSync` kind to advertise: 2 (incremental) unless the
+    /// editor opted out, then 1 (full).
+    pub fn text_document_sync_kind(&self) -> i32 {
+        let incremental = self
+            .experimental
+            .as_ref()
+            .and_then(|e| e.incremental_sync)
+            .unwrap_or(true);
+        if incremental { 2 } else { 1 }
+    }
+}
+
+/// Result payload of a `workspace/applyEdit` response.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ApplyWorkspaceEditResult {
+    pub applied: bool,
 }
 
 #[derive(Debug, Serialize)]
@@ -95,10 +264,38 @@ pub struct ServerCapabilities {
     pub text_doc_sync: i32, // 1 = full, 2 = incremental
 }
 
+/// Largest message body taken from the editor unless `--max-message-size` says otherwise.
+/// Generous, `didOpen` carries whole files.
+pub const DEFAULT_MAX_MESSAGE_LEN: usize = 64 * 1024 * 1024;
+
+/// Refuses a `Content-Type` header naming any charset but UTF-8, none at all is fine.
+/// LSP only speaks UTF-8, `utf8` is accepted for older clients.
+fn check_charset(content_type: &str) -> Result<()> {
+    let charset = content_type
+        .split(';')
+        .skip(1)
+        .filter_map(|param| param.split_once('='))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case("charset"))
+        .map(|(_, value)| value.trim().trim_matches('"'));
+    match charset {
+        None => Ok(()),
+        Some(charset) if charset.eq_ignore_ascii_case("utf-8") => Ok(()),
+        Some(charset) if charset.eq_ignore_ascii_case("utf8") => Ok(()),
+        Some(charset) => Err(anyhow!("Unsupported charset: {}", charset)),
+    }
+}
+
+/// Reads one `Content-Length` framed message. Header lines may end in `\r\n`
+/// (as the spec says) or just `\n` (as some minimal clients send), and the
+/// empty line that ends the headers either way, so `\r\n\r\n`, `\n\n` and
+/// mixes of the two all work. The body is exactly `Content-Length` bytes,
+/// one above `max_len` is refused before we allocate for it.
 pub async fn read_message<R: AsyncRead + Unpin>(
     reader: &mut BufReader<R>,
+    max_len: usize,
 ) -> Result<Option<String>> {
     let mut content_length: Option<usize> = None;
+    let mut content_type: Option<String> = None;
     let mut header_lines_read = 0;
 
     loop {
@@ -114,11 +311,14 @@ pub async fn read_message<R: AsyncRead + Unpin>(
 
         header_lines_read += 1;
 
-        if line.trim().is_empty() {
+        let header = line.strip_suffix('\n').map_or(line.as_str(), |line| {
+            line.strip_suffix('\r').unwrap_or(line)
+        });
+        if header.trim().is_empty() {
             break;
         }
 
-        let Some((key, value)) = line.split_once(':') else {
+        let Some((key, value)) = header.split_once(':') else {
             continue;
         };
         if key.trim().eq_ignore_ascii_case("content-length") {
@@ -128,12 +328,25 @@ pub async fn read_message<R: AsyncRead + Unpin>(
                     .parse()
                     .context("Content-Length header is not a number")?,
             );
+        } else if key.trim().eq_ignore_ascii_case("content-type") {
+            content_type = Some(value.trim().to_string());
         }
     }
 
     let length = content_length.ok_or_else(|| anyhow!("Missing Content-Length header"))?;
+    if length > max_len {
+        return Err(anyhow!(
+            "Message of {} bytes exceeds the limit of {} bytes",
+            length,
+            max_len
+        ));
+    }
     let mut body_buffer = vec![0; length];
     reader.read_exact(&mut body_buffer).await?;
+    // Read the body first, so the stream is still at a message boundary
+    if let Some(content_type) = &content_type {
+        check_charset(content_type)?;
+    }
     let body = String::from_utf8(body_buffer).context("LSP body was not valid UTF-8")?;
 
     Ok(Some(body))
@@ -145,10 +358,49 @@ mod tests {
     use std::io::Cursor;
     use tokio::io::BufReader;
 
+    #[test]
+    fn test_utf16_columns_roundtrip() {
+        let rope = Rope::from_str("ab\n😀let x = 1\n");
+
+        // Line 1: the emoji takes columns 0-1, "l" starts at column 2
+        assert_eq!(utf16_to_char_offset(&rope, 1, 2), 4);
+        assert_eq!(
+            char_to_position(&rope, 4),
+            Position {
+                line: 1,
+                character: 2
+            }
+        );
+
+        // Past the end of the document clamps
+        assert_eq!(utf16_to_char_offset(&rope, 9, 0), rope.len_chars());
+
+        // Past the end of a line stays on it, whatever its line break
+        let rope = Rope::from_str("ab\r\ncd\nef");
+        assert_eq!(utf16_to_char_offset(&rope, 0, 9), 2);
+        assert_eq!(utf16_to_char_offset(&rope, 1, 9), 6);
+        assert_eq!(utf16_to_char_offset(&rope, 2, 9), 9);
+        // One past the last line is the end, even without a trailing newline
+        assert_eq!(utf16_to_char_offset(&rope, 3, 0), 9);
+    }
+
+    #[test]
+    fn test_sync_kind_follows_capabilities() {
+        let parse = |caps: serde_json::Value| -> ClientCapabilities {
+            serde_json::from_value(caps).unwrap()
+        };
+        assert_eq!(parse(serde_json::json!({})).text_document_sync_kind(), 2);
+        assert_eq!(
+            parse(serde_json::json!({ "experimental": { "incrementalSync": false } }))
+                .text_document_sync_kind(),
+            1
+        );
+    }
+
     async fn run_parser(input: &[u8]) -> Result<Option<String>> {
         let cursor = Cursor::new(input);
         let mut reader = BufReader::new(cursor);
-        read_message(&mut reader).await
+        read_message(&mut reader, DEFAULT_MAX_MESSAGE_LEN).await
     }
 
     // =========================================================================
@@ -222,6 +474,38 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_error_oversized_content_length() {
+        let input = b"Content-Length: 999999999999\r\n\r\nHello";
+        let err = run_parser(input).await.unwrap_err();
+        assert!(err.to_string().contains("exceeds the limit"));
+
+        let mut reader = BufReader::new(Cursor::new(&b"Content-Length: 5\r\n\r\nHello"[..]));
+        let err = read_message(&mut reader, 4).await.unwrap_err();
+        assert!(err.to_string().contains("exceeds the limit"));
+    }
+
+    #[tokio::test]
+    async fn test_charsets_in_content_type() {
+        let input = b"Content-Length: 2\r\nContent-Type: application/vscode-jsonrpc; charset=latin1\r\n\r\nhi";
+        let err = run_parser(input).await.unwrap_err();
+        assert_eq!(err.to_string(), "Unsupported charset: latin1");
+
+        for content_type in [
+            "application/vscode-jsonrpc; charset=utf-8",
+            "application/vscode-jsonrpc; charset=\"UTF-8\"",
+            "application/vscode-jsonrpc; charset=utf8",
+            "application/vscode-jsonrpc",
+        ] {
+            let input = format!(
+                "Content-Length: 2\r\nContent-Type: {}\r\n\r\nhi",
+                content_type
+            );
+            let result = run_parser(input.as_bytes()).await.unwrap();
+            assert_eq!(result, Some("hi".to_string()), "{}", content_type);
+        }
+    }
+
     #[tokio::test]
     async fn test_error_invalid_utf8_body() {
         let input = b"Content-Length: 2\r\n\r\n\xFF\xFF";
@@ -247,6 +531,39 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_lf_only_framing() {
+        // Back to back, the second body starts with a line break of its own
+        let input = b"Content-Length: 5\n\nHelloContent-Length: 3\nContent-Type: application/vscode-jsonrpc\n\n\n{}";
+        let mut reader = BufReader::new(Cursor::new(&input[..]));
+        assert_eq!(
+            read_message(&mut reader, DEFAULT_MAX_MESSAGE_LEN)
+                .await
+                .unwrap(),
+            Some("Hello".to_string())
+        );
+        assert_eq!(
+            read_message(&mut reader, DEFAULT_MAX_MESSAGE_LEN)
+                .await
+                .unwrap(),
+            Some("\n{}".to_string())
+        );
+        assert_eq!(
+            read_message(&mut reader, DEFAULT_MAX_MESSAGE_LEN)
+                .await
+                .unwrap(),
+            None
+        );
+
+        // CRLF headers ended by a bare LF, and the other way round
+        for input in [
+            &b"Content-Length: 2\r\n\nhi"[..],
+            &b"Content-Length: 2\n\r\nhi"[..],
+        ] {
+            assert_eq!(run_parser(input).await.unwrap(), Some("hi".to_string()));
+        }
+    }
+
     #[tokio::test]
     async fn test_colon_in_values_safe() {
         // SCENARIO: A header has multiple colons.
@@ -0,0 +1,205 @@
+use std::{cmp::Ordering, collections::BTreeMap};
+
+use serde::{Deserialize, Serialize};
+
+/// A version vector: one monotonically increasing counter per agent.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionVector(BTreeMap<String, u64>);
+
+impl VersionVector {
+    pub fn increment(&mut self, agent: &str) {
+        *self.0.entry(agent.to_string()).or_insert(0) += 1;
+    }
+
+    pub fn merge(&mut self, other: &VersionVector) {
+        for (agent, &count) in &other.0 {
+            let entry = self.0.entry(agent.clone()).or_insert(0);
+            *entry = (*entry).max(count);
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.0.values().sum()
+    }
+}
+
+/// Partial order of two vectors. `None` means the histories are concurrent.
+impl PartialOrd for VersionVector {
+    fn partial_cmp(&self, other: &VersionVector) -> Option<Ordering> {
+        let mut less = false;
+        let mut greater = false;
+        for agent in self.0.keys().chain(other.0.keys()) {
+            let a = self.0.get(agent).copied().unwrap_or(0);
+            let b = other.0.get(agent).copied().unwrap_or(0);
+            less |= a < b;
+            greater |= a > b;
+        }
+        match (less, greater) {
+            (false, false) => Some(Ordering::Equal),
+            (true, false) => Some(Ordering::Less),
+            (false, true) => Some(Ordering::Greater),
+            (true, true) => None,
+        }
+    }
+}
+
+/// What travels over the wire in simple-sync mode: the whole file, plus who wrote it when.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LwwUpdate {
+    pub content: String,
+    pub clock: VersionVector,
+    pub writer: String,
+}
+
+/// How an incoming update was resolved.
+#[derive(Debug, PartialEq, Eq)]
+pub enum MergeOutcome {
+    /// The update is older than what we have, or the same.
+    Ignored,
+    /// The update is strictly newer, take it.
+    Accepted,
+    /// Concurrent (or same-version) edits, and the remote side won. Our local version is gone.
+    Overwrote,
+    /// Concurrent (or same-version) edits, and our side won. The peer will drop theirs on receiving ours.
+    KeptLocal,
+}
+
+/// Last-writer-wins register for a single file.
+/// Used instead of the CRDT when the session runs with `--simple-sync`.
+#[derive(Debug, Clone, Default)]
+pub struct LwwRegister {
+    pub clock: VersionVector,
+    pub writer: String,
+    /// Set when a concurrent remote write replaced our content, cleared once reported.
+    pub overwrite_pending: bool,
+}
+
+impl LwwRegister {
+    /// Records a local write and returns the encoded update to broadcast.
+    pub fn record_local(&mut self, agent: &str, content: String) -> Vec<u8> {
+        self.clock.increment(agent);
+        self.writer = agent.to_string();
+        self.encode(content)
+    }
+
+    pub fn encode(&self, content: String) -> Vec<u8> {
+        let update = LwwUpdate {
+            content,
+            clock: self.clock.clone(),
+            writer: self.writer.clone(),
+        };
+        serde_json::to_vec(&update).unwrap()
+    }
+
+    pub fn decode(data: &[u8]) -> serde_json::Result<LwwUpdate> {
+        serde_json::from_slice(data)
+    }
+
+    /// Resolves an incoming update against our state, `local` is our content.
+    /// The tie-break for concurrent writes only looks at data both sides know,
+    /// so every peer picks the same winner.
+    pub fn merge(&mut self, update: &LwwUpdate, local: &str) -> MergeOutcome {
+        let outcome = match update.clock.partial_cmp(&self.clock) {
+            Some(Ordering::Less) => MergeOutcome::Ignored,
+            Some(Ordering::Equal) if update.content == local => MergeOutcome::Ignored,
+            // The same version with other content: both sides started from
+            // their own disk. The larger content wins, on both sides.
+            Some(Ordering::Equal) if update.content.as_str() > local => MergeOutcome::Overwrote,
+            Some(Ordering::Equal) => MergeOutcome::KeptLocal,
+            Some(Ordering::Greater) => MergeOutcome::Accepted,
+            None => {
+                let remote = (update.clock.total(), &update.writer);
+                let local = (self.clock.total(), &self.writer);
+                if remote > local {
+                    MergeOutcome::Overwrote
+                } else {
+                    MergeOutcome::KeptLocal
+                }
+            }
+        };
+
+        match outcome {
+            MergeOutcome::Accepted | MergeOutcome::Overwrote => {
+                self.clock.merge(&update.clock);
+                self.writer = update.writer.clone();
+                self.overwrite_pending |= outcome == MergeOutcome::Overwrote;
+            }
+            MergeOutcome::KeptLocal => self.clock.merge(&update.clock),
+            MergeOutcome::Ignored => {}
+        }
+        outcome
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_vector_ordering() {
+        let mut a = VersionVector::default();
+        let mut b = VersionVector::default();
+        assert_eq!(a.partial_cmp(&b), Some(Ordering::Equal));
+
+        a.increment("A");
+        assert_eq!(a.partial_cmp(&b), Some(Ordering::Greater));
+        assert_eq!(b.partial_cmp(&a), Some(Ordering::Less));
+
+        b.increment("B");
+        assert_eq!(a.partial_cmp(&b), None);
+
+        a.merge(&b);
+        assert_eq!(a.partial_cmp(&b), Some(Ordering::Greater));
+    }
+
+    #[test]
+    fn test_sequential_update_is_accepted() {
+        let mut host = LwwRegister::default();
+        let mut peer = LwwRegister::default();
+
+        let data = host.record_local("host", "v1".into());
+        let update = LwwRegister::decode(&data).unwrap();
+
+        assert_eq!(peer.merge(&update, ""), MergeOutcome::Accepted);
+        assert!(!peer.overwrite_pending);
+
+        // Duplicate delivery is a no-op
+        assert_eq!(peer.merge(&update, "v1"), MergeOutcome::Ignored);
+    }
+
+    #[test]
+    fn test_concurrent_writes_pick_same_winner() {
+        let mut a = LwwRegister::default();
+        let mut b = LwwRegister::default();
+
+        let from_a = LwwRegister::decode(&a.record_local("alice", "A".into())).unwrap();
+        let from_b = LwwRegister::decode(&b.record_local("bob", "B".into())).unwrap();
+
+        let on_a = a.merge(&from_b, "A");
+        let on_b = b.merge(&from_a, "B");
+
+        // Exactly one side loses its write
+        assert!(matches!(
+            (&on_a, &on_b),
+            (MergeOutcome::Overwrote, MergeOutcome::KeptLocal)
+                | (MergeOutcome::KeptLocal, MergeOutcome::Overwrote)
+        ));
+        assert_eq!(a.writer, b.writer);
+        assert_eq!(a.clock, b.clock);
+    }
+
+    #[test]
+    fn test_same_version_with_other_content_picks_same_winner() {
+        let mut a = LwwRegister::default();
+        let mut b = LwwRegister::default();
+        a.clock.increment("init");
+        b.clock.increment("init");
+
+        let from_a = LwwRegister::decode(&a.encode("from disk A".into())).unwrap();
+        let from_b = LwwRegister::decode(&b.encode("from disk B".into())).unwrap();
+
+        // Both sides end up with B's content
+        assert_eq!(a.merge(&from_b, "from disk A"), MergeOutcome::Overwrote);
+        assert_eq!(b.merge(&from_a, "from disk B"), MergeOutcome::KeptLocal);
+    }
+}
@@ -1,142 +1,341 @@
 use clap::{Arg, Command};
+use std::path::PathBuf;
 use std::process::exit;
-use tokio::sync::mpsc;
-use uuid::Uuid;
-
-// Module definitions
-pub mod core;
-pub mod crypto;
-pub mod diff;
-pub mod fs;
-pub mod handler;
-pub mod logger;
-pub mod lsp;
-pub mod network;
-pub mod state;
-
-use crate::{
-    core::{Core, Event},
-    network::NetworkCommand,
-};
+
+use justsync::{JustSync, logger, network::Timeouts, state::SyncMode};
+
+/// What we were started for.
+enum Cli {
+    Daemon(Box<Context>),
+    /// `justsync status`: ask the daemon running in this directory what it is doing.
+    Status,
+}
 
 struct Context {
     mode: String,
     remote_ip: Option<String>,
+    pair: Option<String>,
     port: u16,
     token: Option<String>,
+    password: Option<String>,
+    room: Option<String>,
+    sync_mode: SyncMode,
+    editor_buffer: usize,
+    max_patch_kib: usize,
+    max_message_kib: usize,
+    max_file_kib: usize,
+    max_sync_kib: usize,
+    audit_log: Option<PathBuf>,
+    timeouts: Timeouts,
+    watch: bool,
+    autosave: Option<u64>,
+    headless: bool,
+    observer: bool,
+    read_only: Vec<String>,
+    ignore: Vec<String>,
+    share: Vec<String>,
+    seed_from_disk: bool,
+    verbosity: logger::Verbosity,
+    name: String,
+    agent_id: Option<String>,
 }
 
 #[tokio::main]
 pub async fn main() {
-    // Setup Environment
-    let _ = rustls::crypto::ring::default_provider().install_default();
-    let ctx = parse_cmd();
+    let ctx = match parse_cmd() {
+        Cli::Daemon(ctx) => *ctx,
+        Cli::Status => exit(justsync::control::run_status().await),
+    };
     let is_host = ctx.mode == "host";
 
     // Logging init
-    crate::logger::init(is_host);
+    logger::set_verbosity(ctx.verbosity);
+    logger::init(is_host);
 
-    // Prepare crypto
-    let (server_cert, server_key, active_token) = if is_host {
-        // Host - generate everything from scratch
-        let (cert, key, token_str) = crypto::generate_cert_and_token();
-
-        // Note: It's eprintln!() so it's automatically picked up by editors (as an lsp error)
-        eprintln!("---------------------------------------------------");
-        eprintln!("🔑 SECRET TOKEN: {}", token_str);
-        eprintln!("---------------------------------------------------");
+    if let Some(path) = ctx.audit_log {
+        justsync::audit::init(path);
+    }
 
-        (Some(cert), Some(key), token_str)
+    let builder = if is_host {
+        JustSync::builder().host(ctx.port).read_only(ctx.read_only)
+    } else if let Some(session) = ctx.pair {
+        JustSync::builder().pair(session)
     } else {
-        // peer - just take token from args
-        if ctx.token.is_none() {
-            eprintln!("Fehler: Als Peer musst du --token <TOKEN> angeben!");
+        JustSync::builder().peer(ctx.remote_ip, ctx.port)
+    };
+    let mut builder = builder
+        .sync_mode(ctx.sync_mode)
+        .name(ctx.name)
+        .editor_buffer(ctx.editor_buffer)
+        .max_file_len(ctx.max_file_kib.saturating_mul(1024))
+        .max_patch_len(ctx.max_patch_kib.saturating_mul(1024))
+        .max_bulk_len(ctx.max_sync_kib.saturating_mul(1024))
+        .timeouts(ctx.timeouts)
+        .max_message_len(ctx.max_message_kib.saturating_mul(1024))
+        .state_dir(".")
+        .control_socket()
+        // Nothing works without the network, a clear line beats a panic
+        .on_error(|e| {
+            eprintln!("JustSync: {}", e);
             exit(1);
-        }
-        (None, None, ctx.token.unwrap())
+        });
+    if let Some(token) = ctx.token {
+        builder = builder.token(token);
+    }
+    if let Some(password) = ctx.password {
+        builder = builder.password(password);
+    }
+    if let Some(room) = ctx.room {
+        builder = builder.room(room);
+    }
+    if let Some(id) = ctx.agent_id {
+        builder = builder.agent_id(id);
+    }
+    let builder = builder
+        .ignore(ctx.ignore)
+        .share(ctx.share)
+        .seed_from_disk(ctx.seed_from_disk)
+        .watch(ctx.watch)
+        .headless(ctx.headless)
+        .observer(ctx.observer);
+    let builder = match ctx.autosave {
+        Some(secs) => builder.autosave(std::time::Duration::from_secs(secs)),
+        None => builder,
     };
 
-    // --- CHANNEL SETUP ---
-
-    // Core Inbox
-    let (core_tx, core_rx) = mpsc::channel::<Event>(100);
-    // Network Outbox
-    let (net_out_tx, net_out_rx) = mpsc::channel::<NetworkCommand>(100);
-    // Editor Outbox
-    let (editor_out_tx, editor_out_rx) = mpsc::channel(100);
-
-    // --- CORE ACTOR ---
-    let agent_id = Uuid::new_v4().to_string();
-    let core = Core::new(agent_id, net_out_tx, editor_out_tx);
-
-    // Host: Scan files
-    if is_host {
-        logger::log(">> [Host] Scanning workspace files...");
-        let files = crate::fs::scan_project_directory(".");
-        for (uri, content) in files {
-            let _ = core_tx.send(Event::LoadFromDisk { uri, content }).await;
+    let mut engine = match builder.start().await {
+        Ok(engine) => engine,
+        Err(e) => {
+            eprintln!("Fehler: {}", e);
+            exit(1);
         }
+    };
+    if let Some(token) = engine.token() {
+        // Note: It's eprintln!() so it's automatically picked up by editors (as an lsp error)
+        eprintln!("---------------------------------------------------");
+        eprintln!("🔑 SECRET TOKEN: {}", token);
+        eprintln!("---------------------------------------------------");
     }
 
-    // Spawn Core
-    tokio::spawn(async move {
-        core.run(core_rx).await;
-    });
-
-    // --- NETWORK ACTOR ---
-
-    let net_core_tx = core_tx.clone();
-
-    let net_mode = ctx.mode.clone();
-    let net_ip = ctx.remote_ip.clone();
-    let net_port = ctx.port;
-
-    tokio::spawn(async move {
-        crate::network::run(
-            net_mode,
-            net_ip,
-            net_port,
-            net_core_tx, // Send to Core
-            net_out_rx,  // Receive from Core
-            active_token,
-            server_cert,
-            server_key,
-        )
-        .await;
-    });
+    match engine.subscribe_remote_edits() {
+        // --- EDITOR ADAPTER (Main Thread) ---
+        Some(editor_rx) => {
+            justsync::handler::run(engine.events(), editor_rx, engine.max_message_len()).await
+        }
+        None => {
+            // Nobody edits here, run until we are told to stop
+            logger::log(&format!(
+                ">> [Host] Relaying on port {}, no editor",
+                ctx.port
+            ));
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
 
-    // --- EDITOR ADAPTER (Main Thread) ---
-    crate::handler::run(core_tx, editor_out_rx).await;
+    // Give the network a moment to tell peers why we are leaving
+    engine.shutdown().await;
 }
 
-fn parse_cmd() -> Context {
+fn parse_cmd() -> Cli {
     let matches = Command::new("JustSync")
         .version("1.0")
         .about("A real-time, editor agnostic collaboration engine")
+        .subcommand_negates_reqs(true)
+        .subcommand(
+            Command::new("status")
+                .about("Show the peers, files and traffic of the daemon running in this directory"),
+        )
         .arg(
             Arg::new("mode")
                 .long("mode")
                 .help("The daemon mode (host / peer)")
-                .required(true),
+                .required_unless_present("pair"),
         )
         .arg(
             Arg::new("remote-ip")
                 .long("remote-ip")
-                .help("The remote ip address to connect to (required for peer)")
+                .help("The host to connect to: an IPv4 or IPv6 address or a hostname, optionally with a port (a peer without one waits for the editor to join a session)")
+                .required(false),
+        )
+        .arg(
+            Arg::new("pair")
+                .long("pair")
+                .value_name("SESSION_NAME")
+                .help("Sync with the other daemon started with the same --pair on this machine, over a local socket instead of the network (instead of --mode)")
                 .required(false),
         )
         .arg(
             Arg::new("token")
                 .long("token")
-                .help("The security token (required for peer)")
+                .help("The host's security token (peer only, without it the host is not verified)")
+                .required(false),
+        )
+        .arg(
+            Arg::new("password")
+                .long("password")
+                .value_name("PW")
+                .help("A password both sides give instead of (or on top of) the token, easier to pass on by voice")
+                .required(false),
+        )
+        .arg(
+            Arg::new("room")
+                .long("room")
+                .value_name("ID")
+                .help("The room to join on a relay started with --headless (peer only), each room is its own session")
                 .required(false),
         )
         .arg(
             Arg::new("port")
                 .long("port")
-                .help("The port to listen on or connect to")
+                .help("The port to listen on or connect to (also used when --remote-ip has none)")
                 .default_value("4444")
-                .value_parser(clap::value_parser!(u16)),
+                .value_parser(parse_port),
+        )
+        .arg(
+            Arg::new("simple-sync")
+                .long("simple-sync")
+                .help("Use last-writer-wins per file instead of the CRDT (all peers must agree)")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("editor-buffer")
+                .long("editor-buffer")
+                .help("How many remote updates to hold back while the editor is starting up")
+                .default_value("256")
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("max-patch-size")
+                .long("max-patch-size")
+                .value_name("KIB")
+                .help("Largest patch taken from a peer, in KiB. Anything bigger is fetched as a full sync instead")
+                .default_value("4096")
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("max-file-size")
+                .long("max-file-size")
+                .value_name("KIB")
+                .help("Largest file taken into the session, in KiB. Bigger ones are left out of the sync")
+                .default_value("10240")
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("max-sync-size")
+                .long("max-sync-size")
+                .value_name("KIB")
+                .help("Largest full-sync message, and largest file a full sync puts back together, in KiB. Bigger workspaces are sent in several messages")
+                .default_value("102400")
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("max-message-size")
+                .long("max-message-size")
+                .value_name("KIB")
+                .help("Largest message taken from the editor, in KiB")
+                .default_value("65536")
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("audit-log")
+                .long("audit-log")
+                .value_name("PATH")
+                .help("Record who connects from where and which files they touch, as JSON lines")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("idle-timeout")
+                .long("idle-timeout")
+                .value_name("SECS")
+                .help("How long a silent connection is kept before it counts as dropped, in seconds")
+                .default_value("30")
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("keepalive")
+                .long("keepalive")
+                .value_name("SECS")
+                .help("How often an idle connection is pinged, in seconds. Must be less than half of --idle-timeout")
+                .default_value("2")
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("watch")
+                .long("watch")
+                .help("Also sync changes made to files outside the editor (git checkout, formatters, ...)")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("autosave")
+                .long("autosave")
+                .value_name("SECONDS")
+                .help("Write every changed document to disk this often, even if nobody saves it")
+                .value_parser(clap::value_parser!(u64).range(1..)),
+        )
+        .arg(
+            Arg::new("headless")
+                .long("headless")
+                .help("Run the host as a relay without an editor, documents are only kept in memory")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("observer")
+                .long("observer")
+                .help("Follow the session read-only: remote edits show up, yours are never sent or written to disk (peer only)")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("readonly")
+                .long("readonly")
+                .value_name("GLOB")
+                .help("Keep files matching this pattern (gitignore syntax) read-only for guests, can be repeated (host only)")
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            Arg::new("ignore")
+                .long("ignore")
+                .value_name("GLOB")
+                .help("Keep files matching this pattern (gitignore syntax) out of the session, on top of .gitignore and .justsyncignore, can be repeated")
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            Arg::new("share")
+                .long("share")
+                .value_name("PATH")
+                .help("Sync only this file or directory (relative to the project root) instead of the whole project, can be repeated. The ignore rules still apply")
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            Arg::new("verbose")
+                .long("verbose")
+                .help("Print everything the log file gets to stderr, not only warnings and errors")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("quiet")
+                .long("quiet")
+                .help("Print only errors to stderr, the log file still gets everything")
+                .conflicts_with("verbose")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("seed-from-disk")
+                .long("seed-from-disk")
+                .help("Share every file of the project with joining peers, not only the ones you open (host only)")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("name")
+                .long("name")
+                .help("The name the others see next to your cursor (defaults to your OS username)")
+                .required(false),
+        )
+        .arg(
+            Arg::new("agent-id")
+                .long("agent-id")
+                .value_name("ID")
+                .help("The id your edits are recorded under in the history (defaults to one saved in .justsync/agent_id)")
+                .required(false),
         )
         .arg(
             Arg::new("stdio")
@@ -146,20 +345,189 @@ fn parse_cmd() -> Context {
         )
         .get_matches();
 
-    let mode = matches.get_one::<String>("mode").unwrap().clone();
+    if matches.subcommand_matches("status").is_some() {
+        return Cli::Status;
+    }
+
+    let pair = matches.get_one::<String>("pair").cloned();
+    let mode = match &pair {
+        Some(_) => "pair".to_string(),
+        None => matches.get_one::<String>("mode").unwrap().clone(),
+    };
     let remote_ip = matches.get_one::<String>("remote-ip").cloned();
     let token = matches.get_one::<String>("token").cloned();
+    let password = matches.get_one::<String>("password").cloned();
+    let room = matches.get_one::<String>("room").cloned();
+    let agent_id = matches.get_one::<String>("agent-id").cloned();
     let port = *matches.get_one::<u16>("port").unwrap();
+    let editor_buffer = *matches.get_one::<usize>("editor-buffer").unwrap();
+    let max_patch_kib = *matches.get_one::<usize>("max-patch-size").unwrap();
+    let max_message_kib = *matches.get_one::<usize>("max-message-size").unwrap();
+    let max_file_kib = *matches.get_one::<usize>("max-file-size").unwrap();
+    let max_sync_kib = *matches.get_one::<usize>("max-sync-size").unwrap();
+    let audit_log = matches.get_one::<PathBuf>("audit-log").cloned();
+    let idle_timeout = *matches.get_one::<u64>("idle-timeout").unwrap();
+    let keepalive = *matches.get_one::<u64>("keepalive").unwrap();
+    let watch = matches.get_flag("watch");
+    let autosave = matches.get_one::<u64>("autosave").copied();
+    let headless = matches.get_flag("headless");
+    let observer = matches.get_flag("observer");
+    let read_only: Vec<String> = matches
+        .get_many::<String>("readonly")
+        .map(|globs| globs.cloned().collect())
+        .unwrap_or_default();
+    let ignore: Vec<String> = matches
+        .get_many::<String>("ignore")
+        .map(|globs| globs.cloned().collect())
+        .unwrap_or_default();
+    let share: Vec<String> = matches
+        .get_many::<String>("share")
+        .map(|paths| paths.cloned().collect())
+        .unwrap_or_default();
+    let seed_from_disk = matches.get_flag("seed-from-disk");
+    let verbosity = if matches.get_flag("quiet") {
+        logger::Verbosity::Quiet
+    } else if matches.get_flag("verbose") {
+        logger::Verbosity::Verbose
+    } else {
+        logger::Verbosity::Normal
+    };
+    let name = matches
+        .get_one::<String>("name")
+        .cloned()
+        .unwrap_or_else(default_name);
+    let sync_mode = if matches.get_flag("simple-sync") {
+        SyncMode::Simple
+    } else {
+        SyncMode::Crdt
+    };
 
-    if mode != "host" && mode != "peer" {
+    if let Some(session) = &pair {
+        if matches.get_one::<String>("mode").is_some()
+            || remote_ip.is_some()
+            || token.is_some()
+            || password.is_some()
+            || room.is_some()
+            || headless
+            || observer
+        {
+            eprintln!(
+                "--pair doesn't go with --mode, --remote-ip, --token, --password, --room, --headless or --observer."
+            );
+            exit(1);
+        }
+        if session.trim().is_empty() {
+            eprintln!("--pair needs a session name.");
+            exit(1);
+        }
+    } else if mode != "host" && mode != "peer" {
         eprintln!("Invalid mode. Use --mode host or --mode peer.");
         exit(1);
     }
+    if headless && (mode != "host" || watch || seed_from_disk) {
+        eprintln!(
+            "--headless only works with --mode host and without --watch or --seed-from-disk."
+        );
+        exit(1);
+    }
+    if observer && (mode != "peer" || watch) {
+        eprintln!("--observer only works with --mode peer and without --watch.");
+        exit(1);
+    }
+    if autosave.is_some() && (headless || observer) {
+        eprintln!(
+            "--autosave doesn't work with --headless or --observer, they never write to disk."
+        );
+        exit(1);
+    }
+    if !read_only.is_empty() && mode != "host" {
+        eprintln!("--readonly only works with --mode host.");
+        exit(1);
+    }
+    if seed_from_disk && mode != "host" {
+        eprintln!("--seed-from-disk only works with --mode host.");
+        exit(1);
+    }
+    if room.is_some() && mode != "peer" {
+        eprintln!("--room only works with --mode peer.");
+        exit(1);
+    }
+    if room.as_deref() == Some("") {
+        eprintln!("--room can't be empty.");
+        exit(1);
+    }
+    if let Some(id) = &agent_id
+        && (id.trim().is_empty() || justsync::state::is_reserved_agent(id))
+    {
+        eprintln!("--agent-id can't be empty, ROOT or init.");
+        exit(1);
+    }
+    let min_sync_kib = justsync::network::MIN_MAX_BULK_LEN / 1024;
+    if max_sync_kib < min_sync_kib {
+        eprintln!("--max-sync-size can't be below {} KiB.", min_sync_kib);
+        exit(1);
+    }
+    if password.as_deref() == Some("") {
+        eprintln!("--password can't be empty.");
+        exit(1);
+    }
+    let timeouts = match Timeouts::new(
+        std::time::Duration::from_secs(keepalive),
+        std::time::Duration::from_secs(idle_timeout),
+    ) {
+        Ok(timeouts) => timeouts,
+        Err(e) => {
+            eprintln!("Invalid --keepalive/--idle-timeout: {}.", e);
+            exit(1);
+        }
+    };
 
-    Context {
+    Cli::Daemon(Box::new(Context {
         mode,
         remote_ip,
+        pair,
         port,
         token,
+        password,
+        room,
+        sync_mode,
+        editor_buffer,
+        max_patch_kib,
+        max_message_kib,
+        max_file_kib,
+        max_sync_kib,
+        audit_log,
+        timeouts,
+        watch,
+        autosave,
+        headless,
+        observer,
+        read_only,
+        ignore,
+        share,
+        seed_from_disk,
+        verbosity,
+        name,
+        agent_id,
+    }))
+}
+
+/// The OS username, so nobody has to pass `--name` just to not be a UUID.
+fn default_name() -> String {
+    ["USER", "USERNAME"]
+        .iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|name| !name.trim().is_empty())
+        .unwrap_or_else(|| "anonymous".to_string())
+}
+
+/// Accepts 1-65535. Port 0 would make the host bind a random port nobody can find.
+fn parse_port(value: &str) -> Result<u16, String> {
+    match value.parse::<u16>() {
+        Ok(port) if port > 0 => Ok(port),
+        _ => Err(format!(
+            "'{}' is not a valid port, use a number between 1 and 65535",
+            value
+        )),
     }
 }
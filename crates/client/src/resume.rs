@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+/// A remote frontier: `(agent, seq)` pairs, the same on every peer.
+pub type Frontier = Vec<(String, usize)>;
+
+/// Handed from the host to a peer after a sync.
+/// On reconnect the peer presents it, and the host only sends what happened since.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResumeToken {
+    /// Agent id of the host that issued the token. A restarted host has a new one,
+    /// so stale tokens from an earlier session are rejected.
+    pub session: String,
+    /// Frontier of every document at the time the token was issued.
+    pub frontiers: HashMap<String, Frontier>,
+}
+
+/// How much of a document's history a peer has. `ops` counts the operations
+/// of every agent, so two summaries tell how many ops one side is missing.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionSummary {
+    pub frontier: Frontier,
+    pub ops: HashMap<String, usize>,
+}
+
+impl VersionSummary {
+    /// Number of ops `other` has that we don't.
+    pub fn missing_from(&self, other: &VersionSummary) -> usize {
+        other
+            .ops
+            .iter()
+            .map(|(agent, theirs)| theirs.saturating_sub(*self.ops.get(agent).unwrap_or(&0)))
+            .sum()
+    }
+
+    pub fn total(&self) -> usize {
+        self.ops.values().sum()
+    }
+}
+
+impl ResumeToken {
+    /// Encodes the token into an opaque string for the wire.
+    pub fn encode(&self) -> String {
+        hex::encode(serde_json::to_vec(self).unwrap())
+    }
+
+    pub fn decode(token: &str) -> Option<Self> {
+        let bytes = hex::decode(token).ok()?;
+        serde_json::from_slice(&bytes).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summaries_count_missing_ops() {
+        let summary = |ops: &[(&str, usize)]| VersionSummary {
+            frontier: Vec::new(),
+            ops: ops.iter().map(|(a, n)| (a.to_string(), *n)).collect(),
+        };
+        let host = summary(&[("init", 5), ("alice", 10), ("bob", 2)]);
+        let peer = summary(&[("init", 5), ("alice", 7), ("carol", 3)]);
+
+        assert_eq!(peer.missing_from(&host), 3 + 2);
+        assert_eq!(host.missing_from(&peer), 3);
+        assert_eq!(host.missing_from(&host), 0);
+    }
+
+    #[test]
+    fn test_token_roundtrip() {
+        let token = ResumeToken {
+            session: "host-1".into(),
+            frontiers: HashMap::from([("file:///a.txt".to_string(), vec![("A".to_string(), 41)])]),
+        };
+
+        let decoded = ResumeToken::decode(&token.encode()).unwrap();
+        assert_eq!(decoded, token);
+        assert!(ResumeToken::decode("not a token").is_none());
+    }
+}
@@ -0,0 +1,1000 @@
+use std::{
+    future::Future,
+    io::{self, Read, Write},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use rustls::pki_types::ServerName;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, watch},
+};
+
+use crate::framing::{read_frame, read_frame_up_to, write_frame, write_frame_up_to};
+use crate::network::DEFAULT_MAX_BULK_LEN;
+
+// What the network actor needs from a connection, so the same protocol runs
+// over QUIC and, where UDP doesn't get through, over TLS on TCP.
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+pub type ReadHalf = Box<dyn AsyncRead + Send + Unpin>;
+pub type WriteHalf = Box<dyn AsyncWrite + Send + Unpin>;
+/// A connection that is still doing its handshake.
+pub type Incoming = BoxFuture<'static, io::Result<Arc<dyn Connection>>>;
+
+/// Why a connection ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The other side closed it with an application close code.
+    Remote { code: u32, reason: String },
+    /// We closed it.
+    Local,
+    /// Reset, timed out or broken.
+    Lost(String),
+}
+
+impl std::fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CloseReason::Remote { code, reason } => {
+                write!(f, "closed by peer: {} (code {})", reason, code)
+            }
+            CloseReason::Local => write!(f, "closed"),
+            CloseReason::Lost(why) => write!(f, "{}", why),
+        }
+    }
+}
+
+/// One connection to the other side. It carries a single control stream for
+/// the small messages and any number of one-shot bulk messages next to it.
+pub trait Connection: Send + Sync {
+    /// Unique among the connections of this process.
+    fn id(&self) -> usize;
+    fn remote_address(&self) -> SocketAddr;
+    /// Exported from the TLS session, both ends get the same bytes. Someone
+    /// relaying between two sessions sees two different ones.
+    fn session_secret(&self) -> Option<[u8; 32]>;
+    /// Connecting side: opens the control stream.
+    fn open_control(&self) -> BoxFuture<'_, io::Result<(WriteHalf, ReadHalf)>>;
+    /// Accepting side: waits for the control stream the other side opened.
+    fn accept_control(&self) -> BoxFuture<'_, io::Result<(WriteHalf, ReadHalf)>>;
+    /// A stream for one bulk message, it's sent once the writer shuts down.
+    fn open_bulk(&self) -> BoxFuture<'_, io::Result<WriteHalf>>;
+    /// The next bulk message, `None` once the connection is gone.
+    fn accept_bulk(&self) -> BoxFuture<'_, Option<ReadHalf>>;
+    fn close(&self, code: u32, reason: &[u8]);
+    /// `None` while the connection is open.
+    fn close_reason(&self) -> Option<CloseReason>;
+    fn closed(&self) -> BoxFuture<'_, CloseReason>;
+}
+
+/// A way to reach the other side.
+pub trait Transport: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn connect(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<Arc<dyn Connection>>>;
+    /// The next connection attempt, `None` once the transport is closed.
+    fn accept(&self) -> BoxFuture<'_, Option<Incoming>>;
+}
+
+/// Every TLS connection claims to be this server, the certificate is checked against the token.
+const SERVER_NAME: &str = "localhost";
+
+/// Label of the keying material behind `Connection::session_secret`.
+const SESSION_SECRET_LABEL: &[u8] = b"EXPORTER-justsync-session";
+
+// =========================================================================
+//  QUIC
+// =========================================================================
+
+pub struct QuicConnection(pub quinn::Connection);
+
+impl From<quinn::ConnectionError> for CloseReason {
+    fn from(e: quinn::ConnectionError) -> Self {
+        match e {
+            quinn::ConnectionError::ApplicationClosed(close) => CloseReason::Remote {
+                code: close.error_code.into_inner() as u32,
+                reason: String::from_utf8_lossy(&close.reason).into_owned(),
+            },
+            quinn::ConnectionError::LocallyClosed => CloseReason::Local,
+            e => CloseReason::Lost(e.to_string()),
+        }
+    }
+}
+
+impl Connection for QuicConnection {
+    fn id(&self) -> usize {
+        self.0.stable_id()
+    }
+
+    fn remote_address(&self) -> SocketAddr {
+        self.0.remote_address()
+    }
+
+    fn session_secret(&self) -> Option<[u8; 32]> {
+        let mut secret = [0u8; 32];
+        self.0
+            .export_keying_material(&mut secret, SESSION_SECRET_LABEL, b"")
+            .ok()?;
+        Some(secret)
+    }
+
+    fn open_control(&self) -> BoxFuture<'_, io::Result<(WriteHalf, ReadHalf)>> {
+        Box::pin(async move {
+            let (send, recv) = self.0.open_bi().await?;
+            Ok((Box::new(send) as WriteHalf, Box::new(recv) as ReadHalf))
+        })
+    }
+
+    fn accept_control(&self) -> BoxFuture<'_, io::Result<(WriteHalf, ReadHalf)>> {
+        Box::pin(async move {
+            let (send, recv) = self.0.accept_bi().await?;
+            Ok((Box::new(send) as WriteHalf, Box::new(recv) as ReadHalf))
+        })
+    }
+
+    fn open_bulk(&self) -> BoxFuture<'_, io::Result<WriteHalf>> {
+        Box::pin(async move { Ok(Box::new(self.0.open_uni().await?) as WriteHalf) })
+    }
+
+    fn accept_bulk(&self) -> BoxFuture<'_, Option<ReadHalf>> {
+        Box::pin(async move {
+            let recv = self.0.accept_uni().await.ok()?;
+            Some(Box::new(recv) as ReadHalf)
+        })
+    }
+
+    fn close(&self, code: u32, reason: &[u8]) {
+        self.0.close(quinn::VarInt::from_u32(code), reason);
+    }
+
+    fn close_reason(&self) -> Option<CloseReason> {
+        self.0.close_reason().map(CloseReason::from)
+    }
+
+    fn closed(&self) -> BoxFuture<'_, CloseReason> {
+        Box::pin(async move { CloseReason::from(self.0.closed().await) })
+    }
+}
+
+pub struct QuicTransport(pub quinn::Endpoint);
+
+impl Transport for QuicTransport {
+    fn name(&self) -> &'static str {
+        "QUIC"
+    }
+
+    fn connect(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<Arc<dyn Connection>>> {
+        Box::pin(async move {
+            let connecting = self
+                .0
+                .connect(addr, SERVER_NAME)
+                .map_err(io::Error::other)?;
+            let connection = connecting.await?;
+            Ok(Arc::new(QuicConnection(connection)) as Arc<dyn Connection>)
+        })
+    }
+
+    fn accept(&self) -> BoxFuture<'_, Option<Incoming>> {
+        Box::pin(async move {
+            let incoming = self.0.accept().await?;
+            Some(Box::pin(async move {
+                let connection = incoming.await?;
+                Ok(Arc::new(QuicConnection(connection)) as Arc<dyn Connection>)
+            }) as Incoming)
+        })
+    }
+}
+
+// =========================================================================
+//  Streams (TLS over TCP)
+// =========================================================================
+
+// A byte stream has no streams of its own, so every frame on it starts with
+// one of these. Control frames are the control stream's frames, cut apart
+// and put back together on the other side.
+const KIND_CONTROL: u8 = 0;
+/// The control stream finished.
+const KIND_CONTROL_END: u8 = 1;
+/// One whole bulk message.
+const KIND_BULK: u8 = 2;
+/// `[4-byte big-endian close code][reason]`, the last frame of a connection.
+const KIND_CLOSE: u8 = 3;
+
+/// How much of the control stream may sit unread between us and the network actor.
+const CONTROL_BUFFER: usize = 64 * 1024;
+
+/// Frames waiting for the socket, and bulk messages waiting for the network
+/// actor, per stream connection. Past that the side filling the queue waits.
+const STREAM_QUEUE: usize = 256;
+
+/// Ids of stream connections. QUIC's ids are addresses, they never get this small.
+static NEXT_STREAM_ID: AtomicUsize = AtomicUsize::new(1);
+
+enum Outgoing {
+    Frame(u8, Vec<u8>),
+    Close(u32, Vec<u8>),
+    /// The reader is done, nothing more will be sent.
+    Hangup,
+}
+
+/// A connection carried by a single byte stream, see `KIND_CONTROL`.
+pub struct StreamConnection {
+    id: usize,
+    remote: SocketAddr,
+    outgoing: mpsc::Sender<Outgoing>,
+    /// The writer task, stopped outright when its queue is too full for a close.
+    writer: tokio::task::AbortHandle,
+    control: Mutex<Option<(WriteHalf, ReadHalf)>>,
+    bulk: tokio::sync::Mutex<mpsc::Receiver<Vec<u8>>>,
+    close_reason: watch::Sender<Option<CloseReason>>,
+    session_secret: Option<[u8; 32]>,
+    max_bulk_len: usize,
+}
+
+impl StreamConnection {
+    /// `session_secret` comes from whatever secures `stream`, `None` if nothing does.
+    /// Bulk messages above `max_bulk_len` are refused both ways.
+    pub fn new<S>(
+        stream: S,
+        remote: SocketAddr,
+        session_secret: Option<[u8; 32]>,
+        max_bulk_len: usize,
+    ) -> Arc<Self>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (reader, writer) = tokio::io::split(stream);
+        let (outgoing, outgoing_rx) = mpsc::channel(STREAM_QUEUE);
+        let (bulk_tx, bulk_rx) = mpsc::channel(STREAM_QUEUE);
+        let (close_reason, _) = watch::channel(None);
+
+        // The network actor sees the control stream as a pipe, we move its frames
+        let (ours, theirs) = tokio::io::duplex(CONTROL_BUFFER);
+        let (from_app, to_app) = tokio::io::split(ours);
+        let (app_read, app_write) = tokio::io::split(theirs);
+
+        // Largest frame: a full sync plus its kind byte
+        let max_frame_len = max_bulk_len + 1;
+        let writer = tokio::spawn(write_loop(writer, outgoing_rx, max_frame_len)).abort_handle();
+        tokio::spawn(forward_control(from_app, outgoing.clone()));
+        tokio::spawn(read_loop(
+            reader,
+            to_app,
+            bulk_tx,
+            close_reason.clone(),
+            outgoing.clone(),
+            writer.clone(),
+            max_frame_len,
+        ));
+
+        Arc::new(Self {
+            id: NEXT_STREAM_ID.fetch_add(1, Ordering::Relaxed),
+            remote,
+            outgoing,
+            writer,
+            control: Mutex::new(Some((Box::new(app_write), Box::new(app_read)))),
+            bulk: tokio::sync::Mutex::new(bulk_rx),
+            close_reason,
+            session_secret,
+            max_bulk_len,
+        })
+    }
+
+    /// Both ends of a stream connection share the one control stream, there is nothing to open.
+    fn take_control(&self) -> io::Result<(WriteHalf, ReadHalf)> {
+        self.control.lock().unwrap().take().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::AlreadyExists, "control stream already taken")
+        })
+    }
+}
+
+/// Records why the connection ended, unless we already know.
+fn set_close_reason(slot: &watch::Sender<Option<CloseReason>>, reason: CloseReason) {
+    slot.send_if_modified(|current| {
+        if current.is_some() {
+            return false;
+        }
+        *current = Some(reason);
+        true
+    });
+}
+
+impl Connection for StreamConnection {
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn remote_address(&self) -> SocketAddr {
+        self.remote
+    }
+
+    fn session_secret(&self) -> Option<[u8; 32]> {
+        self.session_secret
+    }
+
+    fn open_control(&self) -> BoxFuture<'_, io::Result<(WriteHalf, ReadHalf)>> {
+        Box::pin(async move { self.take_control() })
+    }
+
+    fn accept_control(&self) -> BoxFuture<'_, io::Result<(WriteHalf, ReadHalf)>> {
+        Box::pin(async move { self.take_control() })
+    }
+
+    fn open_bulk(&self) -> BoxFuture<'_, io::Result<WriteHalf>> {
+        Box::pin(async move {
+            if self.close_reason().is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "connection closed",
+                ));
+            }
+            Ok(Box::new(BulkWriter {
+                buffer: Vec::new(),
+                outgoing: self.outgoing.clone(),
+                reserve: None,
+                sent: false,
+                limit: self.max_bulk_len,
+            }) as WriteHalf)
+        })
+    }
+
+    fn accept_bulk(&self) -> BoxFuture<'_, Option<ReadHalf>> {
+        Box::pin(async move {
+            let bytes = self.bulk.lock().await.recv().await?;
+            Some(Box::new(io::Cursor::new(bytes)) as ReadHalf)
+        })
+    }
+
+    fn close(&self, code: u32, reason: &[u8]) {
+        // Queued before the reader notices and hangs up. A writer this far
+        // behind wouldn't get it out anyway.
+        if self
+            .outgoing
+            .try_send(Outgoing::Close(code, reason.to_vec()))
+            .is_err()
+        {
+            self.writer.abort();
+        }
+        set_close_reason(&self.close_reason, CloseReason::Local);
+    }
+
+    fn close_reason(&self) -> Option<CloseReason> {
+        self.close_reason.borrow().clone()
+    }
+
+    fn closed(&self) -> BoxFuture<'_, CloseReason> {
+        let mut reason = self.close_reason.subscribe();
+        Box::pin(async move {
+            match reason.wait_for(|reason| reason.is_some()).await {
+                Ok(reason) => reason.clone().unwrap_or(CloseReason::Local),
+                Err(_) => CloseReason::Local,
+            }
+        })
+    }
+}
+
+/// Collects one bulk message and sends it as a single frame on shutdown.
+struct BulkWriter {
+    buffer: Vec<u8>,
+    outgoing: mpsc::Sender<Outgoing>,
+    /// Room in the writer's queue, waited for on shutdown.
+    reserve:
+        Option<BoxFuture<'static, Result<mpsc::OwnedPermit<Outgoing>, mpsc::error::SendError<()>>>>,
+    sent: bool,
+    limit: usize,
+}
+
+impl AsyncWrite for BulkWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.buffer.len() + data.len() > this.limit {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "bulk message too large",
+            )));
+        }
+        this.buffer.extend_from_slice(data);
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.sent {
+            return Poll::Ready(Ok(()));
+        }
+        let outgoing = &this.outgoing;
+        let reserve = this
+            .reserve
+            .get_or_insert_with(|| Box::pin(outgoing.clone().reserve_owned()));
+        let permit = std::task::ready!(reserve.as_mut().poll(cx));
+        this.sent = true;
+        this.reserve = None;
+        match permit {
+            Ok(permit) => {
+                permit.send(Outgoing::Frame(KIND_BULK, std::mem::take(&mut this.buffer)));
+                Poll::Ready(Ok(()))
+            }
+            Err(_) => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+}
+
+/// The only writer of the stream, so frames never interleave.
+async fn write_loop<W: AsyncWrite + Unpin>(
+    mut writer: W,
+    mut outgoing: mpsc::Receiver<Outgoing>,
+    max_frame_len: usize,
+) {
+    while let Some(item) = outgoing.recv().await {
+        let (kind, payload, last) = match item {
+            Outgoing::Frame(kind, payload) => (kind, payload, false),
+            Outgoing::Close(code, reason) => {
+                let mut payload = code.to_be_bytes().to_vec();
+                payload.extend_from_slice(&reason);
+                (KIND_CLOSE, payload, true)
+            }
+            Outgoing::Hangup => break,
+        };
+        let mut frame = Vec::with_capacity(payload.len() + 1);
+        frame.push(kind);
+        frame.extend_from_slice(&payload);
+        if write_frame_up_to(&mut writer, &frame, max_frame_len)
+            .await
+            .is_err()
+        {
+            break;
+        }
+        if last {
+            break;
+        }
+    }
+    let _ = writer.shutdown().await;
+}
+
+/// Carries the frames the network actor writes on the control stream to the writer.
+async fn forward_control<R: AsyncRead + Unpin>(mut from_app: R, outgoing: mpsc::Sender<Outgoing>) {
+    loop {
+        match read_frame(&mut from_app).await {
+            Ok(Some(frame)) => {
+                if outgoing
+                    .send(Outgoing::Frame(KIND_CONTROL, frame))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+            Ok(None) => {
+                let _ = outgoing
+                    .send(Outgoing::Frame(KIND_CONTROL_END, Vec::new()))
+                    .await;
+                return;
+            }
+            Err(_) => return,
+        }
+    }
+}
+
+/// Sorts incoming frames until the stream ends or we close it, then records why.
+async fn read_loop<R, W>(
+    mut reader: R,
+    mut to_app: W,
+    bulk: mpsc::Sender<Vec<u8>>,
+    close_reason: watch::Sender<Option<CloseReason>>,
+    outgoing: mpsc::Sender<Outgoing>,
+    writer: tokio::task::AbortHandle,
+    max_frame_len: usize,
+) where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut closing = close_reason.subscribe();
+    let reason = loop {
+        let read = tokio::select! {
+            read = read_frame_up_to(&mut reader, max_frame_len) => read,
+            _ = closing.wait_for(|reason| reason.is_some()) => break CloseReason::Local,
+        };
+        let frame = match read {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break CloseReason::Lost("connection reset".to_string()),
+            Err(e) => break CloseReason::Lost(e.to_string()),
+        };
+        match frame.split_first() {
+            Some((&KIND_CONTROL, payload)) => {
+                // Nobody reads the control stream anymore, the rest still matters
+                let _ = write_frame(&mut to_app, payload).await;
+            }
+            Some((&KIND_CONTROL_END, _)) => {
+                let _ = to_app.shutdown().await;
+            }
+            Some((&KIND_BULK, payload)) => {
+                let _ = bulk.send(payload.to_vec()).await;
+            }
+            Some((&KIND_CLOSE, payload)) if payload.len() >= 4 => {
+                let (code, reason) = payload.split_at(4);
+                break CloseReason::Remote {
+                    code: u32::from_be_bytes(code.try_into().unwrap()),
+                    reason: String::from_utf8_lossy(reason).into_owned(),
+                };
+            }
+            _ => break CloseReason::Lost("bad frame".to_string()),
+        }
+    };
+    set_close_reason(&close_reason, reason);
+    // Like a closed QUIC connection, the control stream ends for whoever still reads it
+    let _ = to_app.shutdown().await;
+    if outgoing.try_send(Outgoing::Hangup).is_err() {
+        writer.abort();
+    }
+}
+
+/// How long a TCP connection may take to get through TLS.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How much we read from the socket or the connection at once.
+const TLS_CHUNK: usize = 16 * 1024;
+
+/// The fallback for networks that drop UDP: TLS over TCP, on the same port as QUIC.
+pub struct TcpTransport {
+    listener: Option<TcpListener>,
+    server: Option<Arc<rustls::ServerConfig>>,
+    client: Option<Arc<rustls::ClientConfig>>,
+    max_bulk_len: usize,
+}
+
+impl TcpTransport {
+    /// Accepts connections on `port`, see `StreamConnection::new` for `max_bulk_len`.
+    pub async fn listen(
+        port: u16,
+        server: rustls::ServerConfig,
+        max_bulk_len: usize,
+    ) -> io::Result<Self> {
+        let listener = TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], port))).await?;
+        Ok(Self {
+            listener: Some(listener),
+            server: Some(Arc::new(server)),
+            client: None,
+            max_bulk_len,
+        })
+    }
+
+    /// Only connects, like a peer does.
+    pub fn dialer(client: rustls::ClientConfig, max_bulk_len: usize) -> Self {
+        Self {
+            listener: None,
+            server: None,
+            client: Some(Arc::new(client)),
+            max_bulk_len,
+        }
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.listener.as_ref()?.local_addr().ok()
+    }
+}
+
+impl Transport for TcpTransport {
+    fn name(&self) -> &'static str {
+        "TCP"
+    }
+
+    fn connect(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<Arc<dyn Connection>>> {
+        Box::pin(async move {
+            let config = self.client.clone().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::Unsupported, "this transport only accepts")
+            })?;
+            let mut tcp = TcpStream::connect(addr).await?;
+            tune(&tcp);
+            let name = ServerName::try_from(SERVER_NAME).map_err(io::Error::other)?;
+            let client = rustls::ClientConnection::new(config, name).map_err(io::Error::other)?;
+            let mut tls = rustls::Connection::from(client);
+            handshake(&mut tcp, &mut tls).await?;
+            Ok(start_tls(tcp, tls, addr, self.max_bulk_len))
+        })
+    }
+
+    fn accept(&self) -> BoxFuture<'_, Option<Incoming>> {
+        Box::pin(async move {
+            let listener = self.listener.as_ref()?;
+            let config = self.server.clone()?;
+            let max_bulk_len = self.max_bulk_len;
+            let incoming: Incoming = match listener.accept().await {
+                Ok((mut tcp, addr)) => Box::pin(async move {
+                    tune(&tcp);
+                    let server = rustls::ServerConnection::new(config).map_err(io::Error::other)?;
+                    let mut tls = rustls::Connection::from(server);
+                    tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake(&mut tcp, &mut tls))
+                        .await
+                        .map_err(|_| {
+                            io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out")
+                        })??;
+                    Ok(start_tls(tcp, tls, addr, max_bulk_len))
+                }),
+                Err(e) => Box::pin(async move { Err(e) }),
+            };
+            Some(incoming)
+        })
+    }
+}
+
+/// Small messages go out right away, and a vanished peer is noticed even when we're quiet.
+fn tune(tcp: &TcpStream) {
+    let _ = tcp.set_nodelay(true);
+    let keepalive = socket2::TcpKeepalive::new().with_time(Duration::from_secs(10));
+    let _ = socket2::SockRef::from(tcp).set_tcp_keepalive(&keepalive);
+}
+
+/// Runs TLS on `tcp` in the background, the connection only sees plaintext.
+fn start_tls(
+    tcp: TcpStream,
+    mut tls: rustls::Connection,
+    remote: SocketAddr,
+    max_bulk_len: usize,
+) -> Arc<dyn Connection> {
+    // We hand rustls at most `TLS_CHUNK` at a time and flush it right after
+    tls.set_buffer_limit(None);
+    let session_secret = tls
+        .export_keying_material([0u8; 32], SESSION_SECRET_LABEL, None)
+        .ok();
+    let (plain, app) = tokio::io::duplex(CONTROL_BUFFER);
+    tokio::spawn(pump_tls(tcp, tls, plain));
+    StreamConnection::new(app, remote, session_secret, max_bulk_len)
+}
+
+/// Writes whatever TLS records rustls has queued.
+async fn flush_tls(tcp: &mut TcpStream, tls: &mut rustls::Connection) -> io::Result<()> {
+    let mut records = Vec::new();
+    while tls.wants_write() {
+        tls.write_tls(&mut records)?;
+    }
+    if !records.is_empty() {
+        tcp.write_all(&records).await?;
+    }
+    Ok(())
+}
+
+/// Feeds records from the socket to rustls.
+fn take_records(tls: &mut rustls::Connection, mut records: &[u8]) -> io::Result<()> {
+    while !records.is_empty() {
+        tls.read_tls(&mut records)?;
+        tls.process_new_packets()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    }
+    Ok(())
+}
+
+/// Moves decrypted data to the connection. `false` once the other side sent close_notify.
+async fn drain_plaintext(
+    tls: &mut rustls::Connection,
+    plain: &mut DuplexStream,
+    buffer: &mut [u8],
+) -> io::Result<bool> {
+    loop {
+        match tls.reader().read(buffer) {
+            Ok(0) => return Ok(false),
+            Ok(n) => plain.write_all(&buffer[..n]).await?,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(true),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+async fn handshake(tcp: &mut TcpStream, tls: &mut rustls::Connection) -> io::Result<()> {
+    let mut buffer = vec![0u8; TLS_CHUNK];
+    while tls.is_handshaking() {
+        flush_tls(tcp, tls).await?;
+        if !tls.is_handshaking() {
+            break;
+        }
+        let n = tcp.read(&mut buffer).await?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed during the TLS handshake",
+            ));
+        }
+        if let Err(e) = take_records(tls, &buffer[..n]) {
+            // Let the other side know what went wrong
+            let _ = flush_tls(tcp, tls).await;
+            return Err(e);
+        }
+    }
+    flush_tls(tcp, tls).await
+}
+
+/// Encrypts what the connection writes to `plain` and decrypts what arrives for it,
+/// until both directions are done.
+async fn pump_tls(mut tcp: TcpStream, mut tls: rustls::Connection, mut plain: DuplexStream) {
+    let mut from_net = vec![0u8; TLS_CHUNK];
+    let mut from_app = vec![0u8; TLS_CHUNK];
+    let mut decrypted = vec![0u8; TLS_CHUNK];
+    let (mut net_open, mut app_open) = (true, true);
+
+    // The handshake may have brought data along
+    if !matches!(
+        drain_plaintext(&mut tls, &mut plain, &mut decrypted).await,
+        Ok(true)
+    ) {
+        return;
+    }
+    while net_open || app_open {
+        if flush_tls(&mut tcp, &mut tls).await.is_err() {
+            return;
+        }
+        tokio::select! {
+            read = tcp.read(&mut from_net), if net_open => {
+                let open = match read {
+                    Ok(n) if n > 0 => match take_records(&mut tls, &from_net[..n]) {
+                        Ok(()) => drain_plaintext(&mut tls, &mut plain, &mut decrypted)
+                            .await
+                            .unwrap_or(false),
+                        Err(_) => {
+                            let _ = flush_tls(&mut tcp, &mut tls).await;
+                            return;
+                        }
+                    },
+                    _ => false,
+                };
+                if !open {
+                    net_open = false;
+                    let _ = plain.shutdown().await;
+                }
+            }
+            read = plain.read(&mut from_app), if app_open => match read {
+                Ok(n) if n > 0 => {
+                    if tls.writer().write_all(&from_app[..n]).is_err() {
+                        return;
+                    }
+                }
+                _ => {
+                    app_open = false;
+                    tls.send_close_notify();
+                    let _ = flush_tls(&mut tcp, &mut tls).await;
+                    let _ = tcp.shutdown().await;
+                }
+            },
+        }
+    }
+}
+
+// =========================================================================
+//  Local (two daemons on one machine)
+// =========================================================================
+
+/// What a local connection reports as the other side's address, it has none.
+pub const LOCAL_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0));
+
+/// Where the two daemons of `--pair session` meet. The name is hashed like
+/// the control socket's project, so any session name makes a valid path.
+pub fn pair_socket_path(session: &str) -> PathBuf {
+    let digest = ring::digest::digest(&ring::digest::SHA256, session.as_bytes());
+    let name = format!("justsync-pair-{}", hex::encode(&digest.as_ref()[..8]));
+    if cfg!(windows) {
+        PathBuf::from(format!(r"\\.\pipe\{}", name))
+    } else {
+        std::env::temp_dir().join(format!("{}.sock", name))
+    }
+}
+
+/// A Unix socket (a named pipe on Windows) between daemons on the same
+/// machine. There is no TLS: on Unix only our own user can open the socket.
+pub struct LocalTransport {
+    path: PathBuf,
+    #[cfg(unix)]
+    listener: Option<tokio::net::UnixListener>,
+    #[cfg(windows)]
+    server: Option<tokio::sync::Mutex<tokio::net::windows::named_pipe::NamedPipeServer>>,
+    max_bulk_len: usize,
+}
+
+impl LocalTransport {
+    /// Accepts connections on `path`, `AddrInUse` if another daemon already does.
+    #[cfg(unix)]
+    pub async fn listen(path: PathBuf, max_bulk_len: usize) -> io::Result<Self> {
+        use std::os::unix::fs::PermissionsExt;
+
+        if Self::reachable(&path).await {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "another daemon listens there",
+            ));
+        }
+        // A daemon that crashed leaves its socket behind
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path)?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+        Ok(Self {
+            path,
+            listener: Some(listener),
+            max_bulk_len,
+        })
+    }
+
+    /// Accepts connections on `path`, `AddrInUse` if another daemon already does.
+    #[cfg(windows)]
+    pub async fn listen(path: PathBuf, max_bulk_len: usize) -> io::Result<Self> {
+        let server = create_pipe(&path, true).map_err(|e| match e.kind() {
+            io::ErrorKind::PermissionDenied => io::Error::new(io::ErrorKind::AddrInUse, e),
+            _ => e,
+        })?;
+        Ok(Self {
+            path,
+            server: Some(tokio::sync::Mutex::new(server)),
+            max_bulk_len,
+        })
+    }
+
+    /// Only connects, to the daemon listening on `path`.
+    pub fn dialer(path: PathBuf, max_bulk_len: usize) -> Self {
+        Self {
+            path,
+            #[cfg(unix)]
+            listener: None,
+            #[cfg(windows)]
+            server: None,
+            max_bulk_len,
+        }
+    }
+
+    /// Whether a daemon listens on `path`. It sees a connection that closes
+    /// right away.
+    pub async fn reachable(path: &Path) -> bool {
+        #[cfg(unix)]
+        let reachable = tokio::net::UnixStream::connect(path).await.is_ok();
+        #[cfg(windows)]
+        let reachable = tokio::net::windows::named_pipe::ClientOptions::new()
+            .open(path)
+            .is_ok();
+        reachable
+    }
+}
+
+#[cfg(windows)]
+fn create_pipe(
+    path: &Path,
+    first: bool,
+) -> io::Result<tokio::net::windows::named_pipe::NamedPipeServer> {
+    tokio::net::windows::named_pipe::ServerOptions::new()
+        .first_pipe_instance(first)
+        .create(path)
+}
+
+impl Transport for LocalTransport {
+    fn name(&self) -> &'static str {
+        "a local socket"
+    }
+
+    /// `addr` means nothing here, there is only the one socket.
+    fn connect(&self, _addr: SocketAddr) -> BoxFuture<'_, io::Result<Arc<dyn Connection>>> {
+        Box::pin(async move {
+            #[cfg(unix)]
+            let stream = tokio::net::UnixStream::connect(&self.path).await?;
+            #[cfg(windows)]
+            let stream = tokio::net::windows::named_pipe::ClientOptions::new().open(&self.path)?;
+            let connection: Arc<dyn Connection> =
+                StreamConnection::new(stream, LOCAL_ADDR, None, self.max_bulk_len);
+            Ok(connection)
+        })
+    }
+
+    #[cfg(unix)]
+    fn accept(&self) -> BoxFuture<'_, Option<Incoming>> {
+        Box::pin(async move {
+            let listener = self.listener.as_ref()?;
+            let incoming: Incoming = match listener.accept().await {
+                Ok((stream, _)) => {
+                    let connection: Arc<dyn Connection> =
+                        StreamConnection::new(stream, LOCAL_ADDR, None, self.max_bulk_len);
+                    Box::pin(async move { Ok(connection) })
+                }
+                Err(e) => Box::pin(async move { Err(e) }),
+            };
+            Some(incoming)
+        })
+    }
+
+    #[cfg(windows)]
+    fn accept(&self) -> BoxFuture<'_, Option<Incoming>> {
+        Box::pin(async move {
+            let mut server = self.server.as_ref()?.lock().await;
+            // A pipe instance serves one client, the next one has to exist before we hand it off
+            let next = match server.connect().await {
+                Ok(()) => create_pipe(&self.path, false),
+                Err(e) => Err(e),
+            };
+            let incoming: Incoming = match next {
+                Ok(next) => {
+                    let client = std::mem::replace(&mut *server, next);
+                    let connection: Arc<dyn Connection> =
+                        StreamConnection::new(client, LOCAL_ADDR, None, self.max_bulk_len);
+                    Box::pin(async move { Ok(connection) })
+                }
+                Err(e) => Box::pin(async move { Err(e) }),
+            };
+            Some(incoming)
+        })
+    }
+}
+
+// =========================================================================
+//  In memory (tests)
+// =========================================================================
+
+/// Two ends of a session inside one process, joined by in-memory pipes
+/// instead of sockets, so tests sync without the network. See `pair`.
+pub struct InMemoryTransport {
+    /// Listening end: the pipes the dialing end hands over
+    incoming: Option<tokio::sync::Mutex<mpsc::UnboundedReceiver<DuplexStream>>>,
+    /// Dialing end
+    dial: Option<mpsc::UnboundedSender<DuplexStream>>,
+}
+
+impl InMemoryTransport {
+    /// A listening end (the host's) and the end that dials it. Bulk messages
+    /// are capped at `DEFAULT_MAX_BULK_LEN`, whatever the engines are told.
+    pub fn pair() -> (Self, Self) {
+        let (dial, incoming) = mpsc::unbounded_channel();
+        let listener = Self {
+            incoming: Some(tokio::sync::Mutex::new(incoming)),
+            dial: None,
+        };
+        let dialer = Self {
+            incoming: None,
+            dial: Some(dial),
+        };
+        (listener, dialer)
+    }
+
+    pub fn is_listener(&self) -> bool {
+        self.incoming.is_some()
+    }
+}
+
+impl Transport for InMemoryTransport {
+    fn name(&self) -> &'static str {
+        "an in-memory pipe"
+    }
+
+    /// `addr` means nothing here, there is only the other end.
+    fn connect(&self, _addr: SocketAddr) -> BoxFuture<'_, io::Result<Arc<dyn Connection>>> {
+        Box::pin(async move {
+            let dial = self.dial.as_ref().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::Unsupported, "the listening end doesn't dial")
+            })?;
+            let (ours, theirs) = tokio::io::duplex(CONTROL_BUFFER);
+            dial.send(theirs).map_err(|_| {
+                io::Error::new(io::ErrorKind::ConnectionRefused, "the other end is gone")
+            })?;
+            let connection: Arc<dyn Connection> =
+                StreamConnection::new(ours, LOCAL_ADDR, None, DEFAULT_MAX_BULK_LEN);
+            Ok(connection)
+        })
+    }
+
+    fn accept(&self) -> BoxFuture<'_, Option<Incoming>> {
+        Box::pin(async move {
+            let stream = self.incoming.as_ref()?.lock().await.recv().await?;
+            let connection: Arc<dyn Connection> =
+                StreamConnection::new(stream, LOCAL_ADDR, None, DEFAULT_MAX_BULK_LEN);
+            let incoming: Incoming = Box::pin(async move { Ok(connection) });
+            Some(incoming)
+        })
+    }
+}
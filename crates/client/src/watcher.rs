@@ -0,0 +1,218 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime},
+};
+
+use tokio::sync::mpsc;
+
+use crate::{
+    core::Event,
+    ignore::{IgnoreCache, IgnoreRules},
+    logger,
+};
+
+/// A file has to sit still this long before we report it, so a `git checkout`
+/// or a formatter touching it several times ends up as one change.
+pub const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// How often the project is checked for changes. Every check walks and stats
+/// the whole project, so this trades how soon we notice for idle CPU.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What a file looked like the last time we checked.
+/// Only files whose stamp changes get read again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Stamp {
+    modified: Option<SystemTime>,
+    len: u64,
+}
+
+/// The files being watched, and the ignore files parsed on the last check.
+struct Project {
+    root: PathBuf,
+    sync_ignore: IgnoreRules,
+    share: Vec<String>,
+    gitignores: IgnoreCache,
+}
+
+impl Project {
+    /// Stamps of every file `scan_project` would sync, keyed by relative URI.
+    fn stamps(&mut self) -> HashMap<String, Stamp> {
+        let mut stamps = HashMap::new();
+        crate::fs::walk_project_cached(
+            &self.root,
+            &self.sync_ignore,
+            &self.share,
+            &mut self.gitignores,
+            &mut |uri, path| {
+                if let Ok(meta) = std::fs::metadata(path) {
+                    let stamp = Stamp {
+                        modified: meta.modified().ok(),
+                        len: meta.len(),
+                    };
+                    stamps.insert(uri, stamp);
+                }
+            },
+        );
+        stamps
+    }
+}
+
+/// Watches `root` for changes made outside the editor and reports them as
+/// `Event::ExternalFileChange`. Polls file metadata, so it works the same on
+/// every platform and honors the ignore rules and `share` paths of the initial
+/// scan for free.
+pub async fn run(
+    root: PathBuf,
+    sync_ignore: IgnoreRules,
+    share: Vec<String>,
+    core_tx: mpsc::Sender<Event>,
+) {
+    let project = Project {
+        root,
+        sync_ignore,
+        share,
+        gitignores: IgnoreCache::default(),
+    };
+    watch(project, POLL_INTERVAL, core_tx).await;
+}
+
+async fn watch(mut project: Project, every: Duration, core_tx: mpsc::Sender<Event>) {
+    let root = project.root.clone();
+    logger::log(&format!(">> [Watch] Watching {}", root.display()));
+    let mut known = project.stamps();
+    // Changed files waiting for the debounce: uri -> last time it changed
+    let mut pending: HashMap<String, Instant> = HashMap::new();
+
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+
+        let current = project.stamps();
+        let now = Instant::now();
+        for (uri, stamp) in &current {
+            if known.get(uri) != Some(stamp) {
+                pending.insert(uri.clone(), now);
+            }
+        }
+        known = current;
+
+        let settled: Vec<String> = pending
+            .iter()
+            .filter(|(_, changed)| now.duration_since(**changed) >= DEBOUNCE)
+            .map(|(uri, _)| uri.clone())
+            .collect();
+        for uri in settled {
+            pending.remove(&uri);
+            let Some(content) = crate::fs::read_project_file_in(&root, &uri) else {
+                continue;
+            };
+            logger::log(&format!(">> [Watch] {} changed on disk", uri));
+            if core_tx
+                .send(Event::ExternalFileChange { uri, content })
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    const TEST_POLL: Duration = Duration::from_millis(50);
+
+    fn project(root: &Path) -> Project {
+        Project {
+            root: root.to_path_buf(),
+            sync_ignore: crate::fs::sync_ignore_rules(root, &[]),
+            share: Vec::new(),
+            gitignores: IgnoreCache::default(),
+        }
+    }
+
+    async fn next_change(rx: &mut mpsc::Receiver<Event>) -> Option<(String, String)> {
+        match tokio::time::timeout(Duration::from_secs(2), rx.recv()).await {
+            Ok(Some(Event::ExternalFileChange { uri, content })) => Some((uri, content)),
+            Ok(other) => panic!("Expected ExternalFileChange, got {:?}", other),
+            Err(_) => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_watcher_reports_settled_changes_once() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("src")).unwrap();
+        std::fs::write(dir.path().join("src/lib.rs"), "v1").unwrap();
+
+        let (tx, mut rx) = mpsc::channel(10);
+        let watcher = tokio::spawn(watch(project(dir.path()), TEST_POLL, tx));
+        tokio::time::sleep(TEST_POLL * 2).await;
+
+        // A burst of writes is reported once, with the final content
+        for version in ["v2", "v3 longer", "v4"] {
+            std::fs::write(dir.path().join("src/lib.rs"), version).unwrap();
+            tokio::time::sleep(Duration::from_millis(30)).await;
+        }
+        assert_eq!(
+            next_change(&mut rx).await,
+            Some(("src/lib.rs".to_string(), "v4".to_string()))
+        );
+
+        // New files show up too
+        std::fs::write(dir.path().join("new.rs"), "fresh").unwrap();
+        assert_eq!(
+            next_change(&mut rx).await,
+            Some(("new.rs".to_string(), "fresh".to_string()))
+        );
+        assert!(rx.try_recv().is_err());
+
+        watcher.abort();
+    }
+
+    #[tokio::test]
+    async fn test_watcher_respects_ignore_rules() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join(".gitignore"), "*.log\n").unwrap();
+
+        let (tx, mut rx) = mpsc::channel(10);
+        let watcher = tokio::spawn(watch(project(dir.path()), TEST_POLL, tx));
+        tokio::time::sleep(TEST_POLL * 2).await;
+
+        std::fs::write(dir.path().join("build.log"), "noise").unwrap();
+        std::fs::write(dir.path().join(".env"), "SECRET=1").unwrap();
+        std::fs::write(dir.path().join("main.rs"), "fn main() {}").unwrap();
+
+        assert_eq!(
+            next_change(&mut rx).await,
+            Some(("main.rs".to_string(), "fn main() {}".to_string()))
+        );
+        tokio::time::sleep(DEBOUNCE * 2).await;
+        assert!(rx.try_recv().is_err());
+
+        watcher.abort();
+    }
+
+    #[test]
+    fn test_ignore_files_are_reparsed_only_when_they_change() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join(".gitignore"), "*.log\n").unwrap();
+        std::fs::write(dir.path().join("build.log"), "noise").unwrap();
+        std::fs::write(dir.path().join("main.rs"), "").unwrap();
+
+        let mut project = project(dir.path());
+        assert!(!project.stamps().contains_key("build.log"));
+
+        // Editing the ignore file takes effect on the next check
+        std::thread::sleep(Duration::from_millis(20));
+        std::fs::write(dir.path().join(".gitignore"), "main.rs\n").unwrap();
+        let stamps = project.stamps();
+        assert!(stamps.contains_key("build.log"));
+        assert!(!stamps.contains_key("main.rs"));
+    }
+}
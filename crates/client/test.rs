@@ -0,0 +1 @@
+hello
\ No newline at end of file
@@ -3,6 +3,8 @@ local M = {}
 M.config = {
     cmd_path = "justsync", 
     log_level = vim.log.levels.INFO,
+    -- Port to host on, and to connect to when the host IP has none
+    port = 4444,
 }
 
 M.autocmd_registered = false
@@ -30,21 +32,38 @@ local function status_msg(msg, is_error)
     vim.api.nvim_echo({{prefix, "Identifier"}, {msg, hl}}, true, {})
 end
 
+-- Extmarks per remote agent: agent_id -> { bufnr = ..., id = ..., label_id = ... }
+local remote_marks = {}
+
+local function clear_remote_cursor(agent_id)
+    local mark = remote_marks[agent_id]
+    if mark and vim.api.nvim_buf_is_valid(mark.bufnr) then
+        pcall(vim.api.nvim_buf_del_extmark, mark.bufnr, ns_id, mark.id)
+        if mark.label_id then
+            pcall(vim.api.nvim_buf_del_extmark, mark.bufnr, ns_id, mark.label_id)
+        end
+    end
+    remote_marks[agent_id] = nil
+end
+
 local function handle_remote_cursor(err, result, ctx, config)
     if err then return end
-    if not result or not result.uri or not result.position then return end
+    if not result or not result.uri then return end
 
-    local raw_uri = result.uri
+    local agent_id = result.agentId or ""
     local position = result.position
+    clear_remote_cursor(agent_id)
+    -- A null position means the cursor left (e.g. its peer disconnected)
+    if position == nil or position == vim.NIL then return end
+
+    local raw_uri = result.uri
     local uri = raw_uri:match("^%w+://") and raw_uri or vim.uri_from_fname(raw_uri)
     local bufnr = vim.uri_to_bufnr(uri)
     
     if not vim.api.nvim_buf_is_loaded(bufnr) then return end
 
-    vim.api.nvim_buf_clear_namespace(bufnr, ns_id, 0, -1)
-    
     -- Draw the remote cursor
-    pcall(vim.api.nvim_buf_set_extmark, bufnr, ns_id, position.line, position.character, {
+    local ok, id = pcall(vim.api.nvim_buf_set_extmark, bufnr, ns_id, position.line, position.character, {
         end_col = position.character + 1,
         hl_group = 'JustSyncRemoteCursor',
         hl_mode = 'replace',
@@ -53,6 +72,15 @@ local function handle_remote_cursor(err, result, ctx, config)
         virt_text = {{ "┃", "JustSyncRemoteCursor" }},
         virt_text_pos = "overlay",
     })
+    if ok then
+        -- Who it is, at the end of the line
+        local name = result.displayName or agent_id
+        local _, label_id = pcall(vim.api.nvim_buf_set_extmark, bufnr, ns_id, position.line, 0, {
+            virt_text = {{ " " .. name, "Comment" }},
+            virt_text_pos = "eol",
+        })
+        remote_marks[agent_id] = { bufnr = bufnr, id = id, label_id = label_id }
+    end
 end
 
 local function scan_log_for_token()
@@ -165,7 +193,7 @@ local function launch_client(args, mode_name)
 end
 
 function M.host()
-    launch_client({ "--mode", "host", "--port", "4444" }, "Host")
+    launch_client({ "--mode", "host", "--port", tostring(M.config.port) }, "Host")
 end
 
 function M.join_interactive()
@@ -176,7 +204,7 @@ function M.join_interactive()
                 status_msg("Token is required!", true)
                 return
             end
-            launch_client({ "--mode", "peer", "--remote-ip", ip, "--token", token }, "Peer")
+            launch_client({ "--mode", "peer", "--remote-ip", ip, "--port", tostring(M.config.port), "--token", token }, "Peer")
         end)
     end)
 end